@@ -2,13 +2,21 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{env::var, path::PathBuf};
+use std::{
+    env::var,
+    fs::copy,
+    panic,
+    path::{Path, PathBuf},
+};
 
-use bindgen::Builder;
+use bindgen::{BindgenError, Bindings, Builder};
 
-fn main() {
-    // Generate bindings for dm-ioctl.h and libdevmapper.h
-    let bindings = Builder::default()
+// The dm-ioctl bindings shipped with the crate.
+const VENDORED_DM_BINDINGS: &str = "dm-bindings.rs";
+
+// Generate bindings for dm-ioctl.h and libdevmapper.h
+fn generate_dm_bindings() -> Result<Bindings, BindgenError> {
+    Builder::default()
         .header("dm.h")
         .allowlist_var("DM.*")
         .allowlist_type("__u16")
@@ -21,13 +29,36 @@ fn main() {
         .derive_debug(true)
         .derive_default(true)
         .generate()
-        .expect("Could not generate dm.h bindings");
+}
+
+// Write the dm-ioctl bindings: generated from the system's headers if the
+// dm-ioctl-bindgen feature is enabled and they can be found, the vendored
+// ones otherwise.
+fn write_dm_bindings(bindings_path: &Path) {
+    if cfg!(feature = "dm-ioctl-bindgen") {
+        // bindgen panics if libclang can not be loaded.
+        match panic::catch_unwind(generate_dm_bindings) {
+            Ok(Ok(bindings)) => {
+                bindings
+                    .write_to_file(bindings_path)
+                    .expect("Could not write bindings to file");
+                return;
+            }
+            Ok(Err(err)) => println!(
+                "cargo:warning=Could not generate dm.h bindings, using vendored bindings: {err}"
+            ),
+            Err(_) => {
+                println!("cargo:warning=Could not load libclang, using vendored dm.h bindings")
+            }
+        }
+    }
+    copy(VENDORED_DM_BINDINGS, bindings_path).expect("Could not write bindings to file");
+}
 
+fn main() {
     let mut bindings_path = PathBuf::from(var("OUT_DIR").unwrap());
     bindings_path.push("dm-bindings.rs");
-    bindings
-        .write_to_file(&bindings_path)
-        .expect("Could not write bindings to file");
+    write_dm_bindings(&bindings_path);
 
     // Generate bindings for SysV Semaphore IPC
     let bindings = Builder::default()
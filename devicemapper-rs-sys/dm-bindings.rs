@@ -0,0 +1,156 @@
+/* The dm-ioctl bindings used unless the dm-ioctl-bindgen feature is enabled,
+ * as generated by rust-bindgen from linux/dm-ioctl.h (interface version
+ * 4.47.0) and the udev flags of libdevmapper.h. */
+
+pub const DM_DIR: &[u8; 7] = b"mapper\0";
+pub const DM_CONTROL_NODE: &[u8; 8] = b"control\0";
+pub const DM_VERSION_EXTRA: &[u8; 20] = b"-ioctl (2022-07-28)\0";
+pub const DM_ACTIVE_PRESENT_FLAG: u32 = 32;
+pub const DM_BUFFER_FULL_FLAG: u32 = 256;
+pub const DM_DATA_OUT_FLAG: u32 = 65536;
+pub const DM_DEFERRED_REMOVE: u32 = 131072;
+pub const DM_IMA_MEASUREMENT_FLAG: u32 = 524288;
+pub const DM_INACTIVE_PRESENT_FLAG: u32 = 64;
+pub const DM_INTERNAL_SUSPEND_FLAG: u32 = 262144;
+pub const DM_IOCTL: u32 = 253;
+pub const DM_MAX_TYPE_NAME: u32 = 16;
+pub const DM_NAME_LEN: u32 = 128;
+pub const DM_NAME_LIST_FLAG_DOESNT_HAVE_UUID: u32 = 2;
+pub const DM_NAME_LIST_FLAG_HAS_UUID: u32 = 1;
+pub const DM_NOFLUSH_FLAG: u32 = 2048;
+pub const DM_PERSISTENT_DEV_FLAG: u32 = 8;
+pub const DM_QUERY_INACTIVE_TABLE_FLAG: u32 = 4096;
+pub const DM_READONLY_FLAG: u32 = 1;
+pub const DM_SECURE_DATA_FLAG: u32 = 32768;
+pub const DM_SKIP_BDGET_FLAG: u32 = 512;
+pub const DM_SKIP_LOCKFS_FLAG: u32 = 1024;
+pub const DM_STATUS_TABLE_FLAG: u32 = 16;
+pub const DM_SUSPEND_FLAG: u32 = 2;
+pub const DM_UEVENT_GENERATED_FLAG: u32 = 8192;
+pub const DM_UUID_FLAG: u32 = 16384;
+pub const DM_UUID_LEN: u32 = 129;
+pub const DM_VERSION_MAJOR: u32 = 4;
+pub const DM_VERSION_MINOR: u32 = 47;
+pub const DM_VERSION_PATCHLEVEL: u32 = 0;
+pub const DM_VERSION_CMD: u32 = 0;
+pub const DM_REMOVE_ALL_CMD: u32 = 1;
+pub const DM_LIST_DEVICES_CMD: u32 = 2;
+pub const DM_DEV_CREATE_CMD: u32 = 3;
+pub const DM_DEV_REMOVE_CMD: u32 = 4;
+pub const DM_DEV_RENAME_CMD: u32 = 5;
+pub const DM_DEV_SUSPEND_CMD: u32 = 6;
+pub const DM_DEV_STATUS_CMD: u32 = 7;
+pub const DM_DEV_WAIT_CMD: u32 = 8;
+pub const DM_TABLE_LOAD_CMD: u32 = 9;
+pub const DM_TABLE_CLEAR_CMD: u32 = 10;
+pub const DM_TABLE_DEPS_CMD: u32 = 11;
+pub const DM_TABLE_STATUS_CMD: u32 = 12;
+pub const DM_LIST_VERSIONS_CMD: u32 = 13;
+pub const DM_TARGET_MSG_CMD: u32 = 14;
+pub const DM_DEV_SET_GEOMETRY_CMD: u32 = 15;
+pub const DM_DEV_ARM_POLL_CMD: u32 = 16;
+pub const DM_GET_TARGET_VERSION_CMD: u32 = 17;
+pub type __u16 = ::std::os::raw::c_ushort;
+pub type __u32 = ::std::os::raw::c_uint;
+pub type __s32 = ::std::os::raw::c_int;
+pub type __u64 = ::std::os::raw::c_ulonglong;
+pub const DM_UDEV_FLAGS_SHIFT: u32 = 16;
+pub const DM_UDEV_FLAGS_MASK: u32 = 4294901760;
+pub const DM_COOKIE_MAGIC: u32 = 3405;
+pub const DM_UDEV_DISABLE_DM_RULES_FLAG: u32 = 1;
+pub const DM_UDEV_DISABLE_SUBSYSTEM_RULES_FLAG: u32 = 2;
+pub const DM_UDEV_DISABLE_DISK_RULES_FLAG: u32 = 4;
+pub const DM_UDEV_DISABLE_OTHER_RULES_FLAG: u32 = 8;
+pub const DM_UDEV_LOW_PRIORITY_FLAG: u32 = 16;
+pub const DM_UDEV_DISABLE_LIBRARY_FALLBACK: u32 = 32;
+pub const DM_UDEV_PRIMARY_SOURCE_FLAG: u32 = 64;
+
+#[repr(C)]
+#[derive(Default)]
+pub struct __IncompleteArrayField<T>(::std::marker::PhantomData<T>, [T; 0]);
+impl<T> __IncompleteArrayField<T> {
+    #[inline]
+    pub const fn new() -> Self {
+        __IncompleteArrayField(::std::marker::PhantomData, [])
+    }
+    #[inline]
+    pub fn as_ptr(&self) -> *const T {
+        self as *const _ as *const T
+    }
+    #[inline]
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self as *mut _ as *mut T
+    }
+}
+impl<T> ::std::fmt::Debug for __IncompleteArrayField<T> {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        fmt.write_str("__IncompleteArrayField")
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct dm_ioctl {
+    pub version: [__u32; 3usize],
+    pub data_size: __u32,
+    pub data_start: __u32,
+    pub target_count: __u32,
+    pub open_count: __s32,
+    pub flags: __u32,
+    pub event_nr: __u32,
+    pub padding: __u32,
+    pub dev: __u64,
+    pub name: [::std::os::raw::c_char; 128usize],
+    pub uuid: [::std::os::raw::c_char; 129usize],
+    pub data: [::std::os::raw::c_char; 7usize],
+}
+impl Default for dm_ioctl {
+    fn default() -> Self {
+        let mut s = ::std::mem::MaybeUninit::<Self>::uninit();
+        unsafe {
+            ::std::ptr::write_bytes(s.as_mut_ptr(), 0, 1);
+            s.assume_init()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct dm_target_spec {
+    pub sector_start: __u64,
+    pub length: __u64,
+    pub status: __s32,
+    pub next: __u32,
+    pub target_type: [::std::os::raw::c_char; 16usize],
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct dm_target_deps {
+    pub count: __u32,
+    pub padding: __u32,
+    pub dev: __IncompleteArrayField<__u64>,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct dm_name_list {
+    pub dev: __u64,
+    pub next: __u32,
+    pub name: __IncompleteArrayField<::std::os::raw::c_char>,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct dm_target_versions {
+    pub next: __u32,
+    pub version: [__u32; 3usize],
+    pub name: __IncompleteArrayField<::std::os::raw::c_char>,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct dm_target_msg {
+    pub sector: __u64,
+    pub message: __IncompleteArrayField<::std::os::raw::c_char>,
+}
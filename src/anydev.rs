@@ -0,0 +1,174 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// A single type for devices of any of the kinds that this crate manages,
+// so that devices of different kinds may be kept in one collection.
+
+use std::path::PathBuf;
+
+use crate::{
+    cachedev::CacheDev,
+    core::{Device, DmName, DmOptions, DmUuid, DM},
+    lineardev::LinearDev,
+    result::DmResult,
+    shared::{DmDevice, RetryPolicy, TargetTable},
+    thindev::ThinDev,
+    thinpooldev::ThinPoolDev,
+    units::Sectors,
+};
+
+/// A device of any of the kinds managed by this crate.
+///
+/// The DmDevice trait is generic in the device's table type, so devices of
+/// different kinds can not be held together as trait objects. Each method
+/// of this type dispatches to the DmDevice method of the same name.
+#[derive(Debug)]
+pub enum AnyDmDevice {
+    /// A cache device
+    Cache(CacheDev),
+    /// A linear device
+    Linear(LinearDev),
+    /// A thin device
+    Thin(ThinDev),
+    /// A thin pool device
+    ThinPool(ThinPoolDev),
+}
+
+macro_rules! dispatch {
+    ($self:expr, $dev:ident => $e:expr) => {
+        match $self {
+            AnyDmDevice::Cache($dev) => $e,
+            AnyDmDevice::Linear($dev) => $e,
+            AnyDmDevice::Thin($dev) => $e,
+            AnyDmDevice::ThinPool($dev) => $e,
+        }
+    };
+}
+
+impl AnyDmDevice {
+    /// The device.
+    pub fn device(&self) -> Device {
+        dispatch!(self, dev => dev.device())
+    }
+
+    /// The device's device node.
+    pub fn devnode(&self) -> PathBuf {
+        dispatch!(self, dev => dev.devnode())
+    }
+
+    /// The device's name.
+    pub fn name(&self) -> &DmName {
+        dispatch!(self, dev => dev.name())
+    }
+
+    /// The device's UUID, if any.
+    pub fn uuid(&self) -> Option<&DmUuid> {
+        dispatch!(self, dev => dev.uuid())
+    }
+
+    /// The number of sectors available for user data.
+    pub fn size(&self) -> Sectors {
+        dispatch!(self, dev => dev.size())
+    }
+
+    /// The device's table, as it would be passed to DM::table_load().
+    pub fn raw_table(&self) -> Vec<(u64, u64, String, String)> {
+        dispatch!(self, dev => dev.table().to_raw_table())
+    }
+
+    /// Resume I/O on the device, returning its event number.
+    pub fn resume(&mut self, dm: &DM) -> DmResult<u32> {
+        dispatch!(self, dev => dev.resume(dm))
+    }
+
+    /// Suspend I/O on the device.
+    pub fn suspend(&mut self, dm: &DM, options: DmOptions) -> DmResult<()> {
+        dispatch!(self, dev => dev.suspend(dm, options))
+    }
+
+    /// Erase the kernel's memory of this device.
+    pub fn teardown(&mut self, dm: &DM) -> DmResult<()> {
+        dispatch!(self, dev => dev.teardown(dm))
+    }
+
+    /// Tear down the device, retrying while it is busy.
+    pub fn teardown_with_retries(&mut self, dm: &DM, policy: RetryPolicy) -> DmResult<()> {
+        dispatch!(self, dev => dev.teardown_with_retries(dm, policy))
+    }
+}
+
+impl From<CacheDev> for AnyDmDevice {
+    fn from(dev: CacheDev) -> AnyDmDevice {
+        AnyDmDevice::Cache(dev)
+    }
+}
+
+impl From<LinearDev> for AnyDmDevice {
+    fn from(dev: LinearDev) -> AnyDmDevice {
+        AnyDmDevice::Linear(dev)
+    }
+}
+
+impl From<ThinDev> for AnyDmDevice {
+    fn from(dev: ThinDev) -> AnyDmDevice {
+        AnyDmDevice::Thin(dev)
+    }
+}
+
+impl From<ThinPoolDev> for AnyDmDevice {
+    fn from(dev: ThinPoolDev) -> AnyDmDevice {
+        AnyDmDevice::ThinPool(dev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        core::devnode_to_devno,
+        lineardev::{LinearDevTargetParams, LinearTargetParams},
+        shared::TargetLine,
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    /// Verify that devices of different kinds held in one collection can be
+    /// inspected and torn down uniformly.
+    fn test_heterogeneous_teardown(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev_size = Sectors(2048);
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let lineardev = LinearDev::setup(
+            &dm,
+            &test_name("anydev").expect("valid format"),
+            None,
+            vec![TargetLine::new(
+                Sectors(0),
+                dev_size,
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+            )],
+        )
+        .unwrap();
+
+        let mut devices = vec![AnyDmDevice::from(lineardev)];
+        for device in devices.iter_mut() {
+            assert_eq!(device.size(), dev_size);
+            assert!(device.devnode().exists());
+            device.suspend(&dm, DmOptions::default()).unwrap();
+            device.resume(&dm).unwrap();
+        }
+        for mut device in devices {
+            device.teardown(&dm).unwrap();
+        }
+    }
+
+    #[test]
+    fn loop_test_heterogeneous_teardown() {
+        test_with_spec(1, test_heterogeneous_teardown);
+    }
+}
@@ -7,6 +7,8 @@ use std::{
     fmt,
     path::PathBuf,
     str::FromStr,
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
@@ -15,11 +17,12 @@ use crate::{
     lineardev::{LinearDev, LinearDevTargetParams},
     result::{DmError, DmResult, ErrorEnum},
     shared::{
-        device_create, device_exists, device_match, get_status, get_status_line_fields,
-        make_unexpected_value_error, parse_device, parse_value, DmDevice, TargetLine, TargetParams,
-        TargetTable, TargetTypeBuf,
+        check_superblock_magic, device_create, device_info_if_exists, device_match, escape_param,
+        get_status, get_status_line_fields, make_unexpected_value_error, parse_device, parse_value,
+        split_params, validate_block_size, DmDevice, TargetLine, TargetParams, TargetTable,
+        TargetType, TargetTypeBuf,
     },
-    units::{DataBlocks, MetaBlocks, Sectors},
+    units::{DataBlocks, MetaBlocks, Sectors, MAX_META_DEV_SIZE},
 };
 
 // Specified in kernel docs
@@ -28,7 +31,60 @@ pub const MIN_CACHE_BLOCK_SIZE: Sectors = Sectors(64); // 32 KiB
 /// The maximum size recommended in the docs for a cache block.
 pub const MAX_CACHE_BLOCK_SIZE: Sectors = Sectors(2 * IEC::Mi); // 1 GiB
 
-const CACHE_TARGET_NAME: &str = "cache";
+pub(crate) const CACHE_TARGET_NAME: &str = "cache";
+
+/// The magic number of a cache's metadata superblock, which the kernel
+/// gives in octal.
+const CACHE_SUPERBLOCK_MAGIC: u64 = 0o6142003;
+
+/// How often the status is polled by CacheDev::wait_clean.
+const WAIT_CLEAN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Check that cache_block_size is one the kernel accepts for a cache: a
+/// multiple of MIN_CACHE_BLOCK_SIZE, no greater than MAX_CACHE_BLOCK_SIZE.
+pub fn validate_cache_block_size(cache_block_size: Sectors) -> DmResult<()> {
+    validate_block_size(
+        cache_block_size,
+        MIN_CACHE_BLOCK_SIZE,
+        MAX_CACHE_BLOCK_SIZE,
+        "cache block size",
+    )
+}
+
+/// The fixed portion of the metadata size recommended in the kernel docs.
+const CACHE_METADATA_BASE_SIZE: Sectors = Sectors(8 * IEC::Ki); // 4 MiB
+/// The number of cache blocks whose metadata fits in one meta block, at the
+/// 16 bytes per cache block recommended in the kernel docs.
+const CACHE_BLOCKS_PER_META_BLOCK: u64 = 256;
+
+/// The format of a cache's metadata. Version 2 stores the dirty bits of the
+/// cache blocks in a separate bitset, which greatly reduces the size of the
+/// metadata written as blocks are dirtied and cleaned.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CacheMetadataVersion {
+    /// The original format
+    #[default]
+    V1,
+    /// The format selected by the "metadata2" feature argument, available
+    /// since Linux 4.11
+    V2,
+}
+
+/// The feature argument that selects version 2 of the cache metadata format.
+const METADATA2_FEATURE_ARG: &str = "metadata2";
+
+impl CacheMetadataVersion {
+    fn from_superblock_version(version: u32) -> DmResult<CacheMetadataVersion> {
+        match version {
+            1 => Ok(CacheMetadataVersion::V1),
+            2 => Ok(CacheMetadataVersion::V2),
+            _ => Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                format!("unknown cache metadata version {version}"),
+            )),
+        }
+    }
+}
 
 /// Struct representing params for a cache target
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -72,6 +128,17 @@ impl CacheTargetParams {
     }
 }
 
+impl CacheTargetParams {
+    /// The format of the cache's metadata, as selected by the feature args.
+    pub fn metadata_version(&self) -> CacheMetadataVersion {
+        if self.feature_args.contains(METADATA2_FEATURE_ARG) {
+            CacheMetadataVersion::V2
+        } else {
+            CacheMetadataVersion::V1
+        }
+    }
+}
+
 impl fmt::Display for CacheTargetParams {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{} {}", CACHE_TARGET_NAME, self.param_str())
@@ -82,7 +149,8 @@ impl FromStr for CacheTargetParams {
     type Err = DmError;
 
     fn from_str(s: &str) -> DmResult<CacheTargetParams> {
-        let vals = s.split(' ').collect::<Vec<_>>();
+        let vals = split_params(s);
+        let vals = vals.iter().map(String::as_str).collect::<Vec<_>>();
 
         if vals.len() < 8 {
             let err_msg = format!(
@@ -144,29 +212,26 @@ impl TargetParams for CacheTargetParams {
         let feature_args = if self.feature_args.is_empty() {
             "0".to_owned()
         } else {
-            format!(
-                "{} {}",
-                self.feature_args.len(),
-                self.feature_args
-                    .iter()
-                    .cloned()
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            )
+            // Sorted, so that equal params always have equal param strings
+            let mut args = self
+                .feature_args
+                .iter()
+                .map(|x| escape_param(x))
+                .collect::<Vec<_>>();
+            args.sort();
+            format!("{} {}", args.len(), args.join(" "))
         };
 
         let policy_args = if self.policy_args.is_empty() {
             "0".to_owned()
         } else {
-            format!(
-                "{} {}",
-                self.policy_args.len(),
-                self.policy_args
-                    .iter()
-                    .map(|(k, v)| format!("{k} {v}"))
-                    .collect::<Vec<String>>()
-                    .join(" ")
-            )
+            let mut args = self
+                .policy_args
+                .iter()
+                .map(|(k, v)| format!("{} {}", escape_param(k), escape_param(v)))
+                .collect::<Vec<String>>();
+            args.sort();
+            format!("{} {}", self.policy_args.len(), args.join(" "))
         };
 
         format!(
@@ -176,13 +241,13 @@ impl TargetParams for CacheTargetParams {
             self.origin,
             *self.cache_block_size,
             feature_args,
-            self.policy,
+            escape_param(&self.policy),
             policy_args
         )
     }
 
     fn target_type(&self) -> TargetTypeBuf {
-        TargetTypeBuf::new(CACHE_TARGET_NAME.into()).expect("CACHE_TARGET_NAME is valid")
+        TargetType::CACHE.to_owned()
     }
 }
 
@@ -210,6 +275,8 @@ impl fmt::Display for CacheDevTargetTable {
 }
 
 impl TargetTable for CacheDevTargetTable {
+    type Params = CacheTargetParams;
+
     fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<CacheDevTargetTable> {
         if table.len() != 1 {
             let err_msg = format!(
@@ -226,8 +293,8 @@ impl TargetTable for CacheDevTargetTable {
         ))
     }
 
-    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
-        to_raw_table_unique!(self)
+    fn lines(&self) -> &[TargetLine<Self::Params>] {
+        std::slice::from_ref(&self.table)
     }
 }
 
@@ -249,6 +316,16 @@ pub struct CacheDevUsage {
     pub total_cache: DataBlocks,
 }
 
+serialize_fields!(
+    CacheDevUsage,
+    meta_block_size,
+    used_meta,
+    total_meta,
+    cache_block_size,
+    used_cache,
+    total_cache
+);
+
 impl CacheDevUsage {
     /// Make a new CacheDevUsage struct
     pub fn new(
@@ -291,6 +368,17 @@ pub struct CacheDevPerformance {
     pub dirty: u64,
 }
 
+serialize_fields!(
+    CacheDevPerformance,
+    read_hits,
+    read_misses,
+    write_hits,
+    write_misses,
+    demotions,
+    promotions,
+    dirty
+);
+
 impl CacheDevPerformance {
     /// Construct a new CacheDevPerformance struct
     pub fn new(
@@ -323,6 +411,8 @@ pub enum CacheDevMetadataMode {
     ReadOnly,
 }
 
+serialize_names!(CacheDevMetadataMode, Good => "good", ReadOnly => "read_only");
+
 /// Status values of a cache device when it is working
 #[derive(Debug)]
 pub struct CacheDevWorkingStatus {
@@ -343,8 +433,25 @@ pub struct CacheDevWorkingStatus {
     pub metadata_mode: CacheDevMetadataMode,
     /// needs_check flag has been set in metadata superblock
     pub needs_check: bool,
+    /// Any fields following those above, which newer kernels may report,
+    /// and, if the status was parsed leniently, an unrecognized needs check
+    /// flag.
+    pub extra: Vec<String>,
 }
 
+serialize_fields!(
+    CacheDevWorkingStatus,
+    usage,
+    performance,
+    feature_args,
+    core_args,
+    policy,
+    policy_args,
+    metadata_mode,
+    needs_check,
+    extra
+);
+
 impl CacheDevWorkingStatus {
     /// Make a new CacheDevWorkingStatus struct
     #[allow(clippy::too_many_arguments)]
@@ -367,6 +474,7 @@ impl CacheDevWorkingStatus {
             policy_args,
             metadata_mode,
             needs_check,
+            extra: Vec::new(),
         }
     }
 }
@@ -382,12 +490,27 @@ pub enum CacheDevStatus {
     Fail,
 }
 
+serialize_status!(CacheDevStatus);
+
 impl FromStr for CacheDevStatus {
     type Err = DmError;
 
+    fn from_str(status_line: &str) -> DmResult<CacheDevStatus> {
+        CacheDevStatus::parse(status_line, false)
+    }
+}
+
+impl CacheDevStatus {
+    /// Parse a status line, like from_str(), but return any word that is
+    /// not recognized in place of the needs check flag in the extra field,
+    /// taking the flag to be unset, rather than failing.
+    pub fn from_str_lenient(status_line: &str) -> DmResult<CacheDevStatus> {
+        CacheDevStatus::parse(status_line, true)
+    }
+
     // Note: This method is not entirely complete. In particular, *_args values
     // may require more or better checking or processing.
-    fn from_str(status_line: &str) -> DmResult<CacheDevStatus> {
+    fn parse(status_line: &str, lenient: bool) -> DmResult<CacheDevStatus> {
         if status_line.starts_with("Error") {
             return Ok(CacheDevStatus::Error);
         }
@@ -449,9 +572,15 @@ impl FromStr for CacheDevStatus {
             }
         };
 
+        let mut extra = Vec::new();
+
         let needs_check = match status_vals[rest_start_index + 1] {
             "-" => false,
             "needs_check" => true,
+            val if lenient => {
+                extra.push(val.to_string());
+                false
+            }
             val => {
                 return Err(make_unexpected_value_error(
                     rest_start_index + 1,
@@ -461,18 +590,25 @@ impl FromStr for CacheDevStatus {
             }
         };
 
-        Ok(CacheDevStatus::Working(Box::new(
-            CacheDevWorkingStatus::new(
-                usage,
-                performance,
-                feature_args,
-                core_args,
-                policy,
-                policy_args,
-                cache_metadata_mode,
-                needs_check,
-            ),
-        )))
+        extra.extend(
+            status_vals
+                .iter()
+                .skip(rest_start_index + 2)
+                .map(|v| v.to_string()),
+        );
+
+        let mut status = CacheDevWorkingStatus::new(
+            usage,
+            performance,
+            feature_args,
+            core_args,
+            policy,
+            policy_args,
+            cache_metadata_mode,
+            needs_check,
+        );
+        status.extra = extra;
+        Ok(CacheDevStatus::Working(Box::new(status)))
     }
 }
 
@@ -533,6 +669,10 @@ impl DmDevice<CacheDevTargetTable> for CacheDev {
         name!(self)
     }
 
+    fn record_table(&mut self, table: CacheDevTargetTable) {
+        self.table = table;
+    }
+
     fn size(&self) -> Sectors {
         self.origin_dev.size()
     }
@@ -557,7 +697,11 @@ impl DmDevice<CacheDevTargetTable> for CacheDev {
 /// Cache device implementation.
 impl CacheDev {
     /// Construct a new CacheDev with the given data and meta devs.
+    /// If the metadata device is blank, the kernel formats it with the
+    /// given metadata version.
     /// Returns an error if the device is already known to the kernel.
+    /// Returns an error if `cache_block_size` is not valid.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         dm: &DM,
         name: &DmName,
@@ -566,13 +710,16 @@ impl CacheDev {
         cache: LinearDev,
         origin: LinearDev,
         cache_block_size: Sectors,
+        metadata_version: CacheMetadataVersion,
     ) -> DmResult<CacheDev> {
-        if device_exists(dm, name)? {
+        validate_cache_block_size(cache_block_size)?;
+        if device_info_if_exists(dm, name)?.is_some() {
             let err_msg = format!("cachedev {name} already exists");
             return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
         }
 
-        let table = CacheDev::gen_default_table(&meta, &cache, &origin, cache_block_size);
+        let table =
+            CacheDev::gen_default_table(&meta, &cache, &origin, cache_block_size, metadata_version);
         let dev_info = device_create(dm, name, uuid, &table, DmOptions::private())?;
 
         Ok(CacheDev {
@@ -585,6 +732,11 @@ impl CacheDev {
     }
 
     /// Set up a cache device from the given metadata and data devices.
+    /// Returns an error if `cache_block_size` is not valid.
+    ///
+    /// Like [`LinearDev::setup`], this takes two ioctls if the device
+    /// already exists, and four if it is created.
+    #[allow(clippy::too_many_arguments)]
     pub fn setup(
         dm: &DM,
         name: &DmName,
@@ -593,10 +745,12 @@ impl CacheDev {
         cache: LinearDev,
         origin: LinearDev,
         cache_block_size: Sectors,
+        metadata_version: CacheMetadataVersion,
     ) -> DmResult<CacheDev> {
-        let table = CacheDev::gen_default_table(&meta, &cache, &origin, cache_block_size);
-        let dev = if device_exists(dm, name)? {
-            let dev_info = dm.device_info(&DevId::Name(name))?;
+        validate_cache_block_size(cache_block_size)?;
+        let table =
+            CacheDev::gen_default_table(&meta, &cache, &origin, cache_block_size, metadata_version);
+        let dev = if let Some(dev_info) = device_info_if_exists(dm, name)? {
             let dev = CacheDev {
                 dev_info: Box::new(dev_info),
                 meta_dev: meta,
@@ -620,6 +774,36 @@ impl CacheDev {
         Ok(dev)
     }
 
+    /// Set up a cache device from metadata, cache and origin devices that
+    /// already hold a cache, as [`CacheDev::setup`] does, but first check
+    /// that the metadata device begins with a cache superblock. Returns an
+    /// error if it does not, so that a device that was never formatted, or
+    /// holds something else, is not mistaken for a cache's metadata.
+    /// The cache is set up with the metadata version found in the
+    /// superblock.
+    pub fn adopt(
+        dm: &DM,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        meta: LinearDev,
+        cache: LinearDev,
+        origin: LinearDev,
+        cache_block_size: Sectors,
+    ) -> DmResult<CacheDev> {
+        let version = check_superblock_magic(&meta.devnode(), CACHE_SUPERBLOCK_MAGIC, "cache")?;
+        let metadata_version = CacheMetadataVersion::from_superblock_version(version)?;
+        CacheDev::setup(
+            dm,
+            name,
+            uuid,
+            meta,
+            cache,
+            origin,
+            cache_block_size,
+            metadata_version,
+        )
+    }
+
     /// Set the table for the existing origin device.
     /// This action puts the device in a state where it is ready to be resumed.
     /// Warning: It is the client's responsibility to make sure the designated
@@ -705,7 +889,12 @@ impl CacheDev {
         cache: &LinearDev,
         origin: &LinearDev,
         cache_block_size: Sectors,
+        metadata_version: CacheMetadataVersion,
     ) -> CacheDevTargetTable {
+        let mut feature_args = vec!["writethrough".into()];
+        if metadata_version == CacheMetadataVersion::V2 {
+            feature_args.push(METADATA2_FEATURE_ARG.into());
+        }
         CacheDevTargetTable::new(
             Sectors::default(),
             origin.size(),
@@ -714,7 +903,7 @@ impl CacheDev {
                 cache.device(),
                 origin.device(),
                 cache_block_size,
-                vec!["writethrough".into()],
+                feature_args,
                 "default".to_owned(),
                 vec![],
             ),
@@ -742,6 +931,77 @@ impl CacheDev {
     pub fn status(&self, dm: &DM, options: DmOptions) -> DmResult<CacheDevStatus> {
         status!(self, dm, options)
     }
+
+    /// Wait until the cache holds no dirty blocks, i.e., until every block
+    /// written to the cache has been written back to the origin, e.g.
+    /// before the origin is suspended to be snapshotted. Return true once
+    /// the cache is clean, or false if timeout, if one is given, expires
+    /// first. progress is called with the number of dirty blocks each time
+    /// the status is polled.
+    ///
+    /// Dirty blocks are written back according to the cache's policy, so
+    /// that a busy cache may never become clean. Switching the cache to the
+    /// "cleaner" policy writes back all dirty blocks as quickly as possible.
+    ///
+    /// Return an error if the cache has failed.
+    pub fn wait_clean<F>(
+        &self,
+        dm: &DM,
+        timeout: Option<Duration>,
+        mut progress: F,
+    ) -> DmResult<bool>
+    where
+        F: FnMut(u64),
+    {
+        let start = Instant::now();
+        loop {
+            let dirty = match self.status(dm, DmOptions::default().set_no_flush(true))? {
+                CacheDevStatus::Working(status) => status.performance.dirty,
+                CacheDevStatus::Error | CacheDevStatus::Fail => {
+                    let err_msg = format!("cache {} has failed", self.name());
+                    return Err(DmError::Dm(ErrorEnum::Error, err_msg));
+                }
+            };
+            progress(dirty);
+            if dirty == 0 {
+                return Ok(true);
+            }
+
+            let mut interval = WAIT_CLEAN_INTERVAL;
+            if let Some(timeout) = timeout {
+                match timeout.checked_sub(start.elapsed()) {
+                    Some(remaining) if !remaining.is_zero() => {
+                        interval = interval.min(remaining);
+                    }
+                    _ => return Ok(false),
+                }
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+/// Estimate the size of the metadata device required by a cache, following
+/// the kernel docs' rule of thumb of 4 MiB plus 16 bytes per cache block.
+///
+/// cache_size is the size of the cache device and cache_block_size the size
+/// of a single cache block. The estimate is never more than the largest
+/// metadata device the kernel can make use of.
+pub fn cache_metadata_size(cache_size: Sectors, cache_block_size: Sectors) -> DmResult<MetaBlocks> {
+    if cache_block_size == Sectors(0) {
+        let err_msg = "cache block size must be greater than 0";
+        return Err(DmError::Dm(ErrorEnum::Invalid, err_msg.into()));
+    }
+
+    let nr_blocks =
+        *cache_size / *cache_block_size + u64::from(*cache_size % *cache_block_size != 0);
+    let mapping_blocks = nr_blocks / CACHE_BLOCKS_PER_META_BLOCK
+        + u64::from(nr_blocks % CACHE_BLOCKS_PER_META_BLOCK != 0);
+
+    let estimate =
+        MetaBlocks((*CACHE_METADATA_BASE_SIZE.metablocks()).saturating_add(mapping_blocks));
+
+    Ok(estimate.min(MAX_META_DEV_SIZE))
 }
 
 #[cfg(test)]
@@ -807,6 +1067,7 @@ pub fn minimal_cachedev(dm: &DM, paths: &[&Path]) -> CacheDev {
         cache,
         origin,
         MIN_CACHE_BLOCK_SIZE,
+        CacheMetadataVersion::default(),
     )
     .unwrap()
 }
@@ -815,10 +1076,12 @@ pub fn minimal_cachedev(dm: &DM, paths: &[&Path]) -> CacheDev {
 mod tests {
     use std::path::Path;
 
-    use crate::testing::test_with_spec;
+    use crate::testing::{test_with_devices, DeviceRequirements};
 
     use super::*;
 
+    const CACHE_TARGETS: &[&str] = &["cache"];
+
     // Test creating a minimal cache dev.
     // Verify that status method executes and gives reasonable values.
     fn test_minimal_cache_dev(paths: &[&Path]) {
@@ -885,7 +1148,102 @@ mod tests {
 
     #[test]
     fn loop_test_minimal_cache_dev() {
-        test_with_spec(2, test_minimal_cache_dev);
+        test_with_devices(
+            DeviceRequirements::new(2).targets(CACHE_TARGETS),
+            test_minimal_cache_dev,
+        );
+    }
+
+    /// Verify that waiting for a cache that has not been written to to be
+    /// clean returns at once, reporting that no blocks are dirty.
+    fn test_wait_clean(paths: &[&Path]) {
+        assert!(paths.len() >= 2);
+        let dm = DM::new().unwrap();
+        let mut cache = minimal_cachedev(&dm, paths);
+
+        let mut reported = Vec::new();
+        assert!(cache
+            .wait_clean(&dm, Some(Duration::from_secs(10)), |dirty| reported
+                .push(dirty))
+            .unwrap());
+        assert_eq!(reported, vec![0]);
+
+        cache.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_wait_clean() {
+        test_with_devices(
+            DeviceRequirements::new(2).targets(CACHE_TARGETS),
+            test_wait_clean,
+        );
+    }
+
+    #[test]
+    fn test_cache_status_lenient() {
+        let status_line =
+            "8 20/512 128 3/64 5 6 7 8 0 1 2 1 writethrough 2 migration_threshold 2048 smq 0 rw new_check new1";
+        assert_matches!(status_line.parse::<CacheDevStatus>(), Err(_));
+
+        match CacheDevStatus::from_str_lenient(status_line).unwrap() {
+            CacheDevStatus::Working(status) => {
+                assert!(!status.needs_check);
+                assert_eq!(status.extra, vec!["new_check", "new1"]);
+            }
+            _ => panic!("status should be working"),
+        }
+    }
+
+    #[test]
+    fn test_metadata_version() {
+        let params = "cache 253:0 253:1 253:2 64 2 metadata2 writethrough default 0"
+            .parse::<CacheTargetParams>()
+            .unwrap();
+        assert_eq!(params.metadata_version(), CacheMetadataVersion::V2);
+        let params = "cache 253:0 253:1 253:2 64 1 writethrough default 0"
+            .parse::<CacheTargetParams>()
+            .unwrap();
+        assert_eq!(params.metadata_version(), CacheMetadataVersion::V1);
+
+        assert_eq!(
+            CacheMetadataVersion::from_superblock_version(2).unwrap(),
+            CacheMetadataVersion::V2
+        );
+        assert!(CacheMetadataVersion::from_superblock_version(3).is_err());
+    }
+
+    #[test]
+    fn test_validate_cache_block_size() {
+        assert!(validate_cache_block_size(MIN_CACHE_BLOCK_SIZE).is_ok());
+        assert!(validate_cache_block_size(3u64 * MIN_CACHE_BLOCK_SIZE).is_ok());
+        assert!(validate_cache_block_size(Sectors(0)).is_err());
+        assert!(validate_cache_block_size(MIN_CACHE_BLOCK_SIZE + Sectors(8)).is_err());
+        assert!(validate_cache_block_size(2u64 * MAX_CACHE_BLOCK_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_cache_metadata_size() {
+        assert_matches!(
+            cache_metadata_size(Sectors(IEC::Gi), Sectors(0)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        // 4 MiB, plus 16 bytes for the partial block
+        assert_eq!(
+            cache_metadata_size(Sectors(1), MIN_CACHE_BLOCK_SIZE).unwrap(),
+            MetaBlocks(1024 + 1)
+        );
+
+        // 1 TiB of 32 KiB blocks, at 16 bytes per block, is 512 MiB
+        assert_eq!(
+            cache_metadata_size(Sectors(2 * IEC::Gi), MIN_CACHE_BLOCK_SIZE).unwrap(),
+            MetaBlocks(1024 + 128 * 1024)
+        );
+
+        assert_eq!(
+            cache_metadata_size(Sectors(u64::MAX), Sectors(1)).unwrap(),
+            MAX_META_DEV_SIZE
+        );
     }
 
     /// Basic test of meta size change.
@@ -938,7 +1296,10 @@ mod tests {
 
     #[test]
     fn loop_test_meta_size_change() {
-        test_with_spec(3, test_meta_size_change);
+        test_with_devices(
+            DeviceRequirements::new(3).targets(CACHE_TARGETS),
+            test_meta_size_change,
+        );
     }
 
     /// Basic test of cache size change
@@ -1007,7 +1368,10 @@ mod tests {
 
     #[test]
     fn loop_test_cache_size_change() {
-        test_with_spec(3, test_cache_size_change);
+        test_with_devices(
+            DeviceRequirements::new(3).targets(CACHE_TARGETS),
+            test_cache_size_change,
+        );
     }
 
     /// Test changing the size of the origin device.
@@ -1045,7 +1409,10 @@ mod tests {
 
     #[test]
     fn loop_test_origin_size_change() {
-        test_with_spec(3, test_origin_size_change);
+        test_with_devices(
+            DeviceRequirements::new(3).targets(CACHE_TARGETS),
+            test_origin_size_change,
+        );
     }
 
     /// Verify that suspending and resuming the cache doesn't fail.
@@ -1067,6 +1434,9 @@ mod tests {
 
     #[test]
     fn loop_test_suspend() {
-        test_with_spec(2, test_suspend);
+        test_with_devices(
+            DeviceRequirements::new(2).targets(CACHE_TARGETS),
+            test_suspend,
+        );
     }
 }
@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{fmt, path::Path, str::FromStr};
+use std::{fmt, fs, io, path::Path, str::FromStr};
 
 use nix::libc::{dev_t, major, makedev, minor};
 use nix::sys::stat::{self, SFlag};
@@ -30,6 +30,16 @@ impl fmt::Display for Device {
     }
 }
 
+/// Serialized in the same `<major>:<minor>` format
+impl serde::Serialize for Device {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 impl FromStr for Device {
     type Err = DmError;
 
@@ -111,6 +121,23 @@ impl Device {
 
         Some((self.minor & 0xff) | (self.major << 8) | ((self.minor & !0xff) << 12))
     }
+
+    /// Whether the device supports DAX, i.e., is persistent memory that can
+    /// be mapped directly, according to its queue's "dax" attribute in
+    /// sysfs. Such a device suits the "p" mode of a writecache target,
+    /// others the "s" mode or a cache target. Return false if the device
+    /// has no such attribute.
+    pub fn supports_dax(self) -> DmResult<bool> {
+        let path = format!("/sys/dev/block/{self}/queue/dax");
+        match fs::read_to_string(&path) {
+            Ok(val) => Ok(val.trim_end() == "1"),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(DmError::Core(errors::Error::MetadataIo(
+                path.into(),
+                err.to_string(),
+            ))),
+        }
+    }
 }
 
 /// Get a device number from a device node.
@@ -134,6 +161,16 @@ pub fn devnode_to_devno(path: &Path) -> DmResult<Option<u64>> {
     }
 }
 
+/// Whether the block device at path is a DM device, according to sysfs,
+/// which has a "dm" directory for each. Return false if path is not a block
+/// device, or appears not to exist.
+pub fn is_dm_device(path: &Path) -> DmResult<bool> {
+    Ok(match devnode_to_devno(path)? {
+        Some(devno) => Path::new(&format!("/sys/dev/block/{}/dm", Device::from(devno))).exists(),
+        None => false,
+    })
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -171,4 +208,19 @@ mod tests {
         let dev2 = Device::from(0xabcd_ef12_3456_7890);
         assert_eq!(dev2.to_kdev_t(), None);
     }
+
+    #[test]
+    /// Verify that a character device, and a missing path, are not DM
+    /// devices.
+    fn test_is_dm_device_not_block() {
+        assert!(!is_dm_device(Path::new("/dev/null")).unwrap());
+        assert!(!is_dm_device(Path::new("/dev/no-such-device")).unwrap());
+    }
+
+    #[test]
+    /// Verify that a device unknown to sysfs is reported not to support DAX.
+    fn test_supports_dax_missing() {
+        let dev = Device { major: 0, minor: 0 };
+        assert!(!dev.supports_dax().unwrap());
+    }
 }
@@ -0,0 +1,136 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Advisory locks on DM devices, shared by all threads of a process, so that
+// sequences of operations on the same device, e.g. suspend, load, resume,
+// are not interleaved.
+
+use std::{
+    collections::HashSet,
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::core::types::DevId;
+
+static DEVICE_LOCKS: Lazy<DeviceLocks> = Lazy::new(DeviceLocks::default);
+
+#[derive(Debug, Default)]
+struct DeviceLocks {
+    // The keys of the devices that are locked
+    held: Mutex<HashSet<String>>,
+    released: Condvar,
+}
+
+impl DeviceLocks {
+    fn lock(&self) -> MutexGuard<'_, HashSet<String>> {
+        // The set is consistent whenever the lock is released.
+        self.held.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The key under which a device is locked. Names and uuids are kept apart,
+/// so that a name can not be mistaken for a uuid.
+fn lock_key(id: &DevId<'_>) -> String {
+    match id {
+        DevId::Name(name) => format!("name:{name}"),
+        DevId::Uuid(uuid) => format!("uuid:{uuid}"),
+    }
+}
+
+/// An advisory lock on a DM device, held until dropped.
+///
+/// The lock is only respected by code in the same process that also takes
+/// it. A device is locked by name or by uuid, and the two are distinct, so
+/// code that shares a device must agree on which one identifies it.
+#[derive(Debug)]
+pub struct DeviceLock {
+    key: String,
+}
+
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        DEVICE_LOCKS.lock().remove(&self.key);
+        DEVICE_LOCKS.released.notify_all();
+    }
+}
+
+/// Lock the device identified by id, waiting until no other thread in the
+/// process holds the lock.
+pub fn lock_device(id: &DevId<'_>) -> DeviceLock {
+    let key = lock_key(id);
+    let mut held = DEVICE_LOCKS.lock();
+    while held.contains(&key) {
+        held = DEVICE_LOCKS
+            .released
+            .wait(held)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+    held.insert(key.clone());
+    DeviceLock { key }
+}
+
+/// Lock the device identified by id, if no other thread in the process
+/// holds the lock. Return None if one does.
+pub fn try_lock_device(id: &DevId<'_>) -> Option<DeviceLock> {
+    let key = lock_key(id);
+    if DEVICE_LOCKS.lock().insert(key.clone()) {
+        Some(DeviceLock { key })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use crate::core::types::{DmName, DmUuid};
+
+    use super::*;
+
+    #[test]
+    /// Test that a lock excludes others on the same device only.
+    fn test_try_lock_device() {
+        let name = DmName::new("device-lock-test-try").expect("is valid DM name");
+        let uuid = DmUuid::new("device-lock-test-try").expect("is valid DM uuid");
+
+        let lock = try_lock_device(&DevId::Name(name)).unwrap();
+        assert!(try_lock_device(&DevId::Name(name)).is_none());
+        let uuid_lock = try_lock_device(&DevId::Uuid(uuid)).unwrap();
+
+        drop(lock);
+        assert!(try_lock_device(&DevId::Name(name)).is_some());
+        drop(uuid_lock);
+    }
+
+    #[test]
+    /// Test that lock_device waits until the lock is released.
+    fn test_lock_device_waits() {
+        let name = DmName::new("device-lock-test-wait").expect("is valid DM name");
+        let released = Arc::new(AtomicBool::new(false));
+
+        let lock = lock_device(&DevId::Name(name));
+        let handle = {
+            let released = Arc::clone(&released);
+            thread::spawn(move || {
+                let name = DmName::new("device-lock-test-wait").expect("is valid DM name");
+                let _lock = lock_device(&DevId::Name(name));
+                assert!(released.load(Ordering::SeqCst));
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        released.store(true, Ordering::SeqCst);
+        drop(lock);
+        handle.join().unwrap();
+    }
+}
@@ -3,17 +3,37 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::{
-    cmp,
-    fs::File,
-    io::{Cursor, Read, Write},
+    cmp, fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
     mem::size_of,
-    os::unix::io::{AsRawFd, RawFd},
-    slice, str,
+    ops::Range,
+    os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    panic,
+    path::Path,
+    ptr, slice, str,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use nix::{errno, libc::ioctl as nix_ioctl};
+#[cfg(feature = "modprobe")]
+use std::process::Command;
+
+use nix::{
+    errno,
+    libc::ioctl as nix_ioctl,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    sys::stat::{makedev, mknod, Mode, SFlag},
+};
+use once_cell::sync::OnceCell;
 use retry::{delay::Fixed, retry_with_index, Error as RetryError, OperationResult};
 use semver::Version;
+#[cfg(feature = "zeroize")]
+use zeroize::Zeroize;
 
 use crate::{
     core::{
@@ -23,17 +43,19 @@ use crate::{
         dm_ioctl as dmi,
         dm_options::DmOptions,
         errors,
-        types::{DevId, DmName, DmNameBuf, DmUuid},
+        ioctl_buffer::{IoctlBuffer, MmapBuffer},
+        ioctl_queue::{IoctlQueue, IoctlScheduling},
+        types::{DevId, DmName, DmNameBuf, DmUuid, DmUuidBuf},
         util::{
-            align_to, c_struct_from_slice, mut_slice_from_c_str, slice_from_c_struct,
-            str_from_byte_slice, str_from_c_str,
+            align_to, c_struct_from_slice, copy_to_c_str, mut_slice_from_c_str,
+            slice_from_c_struct, str_from_byte_slice, str_from_c_str,
         },
     },
     result::{DmError, DmResult, ErrorEnum},
 };
 
 #[cfg(feature = "udev-sync")]
-use crate::core::dm_udev_sync::{UdevSync, UdevSyncAction};
+use crate::core::dm_udev_sync::{with_batch, UdevSync, UdevSyncAction};
 
 #[cfg(target_os = "linux")]
 /// Control path for user space to pass IOCTL to kernel DM
@@ -42,6 +64,12 @@ const DM_CTL_PATH: &str = "/dev/mapper/control";
 /// Control path for user space to pass IOCTL to kernel DM
 const DM_CTL_PATH: &str = "/dev/device-mapper";
 
+/// Major number of misc character devices, which include the control device
+const MISC_MAJOR: u64 = 10;
+
+/// The name under which the control device is listed in /proc/misc
+const DM_MISC_NAME: &str = "device-mapper";
+
 /// Start with a large buffer to make BUFFER_FULL rare. Libdm does this too.
 const MIN_BUF_SIZE: usize = 16 * 1024;
 
@@ -51,9 +79,63 @@ const DM_REMOVE_RETRIES: usize = 5;
 /// Delay between remove attempts
 const DM_REMOVE_MSLEEP_DELAY: u64 = 200;
 
+/// Maximum number of idle ioctl buffers kept for reuse by a DM context
+const MAX_POOLED_BUFFERS: usize = 4;
+
 /// Context needed for communicating with devicemapper.
 pub struct DM {
     file: File,
+    buffers: Mutex<Vec<Vec<u8>>>,
+    ioctl_queue: IoctlQueue,
+    version: OnceCell<(u32, u32, u32)>,
+    ioctls: AtomicU64,
+    #[cfg(devicemapper41supported)]
+    #[allow(clippy::type_complexity)]
+    targets: Mutex<Option<Vec<(String, u32, u32, u32)>>>,
+}
+
+/// The length of the longest message that can be sent with
+/// [`DM::target_msg`], which is bounded by the ioctl's 32 bit data size;
+/// the kernel places no further limit on it.
+pub const DM_MAX_MESSAGE_LEN: usize = u32::MAX as usize
+    - size_of::<dmi::Struct_dm_ioctl>()
+    - size_of::<dmi::Struct_dm_target_msg>()
+    - 1;
+
+/// Parts of the DM ioctl interface that are missing from older kernels.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DmFeature {
+    /// DM::list_versions(), since interface version 4.1
+    ListVersions,
+    /// DM::target_msg(), since interface version 4.2
+    TargetMessages,
+    /// The event numbers returned by DM::list_devices(), since interface
+    /// version 4.37
+    ListEventNumbers,
+    /// DM::arm_poll(), since interface version 4.37
+    ArmPoll,
+    /// The uuids returned with the list of devices, used by
+    /// DM::list_devices_with_uuid_prefix(), since interface version 4.45
+    ListDeviceUuids,
+}
+
+impl DmFeature {
+    /// The first interface version to support the feature.
+    fn version(self) -> (u32, u32, u32) {
+        match self {
+            DmFeature::ListVersions => (4, 1, 0),
+            DmFeature::TargetMessages => (4, 2, 0),
+            DmFeature::ListEventNumbers | DmFeature::ArmPoll => (4, 37, 0),
+            DmFeature::ListDeviceUuids => (4, 45, 0),
+        }
+    }
+}
+
+/// Whether an interface of version available provides what is in version
+/// required. As in the kernel's check, the major versions must match and
+/// the patch levels are ignored.
+fn version_supports(available: (u32, u32, u32), required: (u32, u32, u32)) -> bool {
+    available.0 == required.0 && available.1 >= required.1
 }
 
 impl DmOptions {
@@ -62,7 +144,7 @@ impl DmOptions {
         self,
         id: Option<&DevId<'_>>,
         allowable_flags: DmFlags,
-    ) -> DmResult<dmi::Struct_dm_ioctl> {
+    ) -> dmi::Struct_dm_ioctl {
         let clean_flags = allowable_flags & self.flags();
         let event_nr = self.udev_flags().bits() << dmi::DM_UDEV_FLAGS_SHIFT;
         let mut hdr: dmi::Struct_dm_ioctl = devicemapper_sys::dm_ioctl {
@@ -72,40 +154,150 @@ impl DmOptions {
             ..Default::default()
         };
 
-        if let Some(id) = id {
-            match id {
-                DevId::Name(name) => DM::hdr_set_name(&mut hdr, name)?,
-                DevId::Uuid(uuid) => DM::hdr_set_uuid(&mut hdr, uuid)?,
-            };
-        };
+        match id {
+            Some(DevId::Name(name)) => DM::hdr_set_name(&mut hdr, name),
+            Some(DevId::Uuid(uuid)) => DM::hdr_set_uuid(&mut hdr, uuid),
+            None => (),
+        }
 
-        Ok(hdr)
+        hdr
     }
 }
 
 impl DM {
     /// Create a new context for communicating with DM.
     pub fn new() -> DmResult<DM> {
-        Ok(DM {
-            file: File::open(DM_CTL_PATH)
-                .map_err(|err| DmError::Core(errors::Error::ContextInit(err.to_string())))?,
-        })
+        DM::open(Path::new(DM_CTL_PATH), false)
     }
 
-    fn hdr_set_name(hdr: &mut dmi::Struct_dm_ioctl, name: &DmName) -> DmResult<()> {
-        let _ = name
-            .as_bytes()
-            .read(mut_slice_from_c_str(&mut hdr.name))
-            .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
-        Ok(())
+    /// Create a new context for communicating with DM through the control
+    /// device at path.
+    ///
+    /// If nothing exists at path, a control device node is created there,
+    /// along with any missing parent directories, using the minor number
+    /// listed for devicemapper in /proc/misc. This allows a context to be
+    /// created in containers and initramfs environments where udev has not
+    /// populated /dev.
+    pub fn with_control_path<P: AsRef<Path>>(path: P) -> DmResult<DM> {
+        DM::open(path.as_ref(), true)
     }
 
-    fn hdr_set_uuid(hdr: &mut dmi::Struct_dm_ioctl, uuid: &DmUuid) -> DmResult<()> {
-        let _ = uuid
-            .as_bytes()
-            .read(mut_slice_from_c_str(&mut hdr.uuid))
-            .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
-        Ok(())
+    /// Open the control device at path, creating it first if create is set
+    /// and nothing exists there.
+    fn open(path: &Path, create: bool) -> DmResult<DM> {
+        let file = match File::open(path) {
+            Err(err) if create && err.kind() == io::ErrorKind::NotFound => {
+                DM::create_control_node(path)?;
+                File::open(path)
+            }
+            res => res,
+        }
+        .map_err(|err| DmError::Core(errors::Error::ContextInit(err.to_string())))?;
+        let dm = DM {
+            file,
+            buffers: Mutex::new(Vec::new()),
+            ioctl_queue: IoctlQueue::default(),
+            version: OnceCell::new(),
+            ioctls: AtomicU64::new(0),
+            #[cfg(devicemapper41supported)]
+            targets: Mutex::new(None),
+        };
+        dm.version()?;
+        Ok(dm)
+    }
+
+    /// Create the control device node at path.
+    fn create_control_node(path: &Path) -> DmResult<()> {
+        let context_err = |err: String| DmError::Core(errors::Error::ContextInit(err));
+
+        let misc = fs::read_to_string("/proc/misc")
+            .map_err(|err| context_err(format!("failed to read /proc/misc: {err}")))?;
+        let minor = misc_minor(&misc, DM_MISC_NAME).ok_or_else(|| {
+            context_err(format!(
+                "no \"{DM_MISC_NAME}\" entry in /proc/misc; is the dm-mod module loaded?"
+            ))
+        })?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| {
+                context_err(format!("failed to create {}: {err}", parent.display()))
+            })?;
+        }
+
+        match mknod(
+            path,
+            SFlag::S_IFCHR,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+            makedev(MISC_MAJOR, minor),
+        ) {
+            // Another process may have created the node in the meantime.
+            Ok(()) | Err(errno::Errno::EEXIST) => Ok(()),
+            Err(err) => Err(context_err(format!(
+                "failed to create control device {}: {err}",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Make sure that the buffers used for subsequent ioctls hold at least
+    /// size bytes, so that an ioctl with a large payload or response, such
+    /// as loading or reading a large table, need not grow its buffer.
+    ///
+    /// Buffers are retained by the context and reused across ioctls, so
+    /// this also increases the memory held by the context.
+    pub fn reserve_ioctl_buffer(&self, size: usize) {
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.is_empty() {
+                buffers.push(Vec::with_capacity(size));
+            } else {
+                for buffer in buffers.iter_mut() {
+                    buffer.reserve(size);
+                }
+            }
+        }
+    }
+
+    // Get a buffer for an ioctl, reusing an idle one if available.
+    fn take_buffer(&self) -> Vec<u8> {
+        self.buffers
+            .lock()
+            .ok()
+            .and_then(|mut buffers| buffers.pop())
+            .unwrap_or_default()
+    }
+
+    /// Set how ioctls issued concurrently through this context, e.g. by
+    /// threads sharing it, are scheduled. The default is
+    /// IoctlScheduling::Concurrent. The setting applies to ioctls made
+    /// after it is changed.
+    pub fn set_ioctl_scheduling(&self, scheduling: IoctlScheduling) {
+        self.ioctl_queue.set_scheduling(scheduling);
+    }
+
+    /// How ioctls issued concurrently through this context are scheduled.
+    pub fn ioctl_scheduling(&self) -> IoctlScheduling {
+        self.ioctl_queue.scheduling()
+    }
+
+    // Return a buffer to the pool once an ioctl is done with it.
+    fn put_buffer(&self, mut buffer: Vec<u8>) {
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < MAX_POOLED_BUFFERS {
+                buffer.clear();
+                buffers.push(buffer);
+            }
+        }
+    }
+
+    // A DmName is always shorter than the header's name field, so it is
+    // copied into it directly, leaving room for the terminating null byte.
+    fn hdr_set_name(hdr: &mut dmi::Struct_dm_ioctl, name: &DmName) {
+        copy_to_c_str(&mut hdr.name, name.as_bytes());
+    }
+
+    // A DmUuid is always shorter than the header's uuid field.
+    fn hdr_set_uuid(hdr: &mut dmi::Struct_dm_ioctl, uuid: &DmUuid) {
+        copy_to_c_str(&mut hdr.uuid, uuid.as_bytes());
     }
 
     /// Get the file within the DM context, likely for polling purposes.
@@ -121,11 +313,69 @@ impl DM {
         hdr: &mut dmi::Struct_dm_ioctl,
         in_data: Option<&[u8]>,
     ) -> DmResult<(DeviceInfo, Vec<u8>)> {
+        self.do_ioctl_up_to(ioctl, hdr, in_data, None)
+    }
+
+    // As do_ioctl, but if max_size is given, the buffer is not grown beyond
+    // it. If the kernel's response still does not fit, the truncated
+    // response is returned, with DM_BUFFER_FULL set in the flags.
+    fn do_ioctl_up_to(
+        &self,
+        ioctl: u8,
+        hdr: &mut dmi::Struct_dm_ioctl,
+        in_data: Option<&[u8]>,
+        max_size: Option<usize>,
+    ) -> DmResult<(DeviceInfo, Vec<u8>)> {
+        let mut buffer = self.take_buffer();
+        let result = self
+            .do_ioctl_with_buffer(ioctl, hdr, in_data, max_size, &mut buffer)
+            .map(|(hdr_out, data_range)| (hdr_out, buffer[data_range].to_vec()));
+        #[cfg(feature = "zeroize")]
+        if hdr.flags & DmFlags::DM_SECURE_DATA.bits() != 0 {
+            buffer.zeroize();
+        }
+        self.put_buffer(buffer);
+        result
+    }
+
+    // Make the ioctl call specified by the given ioctl number, using buffer
+    // for the ioctl's header and payload. The buffer's existing capacity is
+    // used if it is sufficient, otherwise the buffer is grown, up to
+    // max_size, if given.
+    // Return the range within buffer of the data returned by the kernel.
+    fn do_ioctl_with_buffer<B: IoctlBuffer>(
+        &self,
+        ioctl: u8,
+        hdr: &mut dmi::Struct_dm_ioctl,
+        in_data: Option<&[u8]>,
+        max_size: Option<usize>,
+        buffer: &mut B,
+    ) -> DmResult<(DeviceInfo, Range<usize>)> {
         let op = request_code_readwrite!(dmi::DM_IOCTL, ioctl, size_of::<dmi::Struct_dm_ioctl>());
-        #[cfg(target_os = "android")]
+        // musl and bionic declare the request argument of ioctl() as an int.
+        #[cfg(any(target_os = "android", target_env = "musl"))]
         let op = op as i32;
 
         let ioctl_version = dmi::ioctl_to_version(ioctl);
+
+        // Fail with an explanation, rather than with the kernel's EINVAL, if
+        // the kernel's interface is too old for the ioctl.
+        if ioctl as u32 != dmi::DM_VERSION_CMD {
+            let version = self.version()?;
+            if !version_supports(version, ioctl_version) {
+                let err_msg = format!(
+                    "ioctl {} requires DM interface version {}.{}.{}, but the kernel provides only {}.{}.{}",
+                    ioctl,
+                    ioctl_version.0,
+                    ioctl_version.1,
+                    ioctl_version.2,
+                    version.0,
+                    version.1,
+                    version.2
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+        }
         hdr.version[0] = ioctl_version.0;
         hdr.version[1] = ioctl_version.1;
         hdr.version[2] = ioctl_version.2;
@@ -140,10 +390,17 @@ impl DM {
             size_of::<dmi::Struct_dm_ioctl>() + in_data.map_or(0, |x| x.len()),
         );
 
-        let mut buffer: Vec<u8> = Vec::with_capacity(data_size);
+        buffer.reserve_total(data_size)?;
         let mut buffer_hdr;
         loop {
-            hdr.data_size = buffer.capacity() as u32;
+            // The size of the buffer can never be expressed as more than
+            // u32::MAX in the data_size field.
+            hdr.data_size = cmp::min(
+                buffer.capacity(),
+                max_size.map_or(u32::MAX as usize, |max_size| {
+                    cmp::min(max_size, u32::MAX as usize)
+                }),
+            ) as u32;
 
             let hdr_slc = unsafe {
                 let len = hdr.data_start as usize;
@@ -151,18 +408,22 @@ impl DM {
                 slice::from_raw_parts_mut(ptr, len)
             };
 
-            buffer.clear();
-            buffer.extend_from_slice(hdr_slc);
+            let bytes = buffer.zeroed(hdr.data_size as usize);
+            bytes[..hdr_slc.len()].copy_from_slice(hdr_slc);
             if let Some(in_data) = in_data {
-                buffer.extend(in_data.iter().cloned());
+                bytes[hdr_slc.len()..hdr_slc.len() + in_data.len()].copy_from_slice(in_data);
             }
-            buffer.resize(buffer.capacity(), 0);
+            let bytes_ptr = bytes.as_mut_ptr();
+
+            buffer_hdr = unsafe { &mut *(bytes_ptr as *mut dmi::Struct_dm_ioctl) };
 
-            buffer_hdr = unsafe { &mut *(buffer.as_mut_ptr() as *mut dmi::Struct_dm_ioctl) };
+            let turn = self.ioctl_queue.wait_turn(ioctl);
+            self.ioctls.fetch_add(1, Ordering::Relaxed);
+            let res =
+                unsafe { convert_ioctl_res!(nix_ioctl(self.file.as_raw_fd(), op, bytes_ptr)) };
+            drop(turn);
 
-            if let Err(err) = unsafe {
-                convert_ioctl_res!(nix_ioctl(self.file.as_raw_fd(), op, buffer.as_mut_ptr()))
-            } {
+            if let Err(err) = res {
                 // Cancel udev sync and clean up semaphore
                 #[cfg(feature = "udev-sync")]
                 sync.cancel();
@@ -184,11 +445,21 @@ impl DM {
             // ioctl. If the size of the buffer is already as large as can be
             // possibly expressed in data_size field, return an error.
             // Never allow the size to exceed u32::MAX.
-            let len = buffer.capacity();
+            let len = hdr.data_size as usize;
+            if max_size.is_some_and(|max_size| len >= max_size) {
+                break;
+            }
             if len == u32::MAX as usize {
                 return Err(DmError::Core(errors::Error::IoctlResultTooLarge));
             }
-            buffer.resize((len as u32).saturating_mul(2) as usize, 0);
+            // Growing the buffer may move it, leaving the payload behind.
+            #[cfg(feature = "zeroize")]
+            if hdr.flags & DmFlags::DM_SECURE_DATA.bits() != 0 {
+                let capacity = buffer.capacity();
+                buffer.zeroed(capacity).zeroize();
+            }
+            let new_len = (len as u32).saturating_mul(2) as usize;
+            buffer.reserve_total(max_size.map_or(new_len, |max_size| new_len.min(max_size)))?;
         }
 
         let data_end = cmp::max(buffer_hdr.data_size, buffer_hdr.data_start);
@@ -198,13 +469,50 @@ impl DM {
         sync.end(buffer_hdr.flags)?;
         Ok((
             DeviceInfo::try_from(*buffer_hdr)?,
-            buffer[buffer_hdr.data_start as usize..data_end as usize].to_vec(),
+            buffer_hdr.data_start as usize..data_end as usize,
         ))
     }
 
     /// Devicemapper version information: Major, Minor, and patchlevel versions.
+    /// The version is obtained from the kernel when the context is created.
     pub fn version(&self) -> DmResult<(u32, u32, u32)> {
-        let mut hdr = DmOptions::default().to_ioctl_hdr(None, DmFlags::empty())?;
+        self.version
+            .get_or_try_init(|| self.query_version())
+            .copied()
+    }
+
+    /// Whether the kernel's DM interface provides the given feature.
+    pub fn supports(&self, feature: DmFeature) -> DmResult<bool> {
+        Ok(version_supports(self.version()?, feature.version()))
+    }
+
+    /// The number of ioctls made through this context so far, counting
+    /// each retry with a larger buffer, for measuring the cost of
+    /// operations.
+    pub fn ioctl_count(&self) -> u64 {
+        self.ioctls.load(Ordering::Relaxed)
+    }
+
+    /// Run f in a batch: the ioctls that generate uevents, i.e. those that
+    /// remove, rename and resume devices, which f makes on this thread, all
+    /// share one udev cookie, and are waited on once, when f returns, rather
+    /// than each in turn, as libdevmapper does for tasks that share a cookie.
+    /// If a batch is already open on this thread, f joins it. Without udev
+    /// synchronization, f is simply run.
+    ///
+    /// The batch belongs to the calling thread only: ioctls that f makes
+    /// on other threads, such as the workers that [`DM::devices_remove`]
+    /// and [`DM::devices_resume`] start when allowed more than one thread,
+    /// do not join it, and each waits for udev as it would outside a batch.
+    pub fn with_udev_cookie<R, F>(&self, f: F) -> DmResult<R>
+    where
+        F: FnOnce(&UdevBatch) -> DmResult<R>,
+    {
+        with_batch(f)
+    }
+
+    fn query_version(&self) -> DmResult<(u32, u32, u32)> {
+        let mut hdr = DmOptions::default().to_ioctl_hdr(None, DmFlags::empty());
 
         let (hdr_out, _) = self.do_ioctl(dmi::DM_VERSION_CMD as u8, &mut hdr, None)?;
 
@@ -235,7 +543,7 @@ impl DM {
     ///
     /// Valid flags: `DM_DEFERRED_REMOVE`
     pub fn remove_all(&self, options: DmOptions) -> DmResult<()> {
-        let mut hdr = options.to_ioctl_hdr(None, DmFlags::DM_DEFERRED_REMOVE)?;
+        let mut hdr = options.to_ioctl_hdr(None, DmFlags::DM_DEFERRED_REMOVE);
 
         self.do_ioctl(dmi::DM_REMOVE_ALL_CMD as u8, &mut hdr, None)?;
 
@@ -246,76 +554,91 @@ impl DM {
     /// holds their major and minor device numbers, and on kernels that
     /// support it, each device's last event_nr.
     pub fn list_devices(&self) -> DmResult<Vec<(DmNameBuf, Device, Option<u32>)>> {
-        let mut hdr = DmOptions::default().to_ioctl_hdr(None, DmFlags::empty())?;
-        let (hdr_out, data_out) = self.do_ioctl(dmi::DM_LIST_DEVICES_CMD as u8, &mut hdr, None)?;
-
-        let event_nr_set = hdr_out.version() >= &Version::new(4, 37, 0);
-
-        let mut devs = Vec::new();
-        if !data_out.is_empty() {
-            let mut result = &data_out[..];
-
-            loop {
-                let device =
-                    c_struct_from_slice::<dmi::Struct_dm_name_list>(result).ok_or_else(|| {
-                        DmError::Dm(
-                            ErrorEnum::Invalid,
-                            "Received null pointer from kernel".to_string(),
-                        )
-                    })?;
-                let name_offset = unsafe {
-                    (device.name.as_ptr() as *const u8).offset_from(device as *const _ as *const u8)
-                } as usize;
-
-                let dm_name = str_from_byte_slice(&result[name_offset..])
-                    .map(|s| s.to_owned())
-                    .ok_or_else(|| {
-                        DmError::Dm(
-                            ErrorEnum::Invalid,
-                            "Devicemapper name is not valid UTF8".to_string(),
-                        )
-                    })?;
-
-                // Get each device's event number after its name, if the kernel
-                // DM version supports it.
-                // Should match offset calc in kernel's
-                // drivers/md/dm-ioctl.c:list_devices
-                let event_nr = if event_nr_set {
-                    // offsetof "name" in Struct_dm_name_list.
-                    let offset = align_to(name_offset + dm_name.len() + 1, size_of::<u64>());
-                    let nr = u32::from_ne_bytes(
-                        result[offset..offset + size_of::<u32>()]
-                            .try_into()
-                            .map_err(|_| {
-                                DmError::Dm(
-                                    ErrorEnum::Invalid,
-                                    "Incorrectly sized slice for u32".to_string(),
-                                )
-                            })?,
-                    );
-
-                    Some(nr)
-                } else {
-                    None
-                };
+        let mut buffer = Vec::new();
+        self.devices_iter(&mut buffer)?
+            .map(|res| res.map(|(name, device, event_nr)| (name.to_owned(), device, event_nr)))
+            .collect()
+    }
 
-                devs.push((DmNameBuf::new(dm_name)?, device.dev.into(), event_nr));
+    /// Returns an iterator over the same entries as [`Self::list_devices`],
+    /// parsed lazily from the kernel's response.
+    ///
+    /// The ioctl is made using `buffer`, which is grown if it is too small to
+    /// hold the response. Passing the same buffer on each call avoids
+    /// allocating a new one every time the devices are listed.
+    pub fn devices_iter<'a>(&self, buffer: &'a mut Vec<u8>) -> DmResult<DevicesIter<'a>> {
+        self.list_devices_ioctl(buffer, false)
+    }
 
-                if device.next == 0 {
-                    break;
+    /// Returns the devices whose uuids begin with prefix, e.g. "CRYPT-",
+    /// with their uuids, as for [`Self::list_devices`]. This allows a
+    /// program that gives its devices uuids with a prefix of its own to
+    /// find just those devices.
+    ///
+    /// On kernels that do not support [`DmFeature::ListDeviceUuids`], each
+    /// device's uuid is queried separately.
+    #[allow(clippy::type_complexity)]
+    pub fn list_devices_with_uuid_prefix(
+        &self,
+        prefix: &str,
+    ) -> DmResult<Vec<(DmNameBuf, DmUuidBuf, Device, Option<u32>)>> {
+        let uuid_set = self.supports(DmFeature::ListDeviceUuids)?;
+        let mut buffer = Vec::new();
+        let mut devices = Vec::new();
+        for entry in self.list_devices_ioctl(&mut buffer, uuid_set)?.with_uuids() {
+            let (name, device, event_nr, uuid) = entry?;
+            let uuid = if uuid_set {
+                uuid.map(|uuid| uuid.to_owned())
+            } else {
+                match self.device_info(&DevId::Name(name)) {
+                    Ok(info) => info.uuid().map(|uuid| uuid.to_owned()),
+                    // The device was removed after it was listed
+                    Err(err) if err.errno() == Some(errno::Errno::ENXIO) => continue,
+                    Err(err) => return Err(err),
                 }
-
-                result = &result[device.next as usize..];
+            };
+            if let Some(uuid) = uuid.filter(|uuid| uuid.as_bytes().starts_with(prefix.as_bytes())) {
+                devices.push((name.to_owned(), uuid, device, event_nr));
             }
         }
+        Ok(devices)
+    }
+
+    /// Issue DM_LIST_DEVICES, asking for the devices' uuids if with_uuids
+    /// is set.
+    fn list_devices_ioctl<'a>(
+        &self,
+        buffer: &'a mut Vec<u8>,
+        with_uuids: bool,
+    ) -> DmResult<DevicesIter<'a>> {
+        let options = if with_uuids {
+            DmOptions::default().set_flags(DmFlags::DM_UUID)
+        } else {
+            DmOptions::default()
+        };
+        let mut hdr = options.to_ioctl_hdr(None, DmFlags::DM_UUID);
+        let (hdr_out, data_range) = self.do_ioctl_with_buffer(
+            dmi::DM_LIST_DEVICES_CMD as u8,
+            &mut hdr,
+            None,
+            None,
+            buffer,
+        )?;
 
-        Ok(devs)
+        Ok(DevicesIter {
+            data: &buffer[data_range],
+            event_nr_set: hdr_out.version() >= &Version::new(4, 37, 0),
+            uuid_set: with_uuids,
+        })
     }
 
     /// Create a DM device. It starts out in a "suspended" state.
     ///
     /// Valid flags: `DM_READONLY`, `DM_PERSISTENT_DEV`
     ///
+    /// To request a particular minor number, use
+    /// [`DM::device_create_with_minor`].
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -333,12 +656,11 @@ impl DM {
         uuid: Option<&DmUuid>,
         options: DmOptions,
     ) -> DmResult<DeviceInfo> {
-        let mut hdr =
-            options.to_ioctl_hdr(None, DmFlags::DM_READONLY | DmFlags::DM_PERSISTENT_DEV)?;
+        let mut hdr = options.to_ioctl_hdr(None, DmFlags::DM_READONLY | DmFlags::DM_PERSISTENT_DEV);
 
-        Self::hdr_set_name(&mut hdr, name)?;
+        Self::hdr_set_name(&mut hdr, name);
         if let Some(uuid) = uuid {
-            Self::hdr_set_uuid(&mut hdr, uuid)?;
+            Self::hdr_set_uuid(&mut hdr, uuid);
         }
 
         debug!("Creating device {} (uuid={:?})", name, uuid);
@@ -346,17 +668,50 @@ impl DM {
             .map(|(hdr, _)| hdr)
     }
 
+    /// Create a DM device with the given minor number, so that its device
+    /// node, /dev/dm-<minor>, is the same each time it is created.
+    ///
+    /// Fails if the minor number is already in use or is too large to be
+    /// encoded in the ioctl header.
+    ///
+    /// Valid flags: `DM_READONLY`; `DM_PERSISTENT_DEV` is always set.
+    pub fn device_create_with_minor(
+        &self,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        minor: u32,
+        options: DmOptions,
+    ) -> DmResult<DeviceInfo> {
+        let dev = Device { major: 0, minor }.to_kdev_t().ok_or_else(|| {
+            DmError::Core(errors::Error::InvalidArgument(format!(
+                "minor number {minor} can not be requested for a DM device"
+            )))
+        })?;
+
+        let mut hdr = options
+            .set_flags(options.flags() | DmFlags::DM_PERSISTENT_DEV)
+            .to_ioctl_hdr(None, DmFlags::DM_READONLY | DmFlags::DM_PERSISTENT_DEV);
+        hdr.dev = u64::from(dev);
+
+        Self::hdr_set_name(&mut hdr, name);
+        if let Some(uuid) = uuid {
+            Self::hdr_set_uuid(&mut hdr, uuid);
+        }
+
+        debug!(
+            "Creating device {} (uuid={:?}, minor={})",
+            name, uuid, minor
+        );
+        self.do_ioctl(dmi::DM_DEV_CREATE_CMD as u8, &mut hdr, None)
+            .map(|(hdr, _)| hdr)
+    }
+
     fn try_device_remove(
         &self,
         id: &DevId<'_>,
         options: DmOptions,
     ) -> OperationResult<DeviceInfo, DmError> {
-        let mut hdr = match options.to_ioctl_hdr(Some(id), DmFlags::DM_DEFERRED_REMOVE) {
-            Ok(hdr) => hdr,
-            Err(err) => {
-                return OperationResult::Err(err);
-            }
-        };
+        let mut hdr = options.to_ioctl_hdr(Some(id), DmFlags::DM_DEFERRED_REMOVE);
 
         match self.do_ioctl(dmi::DM_DEV_REMOVE_CMD as u8, &mut hdr, None) {
             Err(err) => {
@@ -400,6 +755,247 @@ impl DM {
         }
     }
 
+    /// Remove the named devices, removing devices that do not depend on
+    /// each other concurrently, on at most `max_threads` threads.
+    ///
+    /// A device is removed only after every other named device that is
+    /// stacked on top of it has been removed. Removal stops once a removal
+    /// fails, and the error is returned. If `max_threads` is 1, the devices
+    /// are removed on the calling thread.
+    ///
+    /// Valid flags: `DM_DEFERRED_REMOVE`
+    pub fn devices_remove(
+        &self,
+        names: &[&DmName],
+        options: DmOptions,
+        max_threads: usize,
+    ) -> DmResult<()> {
+        self.for_each_by_deps(names, true, max_threads, |name| {
+            self.device_remove(&DevId::Name(name), options).map(|_| ())
+        })
+    }
+
+    /// Resume the named devices, resuming devices that do not depend on
+    /// each other concurrently, on at most `max_threads` threads.
+    ///
+    /// A device is resumed only after every other named device that it is
+    /// stacked on top of has been resumed, so that any table loaded into
+    /// each device's inactive slot is made live from the bottom of the stack
+    /// up.
+    pub fn devices_resume(&self, names: &[&DmName], max_threads: usize) -> DmResult<()> {
+        self.for_each_by_deps(names, false, max_threads, |name| {
+            self.device_suspend(&DevId::Name(name), DmOptions::private())
+                .map(|_| ())
+        })
+    }
+
+    /// Remove a DM device along with every DM device that is stacked on top
+    /// of it, directly or indirectly, removing each device only after all the
+    /// devices stacked on top of it have been removed.
+    ///
+    /// Each removal is retried if the device is busy, as for
+    /// [`Self::device_remove`].
+    ///
+    /// Valid flags: `DM_DEFERRED_REMOVE`
+    pub fn remove_recursive(&self, id: &DevId<'_>, options: DmOptions) -> DmResult<()> {
+        let tree = self.device_tree(id)?;
+        let names = tree.iter().map(|name| &**name).collect::<Vec<_>>();
+
+        debug!("Removing device {} and its holders {:?}", id, names);
+        self.devices_remove(&names, options, 1)
+    }
+
+    /// Suspend a DM device along with every DM device that is stacked on top
+    /// of it, directly or indirectly, suspending each device only after all
+    /// the devices stacked on top of it have been suspended. This makes the
+    /// whole stack quiescent, e.g. so that a snapshot of the device is
+    /// consistent with the devices above it.
+    ///
+    /// If a device can not be suspended, the devices already suspended are
+    /// resumed and the error is returned.
+    ///
+    /// Valid flags: `DM_NOFLUSH`, `DM_SKIP_LOCKFS`
+    pub fn suspend_tree(&self, id: &DevId<'_>, options: DmOptions) -> DmResult<()> {
+        let tree = self.device_tree(id)?;
+        let names = tree.iter().map(|name| &**name).collect::<Vec<_>>();
+        let options = options.set_flags(options.flags() | DmFlags::DM_SUSPEND);
+
+        debug!("Suspending device {} and its holders {:?}", id, names);
+        let suspended = Mutex::new(Vec::new());
+        let result = self.for_each_by_deps(&names, true, 1, |name| {
+            self.device_suspend(&DevId::Name(name), options)?;
+            suspended
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .push(name.to_owned());
+            Ok(())
+        });
+
+        if result.is_err() {
+            let suspended = suspended
+                .into_inner()
+                .unwrap_or_else(|err| err.into_inner());
+            let names = suspended.iter().map(|name| &**name).collect::<Vec<_>>();
+            if let Err(err) = self.devices_resume(&names, 1) {
+                warn!("Failed to resume devices {:?}: {}", names, err);
+            }
+        }
+        result
+    }
+
+    /// Resume a DM device along with every DM device that is stacked on top
+    /// of it, as suspended by [`Self::suspend_tree`], resuming each device
+    /// only after all the devices it is stacked on top of have been resumed.
+    pub fn resume_tree(&self, id: &DevId<'_>) -> DmResult<()> {
+        let tree = self.device_tree(id)?;
+        let names = tree.iter().map(|name| &**name).collect::<Vec<_>>();
+
+        debug!("Resuming device {} and its holders {:?}", id, names);
+        self.devices_resume(&names, 1)
+    }
+
+    // The names of a DM device and of every DM device stacked on top of it,
+    // directly or indirectly.
+    fn device_tree(&self, id: &DevId<'_>) -> DmResult<Vec<DmNameBuf>> {
+        let target = self.device_info(id)?.device();
+
+        let mut deps = Vec::new();
+        for (name, device, _) in self.list_devices()? {
+            let dev_deps = match self.table_deps(&DevId::Name(&name), DmOptions::default()) {
+                Ok(dev_deps) => dev_deps,
+                // The device was removed since the devices were listed.
+                Err(err) if err.errno() == Some(errno::Errno::ENXIO) => continue,
+                Err(err) => return Err(err),
+            };
+            deps.push((name, device, dev_deps));
+        }
+
+        // Find the holders of the target, then the holders of those, until
+        // no more are found.
+        let mut tree = vec![target];
+        let mut found = true;
+        while found {
+            found = false;
+            for (_, device, dev_deps) in &deps {
+                if !tree.contains(device) && dev_deps.iter().any(|d| tree.contains(d)) {
+                    tree.push(*device);
+                    found = true;
+                }
+            }
+        }
+
+        Ok(deps
+            .into_iter()
+            .filter(|(_, device, _)| tree.contains(device))
+            .map(|(name, _, _)| name)
+            .collect())
+    }
+
+    // Apply f to each of the named devices in an order determined by the
+    // dependencies between them. If holders_first is true, f is applied to a
+    // device only once it has been applied to all the devices stacked on top
+    // of it, otherwise only once it has been applied to all the devices it is
+    // stacked on top of. Devices whose turn has come are handled on this
+    // thread if max_threads is 1, or else by a pool of at most max_threads
+    // threads. Once f fails, no more devices are handled.
+    fn for_each_by_deps<F>(
+        &self,
+        names: &[&DmName],
+        holders_first: bool,
+        max_threads: usize,
+        f: F,
+    ) -> DmResult<()>
+    where
+        F: Fn(&DmName) -> DmResult<()> + Sync,
+    {
+        let devices = names
+            .iter()
+            .map(|name| {
+                self.device_info(&DevId::Name(name))
+                    .map(|info| info.device())
+            })
+            .collect::<DmResult<Vec<_>>>()?;
+
+        // For each device, the number of devices that must be handled before
+        // it, and the devices that must wait on it.
+        let mut waiting_on = vec![0usize; names.len()];
+        let mut blocking = vec![Vec::new(); names.len()];
+        for (holder, name) in names.iter().enumerate() {
+            for dep in self.table_deps(&DevId::Name(name), DmOptions::default())? {
+                if let Some(lower) = devices.iter().position(|d| *d == dep) {
+                    let (first, then) = if holders_first {
+                        (holder, lower)
+                    } else {
+                        (lower, holder)
+                    };
+                    blocking[first].push(then);
+                    waiting_on[then] += 1;
+                }
+            }
+        }
+
+        let f = &f;
+        let mut handled = 0;
+        let mut ready = (0..names.len())
+            .filter(|i| waiting_on[*i] == 0)
+            .collect::<Vec<_>>();
+        while !ready.is_empty() {
+            let threads = cmp::min(cmp::max(max_threads, 1), ready.len());
+            if threads == 1 {
+                for i in &ready {
+                    f(names[*i])?;
+                }
+            } else {
+                // Each worker takes the next device whose turn has come
+                // until there are none left, or until a worker has failed.
+                let next = AtomicUsize::new(0);
+                let failed = AtomicBool::new(false);
+                let worker = || -> DmResult<()> {
+                    while !failed.load(Ordering::Relaxed) {
+                        let i = match ready.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            Some(i) => *i,
+                            None => break,
+                        };
+                        if let Err(err) = f(names[i]) {
+                            failed.store(true, Ordering::Relaxed);
+                            return Err(err);
+                        }
+                    }
+                    Ok(())
+                };
+                thread::scope(|scope| {
+                    (0..threads)
+                        .map(|_| scope.spawn(worker))
+                        .collect::<Vec<_>>()
+                        .into_iter()
+                        .map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                        .collect::<DmResult<Vec<_>>>()
+                })?;
+            }
+            handled += ready.len();
+
+            let mut next = Vec::new();
+            for i in ready {
+                for then in &blocking[i] {
+                    waiting_on[*then] -= 1;
+                    if waiting_on[*then] == 0 {
+                        next.push(*then);
+                    }
+                }
+            }
+            ready = next;
+        }
+
+        if handled != names.len() {
+            return Err(DmError::Dm(
+                ErrorEnum::Invalid,
+                "Devices have circular dependencies".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
     /// Change a DM device's name OR set the device's uuid for the first time.
     ///
     /// Prerequisite: if `new == DevId::Name(new_name)`, `old_name != new_name`
@@ -418,8 +1014,8 @@ impl DM {
 
         let data_in = [id_in, &[b'\0']].concat();
 
-        let mut hdr = options.to_ioctl_hdr(None, DmFlags::DM_UUID)?;
-        Self::hdr_set_name(&mut hdr, old_name)?;
+        let mut hdr = options.to_ioctl_hdr(None, DmFlags::DM_UUID);
+        Self::hdr_set_name(&mut hdr, old_name);
 
         debug!("Renaming device {} to {}", old_name, new);
         self.do_ioctl(dmi::DM_DEV_RENAME_CMD as u8, &mut hdr, Some(&data_in))
@@ -453,7 +1049,7 @@ impl DM {
         let mut hdr = options.to_ioctl_hdr(
             Some(id),
             DmFlags::DM_SUSPEND | DmFlags::DM_NOFLUSH | DmFlags::DM_SKIP_LOCKFS,
-        )?;
+        );
 
         let action = if options.flags().contains(DmFlags::DM_SUSPEND) {
             "Suspending"
@@ -469,13 +1065,33 @@ impl DM {
     /// methods, but if just the DeviceInfo is desired then this just
     /// gets it.
     pub fn device_info(&self, id: &DevId<'_>) -> DmResult<DeviceInfo> {
-        let mut hdr = DmOptions::default().to_ioctl_hdr(Some(id), DmFlags::empty())?;
+        let mut hdr = DmOptions::default().to_ioctl_hdr(Some(id), DmFlags::empty());
 
         trace!("Retrieving info for {}", id);
         self.do_ioctl(dmi::DM_DEV_STATUS_CMD as u8, &mut hdr, None)
             .map(|(hdr, _)| hdr)
     }
 
+    /// Find the DM device with the given device number, e.g. one read from
+    /// /proc/self/mountinfo or from a uevent, with an info ioctl that
+    /// identifies the device by its number. Return None if no DM device has
+    /// the number.
+    pub fn device_by_devno(&self, device: Device) -> DmResult<Option<DeviceInfo>> {
+        // The kernel encodes the number it is given as a kdev_t does.
+        let Some(kdev) = device.to_kdev_t() else {
+            return Ok(None);
+        };
+        let mut hdr = DmOptions::default().to_ioctl_hdr(None, DmFlags::empty());
+        hdr.dev = u64::from(kdev);
+
+        trace!("Retrieving info for device number {}", device);
+        match self.do_ioctl(dmi::DM_DEV_STATUS_CMD as u8, &mut hdr, None) {
+            Ok((info, _)) => Ok(Some(info)),
+            Err(err) if err.errno() == Some(errno::Errno::ENXIO) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
     /// Wait for a device to report an event.
     ///
     /// Once an event occurs, this function behaves just like
@@ -489,7 +1105,7 @@ impl DM {
         id: &DevId<'_>,
         options: DmOptions,
     ) -> DmResult<(DeviceInfo, Vec<(u64, u64, String, String)>)> {
-        let mut hdr = options.to_ioctl_hdr(Some(id), DmFlags::DM_QUERY_INACTIVE_TABLE)?;
+        let mut hdr = options.to_ioctl_hdr(Some(id), DmFlags::DM_QUERY_INACTIVE_TABLE);
 
         trace!("Waiting on event for {}", id);
         let (hdr_out, data_out) = self.do_ioctl(dmi::DM_DEV_WAIT_CMD as u8, &mut hdr, None)?;
@@ -499,11 +1115,96 @@ impl DM {
         Ok((hdr_out, status))
     }
 
+    /// Wait until an event has occurred on a device since its event number
+    /// was since_event_nr, e.g. as returned by [`DeviceInfo::event_nr`],
+    /// and return the device's info. Return at once if the device's event
+    /// number already differs.
+    ///
+    /// If a timeout is given, return None if no event occurs within it.
+    /// Waiting with a timeout arms and polls this context's file, so it
+    /// requires [`DmFeature::ArmPoll`] and should not be combined with
+    /// polling the context elsewhere.
+    pub fn wait_for_event(
+        &self,
+        id: &DevId<'_>,
+        since_event_nr: u32,
+        timeout: Option<Duration>,
+    ) -> DmResult<Option<DeviceInfo>> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => {
+                let mut hdr = DmOptions::default().to_ioctl_hdr(Some(id), DmFlags::empty());
+                // DM_DEV_WAIT returns once the event number differs from
+                // the one given.
+                hdr.event_nr = since_event_nr;
+
+                trace!("Waiting on event {} for {}", since_event_nr, id);
+                return self
+                    .do_ioctl(dmi::DM_DEV_WAIT_CMD as u8, &mut hdr, None)
+                    .map(|(hdr, _)| Some(hdr));
+            }
+        };
+        self.wait_for_event_timeout(id, since_event_nr, timeout)
+    }
+
+    #[cfg(devicemapper437supported)]
+    fn wait_for_event_timeout(
+        &self,
+        id: &DevId<'_>,
+        since_event_nr: u32,
+        timeout: Duration,
+    ) -> DmResult<Option<DeviceInfo>> {
+        let start = Instant::now();
+        loop {
+            // Arm before checking the event number, so that an event after
+            // the check makes the file readable.
+            self.arm_poll()?;
+            let info = self.device_info(id)?;
+            if info.event_nr() != since_event_nr {
+                return Ok(Some(info));
+            }
+
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            let mut fds = [PollFd::new(self.as_fd(), PollFlags::POLLIN)];
+            match poll(
+                &mut fds,
+                PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX),
+            ) {
+                Ok(_) | Err(errno::Errno::EINTR) => (),
+                Err(err) => {
+                    return Err(DmError::Core(errors::Error::GeneralIo(format!(
+                        "failed to poll DM context: {err}"
+                    ))))
+                }
+            }
+        }
+    }
+
+    #[cfg(not(devicemapper437supported))]
+    fn wait_for_event_timeout(
+        &self,
+        _id: &DevId<'_>,
+        _since_event_nr: u32,
+        _timeout: Duration,
+    ) -> DmResult<Option<DeviceInfo>> {
+        let err_msg = "waiting for an event with a timeout requires DM interface version 4.37.0";
+        Err(DmError::Dm(ErrorEnum::Invalid, err_msg.into()))
+    }
+
     /// Load targets for a device into its inactive table slot.
     ///
-    /// `targets` is an array of `(sector_start, sector_length, type, params)`.
+    /// `targets` yields `(sector_start, sector_length, type, params)` for
+    /// each target. The targets are serialized directly into the ioctl's
+    /// payload as they are yielded, so `params` need only implement
+    /// `Display`; it is not necessary to build a `String` for each target.
     ///
-    /// `options` Valid flags: `DM_READ_ONLY`, `DM_SECURE_DATA`
+    /// `options` Valid flags: `DM_READ_ONLY`, `DM_SECURE_DATA`. With
+    /// `DM_SECURE_DATA`, the params are also left out of the log and, if the
+    /// zeroize feature is enabled, wiped from the ioctl buffers once the
+    /// table is loaded.
     ///
     /// # Example
     ///
@@ -513,32 +1214,84 @@ impl DM {
     ///
     /// // Create a 16MiB device (32768 512-byte sectors) that maps to /dev/sdb1
     /// // starting 1MiB into sdb1
-    /// let table = vec![(
-    ///     0,
-    ///     32768,
-    ///     "linear".into(),
-    ///     "/dev/sdb1 2048".into()
-    /// )];
+    /// let table = vec![(0, 32768, "linear", "/dev/sdb1 2048")];
     ///
     /// let name = DmName::new("example-dev").expect("is valid DM name");
     /// let id = DevId::Name(name);
-    /// dm.table_load(&id, &table, DmOptions::default()).unwrap();
+    /// dm.table_load(&id, table, DmOptions::default()).unwrap();
     /// ```
-    pub fn table_load(
+    pub fn table_load<I, T, P>(
         &self,
         id: &DevId<'_>,
-        targets: &[(u64, u64, String, String)],
+        targets: I,
         options: DmOptions,
-    ) -> DmResult<DeviceInfo> {
-        let mut cursor = Cursor::new(Vec::new());
+    ) -> DmResult<DeviceInfo>
+    where
+        I: IntoIterator<Item = (u64, u64, T, P)>,
+        T: AsRef<str>,
+        P: fmt::Display,
+    {
+        let mut hdr =
+            options.to_ioctl_hdr(Some(id), DmFlags::DM_READONLY | DmFlags::DM_SECURE_DATA);
+
+        // Reuse a pooled buffer for the targets, since a table may be large.
+        let mut data_in = self.take_buffer();
 
-        // Construct targets first, since we need to know how many & size
-        // before initializing the header.
+        // Params are left out of the log if they contain secrets.
+        let secure = options.flags().contains(DmFlags::DM_SECURE_DATA);
+
+        trace!("Loading table for {}", id);
+        let result =
+            DM::serialize_targets(targets, &mut data_in, secure).and_then(|target_count| {
+                // io_ioctl() will set hdr.data_size but we must set target_count
+                hdr.target_count = target_count;
+
+                self.do_ioctl(dmi::DM_TABLE_LOAD_CMD as u8, &mut hdr, Some(&data_in))
+                    .map(|(hdr, _)| hdr)
+            });
+
+        #[cfg(feature = "zeroize")]
+        if secure {
+            data_in.zeroize();
+        }
+        self.put_buffer(data_in);
+        result
+    }
+
+    // Serialize targets into buf, each as a Struct_dm_target_spec followed
+    // by its params, and return the number of targets. If secure, the
+    // params are not logged.
+    fn serialize_targets<I, T, P>(targets: I, buf: &mut Vec<u8>, secure: bool) -> DmResult<u32>
+    where
+        I: IntoIterator<Item = (u64, u64, T, P)>,
+        T: AsRef<str>,
+        P: fmt::Display,
+    {
+        let targets = targets.into_iter();
+        let spec_size = size_of::<dmi::Struct_dm_target_spec>();
+        buf.reserve(targets.size_hint().0 * spec_size);
+
+        let mut count = 0u32;
         for (sector_start, length, target_type, params) in targets {
+            let target_type = target_type.as_ref();
+
+            // Write the params first, leaving room for the target spec,
+            // since the spec must record the length of the params.
+            let spec_start = buf.len();
+            buf.resize(spec_start + spec_size, 0);
+            write!(buf, "{params}").map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
+            let params_len = buf.len() - spec_start - spec_size;
+
+            // Size of the largest single member of dm_target_spec
+            let align_to_size = size_of::<u64>();
+            let aligned_len = align_to(params_len + 1usize, align_to_size);
+            buf.resize(spec_start + spec_size + aligned_len, 0);
+
             let mut targ = dmi::Struct_dm_target_spec {
-                sector_start: *sector_start,
-                length: *length,
+                sector_start,
+                length,
                 status: 0,
+                next: (spec_size + aligned_len) as u32,
                 ..Default::default()
             };
 
@@ -552,41 +1305,35 @@ impl DM {
                 .read(dst)
                 .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
 
-            // Size of the largest single member of dm_target_spec
-            let align_to_size = size_of::<u64>();
-            let aligned_len = align_to(params.len() + 1usize, align_to_size);
-            targ.next = (size_of::<dmi::Struct_dm_target_spec>() + aligned_len) as u32;
-
-            cursor
-                .write_all(slice_from_c_struct(&targ))
-                .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
-            cursor
-                .write_all(params.as_bytes())
-                .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
-
-            let padding = aligned_len - params.len();
-            cursor
-                .write_all(vec![0; padding].as_slice())
-                .map_err(|err| errors::Error::GeneralIo(err.to_string()))?;
+            buf[spec_start..spec_start + spec_size].copy_from_slice(slice_from_c_struct(&targ));
+
+            if secure {
+                trace!(
+                    "Target {}: ({}, {}, {}, <redacted>)",
+                    count,
+                    sector_start,
+                    length,
+                    target_type
+                );
+            } else {
+                trace!(
+                    "Target {}: ({}, {}, {}, {})",
+                    count,
+                    sector_start,
+                    length,
+                    target_type,
+                    params
+                );
+            }
+            count += 1;
         }
 
-        let mut hdr =
-            options.to_ioctl_hdr(Some(id), DmFlags::DM_READONLY | DmFlags::DM_SECURE_DATA)?;
-
-        // io_ioctl() will set hdr.data_size but we must set target_count
-        hdr.target_count = targets.len() as u32;
-
-        // Flatten targets into a buf
-        let data_in = cursor.into_inner();
-
-        trace!("Loading table \"{:?}\" for {}", targets, id);
-        self.do_ioctl(dmi::DM_TABLE_LOAD_CMD as u8, &mut hdr, Some(&data_in))
-            .map(|(hdr, _)| hdr)
+        Ok(count)
     }
 
     /// Clear the "inactive" table for a device.
     pub fn table_clear(&self, id: &DevId<'_>) -> DmResult<DeviceInfo> {
-        let mut hdr = DmOptions::default().to_ioctl_hdr(Some(id), DmFlags::empty())?;
+        let mut hdr = DmOptions::default().to_ioctl_hdr(Some(id), DmFlags::empty());
 
         trace!("Clearing inactive table for {}", id);
         self.do_ioctl(dmi::DM_TABLE_CLEAR_CMD as u8, &mut hdr, None)
@@ -601,7 +1348,7 @@ impl DM {
     ///
     /// Valid flags: DM_QUERY_INACTIVE_TABLE
     pub fn table_deps(&self, id: &DevId<'_>, options: DmOptions) -> DmResult<Vec<Device>> {
-        let mut hdr = options.to_ioctl_hdr(Some(id), DmFlags::DM_QUERY_INACTIVE_TABLE)?;
+        let mut hdr = options.to_ioctl_hdr(Some(id), DmFlags::DM_QUERY_INACTIVE_TABLE);
 
         trace!("Querying dependencies for {}", id);
         let (_, data_out) = self.do_ioctl(dmi::DM_TABLE_DEPS_CMD as u8, &mut hdr, None)?;
@@ -610,21 +1357,29 @@ impl DM {
             Ok(vec![])
         } else {
             let result = &data_out[..];
-            let target_deps = unsafe { &*(result.as_ptr() as *const dmi::Struct_dm_target_deps) };
-
-            let dev_slc = unsafe {
-                slice::from_raw_parts(
-                    result[size_of::<dmi::Struct_dm_target_deps>()..].as_ptr() as *const u64,
-                    target_deps.count as usize,
-                )
+            if result.len() < size_of::<dmi::Struct_dm_target_deps>() {
+                return Err(DmError::Dm(
+                    ErrorEnum::Invalid,
+                    format!(
+                        "the {} bytes returned by DM_TABLE_DEPS are too few for its header",
+                        result.len()
+                    ),
+                ));
+            }
+            // The buffer is not necessarily aligned for the header, nor for
+            // the u64 entries, which are read byte-wise.
+            let target_deps = unsafe {
+                ptr::read_unaligned(result.as_ptr() as *const dmi::Struct_dm_target_deps)
             };
 
+            let devs = result[size_of::<dmi::Struct_dm_target_deps>()..]
+                .chunks_exact(size_of::<u64>())
+                .take(target_deps.count as usize)
+                .map(|d| u64::from_ne_bytes(d.try_into().expect("chunks are 8 bytes")));
+
             // Note: The DM target_deps struct reserves 64 bits for each entry
             // but only 32 bits is used by kernel "huge" dev_t encoding.
-            Ok(dev_slc
-                .iter()
-                .map(|d| Device::from_kdev_t(*d as u32))
-                .collect())
+            Ok(devs.map(|d| Device::from_kdev_t(d as u32)).collect())
         }
     }
 
@@ -635,40 +1390,14 @@ impl DM {
     /// Postcondition: The length of the next to last entry in any tuple is
     /// no more than 16 characters.
     fn parse_table_status(count: u32, buf: &[u8]) -> DmResult<Vec<(u64, u64, String, String)>> {
-        let mut targets = Vec::new();
-        if !buf.is_empty() {
-            let mut next_off = 0;
-
-            for _ in 0..count {
-                let result = &buf[next_off..];
-                let targ = unsafe { &*(result.as_ptr() as *const dmi::Struct_dm_target_spec) };
-
-                let target_type = str_from_c_str(&targ.target_type)
-                    .ok_or_else(|| {
-                        DmError::Dm(
-                            ErrorEnum::Invalid,
-                            "Could not convert target type to a String".to_string(),
-                        )
-                    })?
-                    .to_string();
-
-                let params =
-                    str_from_byte_slice(&result[size_of::<dmi::Struct_dm_target_spec>()..])
-                        .ok_or_else(|| {
-                            DmError::Dm(
-                                ErrorEnum::Invalid,
-                                "Invalid DM target parameters returned from kernel".to_string(),
-                            )
-                        })?
-                        .to_string();
-
-                targets.push((targ.sector_start, targ.length, target_type, params));
-
-                next_off = targ.next as usize;
-            }
-        }
-        Ok(targets)
-    }
+        TableStatusTargets::new(count, buf)
+            .map(|target| {
+                target.map(|(start, length, target_type, params)| {
+                    (start, length, target_type.to_string(), params.to_string())
+                })
+            })
+            .collect()
+    }
 
     /// Return the status of all targets for a device's "active"
     /// table.
@@ -707,7 +1436,7 @@ impl DM {
         let mut hdr = options.to_ioctl_hdr(
             Some(id),
             DmFlags::DM_NOFLUSH | DmFlags::DM_STATUS_TABLE | DmFlags::DM_QUERY_INACTIVE_TABLE,
-        )?;
+        );
 
         trace!("Retrieving table status for {}", id);
         let (hdr_out, data_out) = self.do_ioctl(dmi::DM_TABLE_STATUS_CMD as u8, &mut hdr, None)?;
@@ -717,11 +1446,73 @@ impl DM {
         Ok((hdr_out, status))
     }
 
+    /// Return the status of all targets for a device's table, as
+    /// [`DM::table_status`] does, but read into a memory mapped buffer of
+    /// at least initial_size bytes, which is grown as necessary. The
+    /// status is parsed in place, so that a status of many megabytes, e.g.
+    /// of a device with hundreds of thousands of targets, is not copied.
+    ///
+    /// Valid flags: DM_NOFLUSH, DM_STATUS_TABLE, DM_QUERY_INACTIVE_TABLE
+    pub fn table_status_mapped(
+        &self,
+        id: &DevId<'_>,
+        options: DmOptions,
+        initial_size: usize,
+    ) -> DmResult<(DeviceInfo, MappedTableStatus)> {
+        let mut hdr = options.to_ioctl_hdr(
+            Some(id),
+            DmFlags::DM_NOFLUSH | DmFlags::DM_STATUS_TABLE | DmFlags::DM_QUERY_INACTIVE_TABLE,
+        );
+
+        trace!("Retrieving table status for {} into a mapped buffer", id);
+        let mut buffer = MmapBuffer::new(initial_size)?;
+        let (hdr_out, data) = self.do_ioctl_with_buffer(
+            dmi::DM_TABLE_STATUS_CMD as u8,
+            &mut hdr,
+            None,
+            None,
+            &mut buffer,
+        )?;
+        let target_count = hdr_out.target_count;
+
+        Ok((
+            hdr_out,
+            MappedTableStatus {
+                buffer,
+                data,
+                target_count,
+            },
+        ))
+    }
+
     /// Returns a list of each loaded target type with its name, and
     /// version broken into major, minor, and patchlevel.
+    ///
+    /// The list is obtained from the kernel on the first call, and kept by
+    /// the context, since target types are seldom loaded while a program
+    /// runs. Call [`DM::refresh_targets`] to have it obtained again, e.g.
+    /// once a target's module has been loaded.
     #[cfg(devicemapper41supported)]
     pub fn list_versions(&self) -> DmResult<Vec<(String, u32, u32, u32)>> {
-        let mut hdr = DmOptions::default().to_ioctl_hdr(None, DmFlags::empty())?;
+        let mut targets = self.targets.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(targets) = targets.as_ref() {
+            return Ok(targets.clone());
+        }
+        let queried = self.query_versions()?;
+        *targets = Some(queried.clone());
+        Ok(queried)
+    }
+
+    /// Discard the list of loaded target types kept by the context, so that
+    /// the next call of [`DM::list_versions`] obtains it from the kernel.
+    #[cfg(devicemapper41supported)]
+    pub fn refresh_targets(&self) {
+        *self.targets.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    #[cfg(devicemapper41supported)]
+    fn query_versions(&self) -> DmResult<Vec<(String, u32, u32, u32)>> {
+        let mut hdr = DmOptions::default().to_ioctl_hdr(None, DmFlags::empty());
 
         trace!("Listing loaded target versions");
         let (_, data_out) = self.do_ioctl(dmi::DM_LIST_VERSIONS_CMD as u8, &mut hdr, None)?;
@@ -755,9 +1546,62 @@ impl DM {
         Ok(targets)
     }
 
+    /// Check that the target type called target is loaded, and return its
+    /// version. If it is not, call loader, if one is given, to load it, e.g.
+    /// `modprobe_target`, with the "modprobe" feature, and check again.
+    ///
+    /// Returns a NotFound error if the target type is not available.
+    #[cfg(devicemapper41supported)]
+    #[allow(clippy::type_complexity)]
+    pub fn ensure_target_loaded(
+        &self,
+        target: &str,
+        loader: Option<&dyn Fn(&str) -> DmResult<()>>,
+    ) -> DmResult<(u32, u32, u32)> {
+        let find_target = || -> DmResult<Option<(u32, u32, u32)>> {
+            Ok(self
+                .list_versions()?
+                .into_iter()
+                .find(|(name, _, _, _)| name == target)
+                .map(|(_, major, minor, patch)| (major, minor, patch)))
+        };
+
+        if let Some(version) = find_target()? {
+            return Ok(version);
+        }
+        // The target type may have been loaded since the list was obtained.
+        self.refresh_targets();
+        if let Some(version) = find_target()? {
+            return Ok(version);
+        }
+
+        let loader = loader.ok_or_else(|| {
+            let err_msg = format!("target type {target} is not loaded");
+            DmError::Dm(ErrorEnum::NotFound, err_msg)
+        })?;
+        debug!("Loading target type {}", target);
+        loader(target).map_err(|err| {
+            let err_msg =
+                format!("target type {target} is not loaded and loading it failed: {err}");
+            DmError::Dm(ErrorEnum::NotFound, err_msg)
+        })?;
+
+        self.refresh_targets();
+        find_target()?.ok_or_else(|| {
+            let err_msg = format!("target type {target} is not available, even once loaded");
+            DmError::Dm(ErrorEnum::NotFound, err_msg)
+        })
+    }
+
     /// Send a message to the device specified by id and the sector
     /// specified by sector. If sending to the whole device, set sector to
     /// None.
+    ///
+    /// If the message's output does not fit in the buffer passed to the
+    /// kernel, the buffer is enlarged and the message sent again, until the
+    /// whole output is returned. A message which changes the target's state
+    /// as well as producing output may therefore take effect more than
+    /// once; see [`DM::target_msg_bounded`].
     #[cfg(devicemapper42supported)]
     pub fn target_msg(
         &self,
@@ -765,7 +1609,54 @@ impl DM {
         sector: Option<u64>,
         msg: &str,
     ) -> DmResult<(DeviceInfo, Option<String>)> {
-        let mut hdr = DmOptions::default().to_ioctl_hdr(Some(id), DmFlags::empty())?;
+        self.target_msg_up_to(id, sector, msg, None)
+            .map(|(info, output, _)| (info, output))
+    }
+
+    /// Send a message, as for [`DM::target_msg`], but return no more than
+    /// max_output_len bytes of its output. Return also whether the output
+    /// was truncated, which the kernel indicates by setting DM_BUFFER_FULL.
+    /// Some targets, e.g. dust with "listbadblocks", may produce a great
+    /// deal of output; the message is sent again with a larger buffer
+    /// only while the output could still fit within max_output_len.
+    #[cfg(devicemapper42supported)]
+    pub fn target_msg_bounded(
+        &self,
+        id: &DevId<'_>,
+        sector: Option<u64>,
+        msg: &str,
+        max_output_len: usize,
+    ) -> DmResult<(DeviceInfo, Option<String>, bool)> {
+        // The output, with its terminating null byte, follows the header
+        let max_size = size_of::<dmi::Struct_dm_ioctl>()
+            .saturating_add(max_output_len)
+            .saturating_add(1)
+            .max(
+                size_of::<dmi::Struct_dm_ioctl>()
+                    + size_of::<dmi::Struct_dm_target_msg>()
+                    + msg.len()
+                    + 1,
+            );
+        self.target_msg_up_to(id, sector, msg, Some(max_size))
+    }
+
+    #[cfg(devicemapper42supported)]
+    fn target_msg_up_to(
+        &self,
+        id: &DevId<'_>,
+        sector: Option<u64>,
+        msg: &str,
+        max_size: Option<usize>,
+    ) -> DmResult<(DeviceInfo, Option<String>, bool)> {
+        if msg.len() > DM_MAX_MESSAGE_LEN || msg.contains('\0') {
+            return Err(DmError::Core(errors::Error::InvalidArgument(format!(
+                "message of {} bytes is longer than {} bytes or contains a null byte",
+                msg.len(),
+                DM_MAX_MESSAGE_LEN
+            ))));
+        }
+
+        let mut hdr = DmOptions::default().to_ioctl_hdr(Some(id), DmFlags::empty());
 
         let msg_struct = dmi::Struct_dm_target_msg {
             sector: sector.unwrap_or_default(),
@@ -780,12 +1671,23 @@ impl DM {
         data_in.push(b'\0');
 
         debug!("Sending target message \"{}\" to {}", msg, id);
-        let (hdr_out, data_out) =
-            self.do_ioctl(dmi::DM_TARGET_MSG_CMD as u8, &mut hdr, Some(&data_in))?;
+        let (hdr_out, data_out) = self.do_ioctl_up_to(
+            dmi::DM_TARGET_MSG_CMD as u8,
+            &mut hdr,
+            Some(&data_in),
+            max_size,
+        )?;
 
+        let truncated = hdr_out.flags().contains(DmFlags::DM_BUFFER_FULL);
         let output = if (hdr_out.flags().bits() & DmFlags::DM_DATA_OUT.bits()) > 0 {
+            // If the output was truncated, the kernel has not set the size
+            // of the data, so it extends to the end of the buffer.
+            let end = data_out
+                .iter()
+                .position(|&b| b == b'\0')
+                .unwrap_or(data_out.len());
             Some(
-                str::from_utf8(&data_out[..data_out.len() - 1])
+                str::from_utf8(&data_out[..end])
                     .map(|res| res.to_string())
                     .map_err(|_| {
                         DmError::Dm(
@@ -797,7 +1699,15 @@ impl DM {
         } else {
             None
         };
-        Ok((hdr_out, output))
+        if truncated {
+            debug!(
+                "Output of target message \"{}\" to {} was truncated to {} bytes",
+                msg,
+                id,
+                output.as_ref().map_or(0, |output| output.len())
+            );
+        }
+        Ok((hdr_out, output, truncated))
     }
 
     /// If DM is being used to poll for events, once it indicates readiness it
@@ -805,7 +1715,7 @@ impl DM {
     /// does.
     #[cfg(devicemapper437supported)]
     pub fn arm_poll(&self) -> DmResult<DeviceInfo> {
-        let mut hdr = DmOptions::default().to_ioctl_hdr(None, DmFlags::empty())?;
+        let mut hdr = DmOptions::default().to_ioctl_hdr(None, DmFlags::empty());
 
         trace!("Issuing device-mapper arm poll command");
         self.do_ioctl(dmi::DM_DEV_ARM_POLL_CMD as u8, &mut hdr, None)
@@ -813,35 +1723,568 @@ impl DM {
     }
 }
 
+/// Load the kernel module that provides the target type called target, by
+/// running "modprobe dm-<target>"; the kernel requests target modules under
+/// the same alias. For use as the loader of [`DM::ensure_target_loaded`].
+#[cfg(feature = "modprobe")]
+pub fn modprobe_target(target: &str) -> DmResult<()> {
+    let module = format!("dm-{target}");
+    let output = Command::new("modprobe")
+        .arg(&module)
+        .output()
+        .map_err(|err| {
+            DmError::Core(errors::Error::GeneralIo(format!(
+                "failed to run modprobe: {err}"
+            )))
+        })?;
+    if !output.status.success() {
+        return Err(DmError::Core(errors::Error::GeneralIo(format!(
+            "modprobe {module} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ))));
+    }
+    Ok(())
+}
+
+/// Without udev synchronization, ioctls are not batched.
+#[cfg(not(feature = "udev-sync"))]
+fn with_batch<R, F>(f: F) -> DmResult<R>
+where
+    F: FnOnce(&UdevBatch) -> DmResult<R>,
+{
+    f(&UdevBatch::new(0))
+}
+
+/// A batch of ioctls that share a udev cookie, see [`DM::with_udev_cookie`].
+#[derive(Debug)]
+pub struct UdevBatch {
+    cookie: u32,
+}
+
+impl UdevBatch {
+    pub(crate) fn new(cookie: u32) -> UdevBatch {
+        UdevBatch { cookie }
+    }
+
+    /// The batch's udev cookie, or 0 if ioctls are not synchronized with
+    /// udev.
+    pub fn cookie(&self) -> u32 {
+        self.cookie
+    }
+}
+
+/// An iterator over the devices listed by [`DM::devices_iter`].
+///
+/// Yields the name, Device, and, on kernels that support it, the last
+/// event_nr of each device.
+pub struct DevicesIter<'a> {
+    data: &'a [u8],
+    event_nr_set: bool,
+    uuid_set: bool,
+}
+
+/// The entries of a DevicesIter followed by each device's uuid, if the
+/// uuids were asked for and the device has one.
+struct DevicesWithUuidsIter<'a>(DevicesIter<'a>);
+
+impl<'a> DevicesIter<'a> {
+    fn with_uuids(self) -> DevicesWithUuidsIter<'a> {
+        DevicesWithUuidsIter(self)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn parse_next(&mut self) -> DmResult<(&'a DmName, Device, Option<u32>, Option<&'a DmUuid>)> {
+        let result = self.data;
+
+        let device = c_struct_from_slice::<dmi::Struct_dm_name_list>(result).ok_or_else(|| {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                "Received null pointer from kernel".to_string(),
+            )
+        })?;
+        let name_offset = unsafe {
+            (device.name.as_ptr() as *const u8).offset_from(device as *const _ as *const u8)
+        } as usize;
+
+        let dm_name = str_from_byte_slice(&result[name_offset..]).ok_or_else(|| {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                "Devicemapper name is not valid UTF8".to_string(),
+            )
+        })?;
+
+        // Get each device's event number after its name, if the kernel
+        // DM version supports it.
+        // Should match offset calc in kernel's
+        // drivers/md/dm-ioctl.c:list_devices
+        let event_nr = if self.event_nr_set {
+            // offsetof "name" in Struct_dm_name_list.
+            let offset = align_to(name_offset + dm_name.len() + 1, size_of::<u64>());
+            let nr = u32::from_ne_bytes(
+                result[offset..offset + size_of::<u32>()]
+                    .try_into()
+                    .map_err(|_| {
+                        DmError::Dm(
+                            ErrorEnum::Invalid,
+                            "Incorrectly sized slice for u32".to_string(),
+                        )
+                    })?,
+            );
+
+            Some(nr)
+        } else {
+            None
+        };
+
+        // The uuid follows the event number and a word of flags that says
+        // whether the device has one.
+        let uuid = if self.uuid_set && self.event_nr_set {
+            let offset = align_to(name_offset + dm_name.len() + 1, size_of::<u64>());
+            let flags_offset = offset + size_of::<u32>();
+            let flags = result
+                .get(flags_offset..flags_offset + size_of::<u32>())
+                .map(|flags| u32::from_ne_bytes(flags.try_into().expect("slice is 4 bytes long")))
+                .unwrap_or(0);
+            if flags & dmi::DM_NAME_LIST_FLAG_HAS_UUID != 0 {
+                let uuid_offset = flags_offset + size_of::<u32>();
+                let dm_uuid = result
+                    .get(uuid_offset..)
+                    .and_then(str_from_byte_slice)
+                    .ok_or_else(|| {
+                        DmError::Dm(
+                            ErrorEnum::Invalid,
+                            "Devicemapper uuid is not valid UTF8".to_string(),
+                        )
+                    })?;
+                Some(DmUuid::new(dm_uuid)?)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        let entry = (DmName::new(dm_name)?, device.dev.into(), event_nr, uuid);
+
+        self.data = if device.next == 0 {
+            &[]
+        } else {
+            &result[device.next as usize..]
+        };
+
+        Ok(entry)
+    }
+}
+
+impl<'a> Iterator for DevicesIter<'a> {
+    type Item = DmResult<(&'a DmName, Device, Option<u32>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.with_uuids_next()
+            .map(|entry| entry.map(|(name, device, event_nr, _)| (name, device, event_nr)))
+    }
+}
+
+impl<'a> DevicesIter<'a> {
+    #[allow(clippy::type_complexity)]
+    fn with_uuids_next(
+        &mut self,
+    ) -> Option<DmResult<(&'a DmName, Device, Option<u32>, Option<&'a DmUuid>)>> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let entry = self.parse_next();
+        if entry.is_err() {
+            // Parsing can not resume after a malformed entry
+            self.data = &[];
+        }
+        Some(entry)
+    }
+}
+
+impl<'a> Iterator for DevicesWithUuidsIter<'a> {
+    type Item = DmResult<(&'a DmName, Device, Option<u32>, Option<&'a DmUuid>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.with_uuids_next()
+    }
+}
+
+/// The status of a device's targets, as returned by
+/// [`DM::table_status_mapped`], left in the memory mapped buffer into which
+/// the kernel wrote it.
+#[derive(Debug)]
+pub struct MappedTableStatus {
+    buffer: MmapBuffer,
+    data: Range<usize>,
+    target_count: u32,
+}
+
+impl MappedTableStatus {
+    /// The number of targets.
+    pub fn target_count(&self) -> u32 {
+        self.target_count
+    }
+
+    /// The targets' statuses, borrowed from the buffer.
+    pub fn targets(&self) -> TableStatusTargets<'_> {
+        TableStatusTargets::new(
+            self.target_count,
+            &self.buffer.as_slice()[self.data.clone()],
+        )
+    }
+}
+
+/// An iterator over the targets of a table status.
+///
+/// Yields the sector_start, sector_length, type and params of each target,
+/// as [`DM::table_status`] returns them, but borrowed.
+#[derive(Debug)]
+pub struct TableStatusTargets<'a> {
+    data: &'a [u8],
+    offset: usize,
+    remaining: u32,
+}
+
+impl<'a> TableStatusTargets<'a> {
+    fn new(count: u32, data: &'a [u8]) -> TableStatusTargets<'a> {
+        TableStatusTargets {
+            data,
+            offset: 0,
+            remaining: if data.is_empty() { 0 } else { count },
+        }
+    }
+
+    fn parse_next(&mut self) -> DmResult<(u64, u64, &'a str, &'a str)> {
+        let result = self
+            .data
+            .get(self.offset..)
+            .filter(|result| result.len() >= size_of::<dmi::Struct_dm_target_spec>())
+            .ok_or_else(|| {
+                DmError::Dm(
+                    ErrorEnum::Invalid,
+                    "DM target status extends beyond the data returned from kernel".to_string(),
+                )
+            })?;
+        let targ = unsafe { &*(result.as_ptr() as *const dmi::Struct_dm_target_spec) };
+
+        let target_type = str_from_c_str(&targ.target_type).ok_or_else(|| {
+            DmError::Dm(
+                ErrorEnum::Invalid,
+                "Could not convert target type to a String".to_string(),
+            )
+        })?;
+
+        let params = str_from_byte_slice(&result[size_of::<dmi::Struct_dm_target_spec>()..])
+            .ok_or_else(|| {
+                DmError::Dm(
+                    ErrorEnum::Invalid,
+                    "Invalid DM target parameters returned from kernel".to_string(),
+                )
+            })?;
+
+        self.offset = targ.next as usize;
+        Ok((targ.sector_start, targ.length, target_type, params))
+    }
+}
+
+impl<'a> Iterator for TableStatusTargets<'a> {
+    type Item = DmResult<(u64, u64, &'a str, &'a str)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let target = self.parse_next();
+        if target.is_err() {
+            // Parsing can not resume after a malformed target
+            self.remaining = 0;
+        }
+        Some(target)
+    }
+}
+
+/// Find the minor number of the misc device called name in the contents of
+/// /proc/misc, where each line is a minor number followed by a name.
+fn misc_minor(misc: &str, name: &str) -> Option<u64> {
+    misc.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let minor = fields.next()?;
+        if fields.next()? == name {
+            minor.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// The file descriptor of the DM control device.
+///
+/// The descriptor polls readable once a DM event has occurred on any device,
+/// and remains readable until `DM::arm_poll` is called, so it can be
+/// registered with an external event loop. It belongs to the context and is
+/// closed when the context is dropped; a duplicate, obtained with
+/// `BorrowedFd::try_clone_to_owned`, can be passed to a less privileged
+/// process, which may then issue ioctls through it.
+impl AsFd for DM {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+/// The raw file descriptor of the DM control device; see [`DM::as_fd`].
 impl AsRawFd for DM {
     fn as_raw_fd(&self) -> RawFd {
         self.file.as_raw_fd()
     }
 }
 
+/// Give up the context, keeping only its control device file descriptor.
+impl From<DM> for OwnedFd {
+    fn from(dm: DM) -> OwnedFd {
+        dm.file.into()
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::hint::black_box;
 
     use crate::{
-        core::errors::Error,
+        core::{
+            device::is_dm_device,
+            dm_flags::DmUdevFlags,
+            errors::Error,
+            types::{DM_NAME_LEN, DM_UUID_LEN},
+        },
         result::DmError,
-        testing::{test_name, test_uuid},
+        testing::{test_name, test_string, test_uuid},
     };
 
     use super::*;
 
+    #[test]
+    /// Verify that names and uuids are copied into the header, null
+    /// terminated, including ones of the greatest length allowed.
+    fn test_hdr_id() {
+        let name = "n".repeat(DM_NAME_LEN - 1);
+        let name = DmName::new(&name).expect("is valid DM name");
+        let hdr = DmOptions::default().to_ioctl_hdr(Some(&DevId::Name(name)), DmFlags::empty());
+        assert_eq!(str_from_c_str(&hdr.name), Some(&name.to_string()[..]));
+        assert_eq!(str_from_c_str(&hdr.uuid), Some(""));
+
+        let uuid = "u".repeat(DM_UUID_LEN - 1);
+        let uuid = DmUuid::new(&uuid).expect("is valid DM uuid");
+        let hdr = DmOptions::default().to_ioctl_hdr(Some(&DevId::Uuid(uuid)), DmFlags::empty());
+        assert_eq!(str_from_c_str(&hdr.uuid), Some(&uuid.to_string()[..]));
+        assert_eq!(str_from_c_str(&hdr.name), Some(""));
+    }
+
+    #[test]
+    #[ignore = "benchmark; run with make bench"]
+    /// Report the time taken to construct an ioctl header, with no id, with
+    /// a name, and with a uuid. Headers are constructed for every ioctl, so
+    /// this bounds the rate at which ioctls can be issued. Run with
+    /// `make bench` for an optimized build.
+    fn bench_to_ioctl_hdr() {
+        const ITERATIONS: u32 = 100_000;
+
+        let name = DmName::new("bench-name").expect("is valid DM name");
+        let uuid = DmUuid::new("bench-uuid-0123456789abcdef").expect("is valid DM uuid");
+        for (desc, id) in [
+            ("no id", None),
+            ("name", Some(DevId::Name(name))),
+            ("uuid", Some(DevId::Uuid(uuid))),
+        ] {
+            let start = Instant::now();
+            for _ in 0..ITERATIONS {
+                black_box(
+                    DmOptions::default().to_ioctl_hdr(black_box(id.as_ref()), DmFlags::empty()),
+                );
+            }
+            let per_header = start.elapsed() / ITERATIONS;
+            println!("to_ioctl_hdr, {desc}: {per_header:?} per header");
+        }
+    }
+
+    #[test]
+    /// Verify that udev flags are placed in the upper bits of event_nr,
+    /// leaving the lower bits for the udev cookie.
+    fn test_udev_flags_hdr() {
+        let hdr = DmOptions::default().to_ioctl_hdr(None, DmFlags::empty());
+        assert_eq!(hdr.event_nr, 0);
+
+        let flags = DmUdevFlags::DM_UDEV_DISABLE_DM_RULES_FLAG
+            | DmUdevFlags::DM_UDEV_DISABLE_SUBSYSTEM_RULES_FLAG
+            | DmUdevFlags::DM_UDEV_DISABLE_DISK_RULES_FLAG
+            | DmUdevFlags::DM_UDEV_PRIMARY_SOURCE_FLAG;
+        let hdr = DmOptions::default()
+            .set_udev_flags(flags)
+            .to_ioctl_hdr(None, DmFlags::empty());
+        assert_eq!(hdr.event_nr & !dmi::DM_UDEV_FLAGS_MASK, 0);
+        assert_eq!(hdr.event_nr >> dmi::DM_UDEV_FLAGS_SHIFT, flags.bits());
+
+        let hdr = DmOptions::private().to_ioctl_hdr(None, DmFlags::empty());
+        assert_eq!(
+            DmUdevFlags::from_bits(hdr.event_nr >> dmi::DM_UDEV_FLAGS_SHIFT),
+            Some(DmOptions::private().udev_flags())
+        );
+    }
+
+    #[test]
+    /// Verify the layout of serialized targets: each spec is followed by its
+    /// null-terminated params, padded to a multiple of 8 bytes.
+    fn test_serialize_targets() {
+        let spec_size = size_of::<dmi::Struct_dm_target_spec>();
+        let mut buf = Vec::new();
+        let count = DM::serialize_targets(
+            vec![(0, 8, "linear", "8:16 0"), (8, 8, "linear", "8:32 2048")],
+            &mut buf,
+            false,
+        )
+        .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(buf.len(), 2 * spec_size + 8 + 16);
+
+        let targ = c_struct_from_slice::<dmi::Struct_dm_target_spec>(&buf).unwrap();
+        assert_eq!(targ.sector_start, 0);
+        assert_eq!(targ.length, 8);
+        assert_eq!(targ.next as usize, spec_size + 8);
+        assert_eq!(str_from_c_str(&targ.target_type), Some("linear"));
+        assert_eq!(str_from_byte_slice(&buf[spec_size..]), Some("8:16 0"));
+
+        let next = &buf[targ.next as usize..];
+        let targ = c_struct_from_slice::<dmi::Struct_dm_target_spec>(next).unwrap();
+        assert_eq!(targ.sector_start, 8);
+        assert_eq!(targ.next as usize, spec_size + 16);
+        assert_eq!(str_from_byte_slice(&next[spec_size..]), Some("8:32 2048"));
+    }
+
+    #[test]
+    /// Verify the comparison of interface versions.
+    fn test_version_supports() {
+        assert!(version_supports((4, 37, 0), (4, 37, 0)));
+        assert!(version_supports((4, 48, 0), (4, 37, 0)));
+        assert!(version_supports((4, 37, 0), (4, 37, 2)));
+        assert!(!version_supports((4, 36, 9), (4, 37, 0)));
+        assert!(!version_supports((5, 0, 0), (4, 0, 0)));
+    }
+
+    #[test]
+    /// Verify that features are reported as the kernel's version implies.
+    fn sudo_test_supports() {
+        let dm = DM::new().unwrap();
+        let version = dm.version().unwrap();
+        assert!(dm.supports(DmFeature::TargetMessages).unwrap());
+        assert_eq!(dm.supports(DmFeature::ArmPoll).unwrap(), version.1 >= 37);
+    }
+
+    #[test]
+    /// Verify that the control device's minor is found among other entries.
+    fn test_misc_minor() {
+        let misc = "130 watchdog\n236 device-mapper\n 63 vga_arbiter\n";
+        assert_eq!(misc_minor(misc, "device-mapper"), Some(236));
+        assert_eq!(misc_minor(misc, "vga_arbiter"), Some(63));
+        assert_eq!(misc_minor(misc, "device"), None);
+        assert_eq!(misc_minor("", "device-mapper"), None);
+    }
+
+    #[test]
+    /// Verify that a context can be created through a control device that
+    /// did not previously exist.
+    fn sudo_test_with_control_path() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("devicemapper")
+            .tempdir()
+            .unwrap();
+        let path = tmp_dir.path().join("mapper").join("control");
+        let dm = DM::with_control_path(&path).unwrap();
+        assert!(path.exists());
+        assert_matches!(dm.version(), Ok(_));
+    }
+
+    #[test]
+    /// Verify that the context's file descriptor refers to the control
+    /// device, and can be duplicated and taken out of the context.
+    fn sudo_test_fd() {
+        let dm = DM::new().unwrap();
+        assert_eq!(dm.as_fd().as_raw_fd(), dm.as_raw_fd());
+        assert_eq!(dm.as_raw_fd(), dm.file().as_raw_fd());
+
+        let dup = dm.as_fd().try_clone_to_owned().unwrap();
+        assert_ne!(dup.as_raw_fd(), dm.as_raw_fd());
+
+        let raw_fd = dm.as_raw_fd();
+        let owned = OwnedFd::from(dm);
+        assert_eq!(owned.as_raw_fd(), raw_fd);
+    }
+
     #[test]
     /// Test that some version can be obtained.
     fn sudo_test_version() {
         assert_matches!(DM::new().unwrap().version(), Ok(_));
     }
 
+    #[test]
+    /// Test that a reserved buffer is used for, and survives, an ioctl.
+    fn sudo_test_reserve_ioctl_buffer() {
+        let dm = DM::new().unwrap();
+        dm.reserve_ioctl_buffer(1024 * 1024);
+        // The version is cached, so list the devices, which is not.
+        let before = dm.ioctl_count();
+        assert_matches!(dm.list_devices(), Ok(_));
+        assert!(dm.ioctl_count() > before);
+
+        let buffers = dm.buffers.lock().unwrap();
+        assert_eq!(buffers.len(), 1);
+        assert!(buffers[0].capacity() >= 1024 * 1024);
+    }
+
     #[test]
     /// Test that versions for some targets can be obtained.
     fn sudo_test_versions() {
         assert!(!DM::new().unwrap().list_versions().unwrap().is_empty());
     }
 
+    #[test]
+    /// Test that the list of target versions is kept until it is refreshed.
+    fn sudo_test_versions_cached() {
+        let dm = DM::new().unwrap();
+        let versions = dm.list_versions().unwrap();
+
+        let before = dm.ioctl_count();
+        assert_eq!(dm.list_versions().unwrap(), versions);
+        assert_eq!(dm.ioctl_count(), before);
+
+        dm.refresh_targets();
+        assert_eq!(dm.list_versions().unwrap(), versions);
+        assert_eq!(dm.ioctl_count(), before + 1);
+    }
+
+    #[test]
+    /// Verify that a loaded target is found without calling the loader,
+    /// and that a target that can not be loaded is reported as missing.
+    fn sudo_test_ensure_target_loaded() {
+        let dm = DM::new().unwrap();
+        let unreachable = |_: &str| -> DmResult<()> { panic!("linear is always loaded") };
+        assert!(dm
+            .ensure_target_loaded("linear", Some(&unreachable))
+            .is_ok());
+
+        assert_matches!(
+            dm.ensure_target_loaded("no-such-target", None),
+            Err(DmError::Dm(ErrorEnum::NotFound, _))
+        );
+        let noop = |_: &str| -> DmResult<()> { Ok(()) };
+        assert_matches!(
+            dm.ensure_target_loaded("no-such-target", Some(&noop)),
+            Err(DmError::Dm(ErrorEnum::NotFound, _))
+        );
+    }
+
     #[test]
     /// Verify that if no devices have been created the list of test devices
     /// is empty.
@@ -871,6 +2314,125 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    /// Verify that only the devices with uuids that have the prefix are
+    /// listed, whether or not the kernel lists uuids itself.
+    fn sudo_test_list_devices_with_uuid_prefix() {
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let uuid = DmUuidBuf::with_prefix("DMRS-", &test_string("example-uuid"))
+            .expect("is valid DM uuid");
+        let other = test_name("example-other").expect("is valid DM name");
+        dm.device_create(&name, Some(&uuid), DmOptions::default())
+            .unwrap();
+        dm.device_create(&other, None, DmOptions::default())
+            .unwrap();
+
+        let devices = dm.list_devices_with_uuid_prefix("DMRS-").unwrap();
+        assert_matches!(devices.as_slice(), [(nm, id, _, _)] if nm == &name && id == &uuid);
+        assert!(dm
+            .list_devices_with_uuid_prefix("DMRS-none-")
+            .unwrap()
+            .is_empty());
+
+        dm.device_remove(&DevId::Name(&name), DmOptions::default())
+            .unwrap();
+        dm.device_remove(&DevId::Name(&other), DmOptions::default())
+            .unwrap();
+    }
+
+    #[test]
+    /// Verify that a device is found by its device number, and is a DM
+    /// device, and that it is not found once it is removed.
+    fn sudo_test_device_by_devno() {
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let info = dm.device_create(&name, None, DmOptions::default()).unwrap();
+        let device = info.device();
+
+        let found = dm.device_by_devno(device).unwrap().unwrap();
+        assert_eq!(found.name(), Some(&*name));
+        assert!(is_dm_device(Path::new(&format!("/dev/dm-{}", device.minor))).unwrap());
+
+        dm.device_remove(&DevId::Name(&name), DmOptions::default())
+            .unwrap();
+        assert!(dm.device_by_devno(device).unwrap().is_none());
+    }
+
+    #[test]
+    /// Verify that devices removed in a batch are gone once the batch ends,
+    /// and that a nested batch joins the batch already open.
+    fn sudo_test_with_udev_cookie() {
+        let dm = DM::new().unwrap();
+        let names = ["example-dev", "example-other"]
+            .iter()
+            .map(|name| test_name(name).expect("is valid DM name"))
+            .collect::<Vec<_>>();
+        for name in &names {
+            dm.device_create(name, None, DmOptions::default()).unwrap();
+        }
+
+        dm.with_udev_cookie(|batch| {
+            let cookie = batch.cookie();
+            dm.device_remove(&DevId::Name(&names[0]), DmOptions::default())?;
+            dm.with_udev_cookie(|nested| {
+                assert_eq!(nested.cookie(), cookie);
+                dm.device_remove(&DevId::Name(&names[1]), DmOptions::default())
+            })
+        })
+        .unwrap();
+
+        for name in &names {
+            assert!(!dm
+                .list_test_devices()
+                .unwrap()
+                .iter()
+                .any(|(nm, _, _)| nm == name));
+        }
+    }
+
+    #[test]
+    /// Verify that devices_iter finds a created device, and that the same
+    /// buffer can be used for more than one call.
+    fn sudo_test_devices_iter() {
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let info = dm.device_create(&name, None, DmOptions::default()).unwrap();
+
+        let mut buffer = Vec::new();
+        for _ in 0..2 {
+            let devices = dm
+                .devices_iter(&mut buffer)
+                .unwrap()
+                .collect::<DmResult<Vec<_>>>()
+                .unwrap();
+            assert_matches!(
+                devices.iter().find(|(nm, _, _)| *nm == &*name),
+                Some((_, dev, _)) if *dev == info.device()
+            );
+        }
+
+        dm.device_remove(&DevId::Name(&name), DmOptions::default())
+            .unwrap();
+    }
+
+    #[test]
+    /// Verify that devices_remove removes all of several independent devices.
+    fn sudo_test_devices_remove() {
+        let dm = DM::new().unwrap();
+        let names = (0..4)
+            .map(|i| test_name(&format!("example-dev-{i}")).expect("is valid DM name"))
+            .collect::<Vec<_>>();
+        for name in &names {
+            dm.device_create(name, None, DmOptions::default()).unwrap();
+        }
+
+        let names = names.iter().map(|n| &**n).collect::<Vec<_>>();
+        dm.devices_remove(&names, DmOptions::default(), 3).unwrap();
+
+        assert!(dm.list_test_devices().unwrap().is_empty());
+    }
+
     #[test]
     /// Test that device creation gives a device with the expected name.
     fn sudo_test_create() {
@@ -885,6 +2447,33 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    /// Verify that a device created with a requested minor number gets that
+    /// number, and that neither a minor in use nor one too large to encode
+    /// can be requested.
+    fn sudo_test_create_with_minor() {
+        let dm = DM::new().unwrap();
+        let minor = 0xf_0000 + u32::from(rand::random::<u16>());
+        let name = test_name("example-dev").expect("is valid DM name");
+        let result = dm
+            .device_create_with_minor(&name, None, minor, DmOptions::default())
+            .unwrap();
+        assert_eq!(result.device().minor, minor);
+
+        let other = test_name("example-dev-2").expect("is valid DM name");
+        assert_matches!(
+            dm.device_create_with_minor(&other, None, minor, DmOptions::default()),
+            Err(_)
+        );
+        assert_matches!(
+            dm.device_create_with_minor(&other, None, 0x10_0000, DmOptions::default()),
+            Err(DmError::Core(Error::InvalidArgument(_)))
+        );
+
+        dm.device_remove(&DevId::Name(&name), DmOptions::default())
+            .unwrap();
+    }
+
     #[test]
     /// Verify that creation with a UUID results in correct name and UUID.
     fn sudo_test_create_uuid() {
@@ -1106,6 +2695,37 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    /// Verify that a table status read into a mapped buffer, which must be
+    /// grown to hold it, is the same as one read by table_status.
+    fn sudo_test_table_status_mapped() {
+        let dm = DM::new().unwrap();
+        let name = test_name("example-dev").expect("is valid DM name");
+        let id = DevId::Name(&name);
+        dm.device_create(&name, None, DmOptions::default()).unwrap();
+        let table = (0..1000u64)
+            .map(|i| (i * 8, 8, "zero", ""))
+            .collect::<Vec<_>>();
+        dm.table_load(&id, table, DmOptions::default()).unwrap();
+        let options = DmOptions::default().set_flags(DmFlags::DM_QUERY_INACTIVE_TABLE);
+
+        let (_, status) = dm.table_status(&id, options).unwrap();
+        let (_, mapped) = dm.table_status_mapped(&id, options, 1).unwrap();
+        assert_eq!(mapped.target_count(), 1000);
+        assert_eq!(
+            mapped
+                .targets()
+                .map(|target| target.map(|(start, length, target_type, params)| {
+                    (start, length, target_type.to_string(), params.to_string())
+                }))
+                .collect::<DmResult<Vec<_>>>()
+                .unwrap(),
+            status
+        );
+
+        dm.device_remove(&id, DmOptions::default()).unwrap();
+    }
+
     #[test]
     /// Verify that getting the status of a non-existent device specified
     /// by name returns an error.
@@ -2,7 +2,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::core::dm_ioctl as dmi;
+use std::{fmt, str::FromStr};
+
+use crate::{
+    core::{dm_ioctl as dmi, errors},
+    result::{DmError, DmResult},
+};
 
 bitflags! {
     /// Flags used by devicemapper.
@@ -52,6 +57,12 @@ bitflags! {
     /// Flags used by devicemapper, see:
     /// https://sourceware.org/git/?p=lvm2.git;a=blob;f=libdm/libdevmapper.h#l3627
     /// for complete information about the meaning of the flags.
+    ///
+    /// The flags are set with DmOptions::set_udev_flags() and are passed to
+    /// the kernel in the upper 16 bits of the event_nr field of the ioctl
+    /// header, from which they reach udev in the uevents generated by
+    /// resume, rename, and remove ioctls. DmOptions::private() sets the flags
+    /// that keep udev from scanning internal or transient devices.
     #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
     pub struct DmUdevFlags: u32 {
         /// Disables basic device-mapper udev rules that create symlinks in /dev/<DM_DIR>
@@ -72,3 +83,131 @@ bitflags! {
         const DM_UDEV_PRIMARY_SOURCE_FLAG = dmi::DM_UDEV_PRIMARY_SOURCE_FLAG;
     }
 }
+
+// Implement Display and FromStr for a flags type, writing the names of the
+// set flags separated by "|", e.g., "READONLY|SUSPEND". A name is written
+// without the prefix and suffix common to all the type's flag names, and
+// bits that do not correspond to any flag are written in hexadecimal.
+macro_rules! flags_display {
+    ($T:ident, $prefix:expr, $suffix:expr) => {
+        impl $T {
+            fn short_name(name: &str) -> &str {
+                let name = name.strip_prefix($prefix).unwrap_or(name);
+                name.strip_suffix($suffix).unwrap_or(name)
+            }
+        }
+
+        impl fmt::Display for $T {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let mut names = self.iter_names();
+                let mut parts = names
+                    .by_ref()
+                    .map(|(name, _)| $T::short_name(name).to_string())
+                    .collect::<Vec<_>>();
+                let remaining = names.remaining().bits();
+                if remaining != 0 {
+                    parts.push(format!("{remaining:#x}"));
+                }
+                write!(f, "{}", parts.join("|"))
+            }
+        }
+
+        impl FromStr for $T {
+            type Err = DmError;
+
+            fn from_str(s: &str) -> DmResult<$T> {
+                s.split('|')
+                    .map(str::trim)
+                    .filter(|part| !part.is_empty())
+                    .try_fold($T::empty(), |flags, part| {
+                        let flag = match part.strip_prefix("0x") {
+                            Some(hex) => {
+                                u32::from_str_radix(hex, 16).ok().map($T::from_bits_retain)
+                            }
+                            None => $T::all()
+                                .iter_names()
+                                .find(|(name, _)| $T::short_name(name) == part)
+                                .map(|(_, flag)| flag),
+                        };
+                        flag.map(|flag| flags | flag).ok_or_else(|| {
+                            DmError::Core(errors::Error::InvalidArgument(format!(
+                                "\"{part}\" is not a flag of {}",
+                                stringify!($T)
+                            )))
+                        })
+                    })
+            }
+        }
+    };
+}
+
+flags_display!(DmFlags, "DM_", "");
+flags_display!(DmUdevFlags, "DM_UDEV_", "_FLAG");
+
+impl DmFlags {
+    /// Whether the device is read-only.
+    pub fn is_read_only(self) -> bool {
+        self.contains(DmFlags::DM_READONLY)
+    }
+
+    /// Whether the device is suspended.
+    pub fn is_suspended(self) -> bool {
+        self.contains(DmFlags::DM_SUSPEND)
+    }
+
+    /// Whether the device is suspended internally, e.g. by a thin pool
+    /// while its thin devices' tables are swapped.
+    pub fn is_internally_suspended(self) -> bool {
+        self.contains(DmFlags::DM_INTERNAL_SUSPEND)
+    }
+
+    /// Whether the device has a live table.
+    pub fn has_active_table(self) -> bool {
+        self.contains(DmFlags::DM_ACTIVE_PRESENT)
+    }
+
+    /// Whether the device has a table loaded, but not yet made live.
+    pub fn has_inactive_table(self) -> bool {
+        self.contains(DmFlags::DM_INACTIVE_PRESENT)
+    }
+
+    /// Whether the device is scheduled to be removed when it is closed.
+    pub fn is_deferred_remove(self) -> bool {
+        self.contains(DmFlags::DM_DEFERRED_REMOVE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that flags are written by their short names and read back.
+    fn test_flags_display() {
+        let flags = DmFlags::DM_READONLY | DmFlags::DM_SUSPEND;
+        assert_eq!(flags.to_string(), "READONLY|SUSPEND");
+        assert_eq!(flags.to_string().parse::<DmFlags>().unwrap(), flags);
+        assert!(flags.is_read_only() && flags.is_suspended());
+        assert!(!flags.has_active_table());
+
+        assert_eq!(DmFlags::empty().to_string(), "");
+        assert_eq!("".parse::<DmFlags>().unwrap(), DmFlags::empty());
+        assert_eq!(
+            " SKIP_LOCKFS | NOFLUSH ".parse::<DmFlags>().unwrap(),
+            DmFlags::DM_SKIP_LOCKFS | DmFlags::DM_NOFLUSH
+        );
+        assert!("READONLY|BOGUS".parse::<DmFlags>().is_err());
+
+        let unknown = DmFlags::DM_READONLY | DmFlags::from_bits_retain(1 << 30);
+        assert_eq!(unknown.to_string(), "READONLY|0x40000000");
+        assert_eq!(unknown.to_string().parse::<DmFlags>().unwrap(), unknown);
+
+        let udev_flags =
+            DmUdevFlags::DM_UDEV_DISABLE_DM_RULES_FLAG | DmUdevFlags::DM_UDEV_PRIMARY_SOURCE_FLAG;
+        assert_eq!(udev_flags.to_string(), "DISABLE_DM_RULES|PRIMARY_SOURCE");
+        assert_eq!(
+            udev_flags.to_string().parse::<DmUdevFlags>().unwrap(),
+            udev_flags
+        );
+    }
+}
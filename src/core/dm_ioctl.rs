@@ -2,7 +2,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    mem::{align_of, size_of},
+};
 
 use once_cell::sync::Lazy;
 
@@ -12,6 +15,23 @@ pub use devicemapper_sys::{
     dm_target_spec as Struct_dm_target_spec, dm_target_versions as Struct_dm_target_versions, *,
 };
 
+// The kernel's ioctl structures have the same layout on 32-bit and 64-bit
+// architectures, and a 64-bit kernel does not translate them for 32-bit
+// processes; check that the bindings agree on every target.
+const _: () = assert!(size_of::<Struct_dm_ioctl>() == 312);
+const _: () = assert!(size_of::<Struct_dm_ioctl>() % 8 == 0);
+const _: () = assert!(size_of::<Struct_dm_target_spec>() == 40);
+const _: () = assert!(size_of::<Struct_dm_target_deps>() == 8);
+const _: () = assert!(size_of::<Struct_dm_target_versions>() == 16);
+const _: () = assert!(size_of::<Struct_dm_target_msg>() == 8);
+// The name follows dev and next, at offset 12, whether or not the structure
+// is padded to 8 bytes.
+const _: () = assert!(
+    size_of::<Struct_dm_name_list>() == 16
+        || (size_of::<Struct_dm_name_list>() == 12 && align_of::<Struct_dm_name_list>() == 4)
+);
+const _: () = assert!(size_of::<__u64>() == 8 && size_of::<__u32>() == 4);
+
 // Map device-mapper ioctl commands to the minimum ioctl interface version
 // required. The mapping is based on the _cmd_data_v4 table defined in
 // libdm/ioctl/libdm-iface.c in the lvm2/libdevmapper sources.
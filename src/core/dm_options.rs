@@ -25,6 +25,24 @@ impl DmOptions {
         self
     }
 
+    /// Set or clear DM_READONLY in the flags, leaving the others as they
+    /// are, so that a device created or a table loaded with these options
+    /// is read-only, or not. Consumes self.
+    pub fn set_read_only(mut self, read_only: bool) -> DmOptions {
+        self.flags.set(DmFlags::DM_READONLY, read_only);
+        self
+    }
+
+    /// Set or clear DM_NOFLUSH in the flags, leaving the others as they
+    /// are. When retrieving a status with these options, targets with
+    /// metadata, e.g. thin pools, do not commit their metadata first, so
+    /// that frequent polling does not add to the latency of a busy device.
+    /// When suspending, queued I/O is not flushed. Consumes self.
+    pub fn set_no_flush(mut self, no_flush: bool) -> DmOptions {
+        self.flags.set(DmFlags::DM_NOFLUSH, no_flush);
+        self
+    }
+
     /// Retrieve the flags value
     pub fn flags(&self) -> DmFlags {
         self.flags
@@ -37,11 +37,12 @@ pub mod sync_semaphore {
 
     use rand::Rng;
     use retry::{delay::NoDelay, retry, OperationResult};
-    use std::{io, path::Path};
+    use std::{cell::Cell, io, path::Path};
 
     use crate::core::sysvsem::seminfo;
 
     use crate::{
+        core::dm::UdevBatch,
         core::dm_flags::{DmFlags, DmUdevFlags},
         core::sysvsem::{semun, GETVAL, SEM_INFO, SETVAL},
         core::{dm_ioctl as dmi, errors},
@@ -289,10 +290,73 @@ pub mod sync_semaphore {
         }
     }
 
+    thread_local! {
+        /// The cookie and semid of the batch open on this thread, if any.
+        static BATCH: Cell<Option<(u32, i32)>> = const { Cell::new(None) };
+    }
+
+    /// Closes the batch open on this thread and destroys its semaphore, even
+    /// if the batch's closure panicked.
+    struct BatchGuard {
+        cookie: u32,
+        semid: i32,
+    }
+
+    impl Drop for BatchGuard {
+        fn drop(&mut self) {
+            BATCH.with(|batch| batch.set(None));
+            if let Err(err) = notify_sem_destroy(self.cookie, self.semid) {
+                error!("Failed to clean up udev notification semaphore: {}", err);
+            }
+        }
+    }
+
+    /// Run f with one notification semaphore shared by all the ioctls that
+    /// generate uevents that are made on this thread meanwhile, then wait
+    /// once for udev to have processed all of their uevents. Within a
+    /// batch already open on this thread, run f in that batch.
+    pub fn with_batch<R, F>(f: F) -> DmResult<R>
+    where
+        F: FnOnce(&UdevBatch) -> DmResult<R>,
+    {
+        if let Some((cookie, _)) = BATCH.with(|batch| batch.get()) {
+            return f(&UdevBatch::new(cookie));
+        }
+        if !*SYSV_SEM_SUPPORTED {
+            return f(&UdevBatch::new(0));
+        }
+
+        let (cookie, semid) = notify_sem_create()?;
+        debug!(
+            "Created udev batch {{ cookie: {}, semid: {} }}",
+            cookie, semid
+        );
+        BATCH.with(|batch| batch.set(Some((cookie, semid))));
+        let guard = BatchGuard { cookie, semid };
+
+        let result = f(&UdevBatch::new(cookie));
+
+        // The batch is closed before it is waited on, so that no ioctl can
+        // join it while udev completes it.
+        BATCH.with(|batch| batch.set(None));
+        trace!(
+            "Waiting on udev batch {{ cookie: {}, semid: {} }}",
+            cookie,
+            semid
+        );
+        let waited = notify_sem_wait(cookie, semid);
+        // The guard destroys the semaphore.
+        drop(guard);
+        result.and_then(|value| waited.map(|_| value))
+    }
+
     #[derive(Debug)]
     pub struct UdevSync {
         cookie: u32,
         semid: Option<i32>,
+        // Whether the semaphore belongs to a batch, which waits on it and
+        // destroys it.
+        batched: bool,
     }
 
     impl UdevSyncAction for UdevSync {
@@ -314,10 +378,24 @@ pub mod sync_semaphore {
                     return Ok(UdevSync {
                         cookie: 0,
                         semid: None,
+                        batched: false,
                     });
                 }
             };
 
+            if let Some((base_cookie, semid)) = BATCH.with(|batch| batch.get()) {
+                hdr.event_nr |= (DmUdevFlags::DM_UDEV_PRIMARY_SOURCE_FLAG.bits()
+                    << dmi::DM_UDEV_FLAGS_SHIFT)
+                    | (base_cookie & !dmi::DM_UDEV_FLAGS_MASK);
+                // Account for this ioctl's uevent, which udev completes.
+                notify_sem_inc(hdr.event_nr, semid)?;
+                return Ok(UdevSync {
+                    cookie: hdr.event_nr,
+                    semid: Some(semid),
+                    batched: true,
+                });
+            }
+
             let (base_cookie, semid) = notify_sem_create()?;
 
             // Encode the primary source flag and the random base cookie value into
@@ -344,6 +422,7 @@ pub mod sync_semaphore {
             Ok(UdevSync {
                 cookie: hdr.event_nr,
                 semid: Some(semid),
+                batched: false,
             })
         }
 
@@ -352,7 +431,14 @@ pub mod sync_semaphore {
         /// Wait for notification from the udev daemon on the semaphore owned by
         /// this UdevSync instance and destroy the semaphore on success.
         fn end(self, flags: u32) -> DmResult<()> {
-            if self.is_active() {
+            if self.is_active() && self.batched {
+                // The batch waits for udev; only account for a uevent that
+                // was not generated.
+                let semid = self.semid.expect("active UdevSync must have valid semid");
+                if (flags & DmFlags::DM_UEVENT_GENERATED.bits()) == 0 {
+                    notify_sem_dec(self.cookie, semid)?;
+                }
+            } else if self.is_active() {
                 let semid = self.semid.expect("active UdevSync must have valid semid");
                 if (flags & DmFlags::DM_UEVENT_GENERATED.bits()) == 0 {
                     if let Err(err) = notify_sem_dec(self.cookie, semid) {
@@ -378,7 +464,14 @@ pub mod sync_semaphore {
         /// Destroy the notification semaphore owned by this UdevSync instance
         /// without waiting for completion.
         fn cancel(self) {
-            if self.is_active() {
+            if self.is_active() && self.batched {
+                // The failed ioctl generated no uevent for udev to complete.
+                let semid = self.semid.expect("active UdevSync must have valid semid");
+                trace!("Canceling {:?}", self);
+                if let Err(err) = notify_sem_dec(self.cookie, semid) {
+                    error!("Failed to clear notification semaphore state: {}", err);
+                }
+            } else if self.is_active() {
                 let semid = self.semid.expect("active UdevSync must have valid semid");
                 trace!("Canceling {:?}", self);
                 if let Err(err) = notify_sem_destroy(self.cookie, semid) {
@@ -495,7 +588,17 @@ pub mod sync_semaphore {
 #[cfg(target_os = "android")]
 pub mod sync_noop {
     use super::UdevSyncAction;
-    use crate::{core::dm_ioctl as dmi, result::DmResult};
+    use crate::{
+        core::{dm::UdevBatch, dm_ioctl as dmi},
+        result::DmResult,
+    };
+
+    pub fn with_batch<R, F>(f: F) -> DmResult<R>
+    where
+        F: FnOnce(&UdevBatch) -> DmResult<R>,
+    {
+        f(&UdevBatch::new(0))
+    }
 
     #[derive(Debug)]
     pub struct UdevSync {
@@ -528,6 +631,6 @@ pub mod sync_noop {
 }
 
 #[cfg(target_os = "android")]
-pub use self::sync_noop::UdevSync;
+pub use self::sync_noop::{with_batch, UdevSync};
 #[cfg(not(target_os = "android"))]
-pub use self::sync_semaphore::UdevSync;
+pub use self::sync_semaphore::{with_batch, UdevSync};
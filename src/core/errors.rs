@@ -76,10 +76,20 @@ impl std::fmt::Display for Error {
     }
 }
 
+impl Error {
+    /// The errno with which an ioctl failed, if the error is an ioctl error.
+    pub fn errno(&self) -> Option<nix::errno::Errno> {
+        match self {
+            Error::Ioctl(_, _, _, err) => Some(**err),
+            _ => None,
+        }
+    }
+}
+
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::Ioctl(_, _, _, err) => Some(err),
+            Error::Ioctl(_, _, _, err) => Some(err.as_ref()),
             _ => None,
         }
     }
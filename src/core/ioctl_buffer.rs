@@ -0,0 +1,148 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Buffers that hold an ioctl's header and payload: the pooled heap buffers
+// used for most ioctls, and memory mapped buffers, for payloads so large
+// that they are better left in place than copied.
+
+use std::{num::NonZeroUsize, ptr::NonNull, slice};
+
+use nix::{
+    libc::{c_void, sysconf, _SC_PAGESIZE},
+    sys::mman::{mmap_anonymous, munmap, MapFlags, ProtFlags},
+};
+
+use crate::{core::errors, result::DmResult};
+
+/// A buffer which an ioctl's header and payload are written to and read
+/// back from.
+pub trait IoctlBuffer {
+    /// The number of bytes that may be used without growing the buffer.
+    fn capacity(&self) -> usize;
+
+    /// Grow the buffer, if necessary, so that at least len bytes may be
+    /// used. The contents of the buffer are not preserved.
+    fn reserve_total(&mut self, len: usize) -> DmResult<()>;
+
+    /// The first len bytes of the buffer, all zero. len must not exceed
+    /// the buffer's capacity.
+    fn zeroed(&mut self, len: usize) -> &mut [u8];
+}
+
+impl IoctlBuffer for Vec<u8> {
+    fn capacity(&self) -> usize {
+        Vec::capacity(self)
+    }
+
+    fn reserve_total(&mut self, len: usize) -> DmResult<()> {
+        self.reserve(len.saturating_sub(self.len()));
+        Ok(())
+    }
+
+    fn zeroed(&mut self, len: usize) -> &mut [u8] {
+        self.clear();
+        self.resize(len, 0);
+        self
+    }
+}
+
+/// A private, anonymous memory mapping, used as an ioctl buffer.
+#[derive(Debug)]
+pub struct MmapBuffer {
+    ptr: NonNull<c_void>,
+    len: usize,
+    // Whether any of the mapping has been handed out, and so may no longer
+    // be zero
+    dirty: bool,
+}
+
+// The mapping is owned exclusively by the MmapBuffer.
+unsafe impl Send for MmapBuffer {}
+unsafe impl Sync for MmapBuffer {}
+
+impl MmapBuffer {
+    /// Map at least len bytes, rounded up to a whole number of pages.
+    pub fn new(len: usize) -> DmResult<MmapBuffer> {
+        let page_size = match unsafe { sysconf(_SC_PAGESIZE) } {
+            size if size > 0 => size as usize,
+            _ => 4096,
+        };
+        let len = (len.max(1) + page_size - 1) / page_size * page_size;
+        let ptr = unsafe {
+            mmap_anonymous(
+                None,
+                NonZeroUsize::new(len).expect("len is at least one page"),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE,
+            )
+        }
+        .map_err(|err| {
+            errors::Error::GeneralIo(format!("failed to map {len} bytes for ioctl: {err}"))
+        })?;
+        Ok(MmapBuffer {
+            ptr,
+            len,
+            dirty: false,
+        })
+    }
+
+    /// The bytes of the mapping.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr() as *const u8, self.len) }
+    }
+}
+
+impl IoctlBuffer for MmapBuffer {
+    fn capacity(&self) -> usize {
+        self.len
+    }
+
+    fn reserve_total(&mut self, len: usize) -> DmResult<()> {
+        if len > self.len {
+            // The old mapping is unmapped when it is dropped.
+            *self = MmapBuffer::new(len)?;
+        }
+        Ok(())
+    }
+
+    fn zeroed(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.len);
+        let bytes = unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr() as *mut u8, len) };
+        // A fresh anonymous mapping is already zero.
+        if self.dirty {
+            bytes.fill(0);
+        }
+        self.dirty = true;
+        bytes
+    }
+}
+
+impl Drop for MmapBuffer {
+    fn drop(&mut self) {
+        if let Err(err) = unsafe { munmap(self.ptr, self.len) } {
+            warn!("Failed to unmap ioctl buffer: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that a mapped buffer is rounded up to whole pages, zeroed on
+    /// reuse, and grown on demand.
+    fn test_mmap_buffer() {
+        let mut buffer = MmapBuffer::new(1).unwrap();
+        let capacity = buffer.capacity();
+        assert!(capacity >= 1);
+
+        buffer.zeroed(capacity).fill(0xff);
+        assert!(buffer.zeroed(capacity).iter().all(|b| *b == 0));
+
+        buffer.reserve_total(capacity + 1).unwrap();
+        assert!(buffer.capacity() > capacity);
+        assert_eq!(buffer.as_slice().len(), buffer.capacity());
+    }
+}
@@ -0,0 +1,226 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Scheduling of the ioctls issued concurrently through a single DM context.
+
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::core::dm_ioctl as dmi;
+
+/// The number of ioctls that only read state which may be issued ahead of a
+/// waiting ioctl that changes state, under IoctlScheduling::ReadsFirst.
+const MAX_OVERTAKES: usize = 8;
+
+/// How ioctls issued concurrently through the same DM context are scheduled.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IoctlScheduling {
+    /// Ioctls are issued as soon as they are made, and may run in the
+    /// kernel at the same time.
+    #[default]
+    Concurrent,
+    /// One ioctl is issued at a time, in the order in which they were made.
+    Fifo,
+    /// One ioctl is issued at a time. An ioctl that only reads state, such
+    /// as a status query, goes ahead of waiting ioctls that change state,
+    /// but only a few times in a row, so that a steady stream of queries can
+    /// not hold back a change, such as a suspend, indefinitely.
+    ReadsFirst,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    scheduling: IoctlScheduling,
+    next_ticket: u64,
+    // The tickets of the waiting ioctls, and whether each only reads state
+    waiting: VecDeque<(u64, bool)>,
+    busy: bool,
+    // The number of ioctls issued in a row ahead of the longest waiting one
+    overtakes: usize,
+}
+
+impl QueueState {
+    // The ticket of the ioctl to issue next.
+    fn next(&self) -> Option<u64> {
+        let &(front, front_read_only) = self.waiting.front()?;
+        if self.scheduling == IoctlScheduling::ReadsFirst
+            && !front_read_only
+            && self.overtakes < MAX_OVERTAKES
+        {
+            if let Some(&(ticket, _)) = self.waiting.iter().find(|(_, read_only)| *read_only) {
+                return Some(ticket);
+            }
+        }
+        Some(front)
+    }
+}
+
+/// Holds the right to issue an ioctl until dropped.
+pub(crate) struct IoctlTurn<'a> {
+    queue: Option<&'a IoctlQueue>,
+}
+
+impl Drop for IoctlTurn<'_> {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue {
+            queue.lock().busy = false;
+            queue.turn.notify_all();
+        }
+    }
+}
+
+/// The queue through which a DM context's ioctls are scheduled.
+#[derive(Debug, Default)]
+pub(crate) struct IoctlQueue {
+    state: Mutex<QueueState>,
+    turn: Condvar,
+}
+
+impl IoctlQueue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        // The state is consistent whenever the lock is released.
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn scheduling(&self) -> IoctlScheduling {
+        self.lock().scheduling
+    }
+
+    pub(crate) fn set_scheduling(&self, scheduling: IoctlScheduling) {
+        self.lock().scheduling = scheduling;
+        self.turn.notify_all();
+    }
+
+    /// Wait until the given ioctl may be issued.
+    pub(crate) fn wait_turn(&self, ioctl: u8) -> IoctlTurn<'_> {
+        let mut state = self.lock();
+
+        // DM_DEV_WAIT blocks until an event occurs, so it must never hold
+        // back other ioctls.
+        if state.scheduling == IoctlScheduling::Concurrent || ioctl as u32 == dmi::DM_DEV_WAIT_CMD {
+            return IoctlTurn { queue: None };
+        }
+
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back((ticket, is_read_only(ioctl)));
+
+        while state.busy || state.next() != Some(ticket) {
+            state = self
+                .turn
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+
+        let position = state
+            .waiting
+            .iter()
+            .position(|&(t, _)| t == ticket)
+            .expect("ticket is waiting");
+        state.waiting.remove(position);
+        state.overtakes = if position == 0 {
+            0
+        } else {
+            state.overtakes + 1
+        };
+        state.busy = true;
+
+        IoctlTurn { queue: Some(self) }
+    }
+}
+
+/// Whether the ioctl only reads state.
+fn is_read_only(ioctl: u8) -> bool {
+    matches!(
+        ioctl as u32,
+        dmi::DM_VERSION_CMD
+            | dmi::DM_LIST_DEVICES_CMD
+            | dmi::DM_DEV_STATUS_CMD
+            | dmi::DM_TABLE_DEPS_CMD
+            | dmi::DM_TABLE_STATUS_CMD
+            | dmi::DM_LIST_VERSIONS_CMD
+            | dmi::DM_GET_TARGET_VERSION_CMD
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// Hold the queue's turn while more ioctls queue up, then record the
+    /// order in which they are issued.
+    fn issue_order(scheduling: IoctlScheduling, ioctls: &[u32]) -> Vec<usize> {
+        let queue = Arc::new(IoctlQueue::default());
+        queue.set_scheduling(scheduling);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let first = queue.wait_turn(dmi::DM_DEV_SUSPEND_CMD as u8);
+        let handles = ioctls
+            .iter()
+            .enumerate()
+            .map(|(i, &ioctl)| {
+                let (thread_queue, thread_order) = (Arc::clone(&queue), Arc::clone(&order));
+                let handle = thread::spawn(move || {
+                    let _turn = thread_queue.wait_turn(ioctl as u8);
+                    thread_order.lock().unwrap().push(i);
+                });
+                // Wait for the ioctl to be queued, so the tickets are in order
+                while queue.lock().waiting.len() <= i {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                handle
+            })
+            .collect::<Vec<_>>();
+        drop(first);
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        Arc::try_unwrap(order).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_fifo() {
+        let ioctls = [
+            dmi::DM_DEV_SUSPEND_CMD,
+            dmi::DM_TABLE_STATUS_CMD,
+            dmi::DM_TABLE_LOAD_CMD,
+            dmi::DM_DEV_STATUS_CMD,
+        ];
+        assert_eq!(
+            issue_order(IoctlScheduling::Fifo, &ioctls),
+            vec![0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_reads_first() {
+        let ioctls = [
+            dmi::DM_DEV_SUSPEND_CMD,
+            dmi::DM_TABLE_STATUS_CMD,
+            dmi::DM_TABLE_LOAD_CMD,
+            dmi::DM_DEV_STATUS_CMD,
+        ];
+        assert_eq!(
+            issue_order(IoctlScheduling::ReadsFirst, &ioctls),
+            vec![1, 3, 0, 2]
+        );
+    }
+
+    #[test]
+    fn test_reads_first_bounded() {
+        let mut ioctls = vec![dmi::DM_DEV_SUSPEND_CMD];
+        ioctls.extend([dmi::DM_TABLE_STATUS_CMD; MAX_OVERTAKES + 2]);
+        let order = issue_order(IoctlScheduling::ReadsFirst, &ioctls);
+        assert_eq!(order[MAX_OVERTAKES], 0);
+    }
+}
@@ -5,6 +5,7 @@
 //! Modules that support handling of devicemapper ioctls at a low-level.
 
 mod device;
+mod device_lock;
 mod deviceinfo;
 mod dm;
 mod dm_flags;
@@ -15,15 +16,25 @@ mod dm_options;
 mod dm_udev_sync;
 
 pub mod errors;
+mod ioctl_buffer;
+mod ioctl_queue;
 mod sysvsem;
 mod types;
 mod util;
 
 pub use self::{
-    device::{devnode_to_devno, Device},
+    device::{devnode_to_devno, is_dm_device, Device},
+    device_lock::{lock_device, try_lock_device, DeviceLock},
     deviceinfo::DeviceInfo,
-    dm::DM,
+    dm::{
+        DevicesIter, DmFeature, MappedTableStatus, TableStatusTargets, UdevBatch, DM,
+        DM_MAX_MESSAGE_LEN,
+    },
     dm_flags::{DmFlags, DmUdevFlags},
     dm_options::DmOptions,
-    types::{DevId, DmName, DmNameBuf, DmUuid, DmUuidBuf},
+    ioctl_queue::IoctlScheduling,
+    types::{DevId, DmName, DmNameBuf, DmUuid, DmUuidBuf, DM_NAME_LEN, DM_UUID_LEN},
 };
+
+#[cfg(feature = "modprobe")]
+pub use self::dm::modprobe_target;
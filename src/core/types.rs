@@ -5,17 +5,18 @@
 use std::{fmt, ops::Deref};
 
 use crate::{
-    core::{
-        dm_ioctl::{DM_NAME_LEN, DM_UUID_LEN},
-        errors,
-    },
+    core::{dm_ioctl as dmi, errors},
     result::DmError,
 };
 
 // Casts yield correct results since values generated by bindgen from
 // dm-ioctl.h are certainly small enough to fit in usize.
-const DM_NAME_LEN_USIZE: usize = DM_NAME_LEN as usize;
-const DM_UUID_LEN_USIZE: usize = DM_UUID_LEN as usize;
+/// The size of the kernel's buffer for a device's name, including its
+/// terminating null byte, so one more than the longest name allowed.
+pub const DM_NAME_LEN: usize = dmi::DM_NAME_LEN as usize;
+/// The size of the kernel's buffer for a device's uuid, including its
+/// terminating null byte, so one more than the longest uuid allowed.
+pub const DM_UUID_LEN: usize = dmi::DM_UUID_LEN as usize;
 
 /// An error function to construct an error when creating a new string id.
 fn err_func(err_msg: &str) -> DmError {
@@ -25,11 +26,11 @@ fn err_func(err_msg: &str) -> DmError {
 // A devicemapper name. Really just a string, but also the argument type of
 // DevId::Name. Used in function arguments to indicate that the function
 // takes only a name, not a devicemapper uuid.
-str_id!(DmName, DmNameBuf, DM_NAME_LEN_USIZE, err_func);
+str_id!(DmName, DmNameBuf, DM_NAME_LEN, err_func);
 
 // A devicemapper uuid. A devicemapper uuid has a devicemapper-specific
 // format.
-str_id!(DmUuid, DmUuidBuf, DM_UUID_LEN_USIZE, err_func);
+str_id!(DmUuid, DmUuidBuf, DM_UUID_LEN, err_func);
 
 /// Used as a parameter for functions that take either a Device name
 /// or a Device UUID.
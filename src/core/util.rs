@@ -39,6 +39,15 @@ pub fn mut_slice_from_c_str(c_str: &mut [c_char]) -> &mut [u8] {
     unsafe { slice::from_raw_parts_mut(c_str as *mut _ as *mut u8, c_str.len()) }
 }
 
+/// Copy value into the C string c_str, followed by a terminating null byte.
+/// Precondition: value is shorter than c_str.
+#[inline]
+pub fn copy_to_c_str(c_str: &mut [c_char], value: &[u8]) {
+    let dst = mut_slice_from_c_str(c_str);
+    dst[..value.len()].copy_from_slice(value);
+    dst[value.len()] = b'\0';
+}
+
 /// Convert the C struct into a properly-sized byte slice
 pub fn slice_from_c_struct<T>(strct: &T) -> &[u8] {
     unsafe { slice::from_raw_parts(strct as *const _ as *const u8, size_of::<T>()) }
@@ -0,0 +1,223 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Collection of the state of devicemapper and of all its devices into a
+// single bundle, e.g. to attach to a bug report.
+
+use crate::{
+    core::{DevId, Device, DmFlags, DmNameBuf, DmOptions, DmUuidBuf, DM},
+    result::DmResult,
+    units::Sectors,
+};
+
+/// The target type whose table params include key material.
+const CRYPT_TARGET_NAME: &str = "crypt";
+
+/// One line of a device's table or status.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TargetDiagnostics {
+    /// The first sector of the target
+    pub start: Sectors,
+    /// The length of the target
+    pub length: Sectors,
+    /// The target's type
+    pub target_type: String,
+    /// The target's params or status, as reported by the kernel. These are
+    /// in the format parsed by the crate's target params and status types.
+    pub params: String,
+}
+
+serialize_fields!(TargetDiagnostics, start, length, target_type, params);
+
+/// The state of a single DM device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceDiagnostics {
+    /// The device's name
+    pub name: DmNameBuf,
+    /// The device's uuid, if it has one
+    pub uuid: Option<DmUuidBuf>,
+    /// The device's number
+    pub device: Device,
+    /// The number of times the device is open
+    pub open_count: i32,
+    /// The device's event number
+    pub event_nr: u32,
+    /// Whether the device is suspended
+    pub suspended: bool,
+    /// Whether the device is read-only
+    pub read_only: bool,
+    /// The device's live table, with any key material redacted
+    pub table: Vec<TargetDiagnostics>,
+    /// The status of each target of the device's live table
+    pub status: Vec<TargetDiagnostics>,
+    /// The devices that the device is stacked on
+    pub deps: Vec<Device>,
+}
+
+serialize_fields!(
+    DeviceDiagnostics,
+    name,
+    uuid,
+    device,
+    open_count,
+    event_nr,
+    suspended,
+    read_only,
+    table,
+    status,
+    deps
+);
+
+/// The state of devicemapper and of all its devices.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostics {
+    /// The version of the kernel's DM interface
+    pub version: (u32, u32, u32),
+    /// The name and version of each loaded target type
+    pub target_versions: Vec<(String, u32, u32, u32)>,
+    /// The DM devices
+    pub devices: Vec<DeviceDiagnostics>,
+}
+
+serialize_fields!(Diagnostics, version, target_versions, devices);
+
+/// Replace any key material in a target's table params. A crypt target's
+/// key is its second argument, unless it is "-", for no key, or a reference
+/// to a key in the kernel's keyrings, which begins with ':'.
+fn redact_params(target_type: &str, params: &str) -> String {
+    if target_type != CRYPT_TARGET_NAME {
+        return params.to_string();
+    }
+    let mut args = params.splitn(3, ' ').collect::<Vec<_>>();
+    if let Some(key) = args.get_mut(1) {
+        if !key.starts_with(':') && *key != "-" {
+            *key = "<redacted>";
+        }
+    }
+    args.join(" ")
+}
+
+fn to_target_diagnostics(
+    lines: Vec<(u64, u64, String, String)>,
+    redact: bool,
+) -> Vec<TargetDiagnostics> {
+    lines
+        .into_iter()
+        .map(|(start, length, target_type, params)| TargetDiagnostics {
+            start: Sectors(start),
+            length: Sectors(length),
+            params: if redact {
+                redact_params(&target_type, &params)
+            } else {
+                params
+            },
+            target_type,
+        })
+        .collect()
+}
+
+/// Collect the version of devicemapper, the loaded target types, and each
+/// DM device's info, table, status and dependencies. Key material in crypt
+/// tables is redacted, so the result may be shared.
+pub fn collect_diagnostics(dm: &DM) -> DmResult<Diagnostics> {
+    let mut devices = Vec::new();
+    for (name, _, _) in dm.list_devices()? {
+        let id = DevId::Name(&name);
+        let (info, table) = dm.table_status(
+            &id,
+            DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE),
+        )?;
+        let (_, status) = dm.table_status(&id, DmOptions::default().set_no_flush(true))?;
+        let deps = dm.table_deps(&id, DmOptions::default())?;
+        devices.push(DeviceDiagnostics {
+            uuid: info.uuid().map(|uuid| uuid.to_owned()),
+            device: info.device(),
+            open_count: info.open_count(),
+            event_nr: info.event_nr(),
+            suspended: info.flags().contains(DmFlags::DM_SUSPEND),
+            read_only: info.flags().contains(DmFlags::DM_READONLY),
+            table: to_target_diagnostics(table, true),
+            status: to_target_diagnostics(status, false),
+            deps,
+            name,
+        });
+    }
+
+    Ok(Diagnostics {
+        version: dm.version()?,
+        target_versions: dm.list_versions()?,
+        devices,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        core::devnode_to_devno,
+        lineardev::{LinearDev, LinearDevTargetParams, LinearTargetParams},
+        shared::{DmDevice, TargetLine},
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_redact_params() {
+        assert_eq!(
+            redact_params("crypt", "aes-xts-plain64 0123abcd 0 8:0 0 1 allow_discards"),
+            "aes-xts-plain64 <redacted> 0 8:0 0 1 allow_discards"
+        );
+        assert_eq!(
+            redact_params("crypt", "aes-xts-plain64 :32:logon:vol 0 8:0 0"),
+            "aes-xts-plain64 :32:logon:vol 0 8:0 0"
+        );
+        assert_eq!(redact_params("linear", "8:0 2048"), "8:0 2048");
+    }
+
+    /// Verify that a linear device's table and dependencies are collected.
+    fn test_collect_diagnostics(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(8),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+
+        let diagnostics = collect_diagnostics(&dm).unwrap();
+        assert!(diagnostics
+            .target_versions
+            .iter()
+            .any(|(target, _, _, _)| target == "linear"));
+        let device = diagnostics
+            .devices
+            .iter()
+            .find(|device| device.name == name)
+            .unwrap();
+        assert_eq!(device.device, ld.device());
+        assert_eq!(
+            device.table,
+            vec![TargetDiagnostics {
+                start: Sectors(0),
+                length: Sectors(8),
+                target_type: "linear".into(),
+                params: format!("{dev} 0"),
+            }]
+        );
+        assert_eq!(device.deps, vec![dev]);
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_collect_diagnostics() {
+        test_with_spec(1, test_collect_diagnostics);
+    }
+}
@@ -0,0 +1,195 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Making, mounting and unmounting filesystems on DM devices, for tests and
+// for provisioning, by running the usual programs. The programs are run
+// through a trait so that their invocation may be checked without them.
+
+use std::{ffi::OsString, fmt, path::Path, process::Command};
+
+use crate::result::{DmError, DmResult, ErrorEnum};
+
+/// Runs the programs that make and mount filesystems.
+pub trait CommandRunner {
+    /// Run program with args to completion, failing if it does not succeed.
+    fn run(&self, program: &str, args: &[OsString]) -> DmResult<()>;
+}
+
+/// Runs programs as child processes, collecting their output for errors.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[OsString]) -> DmResult<()> {
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        match cmd.output() {
+            Err(err) => Err(DmError::Dm(
+                ErrorEnum::Error,
+                format!("cmd: {cmd:?}, error '{err}'"),
+            )),
+            Ok(result) => {
+                if result.status.success() {
+                    Ok(())
+                } else {
+                    let std_out_txt = String::from_utf8_lossy(&result.stdout);
+                    let std_err_txt = String::from_utf8_lossy(&result.stderr);
+                    let err_msg =
+                        format!("cmd: {cmd:?} stdout: {std_out_txt} stderr: {std_err_txt}");
+                    Err(DmError::Dm(ErrorEnum::Error, err_msg))
+                }
+            }
+        }
+    }
+}
+
+/// The types of filesystem that can be made.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FsType {
+    /// XFS, made with mkfs.xfs
+    Xfs,
+    /// ext4, made with mkfs.ext4
+    Ext4,
+}
+
+impl fmt::Display for FsType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsType::Xfs => write!(f, "xfs"),
+            FsType::Ext4 => write!(f, "ext4"),
+        }
+    }
+}
+
+/// Makes, mounts and unmounts filesystems, with the programs run by R.
+#[derive(Debug, Default)]
+pub struct Filesystems<R = SystemRunner> {
+    runner: R,
+}
+
+impl Filesystems {
+    /// Run the programs as child processes.
+    pub fn new() -> Filesystems {
+        Filesystems::default()
+    }
+}
+
+impl<R: CommandRunner> Filesystems<R> {
+    /// Run the programs with runner.
+    pub fn with_runner(runner: R) -> Filesystems<R> {
+        Filesystems { runner }
+    }
+
+    /// The runner that runs the programs.
+    pub fn runner(&self) -> &R {
+        &self.runner
+    }
+
+    /// Make a filesystem of fs_type on devnode, overwriting any there, with
+    /// the given uuid, or a random one if it is None.
+    pub fn mkfs(&self, devnode: &Path, fs_type: FsType, uuid: Option<&str>) -> DmResult<()> {
+        let mut args: Vec<OsString> = match fs_type {
+            FsType::Xfs => vec!["-f".into(), "-q".into()],
+            FsType::Ext4 => vec!["-F".into(), "-q".into()],
+        };
+        if let Some(uuid) = uuid {
+            let (flag, value) = match fs_type {
+                FsType::Xfs => ("-m", format!("uuid={uuid}")),
+                FsType::Ext4 => ("-U", uuid.to_string()),
+            };
+            args.push(flag.into());
+            args.push(value.into());
+        }
+        args.push(devnode.into());
+        self.runner.run(&format!("mkfs.{fs_type}"), &args)
+    }
+
+    /// Set the uuid of the unmounted filesystem of fs_type on devnode.
+    pub fn set_uuid(&self, devnode: &Path, fs_type: FsType, uuid: &str) -> DmResult<()> {
+        let program = match fs_type {
+            FsType::Xfs => "xfs_admin",
+            FsType::Ext4 => "tune2fs",
+        };
+        self.runner
+            .run(program, &["-U".into(), uuid.into(), devnode.into()])
+    }
+
+    /// Mount the filesystem of fs_type on devnode at mount_point.
+    pub fn mount(&self, devnode: &Path, mount_point: &Path, fs_type: FsType) -> DmResult<()> {
+        self.runner.run(
+            "mount",
+            &[
+                "-t".into(),
+                fs_type.to_string().into(),
+                devnode.into(),
+                mount_point.into(),
+            ],
+        )
+    }
+
+    /// Unmount the filesystem mounted at mount_point.
+    pub fn umount(&self, mount_point: &Path) -> DmResult<()> {
+        self.runner.run("umount", &[mount_point.into()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, path::PathBuf};
+
+    use super::*;
+
+    /// Records the programs run, rather than running them.
+    #[derive(Default)]
+    struct RecordingRunner {
+        runs: RefCell<Vec<(String, Vec<OsString>)>>,
+    }
+
+    impl CommandRunner for RecordingRunner {
+        fn run(&self, program: &str, args: &[OsString]) -> DmResult<()> {
+            self.runs
+                .borrow_mut()
+                .push((program.to_string(), args.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn to_strings(args: &[OsString]) -> Vec<&str> {
+        args.iter().map(|arg| arg.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    /// Verify the programs, and their arguments, that make, mount and
+    /// unmount a filesystem.
+    fn test_filesystem_commands() {
+        let fs = Filesystems::with_runner(RecordingRunner::default());
+        let devnode = PathBuf::from("/dev/mapper/thin");
+        let mount_point = PathBuf::from("/mnt/thin");
+
+        fs.mkfs(&devnode, FsType::Xfs, Some("1234")).unwrap();
+        fs.mkfs(&devnode, FsType::Ext4, None).unwrap();
+        fs.set_uuid(&devnode, FsType::Ext4, "5678").unwrap();
+        fs.mount(&devnode, &mount_point, FsType::Xfs).unwrap();
+        fs.umount(&mount_point).unwrap();
+
+        let runs = fs.runner().runs.borrow();
+        let runs = runs
+            .iter()
+            .map(|(program, args)| (program.as_str(), to_strings(args)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            runs,
+            vec![
+                (
+                    "mkfs.xfs",
+                    vec!["-f", "-q", "-m", "uuid=1234", "/dev/mapper/thin"]
+                ),
+                ("mkfs.ext4", vec!["-F", "-q", "/dev/mapper/thin"]),
+                ("tune2fs", vec!["-U", "5678", "/dev/mapper/thin"]),
+                ("mount", vec!["-t", "xfs", "/dev/mapper/thin", "/mnt/thin"]),
+                ("umount", vec!["/mnt/thin"]),
+            ]
+        );
+    }
+}
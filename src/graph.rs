@@ -0,0 +1,175 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// A snapshot of the DM devices on a machine and of the devices each is
+// stacked on, which can be rendered as a DOT graph or serialized.
+
+use std::fmt::Write;
+
+use crate::{
+    core::{DevId, Device, DmNameBuf, DmOptions, DmUuidBuf, DM},
+    result::DmResult,
+    units::Sectors,
+};
+
+/// A DM device in a [`DependencyGraph`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GraphDevice {
+    /// The device's name
+    pub name: DmNameBuf,
+    /// The device's uuid, if it has one
+    pub uuid: Option<DmUuidBuf>,
+    /// The device's number
+    pub device: Device,
+    /// The types of the targets in the device's live table, in order
+    pub target_types: Vec<String>,
+    /// The size of the device's live table
+    pub size: Sectors,
+}
+
+serialize_fields!(GraphDevice, name, uuid, device, target_types, size);
+
+/// The DM devices on a machine, and the devices each is stacked on.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DependencyGraph {
+    /// The DM devices
+    pub devices: Vec<GraphDevice>,
+    /// A pair for each device that a DM device is stacked on: the DM
+    /// device, and the device beneath it, which need not be a DM device.
+    pub edges: Vec<(Device, Device)>,
+}
+
+serialize_fields!(DependencyGraph, devices, edges);
+
+impl DependencyGraph {
+    /// Render the graph in the DOT language. Nodes are named by device
+    /// number, and each DM device is labelled with its name, target types
+    /// and size. Edges point from a device to the devices beneath it.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph devicemapper {\n");
+        for dev in &self.devices {
+            let mut label = escape_dot(&dev.name.to_string());
+            if !dev.target_types.is_empty() {
+                write!(label, "\\n{}", escape_dot(&dev.target_types.join(",")))
+                    .expect("writes to a String");
+            }
+            write!(label, "\\n{}", dev.size).expect("writes to a String");
+            writeln!(out, "    \"{}\" [label=\"{label}\"];", dev.device)
+                .expect("writes to a String");
+        }
+        for (holder, dep) in &self.edges {
+            writeln!(out, "    \"{holder}\" -> \"{dep}\";").expect("writes to a String");
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Escape a value for use within a quoted DOT string.
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Take a snapshot of the DM devices and of the devices each is stacked on.
+pub fn dependency_graph(dm: &DM) -> DmResult<DependencyGraph> {
+    let mut graph = DependencyGraph::default();
+    for (name, device, _) in dm.list_devices()? {
+        let id = DevId::Name(&name);
+        let uuid = dm.device_info(&id)?.uuid().map(|uuid| uuid.to_owned());
+        let (_, status) = dm.table_status(&id, DmOptions::default().set_no_flush(true))?;
+        let size = status
+            .iter()
+            .map(|(_, length, _, _)| Sectors(*length))
+            .sum();
+        let target_types = status
+            .into_iter()
+            .map(|(_, _, target_type, _)| target_type)
+            .collect();
+        for dep in dm.table_deps(&id, DmOptions::default())? {
+            graph.edges.push((device, dep));
+        }
+        graph.devices.push(GraphDevice {
+            name,
+            uuid,
+            device,
+            target_types,
+            size,
+        });
+    }
+    Ok(graph)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        core::devnode_to_devno,
+        lineardev::{LinearDev, LinearDevTargetParams, LinearTargetParams},
+        shared::{DmDevice, TargetLine},
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_to_dot() {
+        let graph = DependencyGraph {
+            devices: vec![GraphDevice {
+                name: DmNameBuf::new("a\"b".into()).unwrap(),
+                uuid: None,
+                device: Device {
+                    major: 253,
+                    minor: 0,
+                },
+                target_types: vec!["linear".into(), "zero".into()],
+                size: Sectors(16),
+            }],
+            edges: vec![(
+                Device {
+                    major: 253,
+                    minor: 0,
+                },
+                Device { major: 7, minor: 0 },
+            )],
+        };
+        assert_eq!(
+            graph.to_dot(),
+            "digraph devicemapper {\n    \
+             \"253:0\" [label=\"a\\\"b\\nlinear,zero\\n16 sectors\"];\n    \
+             \"253:0\" -> \"7:0\";\n\
+             }\n"
+        );
+    }
+
+    /// Verify that a linear device appears in the graph, stacked on the
+    /// loop device beneath it.
+    fn test_dependency_graph(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(8),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+
+        let graph = dependency_graph(&dm).unwrap();
+        let node = graph.devices.iter().find(|node| node.name == name).unwrap();
+        assert_eq!(node.device, ld.device());
+        assert_eq!(node.target_types, vec!["linear".to_string()]);
+        assert_eq!(node.size, Sectors(8));
+        assert!(graph.edges.contains(&(ld.device(), dev)));
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_dependency_graph() {
+        test_with_spec(1, test_dependency_graph);
+    }
+}
@@ -0,0 +1,201 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Discovery of what keeps a block device busy: processes that have it
+// open, filesystems mounted on it, and devices stacked on top of it.
+
+use std::{
+    fmt, fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::PathBuf,
+};
+
+use crate::{
+    core::{errors, Device},
+    result::{DmError, DmResult},
+};
+
+/// What holds a block device, so that it can not be removed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceUsers {
+    /// The processes that have the device open, by pid and command name
+    pub processes: Vec<(u32, String)>,
+    /// The mount points of the filesystems mounted from the device
+    pub mount_points: Vec<PathBuf>,
+    /// The kernel names, e.g. "dm-4", of the devices stacked on the device
+    pub holders: Vec<String>,
+}
+
+impl DeviceUsers {
+    /// Whether nothing was found to hold the device.
+    pub fn is_empty(&self) -> bool {
+        self.processes.is_empty() && self.mount_points.is_empty() && self.holders.is_empty()
+    }
+}
+
+impl fmt::Display for DeviceUsers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no processes, mounts or holders found");
+        }
+        let mut parts = Vec::new();
+        if !self.processes.is_empty() {
+            let processes = self
+                .processes
+                .iter()
+                .map(|(pid, comm)| format!("{pid} ({comm})"))
+                .collect::<Vec<_>>();
+            parts.push(format!("open in processes {}", processes.join(", ")));
+        }
+        if !self.mount_points.is_empty() {
+            let mount_points = self
+                .mount_points
+                .iter()
+                .map(|mp| mp.display().to_string())
+                .collect::<Vec<_>>();
+            parts.push(format!("mounted at {}", mount_points.join(", ")));
+        }
+        if !self.holders.is_empty() {
+            parts.push(format!("held by {}", self.holders.join(", ")));
+        }
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+// The processes with a file descriptor open on the device. Processes whose
+// file descriptors can not be read, e.g. for lack of permission, or which
+// exit while they are being read, are skipped.
+fn device_processes(device: Device) -> DmResult<Vec<(u32, String)>> {
+    let proc_dir = fs::read_dir("/proc")
+        .map_err(|err| DmError::Core(errors::Error::MetadataIo("/proc".into(), err.to_string())))?;
+
+    let mut processes = Vec::new();
+    for entry in proc_dir.filter_map(Result::ok) {
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let is_open = fds.filter_map(Result::ok).any(|fd| {
+            fs::metadata(fd.path()).is_ok_and(|metadata| {
+                metadata.file_type().is_block_device() && Device::from(metadata.rdev()) == device
+            })
+        });
+        if is_open {
+            let comm = fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            processes.push((pid, comm.trim_end().to_string()));
+        }
+    }
+    Ok(processes)
+}
+
+// The mount points of the filesystems mounted from the device, according to
+// the device numbers in the third field of /proc/self/mountinfo.
+pub(crate) fn device_mount_points(device: Device) -> DmResult<Vec<PathBuf>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").map_err(|err| {
+        DmError::Core(errors::Error::MetadataIo(
+            "/proc/self/mountinfo".into(),
+            err.to_string(),
+        ))
+    })?;
+    let device = device.to_string();
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split(' ').collect::<Vec<_>>();
+            match (fields.get(2), fields.get(4)) {
+                (Some(dev), Some(mount_point)) if *dev == device => {
+                    Some(PathBuf::from(unescape_mountinfo(mount_point)))
+                }
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+// Undo the octal escaping of spaces, tabs, newlines and backslashes in a
+// mountinfo field.
+fn unescape_mountinfo(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        let escaped = rest.get(i + 1..i + 4);
+        match escaped.and_then(|code| u8::from_str_radix(code, 8).ok()) {
+            Some(byte) => {
+                out.push(char::from(byte));
+                rest = &rest[i + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// The kernel names of the devices stacked on the device, from sysfs.
+pub(crate) fn device_holders(device: Device) -> DmResult<Vec<String>> {
+    let path = format!("/sys/dev/block/{device}/holders");
+    match fs::read_dir(&path) {
+        Ok(holders) => Ok(holders
+            .filter_map(Result::ok)
+            .filter_map(|holder| holder.file_name().into_string().ok())
+            .collect()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(DmError::Core(errors::Error::MetadataIo(
+            path.into(),
+            err.to_string(),
+        ))),
+    }
+}
+
+/// Find what holds a block device: the processes that have it open, the
+/// filesystems mounted from it, and the devices stacked on top of it. Only
+/// the processes whose open files the caller may inspect are found.
+pub fn device_users(device: Device) -> DmResult<DeviceUsers> {
+    Ok(DeviceUsers {
+        processes: device_processes(device)?,
+        mount_points: device_mount_points(device)?,
+        holders: device_holders(device)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unescape_mountinfo() {
+        assert_eq!(unescape_mountinfo("/mnt/a\\040b"), "/mnt/a b");
+        assert_eq!(unescape_mountinfo("/mnt/a\\134b\\"), "/mnt/a\\b\\");
+        assert_eq!(unescape_mountinfo("/mnt/plain"), "/mnt/plain");
+    }
+
+    #[test]
+    fn test_device_users_display() {
+        assert_eq!(
+            DeviceUsers::default().to_string(),
+            "no processes, mounts or holders found"
+        );
+        let users = DeviceUsers {
+            processes: vec![(42, "mkfs".into())],
+            mount_points: vec!["/mnt".into()],
+            holders: vec!["dm-4".into()],
+        };
+        assert_eq!(
+            users.to_string(),
+            "open in processes 42 (mkfs); mounted at /mnt; held by dm-4"
+        );
+    }
+}
@@ -76,6 +76,15 @@ macro_rules! str_id {
             }
         }
 
+        impl serde::Serialize for $B {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_str(&self.inner)
+            }
+        }
+
         impl $O {
             /// Construct a new owned identifier.
             pub fn new(value: String) -> $crate::result::DmResult<$O> {
@@ -84,6 +93,19 @@ macro_rules! str_id {
                 }
                 Ok($O { inner: value })
             }
+
+            /// Whether value is within the kernel's limits for the
+            /// identifier, i.e., whether [`Self::new`] would accept it.
+            pub fn fits_kernel_limits(value: &str) -> bool {
+                str_check!(value, $MAX - 1).is_none()
+            }
+
+            /// Construct a new owned identifier consisting of prefix followed
+            /// by value, so that a program's identifiers may share a prefix
+            /// by which they can be told apart from others.
+            pub fn with_prefix(prefix: &str, value: &str) -> $crate::result::DmResult<$O> {
+                $O::new(format!("{}{}", prefix, value))
+            }
         }
 
         impl AsRef<$B> for $O {
@@ -98,6 +120,15 @@ macro_rules! str_id {
             }
         }
 
+        impl serde::Serialize for $O {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_str(&self.inner)
+            }
+        }
+
         impl std::ops::Deref for $O {
             type Target = $B;
             fn deref(&self) -> &$B {
@@ -167,4 +198,32 @@ mod tests {
         assert_eq!(id_buf.deref(), id);
         assert_eq!(*id_buf, *id);
     }
+
+    #[test]
+    /// Test that fits_kernel_limits agrees with new.
+    fn test_fits_kernel_limits() {
+        let longest = "a".repeat(TYPE_LEN - 1);
+        let too_long = "a".repeat(TYPE_LEN);
+        for value in ["id", "", &longest, &too_long, "\u{e9}"] {
+            assert_eq!(
+                IdBuf::fits_kernel_limits(value),
+                IdBuf::new(value.into()).is_ok()
+            );
+        }
+        assert!(IdBuf::fits_kernel_limits(&longest));
+        assert!(!IdBuf::fits_kernel_limits(&too_long));
+    }
+
+    #[test]
+    /// Test that a prefix is prepended, and counts towards the length.
+    fn test_with_prefix() {
+        assert_eq!(
+            IdBuf::with_prefix("pre-", "id").expect("is valid id"),
+            IdBuf::new("pre-id".into()).expect("is valid id")
+        );
+        assert_matches!(
+            IdBuf::with_prefix("prefix-", "toolong"),
+            Err(DmError::Core(Error::InvalidArgument(_)))
+        );
+    }
 }
@@ -0,0 +1,228 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// A record of the steps of an operation on several devices, so that if a
+// step fails the completed steps can be undone in reverse order.
+
+use std::fmt;
+
+use crate::{
+    core::DM,
+    result::{DmError, DmResult},
+};
+
+type Undo<'a> = Box<dyn FnOnce(&DM) -> DmResult<()> + 'a>;
+
+/// A journal of the completed steps of an operation made up of several
+/// steps, e.g. creating a device and then reloading another device's table
+/// to map it. Each step is recorded with a description and a function that
+/// undoes it. If a later step fails, [`Journal::roll_back`] undoes the
+/// recorded steps, most recent first, and returns an error that states
+/// which steps were undone and which, if any, could not be, so that the
+/// state in which the devices were left is known.
+///
+/// A journal must be either committed or rolled back. One dropped with
+/// steps recorded, e.g. by an early return, leaves those steps in place,
+/// and logs a warning that says so.
+#[must_use = "a journal must be committed or rolled back"]
+pub struct Journal<'a> {
+    operation: String,
+    steps: Vec<(String, Undo<'a>)>,
+}
+
+impl fmt::Debug for Journal<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Journal")
+            .field("operation", &self.operation)
+            .field(
+                "steps",
+                &self.steps.iter().map(|(step, _)| step).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl<'a> Journal<'a> {
+    /// Begin a journal for the operation described.
+    pub fn new(operation: &str) -> Journal<'a> {
+        Journal {
+            operation: operation.to_owned(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Record that the step described has been completed, and how to undo
+    /// it.
+    pub fn record<F>(&mut self, step: &str, undo: F)
+    where
+        F: FnOnce(&DM) -> DmResult<()> + 'a,
+    {
+        self.steps.push((step.to_owned(), Box::new(undo)));
+    }
+
+    /// Run a step, recording it with its undo function if it succeeds.
+    /// If it fails, roll back the steps already recorded.
+    pub fn step<T, S, F>(&mut self, dm: &DM, step: &str, run: S, undo: F) -> DmResult<T>
+    where
+        S: FnOnce(&DM) -> DmResult<T>,
+        F: FnOnce(&DM) -> DmResult<()> + 'a,
+    {
+        match run(dm) {
+            Ok(value) => {
+                self.record(step, undo);
+                Ok(value)
+            }
+            Err(err) => Err(self.roll_back(dm, step, err)),
+        }
+    }
+
+    /// The descriptions of the steps completed so far, in order.
+    pub fn completed(&self) -> Vec<&str> {
+        self.steps.iter().map(|(step, _)| step.as_str()).collect()
+    }
+
+    /// The operation has succeeded; discard the journal.
+    pub fn commit(mut self) {
+        self.steps.clear();
+    }
+
+    /// The step described failed with err. Undo the completed steps, most
+    /// recent first, stopping at the first which can not be undone, since
+    /// undoing the steps before it may depend on it. Return a
+    /// `DmError::RolledBack` error which describes the failure and the
+    /// state in which the devices were left, and whose source is err.
+    pub fn roll_back(&mut self, dm: &DM, step: &str, err: DmError) -> DmError {
+        let mut undone = Vec::new();
+        let mut undo_failure = None;
+        while let Some((completed, undo)) = self.steps.pop() {
+            match undo(dm) {
+                Ok(()) => undone.push(completed),
+                Err(undo_err) => {
+                    undo_failure = Some((completed, undo_err));
+                    break;
+                }
+            }
+        }
+
+        let mut err_msg = format!("{} failed at step \"{step}\": {err}", self.operation);
+        if !undone.is_empty() {
+            err_msg.push_str(&format!("; undid \"{}\"", undone.join("\", \"")));
+        }
+        match undo_failure {
+            Some((completed, undo_err)) => {
+                err_msg.push_str(&format!(
+                    "; failed to undo \"{completed}\": {undo_err}; left in place \"{}\"",
+                    self.completed()
+                        .into_iter()
+                        .chain([completed.as_str()])
+                        .collect::<Vec<_>>()
+                        .join("\", \"")
+                ));
+                self.steps.clear();
+            }
+            None => err_msg.push_str("; no changes were left in place"),
+        }
+        warn!("{}", err_msg);
+        DmError::RolledBack(err_msg, Box::new(err))
+    }
+}
+
+impl Drop for Journal<'_> {
+    fn drop(&mut self) {
+        if !self.steps.is_empty() {
+            warn!(
+                "{} was abandoned without being committed or rolled back; left in place \"{}\"",
+                self.operation,
+                self.completed().join("\", \"")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, error::Error};
+
+    use nix::errno::Errno;
+
+    use crate::{core::errors, result::ErrorEnum};
+
+    use super::*;
+
+    #[test]
+    /// Verify that completed steps are undone in reverse order, and that
+    /// the error states what was undone.
+    fn sudo_test_roll_back() {
+        let dm = DM::new().unwrap();
+        let undone = RefCell::new(Vec::new());
+
+        let mut journal = Journal::new("test operation");
+        journal.record("first", |_| {
+            undone.borrow_mut().push(1);
+            Ok(())
+        });
+        journal
+            .step(
+                &dm,
+                "second",
+                |_| Ok(()),
+                |_| {
+                    undone.borrow_mut().push(2);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(journal.completed(), vec!["first", "second"]);
+
+        let err = journal
+            .step(
+                &dm,
+                "third",
+                |_| -> DmResult<()> { Err(DmError::Dm(ErrorEnum::Busy, "busy".into())) },
+                |_| Ok(()),
+            )
+            .unwrap_err();
+        assert_eq!(*undone.borrow(), vec![2, 1]);
+        assert_matches!(
+            err,
+            DmError::RolledBack(ref msg, ref source)
+                if msg.contains("\"third\"")
+                    && msg.contains("undid \"second\", \"first\"")
+                    && msg.contains("no changes were left in place")
+                    && matches!(**source, DmError::Dm(ErrorEnum::Busy, _))
+        );
+        assert!(journal.completed().is_empty());
+    }
+
+    #[test]
+    /// Verify that rolling back stops at a step that can not be undone,
+    /// and that the error lists the steps left in place.
+    fn sudo_test_roll_back_undo_fails() {
+        let dm = DM::new().unwrap();
+        let mut journal = Journal::new("test operation");
+        journal.record("first", |_| Ok(()));
+        journal.record("second", |_| {
+            Err(DmError::Core(errors::Error::InvalidArgument(
+                "can not undo".into(),
+            )))
+        });
+        journal.record("third", |_| Ok(()));
+
+        let err = journal.roll_back(
+            &dm,
+            "fourth",
+            DmError::Core(errors::Error::Ioctl(0, None, None, Box::new(Errno::EBUSY))),
+        );
+        assert_matches!(
+            err,
+            DmError::RolledBack(ref msg, _)
+                if msg.contains("undid \"third\"")
+                    && msg.contains("failed to undo \"second\"")
+                    && msg.contains("left in place \"first\", \"second\"")
+        );
+        // The error that caused the failure is kept as the source.
+        assert_eq!(err.errno(), Some(Errno::EBUSY));
+        assert!(err.source().unwrap().is::<DmError>());
+    }
+}
@@ -0,0 +1,249 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Management of keys in the kernel's keyrings, such as those referred to by
+// a key descriptor in a crypt table or by a verity target's
+// root_hash_sig_key_desc, through the add_key and keyctl system calls.
+
+use std::{ffi::CString, fmt, io, ptr};
+
+use nix::libc::{c_long, syscall, SYS_add_key, SYS_keyctl};
+
+use crate::{
+    core::errors,
+    result::{DmError, DmResult},
+};
+
+const KEYCTL_SEARCH: c_long = 10;
+const KEYCTL_READ: c_long = 11;
+const KEYCTL_INVALIDATE: c_long = 21;
+
+/// The type of a key.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyType {
+    /// A key that may be read back from user space
+    User,
+    /// A key that may only be used by the kernel. A logon key's
+    /// description must begin with a prefix followed by a colon, e.g.
+    /// "cryptsetup:".
+    Logon,
+}
+
+impl KeyType {
+    fn as_str(self) -> &'static str {
+        match self {
+            KeyType::User => "user",
+            KeyType::Logon => "logon",
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A keyring that a key is added to or searched for in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Keyring {
+    /// The calling thread's keyring
+    Thread,
+    /// The calling process's keyring
+    Process,
+    /// The calling process's session keyring
+    Session,
+    /// The calling user's keyring
+    User,
+    /// The calling user's default session keyring
+    UserSession,
+    /// A keyring identified by its serial number
+    Serial(KeySerial),
+}
+
+impl Keyring {
+    fn id(self) -> i32 {
+        match self {
+            Keyring::Thread => -1,
+            Keyring::Process => -2,
+            Keyring::Session => -3,
+            Keyring::User => -4,
+            Keyring::UserSession => -5,
+            Keyring::Serial(serial) => serial.0,
+        }
+    }
+}
+
+/// The serial number of a key.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct KeySerial(pub i32);
+
+impl fmt::Display for KeySerial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+fn keyring_error(action: &str, err: io::Error) -> DmError {
+    DmError::Core(errors::Error::GeneralIo(format!(
+        "failed to {action}: {err}"
+    )))
+}
+
+fn to_c_string(val: &str, desc: &str) -> DmResult<CString> {
+    CString::new(val).map_err(|_| {
+        DmError::Core(errors::Error::InvalidArgument(format!(
+            "{desc} \"{val}\" contains a null byte"
+        )))
+    })
+}
+
+/// Add a key to a keyring, replacing any key of the same type and
+/// description in it, and return the key's serial number.
+pub fn add_key(
+    key_type: KeyType,
+    description: &str,
+    payload: &[u8],
+    keyring: Keyring,
+) -> DmResult<KeySerial> {
+    let key_type_c = to_c_string(key_type.as_str(), "key type")?;
+    let description_c = to_c_string(description, "key description")?;
+    let res = unsafe {
+        syscall(
+            SYS_add_key,
+            key_type_c.as_ptr(),
+            description_c.as_ptr(),
+            payload.as_ptr(),
+            payload.len(),
+            keyring.id(),
+        )
+    };
+    if res < 0 {
+        return Err(keyring_error(
+            &format!("add {key_type} key \"{description}\""),
+            io::Error::last_os_error(),
+        ));
+    }
+    Ok(KeySerial(res as i32))
+}
+
+/// Search a keyring, and the keyrings linked to it, for a key of the given
+/// type and description. Return None if there is no such key.
+pub fn search_key(
+    key_type: KeyType,
+    description: &str,
+    keyring: Keyring,
+) -> DmResult<Option<KeySerial>> {
+    let key_type_c = to_c_string(key_type.as_str(), "key type")?;
+    let description_c = to_c_string(description, "key description")?;
+    let res = unsafe {
+        syscall(
+            SYS_keyctl,
+            KEYCTL_SEARCH,
+            keyring.id(),
+            key_type_c.as_ptr(),
+            description_c.as_ptr(),
+            0,
+        )
+    };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(nix::libc::ENOKEY) => Ok(None),
+            _ => Err(keyring_error(
+                &format!("search for {key_type} key \"{description}\""),
+                err,
+            )),
+        };
+    }
+    Ok(Some(KeySerial(res as i32)))
+}
+
+/// Read a key's payload. The payload of a logon key can not be read.
+pub fn read_key(key: KeySerial) -> DmResult<Vec<u8>> {
+    let mut payload = Vec::new();
+    loop {
+        let res = unsafe {
+            syscall(
+                SYS_keyctl,
+                KEYCTL_READ,
+                key.0,
+                if payload.is_empty() {
+                    ptr::null_mut()
+                } else {
+                    payload.as_mut_ptr()
+                },
+                payload.len(),
+            )
+        };
+        if res < 0 {
+            return Err(keyring_error(
+                &format!("read key {key}"),
+                io::Error::last_os_error(),
+            ));
+        }
+        // The size of the payload is returned, whether or not the buffer
+        // could hold it.
+        let len = res as usize;
+        if len <= payload.len() {
+            payload.truncate(len);
+            return Ok(payload);
+        }
+        payload.resize(len, 0);
+    }
+}
+
+/// Invalidate a key, so that it can no longer be found or used, and
+/// have the kernel remove it.
+pub fn invalidate_key(key: KeySerial) -> DmResult<()> {
+    let res = unsafe { syscall(SYS_keyctl, KEYCTL_INVALIDATE, key.0) };
+    if res < 0 {
+        return Err(keyring_error(
+            &format!("invalidate key {key}"),
+            io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+/// The key argument of a crypt table that refers to a key in the kernel's
+/// keyrings, rather than giving the key itself:
+/// ":<key_size>:<key_type>:<key_description>".
+pub fn crypt_key_desc(key_size: usize, key_type: KeyType, description: &str) -> String {
+    format!(":{key_size}:{key_type}:{description}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crypt_key_desc() {
+        assert_eq!(
+            crypt_key_desc(32, KeyType::Logon, "cryptsetup:vol"),
+            ":32:logon:cryptsetup:vol"
+        );
+    }
+
+    #[test]
+    /// Verify that a user key can be added, found, read and invalidated.
+    fn test_user_key() {
+        let description = format!("devicemapper-rs:test-{}", std::process::id());
+        let key = match add_key(KeyType::User, &description, b"secret", Keyring::Process) {
+            Ok(key) => key,
+            // The keyring system calls may be unavailable, e.g. in a container
+            Err(_) => return,
+        };
+        assert_eq!(
+            search_key(KeyType::User, &description, Keyring::Process).unwrap(),
+            Some(key)
+        );
+        assert_eq!(read_key(key).unwrap(), b"secret");
+        invalidate_key(key).unwrap();
+        assert_eq!(
+            search_key(KeyType::User, &description, Keyring::Process).unwrap(),
+            None
+        );
+    }
+}
@@ -85,22 +85,57 @@ mod core;
 /// Macros shared by device mapper devices.
 #[macro_use]
 mod shared_macros;
+/// hold devices of any kind in one collection
+mod anydev;
 /// cachedev
 mod cachedev;
+/// collect the state of all DM devices, e.g. for a bug report
+#[cfg(devicemapper41supported)]
+mod diagnostics;
+/// make and mount filesystems on DM devices
+#[cfg(any(test, feature = "testing"))]
+mod filesystem;
+/// snapshot the graph of DM devices and the devices they are stacked on
+mod graph;
+/// find what holds a block device open
+mod holders;
+/// undo the completed steps of a failed multi-step operation
+mod journal;
+/// manage keys in the kernel's keyrings
+mod keyring;
 /// functions to create continuous linear space given device segments
 mod lineardev;
+/// attach files to loop devices
+#[cfg(feature = "loopback")]
+mod loopback;
+/// export device statuses as Prometheus metrics
+#[cfg(feature = "metrics")]
+mod metrics;
+/// read partition tables and map a device for each partition
+mod partition;
 /// return results container
 mod result;
 /// functionality shared between devices
 mod shared;
+/// read the state of all DM devices in one pass
+mod snapshot;
+/// build tables that combine targets of different types
+mod tablebuilder;
 /// allocate a device from a pool
 mod thindev;
 /// the id the pool uses to track its devices
 mod thindevid;
+/// read the block mappings of thin devices from pool metadata
+mod thinmeta;
 /// thinpooldev is shared space for  other thin provisioned devices to use
 mod thinpooldev;
+/// listen for the kernel's uevents for DM devices
+#[cfg(feature = "uevent")]
+mod uevent;
 /// representation of units used by the outer layers
 mod units;
+/// report and reset the zones of zoned block devices
+mod zone;
 
 #[cfg(test)]
 mod testing;
@@ -111,28 +146,70 @@ mod testing;
 extern crate assert_matches;
 
 pub use crate::{
+    anydev::AnyDmDevice,
     cachedev::{
-        CacheDev, CacheDevPerformance, CacheDevStatus, CacheDevTargetTable, CacheDevUsage,
-        CacheDevWorkingStatus, CacheTargetParams, MAX_CACHE_BLOCK_SIZE, MIN_CACHE_BLOCK_SIZE,
+        cache_metadata_size, validate_cache_block_size, CacheDev, CacheDevPerformance,
+        CacheDevStatus, CacheDevTargetTable, CacheDevUsage, CacheDevWorkingStatus,
+        CacheMetadataVersion, CacheTargetParams, MAX_CACHE_BLOCK_SIZE, MIN_CACHE_BLOCK_SIZE,
     },
     consts::IEC,
     core::{
-        devnode_to_devno, errors, DevId, Device, DeviceInfo, DmFlags, DmName, DmNameBuf, DmOptions,
-        DmUdevFlags, DmUuid, DmUuidBuf, DM,
+        devnode_to_devno, errors, is_dm_device, lock_device, try_lock_device, DevId, Device,
+        DeviceInfo, DeviceLock, DevicesIter, DmFeature, DmFlags, DmName, DmNameBuf, DmOptions,
+        DmUdevFlags, DmUuid, DmUuidBuf, IoctlScheduling, MappedTableStatus, TableStatusTargets,
+        UdevBatch, DM, DM_MAX_MESSAGE_LEN, DM_NAME_LEN, DM_UUID_LEN,
+    },
+    graph::{dependency_graph, DependencyGraph, GraphDevice},
+    holders::{device_users, DeviceUsers},
+    journal::Journal,
+    keyring::{
+        add_key, crypt_key_desc, invalidate_key, read_key, search_key, KeySerial, KeyType, Keyring,
     },
     lineardev::{
-        FlakeyTargetParams, LinearDev, LinearDevTargetParams, LinearDevTargetTable,
-        LinearTargetParams,
+        DelayClass, DelayTargetParams, FlakeyTargetParams, LinearDev, LinearDevTargetParams,
+        LinearDevTargetTable, LinearTargetParams,
+    },
+    partition::{
+        map_partitions, partition_dev_name, read_partitions, unmap_partitions, Partition,
+        PartitionTable, PartitionTableKind,
     },
     result::{DmError, DmResult, ErrorEnum},
     shared::{
-        device_exists, DmDevice, TargetLine, TargetParams, TargetTable, TargetType, TargetTypeBuf,
+        device_exists, device_info_if_exists, escape_param, message_at, split_params, typed_status,
+        unescape_param, validate_table, DmDevice, KnownTargetType, RawTargetStatus, RetryPolicy,
+        TableDiff, TableLineDiff, TableMismatch, TargetLine, TargetParams, TargetStatus,
+        TargetTable, TargetType, TargetTypeBuf, DM_TARGET_TYPE_LEN,
     },
+    snapshot::{DeviceState, SystemState},
+    tablebuilder::{ErrorTargetParams, GapFill, TableBuilder, ZeroTargetParams},
     thindev::{ThinDev, ThinDevTargetTable, ThinDevWorkingStatus, ThinStatus, ThinTargetParams},
     thindevid::ThinDevId,
+    thinmeta::{read_thin_mappings, ThinMappedRange, ThinMappings},
     thinpooldev::{
-        ThinPoolDev, ThinPoolDevTargetTable, ThinPoolNoSpacePolicy, ThinPoolStatus,
-        ThinPoolStatusSummary, ThinPoolTargetParams, ThinPoolUsage, ThinPoolWorkingStatus,
+        thin_metadata_size, validate_data_block_size, ThinPoolDev, ThinPoolDevTargetTable,
+        ThinPoolNoSpacePolicy, ThinPoolStatus, ThinPoolStatusSummary, ThinPoolTargetParams,
+        ThinPoolUsage, ThinPoolWorkingStatus, MAX_DATA_BLOCK_SIZE, MIN_DATA_BLOCK_SIZE,
     },
     units::{Bytes, DataBlocks, MetaBlocks, Sectors, SECTOR_SIZE},
+    zone::{report_zones, reset_zones, Zone, ZoneCondition, ZoneType},
 };
+
+#[cfg(devicemapper41supported)]
+pub use crate::diagnostics::{
+    collect_diagnostics, DeviceDiagnostics, Diagnostics, TargetDiagnostics,
+};
+
+#[cfg(any(test, feature = "testing"))]
+pub use crate::filesystem::{CommandRunner, Filesystems, FsType, SystemRunner};
+
+#[cfg(feature = "loopback")]
+pub use crate::loopback::{LoopDevice, LoopOptions};
+
+#[cfg(feature = "metrics")]
+pub use crate::metrics::Metrics;
+
+#[cfg(feature = "modprobe")]
+pub use crate::core::modprobe_target;
+
+#[cfg(feature = "uevent")]
+pub use crate::uevent::{DmUevent, UeventAction, UeventListener};
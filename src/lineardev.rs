@@ -5,15 +5,18 @@
 use std::{collections::HashSet, fmt, path::PathBuf, str::FromStr};
 
 use crate::{
-    core::{DevId, Device, DeviceInfo, DmFlags, DmName, DmOptions, DmUuid, DM},
+    core::{DevId, Device, DeviceInfo, DmFlags, DmName, DmNameBuf, DmOptions, DmUuid, DM},
+    journal::Journal,
     result::{DmError, DmResult, ErrorEnum},
     shared::{
-        device_create, device_exists, device_match, parse_device, parse_value, DmDevice,
-        TargetLine, TargetParams, TargetTable, TargetTypeBuf,
+        device_adopt, device_create, device_info_if_exists, parse_device, parse_value,
+        split_params, validate_table_lines, DmDevice, ParamStr, TableMismatch, TargetLine,
+        TargetParams, TargetTable, TargetType, TargetTypeBuf,
     },
     units::Sectors,
 };
 
+const DELAY_TARGET_NAME: &str = "delay";
 const FLAKEY_TARGET_NAME: &str = "flakey";
 const LINEAR_TARGET_NAME: &str = "linear";
 
@@ -38,7 +41,7 @@ impl LinearTargetParams {
 
 impl fmt::Display for LinearTargetParams {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", LINEAR_TARGET_NAME, self.param_str())
+        write!(f, "{} {}", LINEAR_TARGET_NAME, ParamStr(self))
     }
 }
 
@@ -46,7 +49,8 @@ impl FromStr for LinearTargetParams {
     type Err = DmError;
 
     fn from_str(s: &str) -> DmResult<LinearTargetParams> {
-        let vals = s.split(' ').collect::<Vec<_>>();
+        let vals = split_params(s);
+        let vals = vals.iter().map(String::as_str).collect::<Vec<_>>();
         if vals.len() != 3 {
             let err_msg = format!(
                 "expected 3 values in params string \"{}\", found {}",
@@ -73,11 +77,15 @@ impl FromStr for LinearTargetParams {
 
 impl TargetParams for LinearTargetParams {
     fn param_str(&self) -> String {
-        format!("{} {}", self.device, *self.start_offset)
+        ParamStr(self).to_string()
+    }
+
+    fn fmt_params(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.device, *self.start_offset)
     }
 
     fn target_type(&self) -> TargetTypeBuf {
-        TargetTypeBuf::new(LINEAR_TARGET_NAME.into()).expect("LINEAR_TARGET_NAME is valid")
+        TargetType::LINEAR.to_owned()
     }
 }
 
@@ -207,7 +215,7 @@ impl fmt::Display for FlakeyTargetParams {
     ///  If no feature parameters are present, during the periods of
     ///  unreliability, all I/O returns errors.
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", FLAKEY_TARGET_NAME, self.param_str())
+        write!(f, "{} {}", FLAKEY_TARGET_NAME, ParamStr(self))
     }
 }
 
@@ -267,7 +275,8 @@ impl FromStr for FlakeyTargetParams {
             Ok(result)
         }
 
-        let vals = s.split(' ').collect::<Vec<_>>();
+        let vals = split_params(s);
+        let vals = vals.iter().map(String::as_str).collect::<Vec<_>>();
 
         if vals.len() < 5 {
             let err_msg = format!(
@@ -312,43 +321,230 @@ impl FromStr for FlakeyTargetParams {
 
 impl TargetParams for FlakeyTargetParams {
     fn param_str(&self) -> String {
-        let feature_args = if self.feature_args.is_empty() {
-            "0".to_owned()
-        } else {
-            format!(
-                "{} {}",
-                self.feature_args.len(),
-                self.feature_args
-                    .iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            )
-        };
+        ParamStr(self).to_string()
+    }
 
-        format!(
+    fn fmt_params(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
             "{} {} {} {} {}",
-            self.device, *self.start_offset, self.up_interval, self.down_interval, feature_args
-        )
+            self.device,
+            *self.start_offset,
+            self.up_interval,
+            self.down_interval,
+            self.feature_args.len()
+        )?;
+        for arg in &self.feature_args {
+            write!(f, " {arg}")?;
+        }
+        Ok(())
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetType::FLAKEY.to_owned()
+    }
+}
+
+/// Where a class of I/O to a delay target is sent, and by how much it is
+/// delayed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DelayClass {
+    /// The device to which the I/O is sent
+    pub device: Device,
+    /// The starting offset of the segment in the device
+    pub start_offset: Sectors,
+    /// The delay, in milliseconds
+    pub delay: u32,
+}
+
+impl DelayClass {
+    /// Create a new delay class.
+    pub fn new(device: Device, start_offset: Sectors, delay: u32) -> DelayClass {
+        DelayClass {
+            device,
+            start_offset,
+            delay,
+        }
+    }
+
+    fn parse(vals: &[&str], class: &str) -> DmResult<DelayClass> {
+        Ok(DelayClass::new(
+            parse_device(
+                vals[0],
+                &format!("block device for {class} of delay target"),
+            )?,
+            Sectors(parse_value(vals[1], &format!("start offset for {class}"))?),
+            parse_value(vals[2], &format!("delay for {class}"))?,
+        ))
+    }
+}
+
+impl fmt::Display for DelayClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.device, *self.start_offset, self.delay)
+    }
+}
+
+/// Target params for delay target. Reads, writes, and flushes may each be
+/// delayed by a different amount and sent to a different device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DelayTargetParams {
+    /// Where reads are sent, and writes and flushes, unless they are given
+    /// classes of their own
+    pub read: DelayClass,
+    /// Where writes are sent, and flushes, unless they are given a class of
+    /// their own
+    pub write: Option<DelayClass>,
+    /// Where flushes are sent. If set, write is also set.
+    pub flush: Option<DelayClass>,
+}
+
+impl DelayTargetParams {
+    /// Create a new delay target param struct. If flush is given but write
+    /// is not, writes are treated as reads.
+    pub fn new(
+        read: DelayClass,
+        write: Option<DelayClass>,
+        flush: Option<DelayClass>,
+    ) -> DelayTargetParams {
+        let write = match (write, &flush) {
+            (None, Some(_)) => Some(read.clone()),
+            (write, _) => write,
+        };
+        DelayTargetParams { read, write, flush }
+    }
+}
+
+impl fmt::Display for DelayTargetParams {
+    /// Generate params to be passed to DM. The format of the params is:
+    ///
+    /// ```plain
+    /// <device> <offset> <delay> [<write device> <write offset> <write delay>
+    ///     [<flush device> <flush offset> <flush delay>]]
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", DELAY_TARGET_NAME, ParamStr(self))
+    }
+}
+
+impl FromStr for DelayTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<DelayTargetParams> {
+        let vals = split_params(s);
+        let vals = vals.iter().map(String::as_str).collect::<Vec<_>>();
+        if ![4, 7, 10].contains(&vals.len()) {
+            let err_msg = format!(
+                "expected 4, 7, or 10 values in params string \"{}\", found {}",
+                s,
+                vals.len()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if vals[0] != DELAY_TARGET_NAME {
+            let err_msg = format!(
+                "Expected a delay target entry but found target type {}",
+                vals[0]
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let read = DelayClass::parse(&vals[1..4], "reads")?;
+        let write = vals
+            .get(4..7)
+            .map(|vals| DelayClass::parse(vals, "writes"))
+            .transpose()?;
+        let flush = vals
+            .get(7..10)
+            .map(|vals| DelayClass::parse(vals, "flushes"))
+            .transpose()?;
+
+        Ok(DelayTargetParams::new(read, write, flush))
+    }
+}
+
+impl TargetParams for DelayTargetParams {
+    fn param_str(&self) -> String {
+        ParamStr(self).to_string()
+    }
+
+    fn fmt_params(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.read)?;
+        for class in self.write.iter().chain(self.flush.iter()) {
+            write!(f, " {class}")?;
+        }
+        Ok(())
     }
 
     fn target_type(&self) -> TargetTypeBuf {
-        TargetTypeBuf::new(FLAKEY_TARGET_NAME.into()).expect("FLAKEY_TARGET_NAME is valid")
+        TargetType::DELAY.to_owned()
     }
 }
 
-/// Target params for linear dev. These are either flakey or linear.
+/// Target params for linear dev. These are delay, flakey, or linear.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum LinearDevTargetParams {
+    /// A delay target
+    Delay(DelayTargetParams),
     /// A flakey target
     Flakey(FlakeyTargetParams),
     /// A linear target
     Linear(LinearTargetParams),
 }
 
+impl LinearDevTargetParams {
+    /// The device and the offset within it at which the segment resides.
+    /// A delay segment resides where its reads are sent.
+    fn device_and_offset(&self) -> (Device, Sectors) {
+        match *self {
+            LinearDevTargetParams::Delay(ref delay) => (delay.read.device, delay.read.start_offset),
+            LinearDevTargetParams::Flakey(ref flakey) => (flakey.device, flakey.start_offset),
+            LinearDevTargetParams::Linear(ref linear) => (linear.device, linear.start_offset),
+        }
+    }
+
+    /// The same params, but with the segment residing at the given device
+    /// and offset. The classes of a delay segment are moved as its read
+    /// class is, keeping their offsets relative to it, and those on the
+    /// read class's device are moved to the given device.
+    fn with_device_and_offset(&self, device: Device, start_offset: Sectors) -> Self {
+        match *self {
+            LinearDevTargetParams::Delay(ref delay) => {
+                let read = &delay.read;
+                let relocate = |class: &DelayClass| DelayClass {
+                    device: if class.device == read.device {
+                        device
+                    } else {
+                        class.device
+                    },
+                    start_offset: class.start_offset + start_offset - read.start_offset,
+                    delay: class.delay,
+                };
+                LinearDevTargetParams::Delay(DelayTargetParams {
+                    read: relocate(read),
+                    write: delay.write.as_ref().map(relocate),
+                    flush: delay.flush.as_ref().map(relocate),
+                })
+            }
+            LinearDevTargetParams::Flakey(ref flakey) => {
+                LinearDevTargetParams::Flakey(FlakeyTargetParams {
+                    device,
+                    start_offset,
+                    ..flakey.clone()
+                })
+            }
+            LinearDevTargetParams::Linear(_) => {
+                LinearDevTargetParams::Linear(LinearTargetParams::new(device, start_offset))
+            }
+        }
+    }
+}
+
 impl fmt::Display for LinearDevTargetParams {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
+            LinearDevTargetParams::Delay(ref delay) => delay.fmt(f),
             LinearDevTargetParams::Flakey(ref flakey) => flakey.fmt(f),
             LinearDevTargetParams::Linear(ref linear) => linear.fmt(f),
         }
@@ -365,7 +561,11 @@ impl FromStr for LinearDevTargetParams {
                 format!("target line string \"{s}\" did not contain any values"),
             )
         })?;
-        if target_type == FLAKEY_TARGET_NAME {
+        if target_type == DELAY_TARGET_NAME {
+            Ok(LinearDevTargetParams::Delay(
+                s.parse::<DelayTargetParams>()?,
+            ))
+        } else if target_type == FLAKEY_TARGET_NAME {
             Ok(LinearDevTargetParams::Flakey(
                 s.parse::<FlakeyTargetParams>()?,
             ))
@@ -382,24 +582,76 @@ impl FromStr for LinearDevTargetParams {
     }
 }
 
+impl TargetLine<LinearDevTargetParams> {
+    /// Split the line in two at offset sectors into it. The second line
+    /// maps the device's sectors directly following those of the first.
+    /// Returns an error unless 0 < offset < length.
+    pub fn split_at(
+        &self,
+        offset: Sectors,
+    ) -> DmResult<(
+        TargetLine<LinearDevTargetParams>,
+        TargetLine<LinearDevTargetParams>,
+    )> {
+        if offset == Sectors(0) || offset >= self.length {
+            let err_msg = format!(
+                "can not split a line of length {} at offset {}",
+                self.length, offset
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        let (device, start_offset) = self.params.device_and_offset();
+        Ok((
+            TargetLine::new(self.start, offset, self.params.clone()),
+            TargetLine::new(
+                self.start + offset,
+                self.length - offset,
+                self.params
+                    .with_device_and_offset(device, start_offset + offset),
+            ),
+        ))
+    }
+
+    /// Whether next directly follows this line, mapping the device's
+    /// sectors directly following this line's in the same way, so that the
+    /// two lines can be merged into one.
+    fn is_continued_by(&self, next: &TargetLine<LinearDevTargetParams>) -> bool {
+        let (device, start_offset) = self.params.device_and_offset();
+        self.start.checked_add(self.length) == Some(next.start)
+            && start_offset.checked_add(self.length).is_some_and(|offset| {
+                next.params == self.params.with_device_and_offset(device, offset)
+            })
+    }
+}
+
 impl TargetParams for LinearDevTargetParams {
     fn param_str(&self) -> String {
         match *self {
+            LinearDevTargetParams::Delay(ref delay) => delay.param_str(),
             LinearDevTargetParams::Flakey(ref flakey) => flakey.param_str(),
             LinearDevTargetParams::Linear(ref linear) => linear.param_str(),
         }
     }
 
+    fn fmt_params(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            LinearDevTargetParams::Delay(ref delay) => delay.fmt_params(f),
+            LinearDevTargetParams::Flakey(ref flakey) => flakey.fmt_params(f),
+            LinearDevTargetParams::Linear(ref linear) => linear.fmt_params(f),
+        }
+    }
+
     fn target_type(&self) -> TargetTypeBuf {
         match *self {
+            LinearDevTargetParams::Delay(ref delay) => delay.target_type(),
             LinearDevTargetParams::Flakey(ref flakey) => flakey.target_type(),
             LinearDevTargetParams::Linear(ref linear) => linear.target_type(),
         }
     }
 }
 
-/// A target table for a linear device. Such a table allows flakey targets
-/// as well as linear targets.
+/// A target table for a linear device. Such a table allows delay and
+/// flakey targets as well as linear targets.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct LinearDevTargetTable {
     /// The device's table
@@ -411,6 +663,64 @@ impl LinearDevTargetTable {
     pub fn new(table: Vec<TargetLine<LinearDevTargetParams>>) -> LinearDevTargetTable {
         LinearDevTargetTable { table }
     }
+
+    /// Merge each line with the line after it wherever the second continues
+    /// the first, i.e., maps the next sectors of the same device in the
+    /// same way.
+    pub fn coalesce(self) -> LinearDevTargetTable {
+        let mut table: Vec<TargetLine<LinearDevTargetParams>> = Vec::new();
+        for line in self.table {
+            match table.last_mut() {
+                Some(last) if last.is_continued_by(&line) => last.length += line.length,
+                _ => table.push(line),
+            }
+        }
+        LinearDevTargetTable { table }
+    }
+
+    /// Sort the lines by start sector, check that they cover the device
+    /// from sector 0 without gaps or overlaps and that no two of them map
+    /// the same sectors of a device, and coalesce them.
+    pub fn normalize(mut self) -> DmResult<LinearDevTargetTable> {
+        self.table.sort_by_key(|line| line.start);
+        validate_table_lines(
+            self.table.iter().map(|line| {
+                (
+                    line.start,
+                    line.length,
+                    line.params.target_type().to_string(),
+                )
+            }),
+            None,
+        )?;
+
+        let mut extents = self
+            .table
+            .iter()
+            .map(|line| {
+                let (device, start_offset) = line.params.device_and_offset();
+                (device, start_offset, line.length)
+            })
+            .collect::<Vec<_>>();
+        extents
+            .sort_by_key(|(device, start_offset, _)| (device.major, device.minor, *start_offset));
+        for pair in extents.windows(2) {
+            let ((device, start_offset, length), (next_device, next_offset, _)) =
+                (pair[0], pair[1]);
+            if device == next_device
+                && start_offset
+                    .checked_add(length)
+                    .map_or(true, |end| end > next_offset)
+            {
+                let err_msg = format!(
+                    "segments at sectors {start_offset} and {next_offset} of device {device} overlap"
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+        }
+
+        Ok(self.coalesce())
+    }
 }
 
 impl fmt::Display for LinearDevTargetTable {
@@ -423,6 +733,8 @@ impl fmt::Display for LinearDevTargetTable {
 }
 
 impl TargetTable for LinearDevTargetTable {
+    type Params = LinearDevTargetParams;
+
     fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<LinearDevTargetTable> {
         Ok(LinearDevTargetTable {
             table: table
@@ -438,18 +750,8 @@ impl TargetTable for LinearDevTargetTable {
         })
     }
 
-    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
-        self.table
-            .iter()
-            .map(|x| {
-                (
-                    *x.start,
-                    *x.length,
-                    x.params.target_type().to_string(),
-                    x.params.param_str(),
-                )
-            })
-            .collect::<Vec<_>>()
+    fn lines(&self) -> &[TargetLine<Self::Params>] {
+        &self.table
     }
 }
 
@@ -484,6 +786,10 @@ impl DmDevice<LinearDevTargetTable> for LinearDev {
         name!(self)
     }
 
+    fn record_table(&mut self, table: LinearDevTargetTable) {
+        self.table = table;
+    }
+
     fn size(&self) -> Sectors {
         self.table.table.iter().map(|l| l.length).sum()
     }
@@ -502,6 +808,18 @@ impl DmDevice<LinearDevTargetTable> for LinearDev {
     }
 }
 
+/// Undo loading a table into the inactive slot of the device named, and
+/// any suspend that followed: clear the inactive table, then resume the
+/// device with the table that was live.
+fn unload_table(name: DmNameBuf) -> impl FnOnce(&DM) -> DmResult<()> {
+    move |dm| {
+        let id = DevId::Name(&name);
+        dm.table_clear(&id)?;
+        dm.device_suspend(&id, DmOptions::private())?;
+        Ok(())
+    }
+}
+
 /// Use DM to concatenate a list of segments together into a
 /// linear block device of continuous sectors.
 impl LinearDev {
@@ -523,20 +841,36 @@ impl LinearDev {
     /// the existence of the requested device". Of course, a linear device
     /// is usually expected to hold data, so it is important to get the
     /// mapping just right.
+    ///
+    /// Setting up a device that already exists with the same table takes
+    /// two ioctls, one for its info and one for its table. Creating a device
+    /// takes four: info, create, load and resume.
     pub fn setup(
         dm: &DM,
         name: &DmName,
         uuid: Option<&DmUuid>,
         table: Vec<TargetLine<LinearDevTargetParams>>,
+    ) -> DmResult<LinearDev> {
+        LinearDev::ensure(dm, name, uuid, table, TableMismatch::Error)
+    }
+
+    /// Like [`LinearDev::setup`], except that if the device already exists
+    /// with a different table, policy determines whether an error is
+    /// returned or the device's table is replaced.
+    pub fn ensure(
+        dm: &DM,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        table: Vec<TargetLine<LinearDevTargetParams>>,
+        policy: TableMismatch,
     ) -> DmResult<LinearDev> {
         let table = LinearDevTargetTable::new(table);
-        let dev = if device_exists(dm, name)? {
-            let dev_info = dm.device_info(&DevId::Name(name))?;
-            let dev = LinearDev {
+        let dev = if let Some(dev_info) = device_info_if_exists(dm, name)? {
+            let mut dev = LinearDev {
                 dev_info: Box::new(dev_info),
                 table,
             };
-            device_match(dm, &dev, uuid)?;
+            device_adopt(dm, &mut dev, uuid, policy)?;
             dev
         } else {
             let dev_info = device_create(dm, name, uuid, &table, DmOptions::private())?;
@@ -550,6 +884,10 @@ impl LinearDev {
 
     /// Set the segments for this linear device.
     /// This action puts the device in a state where it is ready to be resumed.
+    /// The segments are loaded before the device is suspended, so that it is
+    /// suspended only once they are in place. If the segments are the same as
+    /// the device's current segments, nothing is done, and the device is not
+    /// suspended.
     /// Warning: It is the client's responsibility to make sure the designated
     /// segments are compatible with the device's existing segments.
     /// If they are not, this function will still succeed, but some kind of
@@ -560,12 +898,216 @@ impl LinearDev {
         table: Vec<TargetLine<LinearDevTargetParams>>,
     ) -> DmResult<()> {
         let table = LinearDevTargetTable::new(table);
-        self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
-        self.table_load(dm, &table, DmOptions::default())?;
+        if table == self.table {
+            return Ok(());
+        }
+        self.preload(dm, &table)?;
+        if let Err(err) = self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH)) {
+            if let Err(clear_err) = self.clear_pending_table(dm) {
+                warn!(
+                    "Failed to clear the table loaded into {}: {}",
+                    self.name(),
+                    clear_err
+                );
+            }
+            return Err(err);
+        }
         self.table = table;
         Ok(())
     }
 
+    /// Load the segments for this linear device into its inactive table
+    /// slot without suspending it. Return the table loaded, which takes
+    /// effect, and becomes the device's table, when it is passed to
+    /// [`DmDevice::commit`]. Until then, the device's table is unchanged.
+    /// The same warning as for [`LinearDev::set_table`] applies.
+    pub fn preload_table(
+        &self,
+        dm: &DM,
+        table: Vec<TargetLine<LinearDevTargetParams>>,
+    ) -> DmResult<LinearDevTargetTable> {
+        let table = LinearDevTargetTable::new(table);
+        self.preload(dm, &table)?;
+        Ok(table)
+    }
+
+    /// Insert a new linear device beneath this one, without interrupting
+    /// I/O to this device beyond a brief suspend.
+    ///
+    /// The new device is created with the given name, uuid, and segments,
+    /// which will usually be derived from this device's segments, e.g., by
+    /// replacing its linear segments with flakey ones. The segments must
+    /// cover exactly as many sectors as this device. This device's table is
+    /// then replaced with a single segment mapping the whole of the new
+    /// device. Returns the new device.
+    pub fn interpose(
+        &mut self,
+        dm: &DM,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        table: Vec<TargetLine<LinearDevTargetParams>>,
+    ) -> DmResult<LinearDev> {
+        if device_info_if_exists(dm, name)?.is_some() {
+            let err_msg = format!("device {name} already exists");
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let size = table.iter().map(|l| l.length).sum::<Sectors>();
+        if size != self.size() {
+            let err_msg = format!(
+                "new device's segments cover {} but device {} has {}",
+                size,
+                self.name(),
+                self.size()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        let mut journal = Journal::new(&format!("interposing {} beneath {}", name, self.name()));
+        let middle = LinearDev::setup(dm, name, uuid, table)?;
+        let middle_name = middle.name().to_owned();
+        journal.record(&format!("create {name}"), move |dm| {
+            dm.device_remove(&DevId::Name(&middle_name), DmOptions::default())
+                .map(|_| ())
+        });
+
+        let upper_table = vec![TargetLine::new(
+            Sectors(0),
+            size,
+            LinearDevTargetParams::Linear(LinearTargetParams::new(middle.device(), Sectors(0))),
+        )];
+        let upper_table = journal.step(
+            dm,
+            &format!("load the table of {}", self.name()),
+            |dm| self.preload_table(dm, upper_table),
+            unload_table(self.name().to_owned()),
+        )?;
+        if let Err(err) = self.commit(dm, upper_table) {
+            let step = format!("map {} to {}", self.name(), name);
+            return Err(journal.roll_back(dm, &step, err));
+        }
+
+        journal.commit();
+        Ok(middle)
+    }
+
+    /// Remove a linear device beneath this one from the stack, the inverse
+    /// of [`LinearDev::interpose`].
+    ///
+    /// Every segment of this device which maps middle is rewritten to map
+    /// the devices that middle maps, and middle is then torn down. Every
+    /// segment of middle which this device maps must pass I/O through
+    /// unaltered, i.e., must be a linear segment or a flakey segment with a
+    /// down interval of 0. If middle can not be collapsed, the stack is left
+    /// unchanged.
+    pub fn collapse(&mut self, dm: &DM, middle: &mut LinearDev) -> DmResult<()> {
+        let middle_device = middle.device();
+
+        let mut table = Vec::new();
+        for line in &self.table.table {
+            // The classes of a delay segment may map middle at different
+            // offsets, which are not rewritten.
+            if let LinearDevTargetParams::Delay(ref delay) = line.params {
+                if [
+                    Some(&delay.read),
+                    delay.write.as_ref(),
+                    delay.flush.as_ref(),
+                ]
+                .iter()
+                .flatten()
+                .any(|class| class.device == middle_device)
+                {
+                    let err_msg = format!(
+                        "delay segment at {} of device {} maps device {}",
+                        line.start,
+                        self.name(),
+                        middle.name()
+                    );
+                    return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+                }
+            }
+
+            let (device, offset) = line.params.device_and_offset();
+            if device != middle_device {
+                table.push(line.clone());
+                continue;
+            }
+
+            let mut start = line.start;
+            let mut offset = offset;
+            let end = offset + line.length;
+            for lower in &middle.table.table {
+                let lower_end = lower.start + lower.length;
+                if offset >= end {
+                    break;
+                }
+                if lower_end <= offset || lower.start >= end {
+                    continue;
+                }
+
+                let (lower_device, lower_offset) = match lower.params {
+                    LinearDevTargetParams::Linear(ref params) => {
+                        (params.device, params.start_offset)
+                    }
+                    LinearDevTargetParams::Flakey(ref params) if params.down_interval == 0 => {
+                        (params.device, params.start_offset)
+                    }
+                    LinearDevTargetParams::Delay(_) | LinearDevTargetParams::Flakey(_) => {
+                        let err_msg = format!(
+                            "segment at {} of device {} is not a passthrough segment",
+                            lower.start,
+                            middle.name()
+                        );
+                        return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+                    }
+                };
+
+                // The segments are contiguous and ordered, so the overlap
+                // with this lower segment begins at offset.
+                let length = std::cmp::min(end, lower_end) - offset;
+                table.push(TargetLine::new(
+                    start,
+                    length,
+                    line.params.with_device_and_offset(
+                        lower_device,
+                        lower_offset + (offset - lower.start),
+                    ),
+                ));
+                start += length;
+                offset += length;
+            }
+
+            if offset < end {
+                let err_msg = format!(
+                    "device {} does not map sectors {} to {} of device {}",
+                    middle.name(),
+                    offset,
+                    end,
+                    self.name()
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+        }
+
+        let mut journal = Journal::new(&format!(
+            "collapsing {} from beneath {}",
+            middle.name(),
+            self.name()
+        ));
+        let table = self.preload_table(dm, table)?;
+        journal.record(
+            &format!("load the table of {}", self.name()),
+            unload_table(self.name().to_owned()),
+        );
+        if let Err(err) = self.commit(dm, table) {
+            let step = format!("map {} past {}", self.name(), middle.name());
+            return Err(journal.roll_back(dm, &step, err));
+        }
+        journal.commit();
+
+        middle.teardown(dm)
+    }
+
     /// Set the name for this LinearDev.
     pub fn set_name(&mut self, dm: &DM, name: &DmName) -> DmResult<()> {
         if self.name() == name {
@@ -579,11 +1121,18 @@ impl LinearDev {
 
 #[cfg(test)]
 mod tests {
-    use std::{clone::Clone, fs::OpenOptions, path::Path};
+    use std::{
+        clone::Clone,
+        fs::OpenOptions,
+        path::Path,
+        time::{Duration, Instant},
+    };
 
     use crate::{
-        core::{devnode_to_devno, Device},
-        testing::{blkdev_size, test_name, test_with_spec},
+        core::{devnode_to_devno, errors, Device, DmFeature},
+        holders::device_users,
+        shared::{message_at, RetryPolicy},
+        testing::{blkdev_size, test_name, test_uuid, test_with_spec},
     };
 
     use super::*;
@@ -597,7 +1146,7 @@ mod tests {
                 None,
                 vec![],
             ),
-            Err(_)
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
         );
     }
 
@@ -616,7 +1165,10 @@ mod tests {
         )];
         let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
 
-        assert_matches!(ld.set_table(&dm, vec![]), Err(_));
+        assert_matches!(
+            ld.set_table(&dm, vec![]),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
         ld.resume(&dm).unwrap();
         ld.teardown(&dm).unwrap();
     }
@@ -781,8 +1333,8 @@ mod tests {
         ld.teardown(&dm).unwrap();
     }
 
-    /// Verify that suspending and immediately resuming doesn't fail.
-    fn test_suspend(paths: &[&Path]) {
+    /// Verify that a preloaded table takes effect only on commit.
+    fn test_preload_commit(paths: &[&Path]) {
         assert!(!paths.is_empty());
 
         let dm = DM::new().unwrap();
@@ -794,29 +1346,776 @@ mod tests {
             Sectors(1),
             LinearDevTargetParams::Linear(params),
         )];
-        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+        let mut ld = LinearDev::setup(&dm, &name, None, table.clone()).unwrap();
 
-        ld.suspend(&dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))
-            .unwrap();
-        ld.suspend(&dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))
-            .unwrap();
-        ld.resume(&dm).unwrap();
+        let new_params = LinearTargetParams::new(dev, Sectors(1));
+        let new_table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(2),
+            LinearDevTargetParams::Linear(new_params),
+        )];
+        let preloaded = ld.preload_table(&dm, new_table.clone()).unwrap();
+
+        let loaded_table = LinearDev::read_kernel_table(&dm, &DevId::Name(ld.name())).unwrap();
+        assert!(LinearDev::equivalent_tables(
+            &LinearDevTargetTable::new(table.clone()),
+            &loaded_table
+        )
+        .unwrap());
+        assert_eq!(ld.size(), Sectors(1));
+
+        // Only the table that was preloaded may be recorded.
+        assert_matches!(
+            ld.commit(&dm, LinearDevTargetTable::new(table)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert!(!dm
+            .device_info(&DevId::Name(ld.name()))
+            .unwrap()
+            .flags()
+            .is_suspended());
+        assert_eq!(ld.size(), Sectors(1));
+
+        ld.commit(&dm, preloaded).unwrap();
+
+        let loaded_table = LinearDev::read_kernel_table(&dm, &DevId::Name(ld.name())).unwrap();
+        assert!(
+            LinearDev::equivalent_tables(&LinearDevTargetTable::new(new_table), &loaded_table)
+                .unwrap()
+        );
+        assert_eq!(ld.size(), Sectors(2));
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    /// Verify that a table loaded behind the device's back is reported by
+    /// verify(), and that reloading the recorded table clears the report.
+    fn test_verify(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table.clone()).unwrap();
+        assert_eq!(ld.verify(&dm).unwrap(), None);
+
+        let other = LinearDevTargetTable::new(vec![
+            TargetLine::new(
+                Sectors(0),
+                Sectors(1),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(1))),
+            ),
+            TargetLine::new(
+                Sectors(1),
+                Sectors(1),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(2))),
+            ),
+        ]);
+        ld.table_load(&dm, &other, DmOptions::default()).unwrap();
+        ld.suspend(&dm, DmOptions::default()).unwrap();
         ld.resume(&dm).unwrap();
 
+        let diff = ld.verify(&dm).unwrap().unwrap();
+        assert_eq!(diff.expected, LinearDevTargetTable::new(table));
+        assert_eq!(diff.found, other);
+        assert_eq!(
+            diff.lines.iter().map(|l| l.index).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert_eq!(diff.lines[1].expected, None);
+
+        let table = ld.table().clone();
+        ld.preload(&dm, &table).unwrap();
+        ld.commit(&dm, table).unwrap();
+        assert_eq!(ld.verify(&dm).unwrap(), None);
+
         ld.teardown(&dm).unwrap();
     }
 
     #[test]
-    fn test_flakey_target_params_zero() {
-        let result = "flakey 8:32 0 16 2 0"
-            .parse::<FlakeyTargetParams>()
-            .unwrap();
-        assert_eq!(result.feature_args, HashSet::new());
+    fn loop_test_verify() {
+        test_with_spec(1, test_verify);
     }
 
-    #[test]
-    fn test_flakey_target_params_none() {
-        let result = "flakey 8:32 0 16 2".parse::<FlakeyTargetParams>().unwrap();
+    /// Verify that a table preloaded into the inactive slot is discarded by
+    /// clear_pending_table, so that committing leaves the live table as it
+    /// was.
+    fn test_clear_pending_table(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = |offset| {
+            LinearDevTargetTable::new(vec![TargetLine::new(
+                Sectors(0),
+                Sectors(1),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(offset))),
+            )])
+        };
+        let mut ld = LinearDev::setup(&dm, &name, None, table(0).table).unwrap();
+        let inactive_present = |ld: &LinearDev| {
+            dm.device_info(&DevId::Name(ld.name()))
+                .unwrap()
+                .flags()
+                .contains(DmFlags::DM_INACTIVE_PRESENT)
+        };
+
+        ld.preload(&dm, &table(1)).unwrap();
+        assert!(inactive_present(&ld));
+        ld.clear_pending_table(&dm).unwrap();
+        assert!(!inactive_present(&ld));
+
+        ld.commit(&dm, table(0)).unwrap();
+        assert_eq!(ld.verify(&dm).unwrap(), None);
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_clear_pending_table() {
+        test_with_spec(1, test_clear_pending_table);
+    }
+
+    /// Verify that a device can be made read-only and writable again
+    /// without its mapping changing.
+    fn test_set_read_only(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+        let read_only = |ld: &LinearDev| {
+            dm.device_info(&DevId::Name(ld.name()))
+                .unwrap()
+                .flags()
+                .contains(DmFlags::DM_READONLY)
+        };
+        assert!(!read_only(&ld));
+
+        ld.set_read_only(&dm, true).unwrap();
+        assert!(read_only(&ld));
+        assert_eq!(ld.verify(&dm).unwrap(), None);
+
+        ld.set_read_only(&dm, false).unwrap();
+        assert!(!read_only(&ld));
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_set_read_only() {
+        test_with_spec(1, test_set_read_only);
+    }
+
+    /// Verify that setting the same segments again does not suspend the
+    /// device, and that setting different segments does.
+    fn test_set_table_unchanged(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = |length| {
+            vec![TargetLine::new(
+                Sectors(0),
+                Sectors(length),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+            )]
+        };
+        let mut ld = LinearDev::setup(&dm, &name, None, table(1)).unwrap();
+        let suspended = |ld: &LinearDev| {
+            dm.device_info(&DevId::Name(ld.name()))
+                .unwrap()
+                .flags()
+                .contains(DmFlags::DM_SUSPEND)
+        };
+
+        ld.set_table(&dm, table(1)).unwrap();
+        assert!(!suspended(&ld));
+
+        ld.set_table(&dm, table(2)).unwrap();
+        assert!(suspended(&ld));
+        ld.resume(&dm).unwrap();
+        assert_eq!(ld.size(), Sectors(2));
+        assert_eq!(ld.verify(&dm).unwrap(), None);
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_set_table_unchanged() {
+        test_with_spec(1, test_set_table_unchanged);
+    }
+
+    /// Verify that a device held open can not be torn down, and that the
+    /// error names the process that holds it.
+    fn test_teardown_with_retries(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+        let policy = RetryPolicy::new(2, Duration::from_millis(10));
+
+        let file = OpenOptions::new().read(true).open(ld.devnode()).unwrap();
+        let users = device_users(ld.device()).unwrap();
+        assert!(users
+            .processes
+            .iter()
+            .any(|(pid, _)| *pid == std::process::id()));
+        match ld.teardown_with_retries(&dm, policy) {
+            Err(DmError::Dm(ErrorEnum::Busy, err_msg)) => {
+                assert!(err_msg.contains(&std::process::id().to_string()))
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+
+        drop(file);
+        ld.teardown_with_retries(&dm, policy).unwrap();
+    }
+
+    #[test]
+    fn loop_test_teardown_with_retries() {
+        test_with_spec(1, test_teardown_with_retries);
+    }
+
+    /// Verify that waiting for an event returns at once if the event
+    /// number has moved on, and times out otherwise.
+    fn test_wait_for_event(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+        let table = ld.table().clone();
+        let event_nr = ld.commit(&dm, table).unwrap();
+        let id = DevId::Name(ld.name());
+        assert_eq!(dm.device_info(&id).unwrap().event_nr(), event_nr);
+
+        let info = dm
+            .wait_for_event(&id, event_nr.wrapping_add(1), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.event_nr(), event_nr);
+
+        if dm.supports(DmFeature::ArmPoll).unwrap() {
+            let timeout = Duration::from_millis(100);
+            let start = Instant::now();
+            assert!(dm
+                .wait_for_event(&id, event_nr, Some(timeout))
+                .unwrap()
+                .is_none());
+            assert!(start.elapsed() >= timeout);
+        }
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_wait_for_event() {
+        test_with_spec(1, test_wait_for_event);
+    }
+
+    /// Verify that ensure() adopts a device with a matching table, and that
+    /// a device with a different table is reloaded or rejected per policy.
+    fn test_ensure(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = |offset| {
+            vec![TargetLine::new(
+                Sectors(0),
+                Sectors(1),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(offset))),
+            )]
+        };
+
+        let mut ld = LinearDev::ensure(&dm, &name, None, table(0), TableMismatch::Error).unwrap();
+        let adopted = LinearDev::ensure(&dm, &name, None, table(0), TableMismatch::Error).unwrap();
+        assert_eq!(adopted.device(), ld.device());
+
+        assert_matches!(
+            LinearDev::ensure(&dm, &name, None, table(1), TableMismatch::Error),
+            Err(_)
+        );
+        assert_eq!(ld.verify(&dm).unwrap(), None);
+
+        let reloaded =
+            LinearDev::ensure(&dm, &name, None, table(1), TableMismatch::Reload).unwrap();
+        assert_eq!(reloaded.verify(&dm).unwrap(), None);
+        assert!(ld.verify(&dm).unwrap().is_some());
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_ensure() {
+        test_with_spec(1, test_ensure);
+    }
+
+    /// Verify the number of ioctls that setting up a device takes, when it
+    /// is created and when it already exists.
+    fn test_setup_ioctl_count(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+
+        let before = dm.ioctl_count();
+        let mut ld = LinearDev::setup(&dm, &name, None, table.clone()).unwrap();
+        assert_eq!(dm.ioctl_count() - before, 4);
+
+        let before = dm.ioctl_count();
+        LinearDev::setup(&dm, &name, None, table).unwrap();
+        assert_eq!(dm.ioctl_count() - before, 2);
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_setup_ioctl_count() {
+        test_with_spec(1, test_setup_ioctl_count);
+    }
+
+    /// Verify that a message is addressed to the target that includes the
+    /// sector given, and is refused for a sector beyond the device's end.
+    fn test_message_at(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![
+            TargetLine::new(
+                Sectors(0),
+                Sectors(8),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+            ),
+            TargetLine::new(
+                Sectors(8),
+                Sectors(8),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(8))),
+            ),
+        ];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+
+        // The linear target does not take messages, so the kernel refuses
+        // the message once it has found the target.
+        assert_matches!(
+            message_at(&dm, &ld, Sectors(12), "message"),
+            Err(DmError::Core(errors::Error::Ioctl(..)))
+        );
+        assert_matches!(
+            message_at(&dm, &ld, Sectors(16), "message"),
+            Err(DmError::Core(errors::Error::InvalidArgument(_)))
+        );
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_message_at() {
+        test_with_spec(1, test_message_at);
+    }
+
+    /// Verify that a device of the same name, but with another uuid, is not
+    /// adopted.
+    fn test_identity_mismatch(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let uuid = test_uuid("uuid").expect("valid format");
+        let other_uuid = test_uuid("other").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+
+        let mut ld = LinearDev::setup(&dm, &name, Some(&uuid), table.clone()).unwrap();
+        assert_matches!(
+            LinearDev::setup(&dm, &name, Some(&other_uuid), table.clone()),
+            Err(DmError::Dm(ErrorEnum::IdentityMismatch, _))
+        );
+        assert_matches!(
+            LinearDev::setup(&dm, &name, None, table.clone()),
+            Err(DmError::Dm(ErrorEnum::IdentityMismatch, _))
+        );
+        assert_matches!(
+            LinearDev::ensure(&dm, &name, Some(&other_uuid), table, TableMismatch::Reload),
+            Err(DmError::Dm(ErrorEnum::IdentityMismatch, _))
+        );
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_identity_mismatch() {
+        test_with_spec(1, test_identity_mismatch);
+    }
+
+    /// Verify that devices_remove removes a device stacked on another,
+    /// whatever the order in which they are named.
+    fn test_devices_remove_stacked(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let lower_table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(2),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let lower = LinearDev::setup(
+            &dm,
+            &test_name("lower").expect("valid format"),
+            None,
+            lower_table,
+        )
+        .unwrap();
+
+        let upper_table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(lower.device(), Sectors(0))),
+        )];
+        let upper = LinearDev::setup(
+            &dm,
+            &test_name("upper").expect("valid format"),
+            None,
+            upper_table,
+        )
+        .unwrap();
+
+        dm.devices_remove(&[lower.name(), upper.name()], DmOptions::default(), 2)
+            .unwrap();
+        assert!(dm.list_test_devices().unwrap().is_empty());
+    }
+
+    /// Verify that a device stacked on another is its holder, and that
+    /// neither device has a filesystem mounted.
+    fn test_holders(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let lower_table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(2),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut lower = LinearDev::setup(
+            &dm,
+            &test_name("lower").expect("valid format"),
+            None,
+            lower_table,
+        )
+        .unwrap();
+        assert!(lower.holders().unwrap().is_empty());
+
+        let upper_table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(lower.device(), Sectors(0))),
+        )];
+        let mut upper = LinearDev::setup(
+            &dm,
+            &test_name("upper").expect("valid format"),
+            None,
+            upper_table,
+        )
+        .unwrap();
+
+        assert_eq!(
+            lower.holders().unwrap(),
+            vec![format!("dm-{}", upper.device().minor)]
+        );
+        assert!(upper.holders().unwrap().is_empty());
+        assert!(lower.mounts().unwrap().is_empty());
+        assert!(upper.mounts().unwrap().is_empty());
+
+        upper.teardown(&dm).unwrap();
+        lower.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_holders() {
+        test_with_spec(1, test_holders);
+    }
+
+    /// Verify that remove_recursive removes the devices stacked on top of
+    /// the given device, but not the devices that it is stacked on.
+    fn test_remove_recursive(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let mut devs: Vec<LinearDev> = Vec::new();
+        for (i, length) in [4u64, 3, 2, 1].iter().enumerate() {
+            let lower = devs.last().map(|ld| ld.device()).unwrap_or(dev);
+            let table = vec![TargetLine::new(
+                Sectors(0),
+                Sectors(*length),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(lower, Sectors(0))),
+            )];
+            devs.push(
+                LinearDev::setup(
+                    &dm,
+                    &test_name(&format!("layer{i}")).expect("valid format"),
+                    None,
+                    table,
+                )
+                .unwrap(),
+            );
+        }
+
+        dm.remove_recursive(&DevId::Name(devs[1].name()), DmOptions::default())
+            .unwrap();
+        let remaining = dm.list_test_devices().unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(&*remaining[0].0, devs[0].name());
+
+        devs[0].teardown(&dm).unwrap();
+    }
+
+    /// Verify that suspend_tree suspends the devices stacked on top of the
+    /// given device, but not the devices that it is stacked on, and that
+    /// resume_tree resumes them.
+    fn test_suspend_tree(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let mut devs: Vec<LinearDev> = Vec::new();
+        for (i, length) in [3u64, 2, 1].iter().enumerate() {
+            let lower = devs.last().map(|ld| ld.device()).unwrap_or(dev);
+            let table = vec![TargetLine::new(
+                Sectors(0),
+                Sectors(*length),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(lower, Sectors(0))),
+            )];
+            devs.push(
+                LinearDev::setup(
+                    &dm,
+                    &test_name(&format!("layer{i}")).expect("valid format"),
+                    None,
+                    table,
+                )
+                .unwrap(),
+            );
+        }
+        let suspended = |ld: &LinearDev| {
+            dm.device_info(&DevId::Name(ld.name()))
+                .unwrap()
+                .flags()
+                .contains(DmFlags::DM_SUSPEND)
+        };
+
+        dm.suspend_tree(&DevId::Name(devs[1].name()), DmOptions::default())
+            .unwrap();
+        assert_eq!(
+            devs.iter().map(suspended).collect::<Vec<_>>(),
+            vec![false, true, true]
+        );
+
+        dm.resume_tree(&DevId::Name(devs[1].name())).unwrap();
+        assert!(!devs.iter().any(suspended));
+
+        for ld in devs.iter_mut().rev() {
+            ld.teardown(&dm).unwrap();
+        }
+    }
+
+    /// Verify that after interposing a device beneath a linear device, the
+    /// linear device maps the new device, which maps the original segments.
+    fn test_interpose(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = (0..2)
+            .map(|n| {
+                TargetLine::new(
+                    Sectors(n),
+                    Sectors(1),
+                    LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(2 * n))),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut ld = LinearDev::setup(
+            &dm,
+            &test_name("upper").expect("valid format"),
+            None,
+            table.clone(),
+        )
+        .unwrap();
+
+        let middle_name = test_name("middle").expect("valid format");
+        assert_matches!(
+            ld.interpose(&dm, &middle_name, None, table[..1].to_vec()),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        let mut middle = ld
+            .interpose(&dm, &middle_name, None, table.clone())
+            .unwrap();
+
+        assert_eq!(
+            dm.table_deps(&DevId::Name(ld.name()), DmOptions::default())
+                .unwrap(),
+            vec![middle.device()]
+        );
+        let loaded_table = LinearDev::read_kernel_table(&dm, &DevId::Name(middle.name())).unwrap();
+        assert!(
+            LinearDev::equivalent_tables(&LinearDevTargetTable::new(table), &loaded_table).unwrap()
+        );
+        assert_eq!(ld.size(), middle.size());
+
+        ld.teardown(&dm).unwrap();
+        middle.teardown(&dm).unwrap();
+    }
+
+    /// Verify that collapsing an interposed device restores the original
+    /// segments, and that a device which is not a passthrough can not be
+    /// collapsed.
+    fn test_collapse(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = (0..3)
+            .map(|n| {
+                TargetLine::new(
+                    Sectors(2 * n),
+                    Sectors(2),
+                    LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(4 * n))),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut ld = LinearDev::setup(
+            &dm,
+            &test_name("upper").expect("valid format"),
+            None,
+            table.clone(),
+        )
+        .unwrap();
+
+        let flakey_table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(6),
+            LinearDevTargetParams::Flakey(FlakeyTargetParams::new(dev, Sectors(0), 1, 1, vec![])),
+        )];
+        let mut middle = ld
+            .interpose(
+                &dm,
+                &test_name("flakey").expect("valid format"),
+                None,
+                flakey_table,
+            )
+            .unwrap();
+        assert_matches!(
+            ld.collapse(&dm, &mut middle),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        dm.remove_recursive(&DevId::Name(middle.name()), DmOptions::default())
+            .unwrap();
+
+        let mut ld = LinearDev::setup(
+            &dm,
+            &test_name("upper").expect("valid format"),
+            None,
+            table.clone(),
+        )
+        .unwrap();
+        let mut middle = ld
+            .interpose(
+                &dm,
+                &test_name("middle").expect("valid format"),
+                None,
+                table.clone(),
+            )
+            .unwrap();
+        ld.collapse(&dm, &mut middle).unwrap();
+
+        let loaded_table = LinearDev::read_kernel_table(&dm, &DevId::Name(ld.name())).unwrap();
+        assert!(
+            LinearDev::equivalent_tables(&LinearDevTargetTable::new(table), &loaded_table).unwrap()
+        );
+        assert_eq!(dm.list_test_devices().unwrap().len(), 1);
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    /// Verify that suspending and immediately resuming doesn't fail.
+    fn test_suspend(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let params = LinearTargetParams::new(dev, Sectors(0));
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(1),
+            LinearDevTargetParams::Linear(params),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+
+        ld.suspend(&dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))
+            .unwrap();
+        ld.suspend(&dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))
+            .unwrap();
+        ld.resume(&dm).unwrap();
+        ld.resume(&dm).unwrap();
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn test_flakey_target_params_zero() {
+        let result = "flakey 8:32 0 16 2 0"
+            .parse::<FlakeyTargetParams>()
+            .unwrap();
+        assert_eq!(result.feature_args, HashSet::new());
+    }
+
+    #[test]
+    fn test_flakey_target_params_none() {
+        let result = "flakey 8:32 0 16 2".parse::<FlakeyTargetParams>().unwrap();
         assert_eq!(result.feature_args, HashSet::new());
     }
 
@@ -832,6 +2131,133 @@ mod tests {
         assert_eq!(result.feature_args, expected);
     }
 
+    /// A linear line mapping length sectors at start to offset on device
+    /// major:0.
+    fn segment(
+        start: u64,
+        length: u64,
+        major: u32,
+        offset: u64,
+    ) -> TargetLine<LinearDevTargetParams> {
+        TargetLine::new(
+            Sectors(start),
+            Sectors(length),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(
+                Device { major, minor: 0 },
+                Sectors(offset),
+            )),
+        )
+    }
+
+    #[test]
+    fn test_split_at() {
+        let (first, second) = segment(8, 16, 8, 100).split_at(Sectors(4)).unwrap();
+        assert_eq!(first, segment(8, 4, 8, 100));
+        assert_eq!(second, segment(12, 12, 8, 104));
+        assert_matches!(
+            segment(8, 16, 8, 100).split_at(Sectors(0)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            segment(8, 16, 8, 100).split_at(Sectors(16)),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let table = LinearDevTargetTable::new(vec![
+            segment(0, 4, 8, 100),
+            segment(4, 4, 8, 104),
+            // Not contiguous on the device
+            segment(8, 4, 8, 200),
+            // On another device
+            segment(12, 4, 9, 204),
+        ]);
+        assert_eq!(
+            table.coalesce().table,
+            vec![
+                segment(0, 8, 8, 100),
+                segment(8, 4, 8, 200),
+                segment(12, 4, 9, 204),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let table = LinearDevTargetTable::new(vec![segment(4, 4, 8, 104), segment(0, 4, 8, 100)]);
+        assert_eq!(
+            table.normalize().unwrap().table,
+            vec![segment(0, 8, 8, 100)]
+        );
+
+        // A gap
+        let table = LinearDevTargetTable::new(vec![segment(0, 4, 8, 100), segment(8, 4, 8, 104)]);
+        assert_matches!(table.normalize(), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+
+        // Two lines mapping the same sectors of a device
+        let table = LinearDevTargetTable::new(vec![segment(0, 8, 8, 100), segment(8, 8, 8, 104)]);
+        assert_matches!(table.normalize(), Err(DmError::Dm(ErrorEnum::Invalid, _)));
+    }
+
+    #[test]
+    fn test_delay_target_params() {
+        for params in [
+            "delay 8:32 0 100",
+            "delay 8:32 0 0 8:48 16 400",
+            "delay 8:32 0 0 8:48 16 400 8:64 0 1000",
+        ] {
+            let result = params.parse::<DelayTargetParams>().unwrap();
+            assert_eq!(result.to_string(), params);
+        }
+
+        let result = "delay 8:32 0 0 8:48 16 400 8:64 0 1000"
+            .parse::<DelayTargetParams>()
+            .unwrap();
+        assert_eq!(
+            result.read,
+            DelayClass::new("8:32".parse::<Device>().unwrap(), Sectors(0), 0)
+        );
+        assert_eq!(
+            result.flush,
+            Some(DelayClass::new(
+                "8:64".parse::<Device>().unwrap(),
+                Sectors(0),
+                1000
+            ))
+        );
+
+        let read = DelayClass::new("8:32".parse::<Device>().unwrap(), Sectors(0), 0);
+        let flush = DelayClass::new("8:64".parse::<Device>().unwrap(), Sectors(0), 1000);
+        assert_eq!(
+            DelayTargetParams::new(read, None, Some(flush)).param_str(),
+            "8:32 0 0 8:32 0 0 8:64 0 1000"
+        );
+
+        assert!("delay 8:32 0 0 8:48 16"
+            .parse::<DelayTargetParams>()
+            .is_err());
+        assert!("linear 8:32 0 100".parse::<DelayTargetParams>().is_err());
+    }
+
+    #[test]
+    /// Verify that delay params are parsed and generated as params of a
+    /// linear device's table, and that a delay line is split with each of
+    /// its classes.
+    fn test_linear_dev_delay_params() {
+        let params = "delay 8:32 0 0 8:48 16 400";
+        let result = params.parse::<LinearDevTargetParams>().unwrap();
+        assert_matches!(result, LinearDevTargetParams::Delay(_));
+        assert_eq!(result.to_string(), params);
+        assert_eq!(result.target_type(), TargetType::DELAY.to_owned());
+
+        let line = TargetLine::new(Sectors(0), Sectors(16), result);
+        let (first, second) = line.split_at(Sectors(4)).unwrap();
+        assert_eq!(second.params.to_string(), "delay 8:32 4 0 8:48 20 400");
+        assert!(first.is_continued_by(&second));
+    }
+
     #[test]
     fn test_flakey_target_params_error_writes() {
         let result = "flakey 8:32 0 16 2 1 error_writes"
@@ -910,6 +2336,16 @@ mod tests {
         assert_eq!(result.feature_args, expected);
     }
 
+    #[test]
+    fn loop_test_devices_remove_stacked() {
+        test_with_spec(1, test_devices_remove_stacked);
+    }
+
+    #[test]
+    fn loop_test_collapse() {
+        test_with_spec(1, test_collapse);
+    }
+
     #[test]
     fn loop_test_duplicate_segments() {
         test_with_spec(1, test_duplicate_segments);
@@ -925,6 +2361,26 @@ mod tests {
         test_with_spec(1, test_empty_table_set);
     }
 
+    #[test]
+    fn loop_test_interpose() {
+        test_with_spec(1, test_interpose);
+    }
+
+    #[test]
+    fn loop_test_preload_commit() {
+        test_with_spec(1, test_preload_commit);
+    }
+
+    #[test]
+    fn loop_test_remove_recursive() {
+        test_with_spec(1, test_remove_recursive);
+    }
+
+    #[test]
+    fn loop_test_suspend_tree() {
+        test_with_spec(1, test_suspend_tree);
+    }
+
     #[test]
     fn loop_test_rename() {
         test_with_spec(1, test_rename);
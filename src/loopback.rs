@@ -0,0 +1,359 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Management of loop devices, through the ioctls of /dev/loop-control and
+// of the loop devices themselves, so that DM devices may be stacked on
+// regular files.
+
+use std::{
+    fs::{File, OpenOptions},
+    mem::size_of,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    libc::{c_ulong, ioctl as nix_ioctl},
+};
+
+use crate::{
+    core::{devnode_to_devno, errors, Device},
+    result::{DmError, DmResult, ErrorEnum},
+    units::Bytes,
+};
+
+/// The loop control device, from which free loop devices are obtained.
+const LOOP_CONTROL_PATH: &str = "/dev/loop-control";
+
+// From include/uapi/linux/loop.h
+const LOOP_SET_FD: c_ulong = 0x4c00;
+const LOOP_CLR_FD: c_ulong = 0x4c01;
+const LOOP_SET_STATUS64: c_ulong = 0x4c04;
+const LOOP_GET_STATUS64: c_ulong = 0x4c05;
+const LOOP_SET_DIRECT_IO: c_ulong = 0x4c08;
+const LOOP_SET_BLOCK_SIZE: c_ulong = 0x4c09;
+const LOOP_CTL_GET_FREE: c_ulong = 0x4c82;
+
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+const LO_FLAGS_PARTSCAN: u32 = 8;
+const LO_FLAGS_DIRECT_IO: u32 = 16;
+
+const LO_NAME_SIZE: usize = 64;
+const LO_KEY_SIZE: usize = 32;
+
+/// Another process may take the free loop device before it is bound to
+/// the file, in which case another is obtained, up to this many times.
+const ATTACH_ATTEMPTS: usize = 5;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; LO_NAME_SIZE],
+    lo_crypt_name: [u8; LO_NAME_SIZE],
+    lo_encrypt_key: [u8; LO_KEY_SIZE],
+    lo_init: [u64; 2],
+}
+
+const _: () = assert!(size_of::<LoopInfo64>() == 232);
+
+impl Default for LoopInfo64 {
+    fn default() -> LoopInfo64 {
+        LoopInfo64 {
+            lo_device: 0,
+            lo_inode: 0,
+            lo_rdevice: 0,
+            lo_offset: 0,
+            lo_sizelimit: 0,
+            lo_number: 0,
+            lo_encrypt_type: 0,
+            lo_encrypt_key_size: 0,
+            lo_flags: 0,
+            lo_file_name: [0; LO_NAME_SIZE],
+            lo_crypt_name: [0; LO_NAME_SIZE],
+            lo_encrypt_key: [0; LO_KEY_SIZE],
+            lo_init: [0; 2],
+        }
+    }
+}
+
+/// Options for attaching a file to a loop device.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LoopOptions {
+    read_only: bool,
+    direct_io: bool,
+    autoclear: bool,
+    part_scan: bool,
+    offset: u64,
+    size_limit: Option<u64>,
+    sector_size: Option<u32>,
+}
+
+impl LoopOptions {
+    /// Attach the file read-only.
+    pub fn set_read_only(mut self, read_only: bool) -> LoopOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Read and write the file with direct I/O, bypassing the page cache,
+    /// so that data is not cached both for the loop device and the file.
+    pub fn set_direct_io(mut self, direct_io: bool) -> LoopOptions {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// Detach the file automatically once the loop device is last closed.
+    pub fn set_autoclear(mut self, autoclear: bool) -> LoopOptions {
+        self.autoclear = autoclear;
+        self
+    }
+
+    /// Have the kernel scan the loop device for partitions.
+    pub fn set_part_scan(mut self, part_scan: bool) -> LoopOptions {
+        self.part_scan = part_scan;
+        self
+    }
+
+    /// Map the file from offset, rather than from its beginning.
+    pub fn set_offset(mut self, offset: Bytes) -> LoopOptions {
+        self.offset = *offset as u64;
+        self
+    }
+
+    /// Map at most size of the file, rather than all of it.
+    pub fn set_size_limit(mut self, size: Option<Bytes>) -> LoopOptions {
+        self.size_limit = size.map(|size| *size as u64);
+        self
+    }
+
+    /// Set the loop device's logical sector size, a power of two from 512
+    /// to the page size, rather than 512.
+    pub fn set_sector_size(mut self, sector_size: Option<u32>) -> LoopOptions {
+        self.sector_size = sector_size;
+        self
+    }
+}
+
+/// A loop device, to which a file is attached.
+#[derive(Debug)]
+pub struct LoopDevice {
+    number: u32,
+    file: File,
+}
+
+fn loop_error(action: &str, what: &Path, err: Errno) -> DmError {
+    DmError::Core(errors::Error::GeneralIo(format!(
+        "failed to {action} {}: {err}",
+        what.display()
+    )))
+}
+
+fn open_rw(devnode: &Path, write: bool) -> DmResult<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open(devnode)
+        .map_err(|err| DmError::Core(errors::Error::MetadataIo(devnode.into(), err.to_string())))
+}
+
+fn loop_devnode(number: u32) -> PathBuf {
+    PathBuf::from(format!("/dev/loop{number}"))
+}
+
+impl LoopDevice {
+    /// Attach the file at backing to a free loop device, with the given
+    /// options.
+    pub fn attach(backing: &Path, options: LoopOptions) -> DmResult<LoopDevice> {
+        let backing_file = open_rw(backing, !options.read_only)?;
+        let control = open_rw(Path::new(LOOP_CONTROL_PATH), true)?;
+
+        let mut attempt = 1;
+        let dev = loop {
+            let number =
+                Errno::result(unsafe { nix_ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE as _) })
+                    .map_err(|err| {
+                        loop_error(
+                            "get a free loop device from",
+                            Path::new(LOOP_CONTROL_PATH),
+                            err,
+                        )
+                    })?;
+            let number = number as u32;
+            let devnode = loop_devnode(number);
+            let file = open_rw(&devnode, !options.read_only)?;
+            match Errno::result(unsafe {
+                nix_ioctl(file.as_raw_fd(), LOOP_SET_FD as _, backing_file.as_raw_fd())
+            }) {
+                Ok(_) => break LoopDevice { number, file },
+                // Another process took the device first.
+                Err(Errno::EBUSY) if attempt < ATTACH_ATTEMPTS => {
+                    attempt += 1;
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => return Err(loop_error("attach the file to", &devnode, err)),
+            }
+        };
+
+        if let Err(err) = dev.configure(backing, options) {
+            if let Err(detach_err) = dev.clear() {
+                warn!(
+                    "Failed to detach {} after failing to configure it: {}",
+                    dev.devnode().display(),
+                    detach_err
+                );
+            }
+            return Err(err);
+        }
+        Ok(dev)
+    }
+
+    fn configure(&self, backing: &Path, options: LoopOptions) -> DmResult<()> {
+        let mut info = LoopInfo64 {
+            lo_offset: options.offset,
+            lo_sizelimit: options.size_limit.unwrap_or(0),
+            ..Default::default()
+        };
+        if options.read_only {
+            info.lo_flags |= LO_FLAGS_READ_ONLY;
+        }
+        if options.autoclear {
+            info.lo_flags |= LO_FLAGS_AUTOCLEAR;
+        }
+        if options.part_scan {
+            info.lo_flags |= LO_FLAGS_PARTSCAN;
+        }
+        // The name is informational, and truncated if it is long.
+        let name = backing.as_os_str().as_bytes();
+        let len = name.len().min(LO_NAME_SIZE - 1);
+        info.lo_file_name[..len].copy_from_slice(&name[..len]);
+
+        let devnode = self.devnode();
+        Errno::result(unsafe { nix_ioctl(self.file.as_raw_fd(), LOOP_SET_STATUS64 as _, &info) })
+            .map_err(|err| loop_error("set the status of", &devnode, err))?;
+
+        if let Some(sector_size) = options.sector_size {
+            Errno::result(unsafe {
+                nix_ioctl(
+                    self.file.as_raw_fd(),
+                    LOOP_SET_BLOCK_SIZE as _,
+                    sector_size as c_ulong,
+                )
+            })
+            .map_err(|err| loop_error("set the sector size of", &devnode, err))?;
+        }
+
+        if options.direct_io {
+            Errno::result(unsafe {
+                nix_ioctl(self.file.as_raw_fd(), LOOP_SET_DIRECT_IO as _, 1 as c_ulong)
+            })
+            .map_err(|err| loop_error("enable direct I/O for", &devnode, err))?;
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> DmResult<LoopInfo64> {
+        let mut info = LoopInfo64::default();
+        Errno::result(unsafe {
+            nix_ioctl(self.file.as_raw_fd(), LOOP_GET_STATUS64 as _, &mut info)
+        })
+        .map_err(|err| loop_error("get the status of", &self.devnode(), err))?;
+        Ok(info)
+    }
+
+    fn clear(&self) -> DmResult<()> {
+        Errno::result(unsafe { nix_ioctl(self.file.as_raw_fd(), LOOP_CLR_FD as _, 0) })
+            .map_err(|err| loop_error("detach the file from", &self.devnode(), err))?;
+        Ok(())
+    }
+
+    /// The loop device's number, as in /dev/loopN.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// The loop device's device node.
+    pub fn devnode(&self) -> PathBuf {
+        loop_devnode(self.number)
+    }
+
+    /// The loop device, by its major and minor numbers, e.g. for use in a
+    /// DM table.
+    pub fn device(&self) -> DmResult<Device> {
+        devnode_to_devno(&self.devnode())?
+            .map(Device::from)
+            .ok_or_else(|| {
+                let err_msg = format!("{} is not a block device", self.devnode().display());
+                DmError::Dm(ErrorEnum::Invalid, err_msg)
+            })
+    }
+
+    /// Whether the loop device does direct I/O to its file. Direct I/O may
+    /// be refused if the file's filesystem does not support it.
+    pub fn direct_io(&self) -> DmResult<bool> {
+        Ok(self.status()?.lo_flags & LO_FLAGS_DIRECT_IO != 0)
+    }
+
+    /// Detach the file from the loop device. If the device is open, e.g.
+    /// because a DM device is stacked on it, this fails with EBUSY.
+    pub fn detach(self) -> DmResult<()> {
+        self.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use crate::{consts::IEC, units::Bytes};
+
+    use super::*;
+
+    #[test]
+    /// Verify that a file is attached with the options given, that data
+    /// written to it may be read through the loop device, and that it is
+    /// detached again.
+    fn sudo_test_loopback() {
+        let mut backing = tempfile::NamedTempFile::new().unwrap();
+        backing.as_file().set_len(IEC::Mi).unwrap();
+        let data = [0x5au8; 4096];
+        backing.write_all(&data).unwrap();
+        backing.as_file().sync_all().unwrap();
+
+        let dev = LoopDevice::attach(
+            backing.path(),
+            LoopOptions::default()
+                .set_read_only(true)
+                .set_offset(Bytes(0))
+                .set_sector_size(Some(4096)),
+        )
+        .unwrap();
+        assert!(dev.devnode().exists());
+        assert!(dev.device().is_ok());
+
+        let info = dev.status().unwrap();
+        assert_ne!(info.lo_flags & LO_FLAGS_READ_ONLY, 0);
+
+        let mut read = [0u8; 4096];
+        File::open(dev.devnode())
+            .unwrap()
+            .read_exact(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+
+        dev.detach().unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Conversion of device statuses into metrics in the Prometheus text
+// exposition format.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    cachedev::{CacheDevMetadataMode, CacheDevStatus},
+    core::DmName,
+    thindev::ThinStatus,
+    thinpooldev::{ThinPoolStatus, ThinPoolStatusSummary},
+};
+
+/// The Prometheus type of a metric.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+impl MetricKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MetricFamily {
+    help: &'static str,
+    kind: MetricKind,
+    samples: Vec<(String, u64)>,
+}
+
+/// Collects metrics from the statuses of DM devices, labelled with the
+/// devices' names, and renders them in the Prometheus text format.
+///
+/// Every device contributes a `_up` gauge, which is 1 if the device is
+/// working; the remaining metrics are only available for working devices.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    families: BTreeMap<&'static str, MetricFamily>,
+}
+
+impl Metrics {
+    /// Create an empty set of metrics.
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    fn add(
+        &mut self,
+        metric: &'static str,
+        kind: MetricKind,
+        help: &'static str,
+        name: &DmName,
+        value: u64,
+    ) {
+        self.families
+            .entry(metric)
+            .or_insert_with(|| MetricFamily {
+                help,
+                kind,
+                samples: Vec::new(),
+            })
+            .samples
+            .push((name.to_string(), value));
+    }
+
+    fn gauge(&mut self, metric: &'static str, help: &'static str, name: &DmName, value: u64) {
+        self.add(metric, MetricKind::Gauge, help, name, value);
+    }
+
+    fn counter(&mut self, metric: &'static str, help: &'static str, name: &DmName, value: u64) {
+        self.add(metric, MetricKind::Counter, help, name, value);
+    }
+
+    /// Add the metrics for a thin pool's status.
+    pub fn add_thinpool_status(&mut self, name: &DmName, status: &ThinPoolStatus) {
+        let status = match status {
+            ThinPoolStatus::Working(status) => status,
+            ThinPoolStatus::Error | ThinPoolStatus::Fail => {
+                self.gauge(
+                    "dm_thinpool_up",
+                    "Whether the thin pool is working.",
+                    name,
+                    0,
+                );
+                return;
+            }
+        };
+        self.gauge(
+            "dm_thinpool_up",
+            "Whether the thin pool is working.",
+            name,
+            1,
+        );
+        self.gauge(
+            "dm_thinpool_transaction_id",
+            "The thin pool's transaction id.",
+            name,
+            status.transaction_id,
+        );
+        self.gauge(
+            "dm_thinpool_metadata_used_blocks",
+            "Metadata blocks in use.",
+            name,
+            *status.usage.used_meta,
+        );
+        self.gauge(
+            "dm_thinpool_metadata_total_blocks",
+            "Metadata blocks available.",
+            name,
+            *status.usage.total_meta,
+        );
+        self.gauge(
+            "dm_thinpool_data_used_blocks",
+            "Data blocks in use.",
+            name,
+            *status.usage.used_data,
+        );
+        self.gauge(
+            "dm_thinpool_data_total_blocks",
+            "Data blocks available.",
+            name,
+            *status.usage.total_data,
+        );
+        self.gauge(
+            "dm_thinpool_read_only",
+            "Whether the thin pool has been forced into read-only mode.",
+            name,
+            u64::from(status.summary == ThinPoolStatusSummary::ReadOnly),
+        );
+        self.gauge(
+            "dm_thinpool_out_of_space",
+            "Whether the thin pool is out of data space.",
+            name,
+            u64::from(status.summary == ThinPoolStatusSummary::OutOfSpace),
+        );
+        self.gauge(
+            "dm_thinpool_needs_check",
+            "Whether the thin pool's metadata needs to be checked.",
+            name,
+            u64::from(status.needs_check),
+        );
+    }
+
+    /// Add the metrics for a thin device's status.
+    pub fn add_thin_status(&mut self, name: &DmName, status: &ThinStatus) {
+        let status = match status {
+            ThinStatus::Working(status) => status,
+            ThinStatus::Error | ThinStatus::Fail => {
+                self.gauge("dm_thin_up", "Whether the thin device is working.", name, 0);
+                return;
+            }
+        };
+        self.gauge("dm_thin_up", "Whether the thin device is working.", name, 1);
+        self.gauge(
+            "dm_thin_mapped_sectors",
+            "Sectors mapped to the thin pool's data device.",
+            name,
+            *status.nr_mapped_sectors,
+        );
+    }
+
+    /// Add the metrics for a cache device's status.
+    pub fn add_cache_status(&mut self, name: &DmName, status: &CacheDevStatus) {
+        let status = match status {
+            CacheDevStatus::Working(status) => status,
+            CacheDevStatus::Error | CacheDevStatus::Fail => {
+                self.gauge("dm_cache_up", "Whether the cache is working.", name, 0);
+                return;
+            }
+        };
+        self.gauge("dm_cache_up", "Whether the cache is working.", name, 1);
+        self.gauge(
+            "dm_cache_metadata_used_blocks",
+            "Metadata blocks in use.",
+            name,
+            *status.usage.used_meta,
+        );
+        self.gauge(
+            "dm_cache_metadata_total_blocks",
+            "Metadata blocks available.",
+            name,
+            *status.usage.total_meta,
+        );
+        self.gauge(
+            "dm_cache_used_blocks",
+            "Cache blocks in use.",
+            name,
+            *status.usage.used_cache,
+        );
+        self.gauge(
+            "dm_cache_total_blocks",
+            "Cache blocks available.",
+            name,
+            *status.usage.total_cache,
+        );
+        let performance = &status.performance;
+        self.gauge(
+            "dm_cache_dirty_blocks",
+            "Cache blocks not yet written back to the origin.",
+            name,
+            performance.dirty,
+        );
+        for (metric, help, value) in [
+            (
+                "dm_cache_read_hits_total",
+                "Reads hitting the cache.",
+                performance.read_hits,
+            ),
+            (
+                "dm_cache_read_misses_total",
+                "Reads missing the cache.",
+                performance.read_misses,
+            ),
+            (
+                "dm_cache_write_hits_total",
+                "Writes hitting the cache.",
+                performance.write_hits,
+            ),
+            (
+                "dm_cache_write_misses_total",
+                "Writes missing the cache.",
+                performance.write_misses,
+            ),
+            (
+                "dm_cache_demotions_total",
+                "Blocks demoted from the cache.",
+                performance.demotions,
+            ),
+            (
+                "dm_cache_promotions_total",
+                "Blocks promoted to the cache.",
+                performance.promotions,
+            ),
+        ] {
+            self.counter(metric, help, name, value);
+        }
+        self.gauge(
+            "dm_cache_read_only",
+            "Whether the cache's metadata has been forced into read-only mode.",
+            name,
+            u64::from(status.metadata_mode == CacheDevMetadataMode::ReadOnly),
+        );
+        self.gauge(
+            "dm_cache_needs_check",
+            "Whether the cache's metadata needs to be checked.",
+            name,
+            u64::from(status.needs_check),
+        );
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    /// Metrics are ordered by name, and samples by the order in which their
+    /// devices were added.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (metric, family) in &self.families {
+            writeln!(out, "# HELP {metric} {}", family.help).expect("writes to a String");
+            writeln!(out, "# TYPE {metric} {}", family.kind.as_str()).expect("writes to a String");
+            for (device, value) in &family.samples {
+                writeln!(
+                    out,
+                    "{metric}{{device=\"{}\"}} {value}",
+                    escape_label(device)
+                )
+                .expect("writes to a String");
+            }
+        }
+        out
+    }
+}
+
+/// Escape a label value as required by the text exposition format.
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failed_devices() {
+        let mut metrics = Metrics::new();
+        metrics.add_thin_status(DmName::new("thin-a").unwrap(), &ThinStatus::Fail);
+        metrics.add_thin_status(DmName::new("thin\"b").unwrap(), &ThinStatus::Error);
+        assert_eq!(
+            metrics.render(),
+            "# HELP dm_thin_up Whether the thin device is working.\n\
+             # TYPE dm_thin_up gauge\n\
+             dm_thin_up{device=\"thin-a\"} 0\n\
+             dm_thin_up{device=\"thin\\\"b\"} 0\n"
+        );
+    }
+
+    #[test]
+    fn test_working_devices() {
+        let mut metrics = Metrics::new();
+        let pool = "0 10/512 16/1024 - ro discard_passdown queue_if_no_space - 64"
+            .parse::<ThinPoolStatus>()
+            .unwrap();
+        metrics.add_thinpool_status(DmName::new("pool").unwrap(), &pool);
+        let cache =
+            "8 20/512 128 3/64 5 6 7 8 0 1 2 1 writethrough 2 migration_threshold 2048 smq 0 rw -"
+                .parse::<CacheDevStatus>()
+                .unwrap();
+        metrics.add_cache_status(DmName::new("cache").unwrap(), &cache);
+
+        let rendered = metrics.render();
+        for line in [
+            "dm_thinpool_up{device=\"pool\"} 1",
+            "dm_thinpool_metadata_used_blocks{device=\"pool\"} 10",
+            "dm_thinpool_data_total_blocks{device=\"pool\"} 1024",
+            "dm_thinpool_read_only{device=\"pool\"} 1",
+            "dm_thinpool_out_of_space{device=\"pool\"} 0",
+            "# TYPE dm_cache_read_hits_total counter",
+            "dm_cache_read_hits_total{device=\"cache\"} 5",
+            "dm_cache_used_blocks{device=\"cache\"} 3",
+            "dm_cache_dirty_blocks{device=\"cache\"} 2",
+            "dm_cache_needs_check{device=\"cache\"} 0",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "missing \"{line}\"");
+        }
+        assert_eq!(rendered.matches("# TYPE ").count(), 23);
+    }
+}
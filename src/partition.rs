@@ -0,0 +1,604 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Reading of the partition table, GPT or MBR, on a block device, and
+// creation of a linear device for each partition, as kpartx does, so that
+// the partitions of e.g. a VM image on a loop device may be used.
+
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom},
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use crate::{
+    core::{devnode_to_devno, errors, DevId, Device, DmName, DmNameBuf, DmOptions, DM},
+    journal::Journal,
+    lineardev::{LinearDev, LinearDevTargetParams, LinearTargetParams},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{DmDevice, TargetLine},
+    units::{Sectors, SECTOR_SIZE},
+};
+
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_PRIMARY_ENTRIES: usize = 4;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+/// An extended partition's chain of logical partitions is short, so a
+/// longer one must be corrupt, or contain a cycle.
+const MAX_LOGICAL_PARTITIONS: u32 = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_SECTOR_SIZES: [u64; 2] = [512, 4096];
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+/// The kind of a partition table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PartitionTableKind {
+    /// A GUID partition table
+    Gpt,
+    /// A DOS master boot record, with any logical partitions
+    Mbr,
+}
+
+/// A partition of a block device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Partition {
+    /// The partition's number, from 1. For an MBR, logical partitions are
+    /// numbered from 5, as the kernel numbers them.
+    pub number: u32,
+    /// The partition's first sector
+    pub start: Sectors,
+    /// The partition's length
+    pub length: Sectors,
+}
+
+/// The partition table of a block device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PartitionTable {
+    /// The kind of the table
+    pub kind: PartitionTableKind,
+    /// The partitions, in order of their numbers; extended partitions,
+    /// which only hold logical partitions, are omitted
+    pub partitions: Vec<Partition>,
+}
+
+struct DiskReader<'a> {
+    devnode: &'a Path,
+    file: File,
+    // The size of the device or file, in bytes
+    size: u64,
+}
+
+impl<'a> DiskReader<'a> {
+    fn open(devnode: &'a Path) -> DmResult<DiskReader<'a>> {
+        let io_err = |err: std::io::Error| {
+            DmError::Core(errors::Error::MetadataIo(devnode.into(), err.to_string()))
+        };
+        let mut file = File::open(devnode).map_err(io_err)?;
+        // The length in a block device's metadata is 0, but it may be
+        // sought to its end, as a file may.
+        let size = file.seek(SeekFrom::End(0)).map_err(io_err)?;
+        Ok(DiskReader {
+            devnode,
+            file,
+            size,
+        })
+    }
+
+    fn read(&self, offset: u64, len: usize) -> DmResult<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.file.read_exact_at(&mut buf, offset).map_err(|err| {
+            DmError::Core(errors::Error::MetadataIo(
+                self.devnode.into(),
+                err.to_string(),
+            ))
+        })?;
+        Ok(buf)
+    }
+
+    fn invalid(&self, what: &str) -> DmError {
+        let err_msg = format!(
+            "partition table on {} is invalid: {what}",
+            self.devnode.display()
+        );
+        DmError::Dm(ErrorEnum::Invalid, err_msg)
+    }
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// The CRC32 used by GPT, as in zlib.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// The type, first sector, and length of each entry of an MBR or EBR.
+fn mbr_entries(sector: &[u8]) -> impl Iterator<Item = (u8, u64, u64)> + '_ {
+    (0..MBR_PRIMARY_ENTRIES).map(move |i| {
+        let entry = MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE;
+        (
+            sector[entry + 4],
+            u64::from(le_u32(sector, entry + 8)),
+            u64::from(le_u32(sector, entry + 12)),
+        )
+    })
+}
+
+fn read_gpt(reader: &DiskReader<'_>, sector_size: u64) -> DmResult<Option<Vec<Partition>>> {
+    let header = reader.read(sector_size, sector_size as usize)?;
+    if &header[..GPT_SIGNATURE.len()] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let header_size = le_u32(&header, 12) as usize;
+    if !(GPT_MIN_HEADER_SIZE..=header.len()).contains(&header_size) {
+        return Err(reader.invalid("GPT header size is out of range"));
+    }
+    let mut check = header[..header_size].to_vec();
+    check[16..20].fill(0);
+    if crc32(&check) != le_u32(&header, 16) {
+        return Err(reader.invalid("GPT header checksum does not match"));
+    }
+
+    let entries_lba = le_u64(&header, 72);
+    let entry_count = le_u32(&header, 80);
+    let entry_size = le_u32(&header, 84) as usize;
+    // An entry is 128 bytes, times a power of two, and fits in a sector.
+    if entry_count > GPT_MAX_ENTRIES
+        || entry_size % GPT_MIN_ENTRY_SIZE != 0
+        || !(entry_size / GPT_MIN_ENTRY_SIZE).is_power_of_two()
+        || entry_size as u64 > sector_size
+    {
+        return Err(reader.invalid("GPT partition entries are of unexpected size or number"));
+    }
+    let entries_offset = entries_lba
+        .checked_mul(sector_size)
+        .ok_or_else(|| reader.invalid("GPT partition entries are beyond the device's end"))?;
+    let entries_len = (entry_count as usize)
+        .checked_mul(entry_size)
+        .ok_or_else(|| reader.invalid("GPT partition entries are of unexpected size or number"))?;
+    let entries = reader.read(entries_offset, entries_len)?;
+    if crc32(&entries) != le_u32(&header, 88) {
+        return Err(reader.invalid("GPT partition entries checksum does not match"));
+    }
+
+    let to_sectors = |lba: u64| {
+        lba.checked_mul(sector_size)
+            .map(|bytes| Sectors(bytes / SECTOR_SIZE as u64))
+            .ok_or_else(|| reader.invalid("GPT partition is beyond the device's end"))
+    };
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks_exact(entry_size).enumerate() {
+        // An entry with a type of all zeros is unused.
+        if entry[..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+        let (first, last) = (le_u64(entry, 32), le_u64(entry, 40));
+        if last < first {
+            return Err(reader.invalid("GPT partition ends before it begins"));
+        }
+        let length = (last - first)
+            .checked_add(1)
+            .ok_or_else(|| reader.invalid("GPT partition is beyond the device's end"))?;
+        partitions.push(Partition {
+            number: index as u32 + 1,
+            start: to_sectors(first)?,
+            length: to_sectors(length)?,
+        });
+    }
+    Ok(Some(partitions))
+}
+
+fn read_mbr(reader: &DiskReader<'_>, mbr: &[u8]) -> DmResult<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (index, (partition_type, start, length)) in mbr_entries(mbr).enumerate() {
+        if partition_type == 0 || length == 0 {
+            continue;
+        }
+        if MBR_TYPES_EXTENDED.contains(&partition_type) {
+            extended = Some(start);
+            continue;
+        }
+        partitions.push(Partition {
+            number: index as u32 + 1,
+            start: Sectors(start),
+            length: Sectors(length),
+        });
+    }
+
+    // Each EBR holds a logical partition, relative to the EBR, and a link
+    // to the next EBR, relative to the extended partition.
+    if let Some(extended_start) = extended {
+        let mut ebr_start = extended_start;
+        for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+            let ebr = reader.read(ebr_start * SECTOR_SIZE as u64, SECTOR_SIZE)?;
+            if ebr[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] != MBR_SIGNATURE {
+                return Err(reader.invalid("extended boot record has no signature"));
+            }
+            let mut entries = mbr_entries(&ebr);
+            let (logical_type, logical_start, logical_length) =
+                entries.next().expect("an EBR has four entries");
+            if logical_type != 0 && logical_length != 0 {
+                partitions.push(Partition {
+                    number,
+                    start: Sectors(ebr_start + logical_start),
+                    length: Sectors(logical_length),
+                });
+            }
+            match entries.next().expect("an EBR has four entries") {
+                (next_type, next_start, _) if next_type != 0 && next_start != 0 => {
+                    ebr_start = extended_start + next_start;
+                }
+                _ => return Ok(partitions),
+            }
+        }
+        return Err(reader.invalid("too many logical partitions"));
+    }
+
+    Ok(partitions)
+}
+
+/// Read the partition table of the block device or image file at devnode.
+/// A GPT is read if the MBR is a protective MBR, with either 512 or 4096
+/// byte logical blocks. Otherwise the MBR and any logical partitions are
+/// read. Returns None if there is no partition table.
+/// Partitions which extend past the device's end are rejected.
+pub fn read_partitions(devnode: &Path) -> DmResult<Option<PartitionTable>> {
+    let reader = DiskReader::open(devnode)?;
+    let mbr = reader.read(0, SECTOR_SIZE)?;
+    if mbr[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] != MBR_SIGNATURE {
+        return Ok(None);
+    }
+
+    let table = if mbr_entries(&mbr)
+        .any(|(partition_type, _, _)| partition_type == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        let mut gpt = None;
+        for sector_size in GPT_SECTOR_SIZES {
+            if let Some(partitions) = read_gpt(&reader, sector_size)? {
+                gpt = Some(partitions);
+                break;
+            }
+        }
+        PartitionTable {
+            kind: PartitionTableKind::Gpt,
+            partitions: gpt.ok_or_else(|| reader.invalid("protective MBR, but no GPT header"))?,
+        }
+    } else {
+        PartitionTable {
+            kind: PartitionTableKind::Mbr,
+            partitions: read_mbr(&reader, &mbr)?,
+        }
+    };
+
+    let device_sectors = reader.size / SECTOR_SIZE as u64;
+    for partition in &table.partitions {
+        match (*partition.start).checked_add(*partition.length) {
+            Some(end) if end <= device_sectors => (),
+            _ => {
+                return Err(reader.invalid(&format!(
+                    "partition {} extends past the device's end",
+                    partition.number
+                )))
+            }
+        }
+    }
+    Ok(Some(table))
+}
+
+/// The name of the device for a partition, as kpartx names them: the
+/// name of the partitioned device, followed by "p" and the partition's
+/// number.
+pub fn partition_dev_name(name: &DmName, number: u32) -> DmResult<DmNameBuf> {
+    DmNameBuf::new(format!("{name}p{number}"))
+}
+
+/// Create a linear device for each partition of the device at devnode,
+/// named as by [`partition_dev_name`], with name as the name of the
+/// partitioned device. If a device can not be created, the devices already
+/// created are removed. Returns the devices, in order of the partitions'
+/// numbers, or an empty list if the device has no partition table.
+pub fn map_partitions(dm: &DM, devnode: &Path, name: &DmName) -> DmResult<Vec<LinearDev>> {
+    let device = devnode_to_devno(devnode)?
+        .map(Device::from)
+        .ok_or_else(|| {
+            let err_msg = format!("{} is not a block device", devnode.display());
+            DmError::Dm(ErrorEnum::Invalid, err_msg)
+        })?;
+    let partitions = match read_partitions(devnode)? {
+        Some(table) => table.partitions,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut journal = Journal::new(&format!("mapping partitions of {}", devnode.display()));
+    let mut devs = Vec::with_capacity(partitions.len());
+    for partition in partitions {
+        let part_name = match partition_dev_name(name, partition.number) {
+            Ok(part_name) => part_name,
+            Err(err) => {
+                let step = format!("name partition {}", partition.number);
+                return Err(journal.roll_back(dm, &step, err));
+            }
+        };
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            partition.length,
+            LinearDevTargetParams::Linear(LinearTargetParams::new(device, partition.start)),
+        )];
+        let undo_name = part_name.clone();
+        let dev = journal.step(
+            dm,
+            &format!("create {}", &*part_name),
+            |dm| LinearDev::setup(dm, &part_name, None, table),
+            move |dm| {
+                dm.device_remove(&DevId::Name(&undo_name), DmOptions::default())
+                    .map(|_| ())
+            },
+        )?;
+        devs.push(dev);
+    }
+    journal.commit();
+    Ok(devs)
+}
+
+/// Remove the devices created by [`map_partitions`].
+pub fn unmap_partitions(dm: &DM, devs: Vec<LinearDev>) -> DmResult<()> {
+    for mut dev in devs.into_iter().rev() {
+        dev.teardown(dm)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+
+    use crate::{
+        consts::IEC,
+        shared::device_exists,
+        testing::{test_name, test_with_devices, DeviceRequirements},
+        units::Bytes,
+    };
+
+    use super::*;
+
+    fn mbr_entry(sector: &mut [u8], index: usize, partition_type: u8, start: u32, length: u32) {
+        let entry = MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE;
+        sector[entry + 4] = partition_type;
+        sector[entry + 8..entry + 12].copy_from_slice(&start.to_le_bytes());
+        sector[entry + 12..entry + 16].copy_from_slice(&length.to_le_bytes());
+        sector[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    fn write_sector(file: &mut File, sector: u64, data: &[u8]) {
+        file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))
+            .unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    /// Write an MBR with two primary partitions and an extended partition
+    /// holding two logical partitions.
+    fn write_mbr(file: &mut File) {
+        let mut mbr = vec![0u8; SECTOR_SIZE];
+        mbr_entry(&mut mbr, 0, 0x83, 2048, 2048);
+        mbr_entry(&mut mbr, 1, 0x83, 4096, 1024);
+        mbr_entry(&mut mbr, 2, 0x05, 8192, 8192);
+        write_sector(file, 0, &mbr);
+
+        let mut ebr = vec![0u8; SECTOR_SIZE];
+        mbr_entry(&mut ebr, 0, 0x83, 2048, 1024);
+        mbr_entry(&mut ebr, 1, 0x05, 4096, 4096);
+        write_sector(file, 8192, &ebr);
+
+        let mut ebr = vec![0u8; SECTOR_SIZE];
+        mbr_entry(&mut ebr, 0, 0x83, 2048, 512);
+        write_sector(file, 8192 + 4096, &ebr);
+    }
+
+    #[test]
+    /// Verify that primary and logical partitions are read from an MBR.
+    fn test_read_mbr() {
+        let path = tempfile::NamedTempFile::new().unwrap();
+        let mut file = path.reopen().unwrap();
+        file.set_len(16384 * SECTOR_SIZE as u64).unwrap();
+        write_mbr(&mut file);
+
+        assert_eq!(
+            read_partitions(path.path()).unwrap(),
+            Some(PartitionTable {
+                kind: PartitionTableKind::Mbr,
+                partitions: vec![
+                    Partition {
+                        number: 1,
+                        start: Sectors(2048),
+                        length: Sectors(2048),
+                    },
+                    Partition {
+                        number: 2,
+                        start: Sectors(4096),
+                        length: Sectors(1024),
+                    },
+                    Partition {
+                        number: 5,
+                        start: Sectors(8192 + 2048),
+                        length: Sectors(1024),
+                    },
+                    Partition {
+                        number: 6,
+                        start: Sectors(8192 + 4096 + 2048),
+                        length: Sectors(512),
+                    },
+                ],
+            })
+        );
+
+        let mut mbr = vec![0u8; SECTOR_SIZE];
+        mbr_entry(&mut mbr, 0, 0x83, 2048, 16384);
+        write_sector(&mut file, 0, &mbr);
+        assert_matches!(
+            read_partitions(path.path()),
+            Err(DmError::Dm(ErrorEnum::Invalid, ref msg)) if msg.contains("past the device's end")
+        );
+    }
+
+    /// Write a GPT header at sector 1, whose entries are at sector 2, and
+    /// the entries, returning the header.
+    fn write_gpt(file: &mut File, entries: &[u8]) -> Vec<u8> {
+        write_sector(file, 2, entries);
+
+        let mut header = vec![0u8; SECTOR_SIZE];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&(GPT_MIN_HEADER_SIZE as u32).to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_MIN_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+        let crc = crc32(&header[..GPT_MIN_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        write_sector(file, 1, &header);
+        header
+    }
+
+    #[test]
+    /// Verify that partitions are read from a GPT, that unused entries are
+    /// skipped, and that a GPT with a bad checksum, with entries of an
+    /// unexpected size, or with a partition whose bounds overflow or pass
+    /// the device's end, is rejected.
+    fn test_read_gpt() {
+        let path = tempfile::NamedTempFile::new().unwrap();
+        let mut file = path.reopen().unwrap();
+        file.set_len(8192 * SECTOR_SIZE as u64).unwrap();
+
+        let mut mbr = vec![0u8; SECTOR_SIZE];
+        mbr_entry(&mut mbr, 0, MBR_TYPE_GPT_PROTECTIVE, 1, 8191);
+        write_sector(&mut file, 0, &mbr);
+
+        let mut entries = vec![0u8; 4 * GPT_MIN_ENTRY_SIZE];
+        for (index, first, last) in [(0, 2048u64, 4095u64), (2, 4096, 6143)] {
+            let entry = &mut entries[index * GPT_MIN_ENTRY_SIZE..];
+            entry[0] = 1;
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        let mut header = write_gpt(&mut file, &entries);
+
+        assert_eq!(
+            read_partitions(path.path()).unwrap(),
+            Some(PartitionTable {
+                kind: PartitionTableKind::Gpt,
+                partitions: vec![
+                    Partition {
+                        number: 1,
+                        start: Sectors(2048),
+                        length: Sectors(2048),
+                    },
+                    Partition {
+                        number: 3,
+                        start: Sectors(4096),
+                        length: Sectors(2048),
+                    },
+                ],
+            })
+        );
+
+        header[16] ^= 1;
+        write_sector(&mut file, 1, &header);
+        assert_matches!(
+            read_partitions(path.path()),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        for entry_size in [0u32, 136, 384, 1 << 10, 1 << 31] {
+            let mut header = write_gpt(&mut file, &entries);
+            header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+            header[16..20].fill(0);
+            let crc = crc32(&header[..GPT_MIN_HEADER_SIZE]);
+            header[16..20].copy_from_slice(&crc.to_le_bytes());
+            write_sector(&mut file, 1, &header);
+            assert_matches!(
+                read_partitions(path.path()),
+                Err(DmError::Dm(ErrorEnum::Invalid, ref msg)) if msg.contains("unexpected size")
+            );
+        }
+
+        for (first, last) in [(0, u64::MAX), (u64::MAX / 2, u64::MAX / 2), (6144, 8192)] {
+            let entry = &mut entries[GPT_MIN_ENTRY_SIZE..];
+            entry[0] = 1;
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            write_gpt(&mut file, &entries);
+            assert_matches!(
+                read_partitions(path.path()),
+                Err(DmError::Dm(ErrorEnum::Invalid, ref msg)) if msg.contains("device's end")
+            );
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    /// Verify that a device is mapped for each partition of a loop device,
+    /// and that the devices are removed again.
+    fn test_map_partitions(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("disk").expect("valid format");
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(paths[0])
+            .unwrap();
+        write_mbr(&mut file);
+        file.sync_all().unwrap();
+
+        let devs = map_partitions(&dm, paths[0], &name).unwrap();
+        assert_eq!(
+            devs.iter()
+                .map(|dev| dev.name().to_owned())
+                .collect::<Vec<_>>(),
+            [1, 2, 5, 6]
+                .iter()
+                .map(|number| partition_dev_name(&name, *number).unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(devs[0].size(), Sectors(2048));
+
+        unmap_partitions(&dm, devs).unwrap();
+        assert!(!device_exists(&dm, &partition_dev_name(&name, 1).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn loop_test_map_partitions() {
+        // The partitions written end within 8 MiB.
+        test_with_devices(
+            DeviceRequirements::new(1).min_size(Bytes(u128::from(8 * IEC::Mi))),
+            test_map_partitions,
+        );
+    }
+}
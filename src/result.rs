@@ -8,6 +8,7 @@ use crate::core::errors;
 
 /// A very simple breakdown of outer layer errors.
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub enum ErrorEnum {
     /// generic error code
     Error,
@@ -15,6 +16,10 @@ pub enum ErrorEnum {
     Invalid,
     /// something not found
     NotFound,
+    /// a device is in use
+    Busy,
+    /// a device exists, but is not the device expected
+    IdentityMismatch,
 }
 
 impl fmt::Display for ErrorEnum {
@@ -26,11 +31,17 @@ impl fmt::Display for ErrorEnum {
 /// Super error type, with constructors distinguishing outer errors from
 /// core errors.
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub enum DmError {
     /// DM errors
     Dm(ErrorEnum, String),
     /// Errors in the core devicemapper functionality
     Core(errors::Error),
+    /// An operation of several steps failed, and its completed steps were
+    /// undone, as far as they could be: a description of the failure and of
+    /// the state in which the devices were left, and the error that caused
+    /// the failure
+    RolledBack(String, Box<DmError>),
 }
 
 /// return result for DM functions
@@ -47,8 +58,53 @@ impl fmt::Display for DmError {
         match *self {
             DmError::Core(ref err) => write!(f, "DM Core error: {err}"),
             DmError::Dm(ref err, ref msg) => write!(f, "DM error: {err}: {msg}"),
+            DmError::RolledBack(ref msg, _) => write!(f, "DM error: {msg}"),
         }
     }
 }
 
-impl Error for DmError {}
+impl DmError {
+    /// The errno with which an ioctl failed, if the error is an ioctl error.
+    pub fn errno(&self) -> Option<nix::errno::Errno> {
+        match self {
+            DmError::Core(err) => err.errno(),
+            DmError::Dm(_, _) => None,
+            DmError::RolledBack(_, err) => err.errno(),
+        }
+    }
+}
+
+impl Error for DmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DmError::Core(err) => Some(err),
+            DmError::Dm(_, _) => None,
+            DmError::RolledBack(_, err) => Some(err.as_ref()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::errno::Errno;
+
+    use super::*;
+
+    #[test]
+    /// Verify that an ioctl error's errno is reachable from a DmError, both
+    /// directly and through the chain of sources.
+    fn test_errno_and_source() {
+        let err = DmError::Core(errors::Error::Ioctl(0, None, None, Box::new(Errno::EBUSY)));
+        assert_eq!(err.errno(), Some(Errno::EBUSY));
+        let source = err.source().unwrap();
+        assert!(source.is::<errors::Error>());
+        assert_eq!(
+            source.source().unwrap().downcast_ref::<Errno>(),
+            Some(&Errno::EBUSY)
+        );
+
+        let err = DmError::Dm(ErrorEnum::Invalid, "invalid".into());
+        assert_eq!(err.errno(), None);
+        assert!(err.source().is_none());
+    }
+}
@@ -7,14 +7,27 @@
 
 use std::{
     fmt,
+    fs::File,
+    io::Read,
     ops::Deref,
     path::{Path, PathBuf},
     str::FromStr,
+    thread,
+    time::Duration,
 };
 
+use nix::errno::Errno;
+
 use crate::{
-    core::{devnode_to_devno, DevId, Device, DeviceInfo, DmFlags, DmName, DmOptions, DmUuid, DM},
+    cachedev::CacheDevStatus,
+    core::{
+        devnode_to_devno, errors, lock_device, DevId, Device, DeviceInfo, DeviceLock, DmFlags,
+        DmName, DmOptions, DmUuid, DM,
+    },
+    holders::{device_holders, device_mount_points, device_users},
     result::{DmError, DmResult, ErrorEnum},
+    thindev::ThinStatus,
+    thinpooldev::ThinPoolStatus,
     units::Sectors,
 };
 
@@ -22,20 +35,171 @@ fn err_func(err_msg: &str) -> DmError {
     DmError::Dm(ErrorEnum::Invalid, err_msg.into())
 }
 
-/// Number of bytes in Struct_dm_target_spec::target_type field.
-const DM_TARGET_TYPE_LEN: usize = 16;
+/// Number of bytes in Struct_dm_target_spec::target_type field, including
+/// the terminating null byte, so one more than the longest target type
+/// allowed.
+pub const DM_TARGET_TYPE_LEN: usize = 16;
 
 str_id!(TargetType, TargetTypeBuf, DM_TARGET_TYPE_LEN, err_func);
 
+// So that a line's target type may be passed to DM::table_load().
+impl AsRef<str> for TargetTypeBuf {
+    fn as_ref(&self) -> &str {
+        &self.inner
+    }
+}
+
+impl TargetType {
+    /// The "linear" target type
+    pub const LINEAR: &'static TargetType = TargetType::from_static("linear");
+    /// The "striped" target type
+    pub const STRIPED: &'static TargetType = TargetType::from_static("striped");
+    /// The "error" target type
+    pub const ERROR: &'static TargetType = TargetType::from_static("error");
+    /// The "zero" target type
+    pub const ZERO: &'static TargetType = TargetType::from_static("zero");
+    /// The "flakey" target type
+    pub const FLAKEY: &'static TargetType = TargetType::from_static("flakey");
+    /// The "delay" target type
+    pub const DELAY: &'static TargetType = TargetType::from_static("delay");
+    /// The "crypt" target type
+    pub const CRYPT: &'static TargetType = TargetType::from_static("crypt");
+    /// The "thin" target type
+    pub const THIN: &'static TargetType = TargetType::from_static("thin");
+    /// The "thin-pool" target type
+    pub const THIN_POOL: &'static TargetType = TargetType::from_static("thin-pool");
+    /// The "cache" target type
+    pub const CACHE: &'static TargetType = TargetType::from_static("cache");
+
+    // Construct a borrowed target type from a string known to be valid.
+    // Only for use in defining the constants above, which the tests check.
+    const fn from_static(value: &'static str) -> &'static TargetType {
+        unsafe { &*(value as *const str as *const TargetType) }
+    }
+}
+
+/// A target type, distinguishing the well-known targets from all others.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum KnownTargetType {
+    /// The "linear" target
+    Linear,
+    /// The "striped" target
+    Striped,
+    /// The "error" target
+    Error,
+    /// The "zero" target
+    Zero,
+    /// The "flakey" target
+    Flakey,
+    /// The "delay" target
+    Delay,
+    /// The "crypt" target
+    Crypt,
+    /// The "thin" target
+    Thin,
+    /// The "thin-pool" target
+    ThinPool,
+    /// The "cache" target
+    Cache,
+    /// Any other target
+    Other(TargetTypeBuf),
+}
+
+impl KnownTargetType {
+    /// The target type as it is known to the kernel.
+    pub fn target_type(&self) -> &TargetType {
+        match self {
+            KnownTargetType::Linear => TargetType::LINEAR,
+            KnownTargetType::Striped => TargetType::STRIPED,
+            KnownTargetType::Error => TargetType::ERROR,
+            KnownTargetType::Zero => TargetType::ZERO,
+            KnownTargetType::Flakey => TargetType::FLAKEY,
+            KnownTargetType::Delay => TargetType::DELAY,
+            KnownTargetType::Crypt => TargetType::CRYPT,
+            KnownTargetType::Thin => TargetType::THIN,
+            KnownTargetType::ThinPool => TargetType::THIN_POOL,
+            KnownTargetType::Cache => TargetType::CACHE,
+            KnownTargetType::Other(target_type) => target_type,
+        }
+    }
+}
+
+impl From<&TargetType> for KnownTargetType {
+    fn from(target_type: &TargetType) -> KnownTargetType {
+        [
+            KnownTargetType::Linear,
+            KnownTargetType::Striped,
+            KnownTargetType::Error,
+            KnownTargetType::Zero,
+            KnownTargetType::Flakey,
+            KnownTargetType::Delay,
+            KnownTargetType::Crypt,
+            KnownTargetType::Thin,
+            KnownTargetType::ThinPool,
+            KnownTargetType::Cache,
+        ]
+        .into_iter()
+        .find(|known| known.target_type() == target_type)
+        .unwrap_or_else(|| KnownTargetType::Other(target_type.to_owned()))
+    }
+}
+
+impl From<TargetTypeBuf> for KnownTargetType {
+    fn from(target_type: TargetTypeBuf) -> KnownTargetType {
+        KnownTargetType::from(&*target_type)
+    }
+}
+
+impl From<KnownTargetType> for TargetTypeBuf {
+    fn from(known: KnownTargetType) -> TargetTypeBuf {
+        match known {
+            KnownTargetType::Other(target_type) => target_type,
+            known => known.target_type().to_owned(),
+        }
+    }
+}
+
+impl FromStr for KnownTargetType {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<KnownTargetType> {
+        Ok(KnownTargetType::from(TargetType::new(s)?))
+    }
+}
+
+impl fmt::Display for KnownTargetType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.target_type())
+    }
+}
+
 /// The trait for properties of the params string of TargetType
 pub trait TargetParams: Clone + fmt::Debug + fmt::Display + Eq + FromStr + PartialEq {
     /// Return the param string only
     fn param_str(&self) -> String;
 
+    /// Write the param string only, as [`TargetParams::param_str`] returns
+    /// it. Tables are loaded by writing each line's params with this
+    /// straight into the ioctl's payload, so that targets which override it
+    /// need not build a String for each line.
+    fn fmt_params(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.param_str())
+    }
+
     /// Return the target type
     fn target_type(&self) -> TargetTypeBuf;
 }
 
+/// Displays the param string of some params, by means of
+/// [`TargetParams::fmt_params`].
+pub(crate) struct ParamStr<'a, P>(pub(crate) &'a P);
+
+impl<P: TargetParams> fmt::Display for ParamStr<'_, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_params(f)
+    }
+}
+
 /// One line of a device mapper table.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct TargetLine<T: TargetParams> {
@@ -60,11 +224,29 @@ impl<T: TargetParams> TargetLine<T> {
 
 /// Manages a target's table
 pub trait TargetTable: Clone + fmt::Debug + fmt::Display + Eq + PartialEq + Sized {
+    /// The params of the table's lines
+    type Params: TargetParams;
+
     /// Constructs a table from a raw table returned by DM::table_status()
     fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<Self>;
 
+    /// The table's lines, in order
+    fn lines(&self) -> &[TargetLine<Self::Params>];
+
     /// Generates a table that can be loaded by DM::table_load()
-    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)>;
+    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
+        self.lines()
+            .iter()
+            .map(|line| {
+                (
+                    *line.start,
+                    *line.length,
+                    line.params.target_type().to_string(),
+                    line.params.param_str(),
+                )
+            })
+            .collect()
+    }
 }
 
 /// A trait capturing some shared properties of DM devices.
@@ -85,13 +267,39 @@ pub trait DmDevice<T: TargetTable> {
         T::from_raw_table(&table)
     }
 
+    /// The mount points of the filesystems mounted from the device, from
+    /// /proc/self/mountinfo, so that they may be unmounted before the
+    /// device is suspended or removed.
+    fn mounts(&self) -> DmResult<Vec<PathBuf>> {
+        device_mount_points(self.device())
+    }
+
+    /// The kernel names, e.g. "dm-4", of the devices stacked on the device,
+    /// from its holders directory in sysfs.
+    fn holders(&self) -> DmResult<Vec<String>> {
+        device_holders(self.device())
+    }
+
+    /// Lock the device against other threads of this process that also
+    /// lock it by name, see [`lock_device`], so that a sequence of
+    /// operations on it, e.g. suspend, load and resume, is not interleaved
+    /// with theirs.
+    fn lock(&self) -> DeviceLock {
+        lock_device(&DevId::Name(self.name()))
+    }
+
     /// The device's name.
     fn name(&self) -> &DmName;
 
-    /// Resume I/O on the device.
-    fn resume(&mut self, dm: &DM) -> DmResult<()> {
-        dm.device_suspend(&DevId::Name(self.name()), DmOptions::private())?;
-        Ok(())
+    /// Record table as the device's table, without loading it.
+    fn record_table(&mut self, table: T);
+
+    /// Resume I/O on the device. Return the device's event number once it
+    /// has been resumed, so that later events may be waited for, see
+    /// [`DmDevice::wait_for_event`].
+    fn resume(&mut self, dm: &DM) -> DmResult<u32> {
+        dm.device_suspend(&DevId::Name(self.name()), DmOptions::private())
+            .map(|info| info.event_nr())
     }
 
     /// The number of sectors available for user data.
@@ -111,28 +319,290 @@ pub trait DmDevice<T: TargetTable> {
     /// What the device thinks its table is.
     fn table(&self) -> &T;
 
+    /// Wait until an event has occurred on the device since its event
+    /// number was since_event_nr, as for [`DM::wait_for_event`], and return
+    /// its new event number and its status, of type S, e.g. ThinPoolStatus.
+    /// Return None if no event occurs within the timeout, if one is given.
+    fn wait_for_event<S>(
+        &self,
+        dm: &DM,
+        since_event_nr: u32,
+        timeout: Option<Duration>,
+    ) -> DmResult<Option<(u32, S)>>
+    where
+        Self: Sized,
+        S: FromStr<Err = DmError>,
+    {
+        match dm.wait_for_event(&DevId::Name(self.name()), since_event_nr, timeout)? {
+            Some(info) => {
+                let options = DmOptions::default();
+                Ok(Some((info.event_nr(), status!(self, dm, options)?)))
+            }
+            None => Ok(None),
+        }
+    }
+
     /// Load a table
+    ///
+    /// The table is checked as [`validate_table`] checks it, and its lines
+    /// are then written straight into the ioctl's payload. If
+    /// `DM_SECURE_DATA` is set and the zeroize feature is enabled, the
+    /// payload is wiped once the table is loaded.
     fn table_load(&self, dm: &DM, table: &T, options: DmOptions) -> DmResult<()> {
-        dm.table_load(&DevId::Name(self.name()), &table.to_raw_table(), options)?;
+        validate_table(table)?;
+        load_table(dm, &DevId::Name(self.name()), table, options)?;
+        Ok(())
+    }
+
+    /// Load a table into the device's inactive slot without suspending the
+    /// device. I/O to the device continues undisturbed until the table is
+    /// made live by [`DmDevice::commit`].
+    ///
+    /// Separating the two phases allows the tables of many devices to be
+    /// loaded before any of them are suspended, so that each device is
+    /// suspended only for as long as it takes to swap its tables.
+    ///
+    /// The device's own record of its table is not changed.
+    fn preload(&self, dm: &DM, table: &T) -> DmResult<()> {
+        self.table_load(dm, table, DmOptions::default())
+    }
+
+    /// Clear the table, if any, loaded into the device's inactive slot, so
+    /// that it is not made live when the device is next resumed.
+    fn clear_pending_table(&self, dm: &DM) -> DmResult<()> {
+        dm.table_clear(&DevId::Name(self.name()))?;
+        Ok(())
+    }
+
+    /// Make the table in the device's inactive slot live by suspending and
+    /// immediately resuming the device, and record table, which must be the
+    /// table that was preloaded, as the device's table. Return the device's
+    /// event number once it has been resumed.
+    ///
+    /// If table is not equivalent to the table that resuming would make
+    /// live, the preloaded one or, if none was preloaded, the live one, an
+    /// `ErrorEnum::Invalid` error is returned and the device is not
+    /// suspended. If the device can not be resumed, the preloaded table is
+    /// cleared. If the table can not be made live, the device's record of
+    /// its table is not changed.
+    fn commit(&mut self, dm: &DM, table: T) -> DmResult<u32>
+    where
+        Self: Sized,
+    {
+        let id = DevId::Name(self.name());
+        let (info, inactive) = dm.table_status(
+            &id,
+            DmOptions::default()
+                .set_flags(DmFlags::DM_STATUS_TABLE | DmFlags::DM_QUERY_INACTIVE_TABLE),
+        )?;
+        let pending = if info.flags().contains(DmFlags::DM_INACTIVE_PRESENT) {
+            T::from_raw_table(&inactive)?
+        } else {
+            Self::read_kernel_table(dm, &id)?
+        };
+        if !Self::equivalent_tables(&pending, &table)? {
+            let err_msg = format!(
+                "table {table:?} is not the table {pending:?} that committing would make live on {}",
+                self.name()
+            );
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
+        let event_nr = resume_or_clear(dm, self)?;
+        self.record_table(table);
+        Ok(event_nr)
+    }
+
+    /// Make the device read-only, or writable, by reloading its table with
+    /// or without DM_READONLY. The mapping itself is not changed.
+    /// The table is loaded before the device is suspended, so that a
+    /// failure to load it leaves the device running; if the device can not
+    /// be suspended or resumed, the loaded table is cleared.
+    fn set_read_only(&mut self, dm: &DM, read_only: bool) -> DmResult<()>
+    where
+        Self: Sized,
+    {
+        let table = self.table().clone();
+        self.table_load(dm, &table, DmOptions::default().set_read_only(read_only))?;
+        suspend_or_clear(
+            dm,
+            self,
+            DmOptions::default().set_flags(DmFlags::DM_NOFLUSH),
+        )?;
+        resume_or_clear(dm, self)?;
         Ok(())
     }
 
     /// Erase the kernel's memory of this device.
     fn teardown(&mut self, dm: &DM) -> DmResult<()>;
 
+    /// Tear down the device, as [`DmDevice::teardown`] does, trying again
+    /// according to policy while the device is busy. If it is still busy
+    /// after the last attempt, return an `ErrorEnum::Busy` error that says
+    /// what holds it: the processes that have it open, the filesystems
+    /// mounted from it, and the devices stacked on it.
+    fn teardown_with_retries(&mut self, dm: &DM, policy: RetryPolicy) -> DmResult<()> {
+        let mut attempt = 1;
+        loop {
+            match self.teardown(dm) {
+                Err(err) if err.errno() == Some(Errno::EBUSY) => {
+                    if attempt >= policy.attempts {
+                        let users = match device_users(self.device()) {
+                            Ok(users) => users.to_string(),
+                            Err(users_err) => format!("could not find its users: {users_err}"),
+                        };
+                        let err_msg = format!(
+                            "device {} is still busy after {} attempts to remove it: {}",
+                            self.name(),
+                            attempt,
+                            users
+                        );
+                        return Err(DmError::Dm(ErrorEnum::Busy, err_msg));
+                    }
+                    thread::sleep(policy.delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Check that the kernel's live table for the device is equivalent to
+    /// the device's own record of its table, as determined by
+    /// [`DmDevice::equivalent_tables`]. Return the differences if it is not,
+    /// e.g. because the table was reloaded by another program.
+    fn verify(&self, dm: &DM) -> DmResult<Option<TableDiff<T>>> {
+        let found = Self::read_kernel_table(dm, &DevId::Name(self.name()))?;
+        if Self::equivalent_tables(self.table(), &found)? {
+            return Ok(None);
+        }
+        Ok(Some(TableDiff::new(self.table().clone(), found)))
+    }
+
     /// The device's UUID, if available.
     /// Note that the UUID is not any standard UUID format.
     fn uuid(&self) -> Option<&DmUuid>;
 }
 
+/// A line that differs between two tables, in the form in which it would be
+/// loaded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TableLineDiff {
+    /// The position of the line in the tables
+    pub index: usize,
+    /// The line in the expected table, if it has that many lines
+    pub expected: Option<(u64, u64, String, String)>,
+    /// The line in the table found, if it has that many lines
+    pub found: Option<(u64, u64, String, String)>,
+}
+
+/// The differences between a device's expected table and the table found in
+/// the kernel.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TableDiff<T: TargetTable> {
+    /// The expected table
+    pub expected: T,
+    /// The table found
+    pub found: T,
+    /// The lines that differ
+    pub lines: Vec<TableLineDiff>,
+}
+
+impl<T: TargetTable> TableDiff<T> {
+    /// Compare two tables line by line.
+    pub fn new(expected: T, found: T) -> TableDiff<T> {
+        let mut expected_lines = expected.to_raw_table().into_iter();
+        let mut found_lines = found.to_raw_table().into_iter();
+        let mut lines = Vec::new();
+        for index in 0.. {
+            let (expected, found) = match (expected_lines.next(), found_lines.next()) {
+                (None, None) => break,
+                (expected, found) => (expected, found),
+            };
+            if expected != found {
+                lines.push(TableLineDiff {
+                    index,
+                    expected,
+                    found,
+                });
+            }
+        }
+        TableDiff {
+            expected,
+            found,
+            lines,
+        }
+    }
+}
+
 /// Send a message that expects no reply to target device.
 pub fn message<T: TargetTable, D: DmDevice<T>>(dm: &DM, target: &D, msg: &str) -> DmResult<()> {
     dm.target_msg(&DevId::Name(target.name()), None, msg)?;
     Ok(())
 }
 
+/// Send a message to the target of the device whose range includes sector,
+/// e.g. to one of several switch or dust targets of a device, rather than
+/// to the device as a whole. Return the target's reply, if it makes one.
+pub fn message_at<T: TargetTable, D: DmDevice<T>>(
+    dm: &DM,
+    dev: &D,
+    sector: Sectors,
+    msg: &str,
+) -> DmResult<Option<String>> {
+    if sector >= dev.size() {
+        return Err(DmError::Core(errors::Error::InvalidArgument(format!(
+            "sector {} is beyond the end of {}, which has {} sectors",
+            *sector,
+            dev.name(),
+            *dev.size()
+        ))));
+    }
+    let (_, output) = dm.target_msg(&DevId::Name(dev.name()), Some(*sector), msg)?;
+    Ok(output)
+}
+
+/// Suspend a device into whose inactive slot a new table has just been
+/// loaded, so that the table may be made live by resuming it. If the device
+/// can not be suspended, clear the new table, so that it does not linger to
+/// be made live by a later, unrelated resume, and return the error.
+pub fn suspend_or_clear<T: TargetTable, D: DmDevice<T>>(
+    dm: &DM,
+    dev: &mut D,
+    options: DmOptions,
+) -> DmResult<()> {
+    dev.suspend(dm, options).map_err(|err| {
+        if let Err(clear_err) = dev.clear_pending_table(dm) {
+            warn!(
+                "Failed to clear the inactive table of {}: {}",
+                dev.name(),
+                clear_err
+            );
+        }
+        err
+    })
+}
+
+/// Resume a device into whose inactive slot a new table has just been
+/// loaded. If the device can not be resumed, clear the new table, so that it
+/// does not linger to be made live by a later, unrelated resume, and return
+/// the error. Return the device's event number once it has been resumed.
+pub fn resume_or_clear<T: TargetTable, D: DmDevice<T>>(dm: &DM, dev: &mut D) -> DmResult<u32> {
+    dev.resume(dm).map_err(|err| {
+        if let Err(clear_err) = dev.clear_pending_table(dm) {
+            warn!(
+                "Failed to clear the inactive table of {}: {}",
+                dev.name(),
+                clear_err
+            );
+        }
+        err
+    })
+}
+
 /// Create a device, load a table, and resume it allowing the caller to specify the DmOptions for
-/// resuming.
+/// resuming. Return the device's info once it has been resumed.
 pub fn device_create<T: TargetTable>(
     dm: &DM,
     name: &DmName,
@@ -140,27 +610,34 @@ pub fn device_create<T: TargetTable>(
     table: &T,
     suspend_options: DmOptions,
 ) -> DmResult<DeviceInfo> {
+    validate_table(table)?;
     dm.device_create(name, uuid, DmOptions::default())?;
 
     let id = DevId::Name(name);
-    let dev_info = match dm.table_load(&id, &table.to_raw_table(), DmOptions::default()) {
+    if let Err(e) = load_table(dm, &id, table, DmOptions::default()) {
+        dm.device_remove(&id, DmOptions::default())?;
+        return Err(e);
+    }
+    // If the device can not be resumed, remove it, which also discards the
+    // table loaded into its inactive slot.
+    match dm.device_suspend(&id, suspend_options) {
         Err(e) => {
             dm.device_remove(&id, DmOptions::default())?;
-            return Err(e);
+            Err(e)
         }
-        Ok(dev_info) => dev_info,
-    };
-    dm.device_suspend(&id, suspend_options)?;
-
-    Ok(dev_info)
+        Ok(dev_info) => Ok(dev_info),
+    }
 }
 
-/// Verify that kernel data matches arguments passed.
+/// Verify that kernel data matches arguments passed. If the device's uuid
+/// is not the one given, return an `ErrorEnum::IdentityMismatch` error.
 pub fn device_match<T: TargetTable, D: DmDevice<T>>(
     dm: &DM,
     dev: &D,
     uuid: Option<&DmUuid>,
 ) -> DmResult<()> {
+    check_identity(dev, uuid)?;
+
     let kernel_table = D::read_kernel_table(dm, &DevId::Name(dev.name()))?;
     let device_table = dev.table();
     if !D::equivalent_tables(&kernel_table, device_table)? {
@@ -170,25 +647,104 @@ pub fn device_match<T: TargetTable, D: DmDevice<T>>(
 
         return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
     }
+    Ok(())
+}
 
+/// Verify that an existing device has the uuid expected of it, so that a
+/// device which merely has the same name is not mistaken for it.
+fn check_identity<T: TargetTable, D: DmDevice<T>>(dev: &D, uuid: Option<&DmUuid>) -> DmResult<()> {
     if dev.uuid() != uuid {
         let err_msg = format!(
-            "Specified uuid \"{:?}\" does not match kernel uuuid \"{:?}\"",
-            uuid,
-            dev.uuid()
+            "Device \"{}\" has uuid \"{:?}\", not the expected uuid \"{:?}\"",
+            dev.name(),
+            dev.uuid(),
+            uuid
         );
 
-        return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        return Err(DmError::Dm(ErrorEnum::IdentityMismatch, err_msg));
     }
     Ok(())
 }
 
+/// How many times, and how often, to try an operation that fails because a
+/// device is busy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// The number of attempts, including the first
+    pub attempts: usize,
+    /// The time to wait between attempts
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Make a new RetryPolicy.
+    pub fn new(attempts: usize, delay: Duration) -> RetryPolicy {
+        RetryPolicy { attempts, delay }
+    }
+}
+
+/// What to do when setting up a device that already exists, but has a
+/// table other than the one given.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TableMismatch {
+    /// Return an error, leaving the device unchanged.
+    Error,
+    /// Replace the device's table with the one given.
+    Reload,
+}
+
+/// Verify that kernel data matches arguments passed, as device_match() does,
+/// except that if the tables differ and policy is TableMismatch::Reload,
+/// the device's table is loaded into the kernel in place of the kernel's.
+pub fn device_adopt<T: TargetTable, D: DmDevice<T>>(
+    dm: &DM,
+    dev: &mut D,
+    uuid: Option<&DmUuid>,
+    policy: TableMismatch,
+) -> DmResult<()> {
+    check_identity(dev, uuid)?;
+
+    let kernel_table = D::read_kernel_table(dm, &DevId::Name(dev.name()))?;
+    if D::equivalent_tables(&kernel_table, dev.table())? {
+        return Ok(());
+    }
+
+    match policy {
+        TableMismatch::Error => {
+            let err_msg = format!(
+                "Specified new table \"{:?}\" does not match kernel table \"{kernel_table:?}\"",
+                dev.table()
+            );
+
+            Err(DmError::Dm(ErrorEnum::Invalid, err_msg))
+        }
+        TableMismatch::Reload => {
+            let table = dev.table().clone();
+            dev.preload(dm, &table)?;
+            dev.commit(dm, table)?;
+            Ok(())
+        }
+    }
+}
+
 /// Check if a device of the given name exists.
 pub fn device_exists(dm: &DM, name: &DmName) -> DmResult<bool> {
     dm.list_devices()
         .map(|l| l.iter().any(|(n, _, _)| &**n == name))
 }
 
+/// Get the DeviceInfo of the device of the given name, or None if there is
+/// no such device. This takes a single ioctl, where device_exists() lists
+/// every device, so it is the cheaper check when the device's info is
+/// needed if it exists.
+pub fn device_info_if_exists(dm: &DM, name: &DmName) -> DmResult<Option<DeviceInfo>> {
+    match dm.device_info(&DevId::Name(name)) {
+        Ok(dev_info) => Ok(Some(dev_info)),
+        Err(err) if err.errno() == Some(Errno::ENXIO) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
 /// Parse a device from either of a path or a maj:min pair
 pub fn parse_device(val: &str, desc: &str) -> DmResult<Device> {
     let device = if val.starts_with('/') {
@@ -219,6 +775,68 @@ where
     })
 }
 
+/// Whether the kernel's isspace() holds for c, as it does for the
+/// whitespace that dm_split_args() splits on. Unlike
+/// char::is_ascii_whitespace, this includes vertical tab.
+fn is_kernel_space(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\x0b' | '\x0c' | '\r')
+}
+
+/// Escape a single argument for inclusion in a table params string.
+/// Whitespace and backslashes are preceded by a backslash, so that the
+/// kernel's dm_split_args() reads the result back as one argument.
+pub fn escape_param(param: &str) -> String {
+    let mut escaped = String::with_capacity(param.len());
+    for c in param.chars() {
+        if c == '\\' || is_kernel_space(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Remove the escaping added by escape_param from a single argument.
+/// A backslash makes the character following it literal; a trailing
+/// backslash is kept, as it is by the kernel.
+pub fn unescape_param(param: &str) -> String {
+    let mut unescaped = String::with_capacity(param.len());
+    let mut chars = param.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            unescaped.push(chars.next().unwrap_or('\\'));
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+/// Split a params string into its unescaped arguments, following the
+/// rules of the kernel's dm_split_args(): arguments are separated by runs
+/// of unescaped whitespace.
+pub fn split_params(params: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = params.chars();
+    while let Some(c) = chars.next() {
+        if is_kernel_space(c) {
+            if let Some(arg) = current.take() {
+                args.push(arg);
+            }
+            continue;
+        }
+        let arg = current.get_or_insert_with(String::new);
+        if c == '\\' {
+            arg.push(chars.next().unwrap_or('\\'));
+        } else {
+            arg.push(c);
+        }
+    }
+    args.extend(current);
+    args
+}
+
 /// Get fields for a single status line.
 /// Return an error if an insufficient number of fields are obtained.
 pub fn get_status_line_fields(status_line: &str, number_required: usize) -> DmResult<Vec<&str>> {
@@ -256,6 +874,66 @@ pub fn get_status(status_lines: &[(u64, u64, String, String)]) -> DmResult<Strin
         .to_owned())
 }
 
+/// The status of a target of a type whose status is not parsed by this
+/// crate.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawTargetStatus {
+    /// The target type
+    pub target_type: TargetTypeBuf,
+    /// The status, as returned by the kernel
+    pub params: String,
+}
+
+/// The status of a single target of a device, parsed according to the
+/// target's type.
+#[derive(Debug)]
+pub enum TargetStatus {
+    /// A cache target
+    Cache(CacheDevStatus),
+    /// A thin target
+    Thin(ThinStatus),
+    /// A thin-pool target
+    ThinPool(ThinPoolStatus),
+    /// A target of any other type, e.g. linear, or one not included in
+    /// this crate
+    Raw(RawTargetStatus),
+}
+
+/// Get the status of each target of a device, with the start and length
+/// of the target's segment. The status of a target whose type this crate
+/// does not parse is returned as it is, so that devices combining any
+/// targets may be inspected.
+pub fn typed_status(
+    dm: &DM,
+    id: &DevId<'_>,
+    options: DmOptions,
+) -> DmResult<Vec<(Sectors, Sectors, TargetStatus)>> {
+    let (_, status) = dm.table_status(id, options)?;
+    parse_typed_status(status)
+}
+
+/// Parse the status of each target of a device, as returned by
+/// DM::table_status(), as typed_status() does.
+pub(crate) fn parse_typed_status(
+    status: Vec<(u64, u64, String, String)>,
+) -> DmResult<Vec<(Sectors, Sectors, TargetStatus)>> {
+    status
+        .into_iter()
+        .map(|(start, length, target_type, params)| {
+            let status = match target_type.parse::<KnownTargetType>()? {
+                KnownTargetType::Cache => TargetStatus::Cache(params.parse()?),
+                KnownTargetType::Thin => TargetStatus::Thin(params.parse()?),
+                KnownTargetType::ThinPool => TargetStatus::ThinPool(params.parse()?),
+                known => TargetStatus::Raw(RawTargetStatus {
+                    target_type: known.into(),
+                    params,
+                }),
+            };
+            Ok((Sectors(start), Sectors(length), status))
+        })
+        .collect()
+}
+
 /// Construct an error when parsing yields an unexpected value.
 /// Indicate the location of the unexpected value, 1-indexed, its actual
 /// value, and the name of the expected thing.
@@ -267,3 +945,306 @@ pub fn make_unexpected_value_error(value_index: usize, value: &str, item_name: &
         ),
     )
 }
+
+/// Check that a block size is within the range the kernel accepts and is a
+/// multiple of the minimum, naming the constraint that is violated if not.
+pub fn validate_block_size(size: Sectors, min: Sectors, max: Sectors, what: &str) -> DmResult<()> {
+    let err_msg = if size < min {
+        format!("{what} {size} is less than the minimum of {min}")
+    } else if size > max {
+        format!("{what} {size} is greater than the maximum of {max}")
+    } else if !size.is_aligned_to(min) {
+        format!("{what} {size} is not a multiple of {min} ({})", min.bytes())
+    } else {
+        return Ok(());
+    };
+    Err(DmError::Dm(ErrorEnum::Invalid, err_msg))
+}
+
+/// The offset of the magic number in the superblocks of thin pool and cache
+/// metadata, following the checksum, flags, block number and uuid.
+const SUPERBLOCK_MAGIC_OFFSET: usize = 32;
+
+/// Check that the device at devnode begins with a superblock that has the
+/// given magic number, as the kernel writes at the start of the metadata of
+/// a thin pool or a cache. what names the kind of metadata in errors.
+/// Return the version of the metadata format, which follows the magic
+/// number.
+pub(crate) fn check_superblock_magic(devnode: &Path, magic: u64, what: &str) -> DmResult<u32> {
+    let mut buf = [0u8; SUPERBLOCK_MAGIC_OFFSET + 12];
+    File::open(devnode)
+        .and_then(|mut f| f.read_exact(&mut buf))
+        .map_err(|err| DmError::Core(errors::Error::MetadataIo(devnode.into(), err.to_string())))?;
+    let mut found = [0u8; 8];
+    found.copy_from_slice(&buf[SUPERBLOCK_MAGIC_OFFSET..SUPERBLOCK_MAGIC_OFFSET + 8]);
+    if u64::from_le_bytes(found) != magic {
+        let err_msg = format!("{} does not hold {what} metadata", devnode.display());
+        return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+    }
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&buf[SUPERBLOCK_MAGIC_OFFSET + 8..]);
+    Ok(u32::from_le_bytes(version))
+}
+
+/// Check that the lines of a table, in the order given, cover the device
+/// from sector 0 without gaps or overlaps, and that each has a length
+/// greater than 0 and, if an alignment is given, starts at and has a length
+/// that is a multiple of it. Return the size of the device.
+pub(crate) fn validate_table_lines<I, S>(lines: I, alignment: Option<Sectors>) -> DmResult<Sectors>
+where
+    I: IntoIterator<Item = (Sectors, Sectors, S)>,
+    S: fmt::Display,
+{
+    if alignment == Some(Sectors(0)) {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            "table alignment must be greater than 0".to_string(),
+        ));
+    }
+
+    let mut lines = lines.into_iter().peekable();
+    if lines.peek().is_none() {
+        return Err(DmError::Dm(
+            ErrorEnum::Invalid,
+            "table must have at least one line".to_string(),
+        ));
+    }
+
+    let mut next = Sectors(0);
+    for (start, length, target_type) in lines {
+        if start != next {
+            let err_msg =
+                format!("{target_type} line starts at sector {start}, expected sector {next}");
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if length == Sectors(0) {
+            let err_msg = format!("{target_type} line at sector {start} has length 0");
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+
+        if let Some(alignment) = alignment {
+            if !start.is_aligned_to(alignment) || !length.is_aligned_to(alignment) {
+                let err_msg = format!(
+                    "{target_type} line at sector {start} of length {length} is not aligned"
+                );
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+        }
+
+        next = match start.checked_add(length) {
+            Some(end) => end,
+            None => {
+                let err_msg = format!("{target_type} line at sector {start} is too long");
+                return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+            }
+        };
+    }
+    Ok(next)
+}
+
+/// Check that a table is one the kernel can load: that it has at least one
+/// line, and that its lines cover the device from sector 0 without gaps or
+/// overlaps. Devices check their tables in this way before loading them,
+/// so that an invalid table is reported as such, rather than by a failed
+/// ioctl.
+pub fn validate_table<T: TargetTable>(table: &T) -> DmResult<()> {
+    validate_table_lines(
+        table
+            .lines()
+            .iter()
+            .map(|line| (line.start, line.length, TypeStr(&line.params))),
+        None,
+    )?;
+    Ok(())
+}
+
+/// Displays the target type of some params, so that it is formatted only if
+/// it is reported in an error.
+struct TypeStr<'a, P>(&'a P);
+
+impl<P: TargetParams> fmt::Display for TypeStr<'_, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", &*self.0.target_type())
+    }
+}
+
+/// Load a table, which has been validated, into the inactive slot of the
+/// device, writing each line's params straight into the ioctl's payload.
+fn load_table<T: TargetTable>(
+    dm: &DM,
+    id: &DevId<'_>,
+    table: &T,
+    options: DmOptions,
+) -> DmResult<DeviceInfo> {
+    dm.table_load(
+        id,
+        table.lines().iter().map(|line| {
+            (
+                *line.start,
+                *line.length,
+                line.params.target_type(),
+                ParamStr(&line.params),
+            )
+        }),
+        options,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lineardev::{LinearDevTargetParams, LinearDevTargetTable, LinearTargetParams};
+
+    use super::*;
+
+    #[test]
+    fn test_check_superblock_magic() {
+        let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+        let path = tmp_dir.path().join("meta");
+        let mut block = vec![0u8; 512];
+        std::fs::write(&path, &block).unwrap();
+        assert_matches!(
+            check_superblock_magic(&path, 27022010, "thin pool"),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        block[32..40].copy_from_slice(&27022010u64.to_le_bytes());
+        block[40..44].copy_from_slice(&2u32.to_le_bytes());
+        std::fs::write(&path, &block).unwrap();
+        assert_eq!(
+            check_superblock_magic(&path, 27022010, "thin pool").unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_known_target_types() {
+        for known in [
+            KnownTargetType::Linear,
+            KnownTargetType::Striped,
+            KnownTargetType::Error,
+            KnownTargetType::Zero,
+            KnownTargetType::Flakey,
+            KnownTargetType::Delay,
+            KnownTargetType::Crypt,
+            KnownTargetType::Thin,
+            KnownTargetType::ThinPool,
+            KnownTargetType::Cache,
+        ] {
+            let target_type = known.target_type();
+            assert_eq!(
+                TargetType::new(&target_type.to_string()).unwrap(),
+                target_type
+            );
+            assert_eq!(KnownTargetType::from(target_type), known);
+            assert_eq!(known.to_string().parse::<KnownTargetType>().unwrap(), known);
+        }
+        assert_eq!(TargetType::THIN_POOL.to_string(), "thin-pool");
+        assert_eq!(
+            "era".parse::<KnownTargetType>().unwrap(),
+            KnownTargetType::Other(TargetTypeBuf::new("era".into()).unwrap())
+        );
+        assert_matches!("".parse::<KnownTargetType>(), Err(_));
+    }
+
+    #[test]
+    fn test_validate_table_lines() {
+        let lines = |lines: &[(u64, u64)]| {
+            lines
+                .iter()
+                .map(|&(start, length)| (Sectors(start), Sectors(length), "linear"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            validate_table_lines(lines(&[(0, 8), (8, 8)]), None).unwrap(),
+            Sectors(16)
+        );
+        for invalid in [
+            lines(&[]),
+            lines(&[(0, 8), (16, 8)]),
+            lines(&[(0, 8), (4, 8)]),
+            lines(&[(0, 0)]),
+            lines(&[(0, 8), (8, u64::MAX)]),
+        ] {
+            assert_matches!(
+                validate_table_lines(invalid, None),
+                Err(DmError::Dm(ErrorEnum::Invalid, _))
+            );
+        }
+        assert_matches!(
+            validate_table_lines(lines(&[(0, 8), (8, 4)]), Some(Sectors(8))),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    fn test_table_diff() {
+        let line = |start, offset| {
+            TargetLine::new(
+                Sectors(start),
+                Sectors(8),
+                LinearDevTargetParams::Linear(LinearTargetParams::new(
+                    Device {
+                        major: 8,
+                        minor: 16,
+                    },
+                    Sectors(offset),
+                )),
+            )
+        };
+        let expected = LinearDevTargetTable::new(vec![line(0, 0), line(8, 64)]);
+        let found = LinearDevTargetTable::new(vec![line(0, 0), line(8, 128), line(16, 256)]);
+
+        let diff = TableDiff::new(expected.clone(), expected.clone());
+        assert!(diff.lines.is_empty());
+
+        let diff = TableDiff::new(expected, found);
+        assert_eq!(
+            diff.lines,
+            vec![
+                TableLineDiff {
+                    index: 1,
+                    expected: Some((8, 8, "linear".to_owned(), "8:16 64".to_owned())),
+                    found: Some((8, 8, "linear".to_owned(), "8:16 128".to_owned())),
+                },
+                TableLineDiff {
+                    index: 2,
+                    expected: None,
+                    found: Some((16, 8, "linear".to_owned(), "8:16 256".to_owned())),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escape_param() {
+        assert_eq!(escape_param("plain"), "plain");
+        assert_eq!(escape_param("a b\tc\nd\\"), "a\\ b\\\tc\\\nd\\\\");
+        assert_eq!(escape_param("v\x0bf\x0cr\r"), "v\\\x0bf\\\x0cr\\\r");
+        for param in ["", "plain", "a b", " \t\n\x0b\x0c\r", "\\", "x\\ y"] {
+            assert_eq!(unescape_param(&escape_param(param)), param);
+        }
+        assert_eq!(unescape_param("trailing\\"), "trailing\\");
+    }
+
+    #[test]
+    fn test_split_params() {
+        assert_eq!(split_params(""), Vec::<String>::new());
+        assert_eq!(split_params("  a  b\tc "), vec!["a", "b", "c"]);
+        assert_eq!(split_params("a\\ b c\\\\ d"), vec!["a b", "c\\", "d"]);
+        assert_eq!(split_params("a\x0bb\x0cc\rd"), vec!["a", "b", "c", "d"]);
+        let args = [
+            "one",
+            "two words",
+            "back\\slash",
+            "tab\there",
+            "vtab\x0bhere",
+        ];
+        let joined = args
+            .iter()
+            .map(|a| escape_param(a))
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(split_params(&joined), args);
+    }
+}
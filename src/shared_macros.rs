@@ -34,17 +34,6 @@ macro_rules! devnode {
     };
 }
 
-macro_rules! to_raw_table_unique {
-    ($s:ident) => {
-        vec![(
-            *$s.table.start,
-            *$s.table.length,
-            $s.table.params.target_type().to_string(),
-            $s.table.params.param_str(),
-        )]
-    };
-}
-
 macro_rules! table {
     ($s:ident) => {
         &$s.table
@@ -60,3 +49,66 @@ macro_rules! status {
         .parse()
     };
 }
+
+// Serialize a struct as a map of all its fields, keyed by field name.
+// Listing a field that does not exist, or omitting one, is a compile error.
+macro_rules! serialize_fields {
+    ($T:ident, $($field:ident),+) => {
+        impl serde::Serialize for $T {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                use serde::ser::SerializeStruct;
+
+                let $T { $($field),+ } = self;
+                let mut state =
+                    serializer.serialize_struct(stringify!($T), [$(stringify!($field)),+].len())?;
+                $(state.serialize_field(stringify!($field), $field)?;)+
+                state.end()
+            }
+        }
+    };
+}
+
+// Serialize a fieldless enum as a string.
+macro_rules! serialize_names {
+    ($T:ident, $($variant:ident => $name:expr),+) => {
+        impl serde::Serialize for $T {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_str(match self {
+                    $($T::$variant => $name),+
+                })
+            }
+        }
+    };
+}
+
+// Serialize a status enum with Working, Error, and Fail variants as a map
+// with a "state" of "working", "error", or "fail", and a "status" that holds
+// the working status, or null.
+macro_rules! serialize_status {
+    ($T:ident) => {
+        impl serde::Serialize for $T {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                use serde::ser::SerializeStruct;
+
+                let (state_name, status) = match self {
+                    $T::Working(status) => ("working", Some(&**status)),
+                    $T::Error => ("error", None),
+                    $T::Fail => ("fail", None),
+                };
+                let mut state = serializer.serialize_struct(stringify!($T), 2)?;
+                state.serialize_field("state", state_name)?;
+                state.serialize_field("status", &status)?;
+                state.end()
+            }
+        }
+    };
+}
@@ -0,0 +1,193 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Reading of the info, table and status of every DM device in one pass,
+// checked for changes made while it was read, for tools that monitor many
+// devices.
+
+use nix::errno::Errno;
+
+use crate::{
+    core::{DevId, DeviceInfo, DmFlags, DmName, DmNameBuf, DmOptions, DM},
+    result::DmResult,
+    shared::{parse_typed_status, TargetStatus},
+    units::Sectors,
+};
+
+/// The number of times the devices are read before a state that may be
+/// inconsistent is returned.
+const SNAPSHOT_ATTEMPTS: usize = 3;
+
+/// The state of a single DM device.
+#[derive(Debug)]
+pub struct DeviceState {
+    /// The device's info, as returned with its status
+    pub info: DeviceInfo,
+    /// The device's live table, as (sector_start, sector_length, type,
+    /// params)
+    pub table: Vec<(u64, u64, String, String)>,
+    /// The status of each target of the device's live table
+    pub status: Vec<(Sectors, Sectors, TargetStatus)>,
+}
+
+impl DeviceState {
+    /// The device's name.
+    pub fn name(&self) -> &DmName {
+        self.info.name().expect("device was read by its name")
+    }
+}
+
+/// The state of all DM devices, as read by [`DM::snapshot_state`].
+#[derive(Debug)]
+pub struct SystemState {
+    /// The devices, in the order in which the kernel listed them
+    pub devices: Vec<DeviceState>,
+    /// Whether the state was read without any device changing, appearing,
+    /// or disappearing meanwhile
+    pub consistent: bool,
+}
+
+impl SystemState {
+    /// The state of the device of the given name, if it was read.
+    pub fn device(&self, name: &DmName) -> Option<&DeviceState> {
+        self.devices.iter().find(|device| device.name() == name)
+    }
+}
+
+impl DM {
+    /// Read the info, table and status of every DM device. Each device's
+    /// info is returned with its table and its status, so only two ioctls
+    /// are made for each device, as well as those that list the devices.
+    ///
+    /// The state is consistent if no device's event number changed, and no
+    /// device appeared or disappeared, while it was read. If it is not, it
+    /// is read again, up to three times in all, after which the last state
+    /// read is returned, marked as inconsistent. Statuses are read without
+    /// flushing metadata, see [`DmOptions::set_no_flush`].
+    ///
+    /// The ioctls are made one at a time. They can not be batched into one
+    /// io_uring submission, as the DM control device does not implement
+    /// IORING_OP_URING_CMD, and io_uring has no operation for other ioctls.
+    pub fn snapshot_state(&self) -> DmResult<SystemState> {
+        let mut buffer = Vec::new();
+        let mut listed = list_event_nrs(self, &mut buffer)?;
+        let mut attempt = 1;
+        loop {
+            let (devices, unchanged) = read_devices(self, &listed)?;
+            let relisted = list_event_nrs(self, &mut buffer)?;
+            let consistent = unchanged && relisted == listed;
+            if consistent || attempt == SNAPSHOT_ATTEMPTS {
+                return Ok(SystemState {
+                    devices,
+                    consistent,
+                });
+            }
+            debug!(
+                "DM devices changed while their state was read, reading again, attempt {} of {}",
+                attempt + 1,
+                SNAPSHOT_ATTEMPTS
+            );
+            listed = relisted;
+            attempt += 1;
+        }
+    }
+}
+
+/// List the devices' names and, on kernels that report them, their event
+/// numbers.
+fn list_event_nrs(dm: &DM, buffer: &mut Vec<u8>) -> DmResult<Vec<(DmNameBuf, Option<u32>)>> {
+    dm.devices_iter(buffer)?
+        .map(|res| res.map(|(name, _, event_nr)| (name.to_owned(), event_nr)))
+        .collect()
+}
+
+/// Read the state of each of the devices listed. Return the states, and
+/// whether each device was found unchanged from when it was listed.
+fn read_devices(
+    dm: &DM,
+    listed: &[(DmNameBuf, Option<u32>)],
+) -> DmResult<(Vec<DeviceState>, bool)> {
+    let mut devices = Vec::with_capacity(listed.len());
+    let mut unchanged = true;
+    for (name, listed_event_nr) in listed {
+        let id = DevId::Name(name);
+        let read = dm
+            .table_status(
+                &id,
+                DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE),
+            )
+            .and_then(|(table_info, table)| {
+                dm.table_status(&id, DmOptions::default().set_no_flush(true))
+                    .map(|(info, status)| (table_info, table, info, status))
+            });
+        let (table_info, table, info, status) = match read {
+            Ok(read) => read,
+            // The device was removed after it was listed
+            Err(err) if err.errno() == Some(Errno::ENXIO) => {
+                unchanged = false;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        if table_info.event_nr() != info.event_nr()
+            || listed_event_nr.is_some_and(|event_nr| event_nr != info.event_nr())
+        {
+            unchanged = false;
+        }
+        devices.push(DeviceState {
+            status: parse_typed_status(status)?,
+            info,
+            table,
+        });
+    }
+    Ok((devices, unchanged))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        core::{devnode_to_devno, Device},
+        lineardev::{LinearDev, LinearDevTargetParams, LinearTargetParams},
+        shared::{DmDevice, TargetLine, TargetTable},
+        testing::{test_name, test_with_spec},
+    };
+
+    use super::*;
+
+    /// Verify that a linear device's table and status are read, and that
+    /// the state of an idle system is consistent.
+    fn test_snapshot_state(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let name = test_name("name").expect("valid format");
+        let dev = Device::from(devnode_to_devno(paths[0]).unwrap().unwrap());
+        let table = vec![TargetLine::new(
+            Sectors(0),
+            Sectors(8),
+            LinearDevTargetParams::Linear(LinearTargetParams::new(dev, Sectors(0))),
+        )];
+        let mut ld = LinearDev::setup(&dm, &name, None, table).unwrap();
+
+        let state = dm.snapshot_state().unwrap();
+        assert!(state.consistent);
+        let device = state.device(&name).unwrap();
+        assert_eq!(device.info.device(), ld.device());
+        assert_eq!(device.table, ld.table().to_raw_table());
+        assert_matches!(
+            device.status.as_slice(),
+            [(Sectors(0), Sectors(8), TargetStatus::Raw(_))]
+        );
+
+        ld.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_snapshot_state() {
+        test_with_spec(1, test_snapshot_state);
+    }
+}
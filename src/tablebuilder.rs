@@ -0,0 +1,382 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// A module to contain a builder for tables made up of lines of different
+// target types.
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    core::{DevId, DeviceInfo, DmOptions, DM},
+    result::{DmError, DmResult, ErrorEnum},
+    shared::{validate_table_lines, TargetLine, TargetParams, TargetType, TargetTypeBuf},
+    units::Sectors,
+};
+
+const ERROR_TARGET_NAME: &str = "error";
+const ZERO_TARGET_NAME: &str = "zero";
+
+/// Params for an error target, which fails all I/O. It takes no params.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ErrorTargetParams;
+
+impl fmt::Display for ErrorTargetParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{ERROR_TARGET_NAME}")
+    }
+}
+
+impl FromStr for ErrorTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<ErrorTargetParams> {
+        if s.trim_end() != ERROR_TARGET_NAME {
+            let err_msg = format!("Expected an error target entry but found \"{s}\"");
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        Ok(ErrorTargetParams)
+    }
+}
+
+impl TargetParams for ErrorTargetParams {
+    fn param_str(&self) -> String {
+        String::new()
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetType::ERROR.to_owned()
+    }
+}
+
+/// Params for a zero target, which reads as zeros and discards writes. It
+/// takes no params.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ZeroTargetParams;
+
+impl fmt::Display for ZeroTargetParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{ZERO_TARGET_NAME}")
+    }
+}
+
+impl FromStr for ZeroTargetParams {
+    type Err = DmError;
+
+    fn from_str(s: &str) -> DmResult<ZeroTargetParams> {
+        if s.trim_end() != ZERO_TARGET_NAME {
+            let err_msg = format!("Expected a zero target entry but found \"{s}\"");
+            return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
+        }
+        Ok(ZeroTargetParams)
+    }
+}
+
+impl TargetParams for ZeroTargetParams {
+    fn param_str(&self) -> String {
+        String::new()
+    }
+
+    fn target_type(&self) -> TargetTypeBuf {
+        TargetType::ZERO.to_owned()
+    }
+}
+
+/// The target used to fill the gaps between populated extents of a sparse
+/// table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GapFill {
+    /// Fail all I/O to the gaps
+    Error,
+    /// Read the gaps as zeros and discard writes to them
+    Zero,
+}
+
+/// A builder for a table that may combine targets of different types, e.g.,
+/// linear segments with thin or flakey ones.
+///
+/// Lines may be added in any order. When the table is built, the lines are
+/// checked to be non-empty, to cover the device from sector 0 without gaps
+/// or overlaps, and, if an alignment has been set, to be aligned.
+#[derive(Clone, Debug, Default)]
+pub struct TableBuilder {
+    alignment: Option<Sectors>,
+    lines: Vec<(Sectors, Sectors, String, String)>,
+}
+
+impl TableBuilder {
+    /// Make a new builder for an empty table.
+    pub fn new() -> TableBuilder {
+        TableBuilder::default()
+    }
+
+    /// Require that every line start at, and have a length that is, a
+    /// multiple of alignment.
+    pub fn set_alignment(mut self, alignment: Sectors) -> TableBuilder {
+        self.alignment = Some(alignment);
+        self
+    }
+
+    /// Add a line at start, of length sectors, with the given params.
+    pub fn add_line<P: TargetParams>(
+        mut self,
+        start: Sectors,
+        length: Sectors,
+        params: &P,
+    ) -> TableBuilder {
+        self.lines.push((
+            start,
+            length,
+            params.target_type().to_string(),
+            params.param_str(),
+        ));
+        self
+    }
+
+    /// Add a line of length sectors, with the given params, immediately
+    /// following the line that currently ends furthest into the device.
+    pub fn append<P: TargetParams>(self, length: Sectors, params: &P) -> TableBuilder {
+        let start = self.end();
+        self.add_line(start, length, params)
+    }
+
+    /// Add a typed target line.
+    pub fn add_target_line<P: TargetParams>(self, line: &TargetLine<P>) -> TableBuilder {
+        self.add_line(line.start, line.length, &line.params)
+    }
+
+    /// Make a builder for a sparse table of the given size, populated by
+    /// extents, with the gaps between them filled by fill.
+    pub fn sparse<P: TargetParams>(
+        size: Sectors,
+        extents: &[TargetLine<P>],
+        fill: GapFill,
+    ) -> TableBuilder {
+        extents
+            .iter()
+            .fold(TableBuilder::new(), |builder, line| {
+                builder.add_target_line(line)
+            })
+            .fill_gaps(size, fill)
+    }
+
+    /// Fill every gap between the lines added so far, and between the last
+    /// of them and size, with lines of the fill target type.
+    pub fn fill_gaps(mut self, size: Sectors, fill: GapFill) -> TableBuilder {
+        self.lines.sort_by_key(|(start, _, _, _)| *start);
+
+        let mut gaps = Vec::new();
+        let mut next = Sectors(0);
+        for (start, length, _, _) in &self.lines {
+            if *start > next {
+                gaps.push((next, *start - next));
+            }
+            next = std::cmp::max(
+                next,
+                start.checked_add(*length).unwrap_or(Sectors(u64::MAX)),
+            );
+        }
+        if size > next {
+            gaps.push((next, size - next));
+        }
+
+        gaps.into_iter()
+            .fold(self, |builder, (start, length)| match fill {
+                GapFill::Error => builder.add_line(start, length, &ErrorTargetParams),
+                GapFill::Zero => builder.add_line(start, length, &ZeroTargetParams),
+            })
+    }
+
+    /// The sector at which the line that ends furthest into the device ends.
+    /// This is the size of the device if the table is valid.
+    pub fn end(&self) -> Sectors {
+        self.lines
+            .iter()
+            .map(|(start, length, _, _)| start.checked_add(*length).unwrap_or(Sectors(u64::MAX)))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Validate the table, and return it, ordered by start sector, in the
+    /// form accepted by [`DM::table_load`].
+    pub fn build(mut self) -> DmResult<Vec<(u64, u64, String, String)>> {
+        self.lines.sort_by_key(|(start, _, _, _)| *start);
+        validate_table_lines(
+            self.lines
+                .iter()
+                .map(|(start, length, target_type, _)| (*start, *length, target_type)),
+            self.alignment,
+        )?;
+
+        Ok(self
+            .lines
+            .into_iter()
+            .map(|(start, length, target_type, params)| (*start, *length, target_type, params))
+            .collect())
+    }
+
+    /// Validate the table and load it into the inactive table slot of the
+    /// device.
+    ///
+    /// `options` Valid flags: as for [`DM::table_load`]
+    pub fn load(self, dm: &DM, id: &DevId<'_>, options: DmOptions) -> DmResult<DeviceInfo> {
+        dm.table_load(id, self.build()?, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        core::Device,
+        lineardev::{FlakeyTargetParams, LinearTargetParams},
+        thindev::ThinTargetParams,
+        thindevid::ThinDevId,
+    };
+
+    use super::*;
+
+    #[test]
+    /// Verify that lines of different types are ordered by start sector.
+    fn test_heterogeneous() {
+        let dev = "8:16".parse::<Device>().unwrap();
+        let table = TableBuilder::new()
+            .append(Sectors(8), &LinearTargetParams::new(dev, Sectors(0)))
+            .add_line(
+                Sectors(16),
+                Sectors(8),
+                &FlakeyTargetParams::new(dev, Sectors(0), 1, 1, vec![]),
+            )
+            .add_line(
+                Sectors(8),
+                Sectors(8),
+                &ThinTargetParams::new(dev, ThinDevId::new_u64(1).unwrap(), None),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            table
+                .iter()
+                .map(|(start, length, target_type, _)| (*start, *length, target_type.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(0, 8, "linear"), (8, 8, "thin"), (16, 8, "flakey")]
+        );
+        assert_eq!(table[0].3, "8:16 0");
+    }
+
+    #[test]
+    /// Verify that gaps, overlaps, empty lines, and empty tables are rejected.
+    fn test_invalid() {
+        let params = LinearTargetParams::new("8:16".parse::<Device>().unwrap(), Sectors(0));
+
+        assert_matches!(
+            TableBuilder::new().build(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            TableBuilder::new()
+                .add_line(Sectors(1), Sectors(1), &params)
+                .build(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            TableBuilder::new()
+                .append(Sectors(2), &params)
+                .add_line(Sectors(1), Sectors(2), &params)
+                .build(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            TableBuilder::new()
+                .append(Sectors(2), &params)
+                .append(Sectors(0), &params)
+                .build(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that gaps before, between, and after extents are filled, and
+    /// that error and zero params round trip.
+    fn test_sparse() {
+        let dev = "8:16".parse::<Device>().unwrap();
+        let extents = vec![
+            TargetLine::new(
+                Sectors(16),
+                Sectors(8),
+                LinearTargetParams::new(dev, Sectors(8)),
+            ),
+            TargetLine::new(
+                Sectors(4),
+                Sectors(4),
+                LinearTargetParams::new(dev, Sectors(0)),
+            ),
+        ];
+        let table = TableBuilder::sparse(Sectors(32), &extents, GapFill::Zero)
+            .build()
+            .unwrap();
+        assert_eq!(
+            table,
+            vec![
+                (0, 4, "zero".to_string(), String::new()),
+                (4, 4, "linear".to_string(), "8:16 0".to_string()),
+                (8, 8, "zero".to_string(), String::new()),
+                (16, 8, "linear".to_string(), "8:16 8".to_string()),
+                (24, 8, "zero".to_string(), String::new()),
+            ]
+        );
+
+        let table = TableBuilder::sparse::<LinearTargetParams>(Sectors(8), &[], GapFill::Error)
+            .build()
+            .unwrap();
+        assert_eq!(table, vec![(0, 8, "error".to_string(), String::new())]);
+
+        assert_eq!(
+            ErrorTargetParams
+                .to_string()
+                .parse::<ErrorTargetParams>()
+                .unwrap(),
+            ErrorTargetParams
+        );
+        assert_eq!(
+            ZeroTargetParams
+                .to_string()
+                .parse::<ZeroTargetParams>()
+                .unwrap(),
+            ZeroTargetParams
+        );
+        assert_matches!(
+            "zero".parse::<ErrorTargetParams>(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+
+    #[test]
+    /// Verify that alignment of starts and lengths is enforced.
+    fn test_alignment() {
+        let params = LinearTargetParams::new("8:16".parse::<Device>().unwrap(), Sectors(0));
+
+        assert_matches!(
+            TableBuilder::new()
+                .set_alignment(Sectors(8))
+                .append(Sectors(16), &params)
+                .append(Sectors(8), &params)
+                .build(),
+            Ok(_)
+        );
+        assert_matches!(
+            TableBuilder::new()
+                .set_alignment(Sectors(8))
+                .append(Sectors(12), &params)
+                .build(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            TableBuilder::new()
+                .set_alignment(Sectors(0))
+                .append(Sectors(8), &params)
+                .build(),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+}
@@ -5,16 +5,14 @@
 use std::{
     fs::OpenOptions,
     io::{self, Seek, SeekFrom, Write},
-    panic,
     path::{Path, PathBuf},
 };
 
 use loopdev::{LoopControl, LoopDevice};
-use tempfile::{self, TempDir};
+use tempfile::TempDir;
 
 use crate::{
     consts::IEC,
-    testing::{logger::init_logger, test_lib::clean_up},
     units::{Bytes, Sectors, SECTOR_SIZE},
 };
 
@@ -37,7 +35,7 @@ fn write_sectors<P: AsRef<Path>>(
 }
 
 /// Zero sectors at the given offset for length sectors.
-fn wipe_sectors<P: AsRef<Path>>(path: P, offset: Sectors, length: Sectors) -> io::Result<()> {
+pub fn wipe_sectors<P: AsRef<Path>>(path: P, offset: Sectors, length: Sectors) -> io::Result<()> {
     write_sectors(path, offset, length, &[0u8; SECTOR_SIZE])
 }
 
@@ -68,7 +66,7 @@ impl LoopTestDev {
         LoopTestDev { ld }
     }
 
-    fn path(&self) -> PathBuf {
+    pub fn path(&self) -> PathBuf {
         self.ld.path().unwrap()
     }
 
@@ -84,9 +82,9 @@ impl Drop for LoopTestDev {
 }
 
 /// Setup count loop backed devices in dir.
-/// Make sure each loop device is backed by a sparse 1 GiB file. The entire file will read back
-/// as initialized with zero.
-fn get_devices(count: u8, dir: &TempDir) -> Vec<LoopTestDev> {
+/// Make sure each loop device is backed by a sparse file of the given size. The entire file will
+/// read back as initialized with zero.
+pub fn get_devices(count: u8, size: Bytes, dir: &TempDir) -> Vec<LoopTestDev> {
     let lc = LoopControl::open().unwrap();
     let mut loop_devices = Vec::new();
 
@@ -100,7 +98,7 @@ fn get_devices(count: u8, dir: &TempDir) -> Vec<LoopTestDev> {
             .open(&path)
             .unwrap();
 
-        nix::unistd::ftruncate(&f, IEC::Gi as nix::libc::off_t).unwrap();
+        nix::unistd::ftruncate(&f, *size as nix::libc::off_t).unwrap();
         f.sync_all().unwrap();
 
         let ltd = LoopTestDev::new(&lc, &path);
@@ -109,28 +107,3 @@ fn get_devices(count: u8, dir: &TempDir) -> Vec<LoopTestDev> {
     }
     loop_devices
 }
-
-/// Set up count loopbacked devices.
-/// Then, run the designated test.
-/// Then, take down the loop devices.
-pub fn test_with_spec<F>(count: u8, test: F)
-where
-    F: Fn(&[&Path]) + panic::RefUnwindSafe,
-{
-    init_logger();
-    clean_up().unwrap();
-
-    let tmpdir = tempfile::Builder::new()
-        .prefix("devicemapper")
-        .tempdir()
-        .unwrap();
-    let loop_devices: Vec<LoopTestDev> = get_devices(count, &tmpdir);
-    let device_paths: Vec<PathBuf> = loop_devices.iter().map(|x| x.path()).collect();
-    let device_paths: Vec<&Path> = device_paths.iter().map(|x| x.as_path()).collect();
-
-    let result = panic::catch_unwind(|| test(&device_paths));
-    let tear_down = clean_up();
-
-    result.unwrap();
-    tear_down.unwrap();
-}
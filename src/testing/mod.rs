@@ -6,11 +6,13 @@
 
 mod logger;
 mod loopbacked;
+mod scratch;
 mod test_lib;
 
 pub use self::{
-    loopbacked::test_with_spec,
+    scratch::{test_with_devices, test_with_spec},
     test_lib::{
         blkdev_size, test_name, test_string, test_uuid, udev_settle, xfs_create_fs, xfs_set_uuid,
+        DeviceRequirements,
     },
 };
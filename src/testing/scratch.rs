@@ -0,0 +1,333 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+};
+
+use tempfile::TempDir;
+
+use crate::{
+    consts::IEC,
+    testing::{
+        logger::init_logger,
+        loopbacked::{get_devices, wipe_sectors, LoopTestDev},
+        test_lib::{
+            clean_up, missing_capability, module_exists, skip_test, test_devices_from_env,
+            udev_settle, CleanupGuard, DeviceRequirements, SkipReason,
+        },
+    },
+    units::{Bytes, Sectors},
+};
+
+/// The environment variable that selects the kind of scratch device that
+/// tests run with test_with_spec are given.
+const SCRATCH_KIND_VAR: &str = "DM_TEST_SCRATCH";
+
+const ZRAM_CONTROL_PATH: &str = "/sys/class/zram-control";
+
+/// The kind of block device that tests are run on. Loop devices pass
+/// discards and flushes on to the filesystem of their backing file, so
+/// tests of those semantics may prefer RAM-backed devices.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScratchKind {
+    /// Loop devices, each backed by a sparse file in a temporary directory
+    Loop,
+    /// zram devices, added to any that already exist
+    Zram,
+    /// brd ramdisks; the brd module must not be loaded already, as its
+    /// ramdisks may be in use
+    Brd,
+}
+
+impl ScratchKind {
+    /// The kind named by DM_TEST_SCRATCH, "loop", "zram" or "brd"; loop if
+    /// it is unset. If it names no kind, the reason to skip the test.
+    pub fn from_env() -> Result<ScratchKind, SkipReason> {
+        match env::var(SCRATCH_KIND_VAR).as_deref() {
+            Err(env::VarError::NotPresent) | Ok("loop") => Ok(ScratchKind::Loop),
+            Ok("zram") => Ok(ScratchKind::Zram),
+            Ok("brd") => Ok(ScratchKind::Brd),
+            Ok(value) => Err(SkipReason::DevicesUnavailable(format!(
+                "{SCRATCH_KIND_VAR}={value} is not one of loop, zram or brd"
+            ))),
+            Err(err) => Err(SkipReason::DevicesUnavailable(format!(
+                "{SCRATCH_KIND_VAR} is not valid: {err}"
+            ))),
+        }
+    }
+
+    /// Why devices of this kind can not be provisioned, if they can not.
+    fn unavailable(&self) -> Option<SkipReason> {
+        let (available, reason) = match self {
+            ScratchKind::Loop => (
+                Path::new("/dev/loop-control").exists(),
+                "/dev/loop-control does not exist",
+            ),
+            ScratchKind::Zram => (
+                Path::new(ZRAM_CONTROL_PATH).exists() || module_exists("zram"),
+                "the zram module is not available",
+            ),
+            ScratchKind::Brd => (
+                !Path::new("/sys/module/brd").exists() && module_exists("brd"),
+                "the brd module is not available, or is loaded already",
+            ),
+        };
+        if available {
+            None
+        } else {
+            Some(SkipReason::DevicesUnavailable(reason.to_string()))
+        }
+    }
+}
+
+/// Report that a scratch device could not be released. Panicking while the
+/// test's panic unwinds would abort, and hide the test's failure.
+fn release_failed(msg: &str) {
+    if thread::panicking() {
+        eprintln!("failed to release a scratch device: {msg}");
+    } else {
+        panic!("failed to release a scratch device: {msg}");
+    }
+}
+
+fn modprobe(module: &str, params: &[String]) {
+    let status = Command::new("modprobe")
+        .arg(module)
+        .args(params)
+        .status()
+        .unwrap();
+    assert!(status.success(), "modprobe {module} failed: {status}");
+}
+
+/// A zram device, removed when dropped.
+struct ZramTestDev {
+    id: u32,
+}
+
+impl ZramTestDev {
+    fn new(size: Bytes) -> ZramTestDev {
+        if !Path::new(ZRAM_CONTROL_PATH).exists() {
+            modprobe("zram", &["num_devices=0".to_string()]);
+        }
+        let id = fs::read_to_string(Path::new(ZRAM_CONTROL_PATH).join("hot_add"))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let dev = ZramTestDev { id };
+        fs::write(dev.sysfs_path().join("disksize"), (*size).to_string()).unwrap();
+        dev
+    }
+
+    fn sysfs_path(&self) -> PathBuf {
+        PathBuf::from(format!("/sys/block/zram{}", self.id))
+    }
+
+    fn path(&self) -> PathBuf {
+        PathBuf::from(format!("/dev/zram{}", self.id))
+    }
+}
+
+impl Drop for ZramTestDev {
+    fn drop(&mut self) {
+        let result = fs::write(self.sysfs_path().join("reset"), "1").and_then(|_| {
+            fs::write(
+                Path::new(ZRAM_CONTROL_PATH).join("hot_remove"),
+                self.id.to_string(),
+            )
+        });
+        if let Err(err) = result {
+            release_failed(&format!("zram{}: {err}", self.id));
+        }
+    }
+}
+
+/// The ramdisks of the brd module, which is removed when this is dropped.
+struct BrdTestDevs {
+    count: u8,
+}
+
+impl BrdTestDevs {
+    fn new(count: u8, size: Bytes) -> BrdTestDevs {
+        modprobe(
+            "brd",
+            &[
+                format!("rd_nr={count}"),
+                format!("rd_size={}", *size / u128::from(IEC::Ki)),
+                "max_part=0".to_string(),
+            ],
+        );
+        BrdTestDevs { count }
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        (0..self.count)
+            .map(|index| PathBuf::from(format!("/dev/ram{index}")))
+            .collect()
+    }
+}
+
+impl Drop for BrdTestDevs {
+    fn drop(&mut self) {
+        match Command::new("modprobe").args(["-r", "brd"]).status() {
+            Ok(status) if status.success() => (),
+            Ok(status) => release_failed(&format!("modprobe -r brd failed: {status}")),
+            Err(err) => release_failed(&format!("modprobe -r brd failed: {err}")),
+        }
+    }
+}
+
+/// Scratch devices of one kind, released when dropped.
+enum ScratchDevs {
+    // The devices are dropped before the directory that holds their files.
+    Loop {
+        devs: Vec<LoopTestDev>,
+        _tmpdir: TempDir,
+    },
+    Zram(Vec<ZramTestDev>),
+    Brd(BrdTestDevs),
+    Real(Vec<PathBuf>),
+}
+
+impl ScratchDevs {
+    fn new(kind: ScratchKind, count: u8, size: Bytes) -> ScratchDevs {
+        match kind {
+            ScratchKind::Loop => {
+                let tmpdir = tempfile::Builder::new()
+                    .prefix("devicemapper")
+                    .tempdir()
+                    .unwrap();
+                ScratchDevs::Loop {
+                    devs: get_devices(count, size, &tmpdir),
+                    _tmpdir: tmpdir,
+                }
+            }
+            ScratchKind::Zram => {
+                ScratchDevs::Zram((0..count).map(|_| ZramTestDev::new(size)).collect())
+            }
+            ScratchKind::Brd => ScratchDevs::Brd(BrdTestDevs::new(count, size)),
+        }
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        match self {
+            ScratchDevs::Loop { devs, .. } => devs.iter().map(|dev| dev.path()).collect(),
+            ScratchDevs::Zram(devs) => devs.iter().map(|dev| dev.path()).collect(),
+            ScratchDevs::Brd(devs) => devs.paths(),
+            ScratchDevs::Real(paths) => paths.clone(),
+        }
+    }
+}
+
+/// Run the test on the devices, then clean up the DM devices and filesystems
+/// that the test left, whether it passed or panicked.
+fn run_test<F>(devices: &ScratchDevs, test: F)
+where
+    F: Fn(&[&Path]),
+{
+    udev_settle().unwrap();
+    let device_paths: Vec<PathBuf> = devices.paths();
+    let device_paths: Vec<&Path> = device_paths.iter().map(|x| x.as_path()).collect();
+
+    // Dropped before the devices are released, which DM devices may hold.
+    let _guard = CleanupGuard;
+    test(&device_paths);
+}
+
+/// Set up count scratch devices of the given kind and size, each of which
+/// reads back as zeroed.
+/// Then, run the designated test.
+/// Then, release the devices.
+pub fn test_with_scratch<F>(kind: ScratchKind, count: u8, size: Bytes, test: F)
+where
+    F: Fn(&[&Path]),
+{
+    init_logger();
+    if let Some(reason) = missing_capability(&[]).or_else(|| kind.unavailable()) {
+        return skip_test(&reason);
+    }
+    clean_up().unwrap();
+
+    let devices = ScratchDevs::new(kind, count, size);
+    run_test(&devices, test);
+}
+
+/// Get devices that meet the requirements: the real devices named by
+/// DM_TEST_DEVICES if it is set, or else scratch devices of the kind
+/// selected by DM_TEST_SCRATCH, loop devices by default.
+/// Then, run the designated test.
+/// Then, release the devices.
+/// If the requirements can not be met, or the test can not use DM and the
+/// targets it requires here, it is skipped.
+pub fn test_with_devices<F>(requirements: DeviceRequirements, test: F)
+where
+    F: Fn(&[&Path]),
+{
+    init_logger();
+    if let Some(reason) = missing_capability(requirements.required_targets()) {
+        return skip_test(&reason);
+    }
+    clean_up().unwrap();
+
+    let devices = match test_devices_from_env(&requirements) {
+        Some(Ok(paths)) => {
+            // Devicemapper data may be left on the devices by earlier runs.
+            for path in &paths {
+                wipe_sectors(path, Sectors(0), Bytes(u128::from(IEC::Mi)).sectors()).unwrap();
+            }
+            ScratchDevs::Real(paths)
+        }
+        Some(Err(reason)) => return skip_test(&reason),
+        None => {
+            let kind = match ScratchKind::from_env() {
+                Ok(kind) => kind,
+                Err(reason) => return skip_test(&reason),
+            };
+            if let Some(reason) = kind.unavailable() {
+                return skip_test(&reason);
+            }
+            ScratchDevs::new(kind, requirements.count(), requirements.size())
+        }
+    };
+    run_test(&devices, test);
+}
+
+/// Set up count devices of at least 1 GiB, as test_with_devices does.
+/// Then, run the designated test.
+/// Then, release the devices.
+pub fn test_with_spec<F>(count: u8, test: F)
+where
+    F: Fn(&[&Path]),
+{
+    test_with_devices(DeviceRequirements::new(count), test)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use crate::testing::test_lib::blkdev_size;
+
+    use super::*;
+
+    /// Verify that each kind of scratch device is provisioned with the
+    /// size requested.
+    #[test]
+    fn sudo_test_scratch_sizes() {
+        let size = Bytes(u128::from(64 * IEC::Mi));
+        for kind in [ScratchKind::Loop, ScratchKind::Zram, ScratchKind::Brd] {
+            test_with_scratch(kind, 2, size, |paths| {
+                assert_eq!(paths.len(), 2);
+                for path in paths {
+                    let file = OpenOptions::new().read(true).open(path).unwrap();
+                    assert_eq!(blkdev_size(&file), size);
+                }
+            });
+        }
+    }
+}
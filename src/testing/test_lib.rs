@@ -3,35 +3,41 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::{
-    fs::File,
+    env,
+    fs::{File, OpenOptions},
     io::Read,
     os::unix::io::AsRawFd,
     panic::catch_unwind,
     path::{Path, PathBuf},
     process::Command,
-    sync::Once,
+    thread,
 };
 
 use nix::mount::{umount2, MntFlags};
+use once_cell::sync::OnceCell;
 use uuid::Uuid;
 
 use crate::{
+    consts::IEC,
     core::{DevId, Device, DmNameBuf, DmOptions, DmUuidBuf, DM},
+    filesystem::{Filesystems, FsType},
     result::{DmError, DmResult, ErrorEnum},
     units::Bytes,
 };
 
-static INIT: Once = Once::new();
-static mut DM_CONTEXT: Option<DM> = None;
+static DM_CONTEXT: OnceCell<DM> = OnceCell::new();
+
+/// The DM control device, through which DM's ioctls are made.
+const DM_CONTROL_PATH: &str = "/dev/mapper/control";
 
 impl DM {
     /// Returns a subset of the devices returned by list_devices(), namely
-    /// the devices whose names end with DM_TEST_ID, our test device suffix.
+    /// the devices whose names contain this run's test suffix.
     /// This function is useful for listing devices in tests that should not
-    /// take non-test devices into account.
+    /// take non-test devices, or other runs' test devices, into account.
     pub fn list_test_devices(&self) -> Result<Vec<(DmNameBuf, Device, Option<u32>)>> {
         let mut test_devs = self.list_devices()?;
-        test_devs.retain(|x| x.0.as_bytes().ends_with(DM_TEST_ID.as_bytes()));
+        test_devs.retain(|x| x.0.to_string().contains(run_suffix()));
         Ok(test_devs)
     }
 }
@@ -58,24 +64,238 @@ pub fn blkdev_size(file: &File) -> Bytes {
     Bytes(u128::from(val))
 }
 
-fn get_dm() -> &'static DM {
-    unsafe {
-        INIT.call_once(|| DM_CONTEXT = Some(DM::new().unwrap()));
-        match DM_CONTEXT {
-            Some(ref context) => context,
-            _ => panic!("DM_CONTEXT.is_some()"),
+/// The environment variable that names real block devices, separated by
+/// colons, for tests to use instead of scratch devices. Whatever the
+/// devices contain is destroyed.
+const TEST_DEVICES_VAR: &str = "DM_TEST_DEVICES";
+
+/// The block devices that a test needs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DeviceRequirements {
+    count: u8,
+    min_size: Bytes,
+    targets: &'static [&'static str],
+}
+
+impl DeviceRequirements {
+    /// Require count devices, each of at least 1 GiB.
+    pub fn new(count: u8) -> DeviceRequirements {
+        DeviceRequirements {
+            count,
+            min_size: Bytes(u128::from(IEC::Gi)),
+            targets: &[],
         }
     }
+
+    /// Require that each device be at least size.
+    pub fn min_size(mut self, size: Bytes) -> DeviceRequirements {
+        self.min_size = size;
+        self
+    }
+
+    /// Require the given DM targets, besides those always built in.
+    pub fn targets(mut self, targets: &'static [&'static str]) -> DeviceRequirements {
+        self.targets = targets;
+        self
+    }
+
+    /// The DM targets required.
+    pub fn required_targets(&self) -> &'static [&'static str] {
+        self.targets
+    }
+
+    /// The number of devices required.
+    pub fn count(&self) -> u8 {
+        self.count
+    }
+
+    /// The size each device must have at least.
+    pub fn size(&self) -> Bytes {
+        self.min_size
+    }
+
+    /// Why the given devices do not meet the requirements, if they do not.
+    fn unmet_by(&self, paths: &[PathBuf]) -> Option<String> {
+        if paths.len() < usize::from(self.count) {
+            return Some(format!(
+                "{} devices are required, but only {} are given",
+                self.count,
+                paths.len()
+            ));
+        }
+        for path in paths {
+            let size = match OpenOptions::new().read(true).open(path) {
+                Ok(file) => blkdev_size(&file),
+                Err(err) => return Some(format!("{} can not be opened: {err}", path.display())),
+            };
+            if size < self.min_size {
+                return Some(format!(
+                    "{} is {size}, but {} is required",
+                    path.display(),
+                    self.min_size
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// The real devices named by DM_TEST_DEVICES, as many as are required, if
+/// the variable is set; otherwise None. If the devices do not meet the
+/// requirements, the reason why is returned instead.
+pub fn test_devices_from_env(
+    requirements: &DeviceRequirements,
+) -> Option<std::result::Result<Vec<PathBuf>, SkipReason>> {
+    let value = env::var_os(TEST_DEVICES_VAR)?;
+    let paths = env::split_paths(&value)
+        .filter(|path| !path.as_os_str().is_empty())
+        .take(usize::from(requirements.count))
+        .collect::<Vec<_>>();
+    Some(match requirements.unmet_by(&paths) {
+        Some(reason) => Err(SkipReason::DevicesUnavailable(format!(
+            "{TEST_DEVICES_VAR}: {reason}"
+        ))),
+        None => Ok(paths),
+    })
+}
+
+/// Why a test is skipped rather than run.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SkipReason {
+    /// The test is not run as root, e.g. in an unprivileged container
+    NotPrivileged {
+        /// Whether the test appears to run in a container
+        in_container: bool,
+    },
+    /// The DM control device is missing, or can not be opened
+    NoDmControl(String),
+    /// A DM target the test needs is neither loaded nor loadable
+    MissingTarget(String),
+    /// The block devices the test needs can not be had
+    DevicesUnavailable(String),
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::NotPrivileged { in_container } => write!(
+                f,
+                "not running as root{}",
+                if *in_container {
+                    ", in a container"
+                } else {
+                    ""
+                }
+            ),
+            SkipReason::NoDmControl(msg) => write!(f, "DM is not available: {msg}"),
+            SkipReason::MissingTarget(target) => {
+                write!(f, "the DM target {target} is not available")
+            }
+            SkipReason::DevicesUnavailable(msg) => write!(f, "devices are not available: {msg}"),
+        }
+    }
+}
+
+/// Report that a test is skipped, and why. The test then returns without
+/// running, and so passes.
+pub fn skip_test(reason: &SkipReason) {
+    eprintln!("skipping test: {reason}");
+}
+
+/// Whether the tests appear to run in a container, as detected by the
+/// files and variable that docker, podman and systemd-nspawn set.
+fn in_container() -> bool {
+    Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+        || env::var_os("container").is_some()
+}
+
+/// Whether the module may be loaded, without loading it.
+pub fn module_exists(module: &str) -> bool {
+    Command::new("modprobe")
+        .args(["-n", "-q", module])
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// The names of the targets loaded in the kernel.
+#[cfg(devicemapper41supported)]
+fn loaded_targets(dm: &DM) -> Vec<String> {
+    dm.list_versions()
+        .map(|versions| versions.into_iter().map(|(name, ..)| name).collect())
+        .unwrap_or_default()
+}
+
+/// The names of the targets loaded in the kernel, which can not be listed.
+#[cfg(not(devicemapper41supported))]
+fn loaded_targets(_: &DM) -> Vec<String> {
+    Vec::new()
+}
+
+/// Why a test that uses DM, and the given targets, can not run here, if it
+/// can not. Targets that are not loaded yet are available if their modules
+/// may be loaded, as the kernel loads them on demand.
+pub fn missing_capability(targets: &[&str]) -> Option<SkipReason> {
+    if unsafe { nix::libc::geteuid() } != 0 {
+        return Some(SkipReason::NotPrivileged {
+            in_container: in_container(),
+        });
+    }
+    if !Path::new(DM_CONTROL_PATH).exists() {
+        return Some(SkipReason::NoDmControl(format!(
+            "{DM_CONTROL_PATH} does not exist"
+        )));
+    }
+    let dm = match DM::new() {
+        Ok(dm) => dm,
+        Err(err) => return Some(SkipReason::NoDmControl(err.to_string())),
+    };
+    let loaded = loaded_targets(&dm);
+    targets
+        .iter()
+        .find(|target| {
+            !loaded.iter().any(|name| name == *target) && !module_exists(&format!("dm-{target}"))
+        })
+        .map(|target| SkipReason::MissingTarget(target.to_string()))
+}
+
+fn get_dm() -> &'static DM {
+    DM_CONTEXT.get_or_init(|| DM::new().unwrap())
 }
 
 /// String that is to be concatenated with test supplied name to identify
 /// devices and filesystems generated by tests.
 static DM_TEST_ID: &str = "_dm-rs_test_delme";
 
-/// Generate a string with an identifying test suffix
+/// The environment variable that, if set, makes clean_up() remove the test
+/// devices and filesystems of all runs, rather than only this run's.
+const PURGE_ALL_VAR: &str = "DM_TEST_PURGE_ALL";
+
+/// The suffix of this run's test names: DM_TEST_ID, preceded by an id that
+/// is random for each test process, so that runs on one host at the same
+/// time do not clean up each other's devices.
+fn run_suffix() -> &'static str {
+    static RUN_SUFFIX: OnceCell<String> = OnceCell::new();
+    RUN_SUFFIX.get_or_init(|| {
+        let id = Uuid::new_v4().simple().to_string();
+        format!("_{}{DM_TEST_ID}", &id[..8])
+    })
+}
+
+/// The string that identifies the names of the devices and filesystems
+/// that clean_up() removes.
+fn clean_up_marker() -> &'static str {
+    if env::var_os(PURGE_ALL_VAR).is_some() {
+        DM_TEST_ID
+    } else {
+        run_suffix()
+    }
+}
+
+/// Generate a string with an identifying test suffix, unique to this run
 pub fn test_string(name: &str) -> String {
     let mut namestr = String::from(name);
-    namestr.push_str(DM_TEST_ID);
+    namestr.push_str(run_suffix());
     namestr
 }
 
@@ -101,26 +321,16 @@ fn execute_cmd(cmd: &mut Command) -> DmResult<()> {
 
 /// Generate an XFS FS
 pub fn xfs_create_fs(devnode: &Path, uuid: Option<Uuid>) -> DmResult<()> {
-    let mut command = Command::new("mkfs.xfs");
-    command.arg("-f");
-    command.arg("-q");
-    command.arg(devnode);
-
-    if let Some(uuid) = uuid {
-        command.arg("-m");
-        command.arg(format!("uuid={uuid}"));
-    }
-    execute_cmd(&mut command)
+    Filesystems::new().mkfs(
+        devnode,
+        FsType::Xfs,
+        uuid.map(|uuid| uuid.to_string()).as_deref(),
+    )
 }
 
 /// Set a UUID for a XFS volume.
 pub fn xfs_set_uuid(devnode: &Path, uuid: &Uuid) -> DmResult<()> {
-    execute_cmd(
-        Command::new("xfs_admin")
-            .arg("-U")
-            .arg(format!("{uuid}"))
-            .arg(devnode),
-    )
+    Filesystems::new().set_uuid(devnode, FsType::Xfs, &uuid.to_string())
 }
 
 /// Wait for udev activity to be done.
@@ -153,6 +363,32 @@ mod cleanup_errors {
 
     pub type Result<T> = std::result::Result<T, Error>;
 
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Error::Ioe(err) => write!(f, "IO error: {err}"),
+                Error::Mnt(err) => write!(f, "mountinfo parse error: {err}"),
+                Error::Nix(err) => write!(f, "nix error: {err}"),
+                Error::Msg(msg) => write!(f, "{msg}"),
+                Error::Chained(msg, _) => write!(f, "{msg}"),
+                Error::Dm(err) => write!(f, "{err}"),
+            }
+        }
+    }
+
+    impl std::error::Error for Error {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                Error::Ioe(err) => Some(err),
+                Error::Mnt(err) => Some(err),
+                Error::Nix(err) => Some(err),
+                Error::Msg(_) => None,
+                Error::Chained(_, err) => Some(err.as_ref()),
+                Error::Dm(err) => Some(err),
+            }
+        }
+    }
+
     impl From<nix::Error> for Error {
         fn from(err: nix::Error) -> Error {
             Error::Nix(err)
@@ -186,56 +422,29 @@ mod cleanup_errors {
 
 use self::cleanup_errors::{Error, Result};
 
-/// Attempt to remove all device mapper devices which match the test naming convention.
-/// FIXME: Current implementation complicated by https://bugzilla.redhat.com/show_bug.cgi?id=1506287
+/// Attempt to remove all device mapper devices which match the test naming convention, those
+/// of this run only unless DM_TEST_PURGE_ALL is set.
 fn dm_test_devices_remove() -> Result<()> {
-    /// One iteration of removing devicemapper devices
-    fn one_iteration() -> Result<(bool, Vec<String>)> {
-        let mut progress_made = false;
-        let mut remain = Vec::new();
+    || -> Result<()> {
+        if catch_unwind(get_dm).is_err() {
+            return Err("Unable to initialize DM".to_string().into());
+        }
 
-        for n in get_dm()
-            .list_test_devices()
+        let marker = clean_up_marker();
+        while let Some((name, _, _)) = get_dm()
+            .list_devices()
             .map_err(|e| {
                 Error::Chained(
                     "failed while listing DM devices, giving up".into(),
-                    Box::new(e),
+                    Box::new(Error::Dm(e)),
                 )
             })?
-            .iter()
-            .map(|d| &d.0)
+            .into_iter()
+            .rfind(|(name, _, _)| name.to_string().contains(marker))
         {
-            match get_dm().device_remove(&DevId::Name(n), DmOptions::default()) {
-                Ok(_) => progress_made = true,
-                Err(_) => remain.push(n.to_string()),
-            }
-        }
-        Ok((progress_made, remain))
-    }
-
-    /// Do one iteration of removals until progress stops. Return remaining
-    /// dm devices.
-    fn do_while_progress() -> Result<Vec<String>> {
-        let mut result = one_iteration()?;
-        while result.0 {
-            result = one_iteration()?;
+            get_dm().remove_recursive(&DevId::Name(&name), DmOptions::default())?;
         }
-        Ok(result.1)
-    }
-
-    || -> Result<()> {
-        if catch_unwind(get_dm).is_err() {
-            return Err("Unable to initialize DM".to_string().into());
-        }
-
-        do_while_progress().and_then(|remain| {
-            if !remain.is_empty() {
-                let err_msg = format!("Some test-generated DM devices remaining: {remain:?}");
-                Err(err_msg.into())
-            } else {
-                Ok(())
-            }
-        })
+        Ok(())
     }()
     .map_err(|e| {
         Error::Chained(
@@ -245,7 +454,8 @@ fn dm_test_devices_remove() -> Result<()> {
     })
 }
 
-/// Unmount any filesystems that contain DM_TEST_ID in the mount point.
+/// Unmount any filesystems that contain this run's test suffix, or
+/// DM_TEST_ID if all runs' are purged, in the mount point.
 /// Return immediately on the first unmount failure.
 fn dm_test_fs_unmount() -> Result<()> {
     || -> Result<()> {
@@ -256,7 +466,7 @@ fn dm_test_fs_unmount() -> Result<()> {
         for mount_point in parser
             .filter_map(|x| x.ok())
             .filter_map(|m| m.mount_point.into_owned().into_string().ok())
-            .filter(|mp| mp.contains(DM_TEST_ID))
+            .filter(|mp| mp.contains(clean_up_marker()))
         {
             umount2(&PathBuf::from(mount_point), MntFlags::MNT_DETACH)?;
         }
@@ -270,8 +480,27 @@ fn dm_test_fs_unmount() -> Result<()> {
     })
 }
 
-/// Unmount any filesystems or devicemapper devices which contain DM_TEST_ID
-/// in the path or name. Immediately return on first error.
+/// Unmount any filesystems or devicemapper devices which contain this run's
+/// test suffix in the path or name, or DM_TEST_ID if DM_TEST_PURGE_ALL is
+/// set. Immediately return on first error.
 pub fn clean_up() -> Result<()> {
     dm_test_fs_unmount().and_then(|_| dm_test_devices_remove())
 }
+
+/// Cleans up test filesystems and DM devices when dropped, as clean_up()
+/// does, even if the test panicked.
+pub struct CleanupGuard;
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        if let Err(err) = clean_up() {
+            // Panicking while the test's panic unwinds would abort, and hide
+            // the test's failure.
+            if thread::panicking() {
+                eprintln!("failed to clean up after the test: {err}");
+            } else {
+                panic!("failed to clean up after the test: {err}");
+            }
+        }
+    }
+}
@@ -8,15 +8,17 @@ use crate::{
     core::{DevId, Device, DeviceInfo, DmFlags, DmName, DmOptions, DmUuid, DM},
     result::{DmError, DmResult, ErrorEnum},
     shared::{
-        device_create, device_exists, device_match, get_status, get_status_line_fields, message,
-        parse_device, parse_value, DmDevice, TargetLine, TargetParams, TargetTable, TargetTypeBuf,
+        device_adopt, device_create, device_info_if_exists, get_status, get_status_line_fields,
+        message, parse_device, parse_value, resume_or_clear, split_params, validate_table,
+        DmDevice, ParamStr, TableMismatch, TargetLine, TargetParams, TargetTable, TargetType,
+        TargetTypeBuf,
     },
     thindevid::ThinDevId,
     thinpooldev::ThinPoolDev,
     units::Sectors,
 };
 
-const THIN_TARGET_NAME: &str = "thin";
+pub(crate) const THIN_TARGET_NAME: &str = "thin";
 
 /// Struct representing params for a thin target
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -47,7 +49,7 @@ impl ThinTargetParams {
 
 impl fmt::Display for ThinTargetParams {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", THIN_TARGET_NAME, self.param_str())
+        write!(f, "{} {}", THIN_TARGET_NAME, ParamStr(self))
     }
 }
 
@@ -55,7 +57,8 @@ impl FromStr for ThinTargetParams {
     type Err = DmError;
 
     fn from_str(s: &str) -> DmResult<ThinTargetParams> {
-        let vals = s.split(' ').collect::<Vec<_>>();
+        let vals = split_params(s);
+        let vals = vals.iter().map(String::as_str).collect::<Vec<_>>();
         let len = vals.len();
         if !(3..=4).contains(&len) {
             let err_msg = format!("expected 3 or 4 values in params string \"{s}\", found {len}");
@@ -87,14 +90,19 @@ impl FromStr for ThinTargetParams {
 
 impl TargetParams for ThinTargetParams {
     fn param_str(&self) -> String {
-        match self.external_origin_dev {
-            None => format!("{} {}", self.pool, self.thin_id),
-            Some(dev) => format!("{} {} {}", self.pool, self.thin_id, dev),
+        ParamStr(self).to_string()
+    }
+
+    fn fmt_params(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.pool, self.thin_id)?;
+        if let Some(dev) = self.external_origin_dev {
+            write!(f, " {dev}")?;
         }
+        Ok(())
     }
 
     fn target_type(&self) -> TargetTypeBuf {
-        TargetTypeBuf::new(THIN_TARGET_NAME.into()).expect("THIN_TARGET_NAME is valid")
+        TargetType::THIN.to_owned()
     }
 }
 
@@ -122,6 +130,8 @@ impl fmt::Display for ThinDevTargetTable {
 }
 
 impl TargetTable for ThinDevTargetTable {
+    type Params = ThinTargetParams;
+
     fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<ThinDevTargetTable> {
         if table.len() != 1 {
             let err_msg = format!(
@@ -138,8 +148,8 @@ impl TargetTable for ThinDevTargetTable {
         ))
     }
 
-    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
-        to_raw_table_unique!(self)
+    fn lines(&self) -> &[TargetLine<Self::Params>] {
+        std::slice::from_ref(&self.table)
     }
 }
 
@@ -169,9 +179,13 @@ impl DmDevice<ThinDevTargetTable> for ThinDev {
         name!(self)
     }
 
-    fn resume(&mut self, dm: &DM) -> DmResult<()> {
-        dm.device_suspend(&DevId::Name(self.name()), DmOptions::default())?;
-        Ok(())
+    fn record_table(&mut self, table: ThinDevTargetTable) {
+        self.table = table;
+    }
+
+    fn resume(&mut self, dm: &DM) -> DmResult<u32> {
+        dm.device_suspend(&DevId::Name(self.name()), DmOptions::default())
+            .map(|info| info.event_nr())
     }
 
     fn size(&self) -> Sectors {
@@ -199,8 +213,17 @@ pub struct ThinDevWorkingStatus {
     pub nr_mapped_sectors: Sectors,
     /// The highest mapped sector if any.
     pub highest_mapped_sector: Option<Sectors>,
+    /// Any fields following those above, which newer kernels may report.
+    pub extra: Vec<String>,
 }
 
+serialize_fields!(
+    ThinDevWorkingStatus,
+    nr_mapped_sectors,
+    highest_mapped_sector,
+    extra
+);
+
 impl ThinDevWorkingStatus {
     /// Make a new ThinDevWorkingStatus struct
     pub fn new(
@@ -210,6 +233,7 @@ impl ThinDevWorkingStatus {
         ThinDevWorkingStatus {
             nr_mapped_sectors,
             highest_mapped_sector,
+            extra: Vec::new(),
         }
     }
 }
@@ -226,6 +250,8 @@ pub enum ThinStatus {
     Fail,
 }
 
+serialize_status!(ThinStatus);
+
 impl FromStr for ThinStatus {
     type Err = DmError;
 
@@ -248,9 +274,17 @@ impl FromStr for ThinStatus {
             Some(Sectors(parse_value(status_vals[1], "highest used sector")?))
         };
 
-        Ok(ThinStatus::Working(Box::new(ThinDevWorkingStatus::new(
-            count, highest,
-        ))))
+        let mut status = ThinDevWorkingStatus::new(count, highest);
+        status.extra = status_vals.iter().skip(2).map(|v| v.to_string()).collect();
+        Ok(ThinStatus::Working(Box::new(status)))
+    }
+}
+
+impl ThinStatus {
+    /// Parse a status line, like from_str(). A thin device's status has no
+    /// flags, so there is nothing more to tolerate.
+    pub fn from_str_lenient(status_line: &str) -> DmResult<ThinStatus> {
+        status_line.parse()
     }
 }
 
@@ -268,15 +302,18 @@ impl ThinDev {
         thin_pool: &ThinPoolDev,
         thin_id: ThinDevId,
     ) -> DmResult<ThinDev> {
+        let thin_pool_device = thin_pool.device();
+        let table = ThinDev::gen_default_table(length, thin_pool_device, thin_id);
+        // Check the table before the thin id is allocated in the pool.
+        validate_table(&table)?;
+
         message(dm, thin_pool, &format!("create_thin {thin_id}"))?;
 
-        if device_exists(dm, name)? {
+        if device_info_if_exists(dm, name)?.is_some() {
             let err_msg = "Uncreated device should not be known to kernel";
             return Err(DmError::Dm(ErrorEnum::Invalid, err_msg.into()));
         }
 
-        let thin_pool_device = thin_pool.device();
-        let table = ThinDev::gen_default_table(length, thin_pool_device, thin_id);
         let dev_info = device_create(dm, name, uuid, &table, DmOptions::default())?;
 
         Ok(ThinDev {
@@ -294,6 +331,9 @@ impl ThinDev {
     ///
     /// If the device has no thin id already registered with the thin pool
     /// an error is returned.
+    ///
+    /// Like [`crate::LinearDev::setup`], this takes two ioctls if the device
+    /// already exists, and four if it is created.
     pub fn setup(
         dm: &DM,
         name: &DmName,
@@ -301,16 +341,38 @@ impl ThinDev {
         length: Sectors,
         thin_pool: &ThinPoolDev,
         thin_id: ThinDevId,
+    ) -> DmResult<ThinDev> {
+        ThinDev::ensure(
+            dm,
+            name,
+            uuid,
+            length,
+            thin_pool,
+            thin_id,
+            TableMismatch::Error,
+        )
+    }
+
+    /// Like [`ThinDev::setup`], except that if the device already exists
+    /// with a different table, policy determines whether an error is
+    /// returned or the device's table is replaced.
+    pub fn ensure(
+        dm: &DM,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        length: Sectors,
+        thin_pool: &ThinPoolDev,
+        thin_id: ThinDevId,
+        policy: TableMismatch,
     ) -> DmResult<ThinDev> {
         let thin_pool_device = thin_pool.device();
         let table = ThinDev::gen_default_table(length, thin_pool_device, thin_id);
-        let dev = if device_exists(dm, name)? {
-            let dev_info = dm.device_info(&DevId::Name(name))?;
-            let dev = ThinDev {
+        let dev = if let Some(dev_info) = device_info_if_exists(dm, name)? {
+            let mut dev = ThinDev {
                 dev_info: Box::new(dev_info),
                 table,
             };
-            device_match(dm, &dev, uuid)?;
+            device_adopt(dm, &mut dev, uuid, policy)?;
             dev
         } else {
             let dev_info = device_create(dm, name, uuid, &table, DmOptions::default())?;
@@ -391,9 +453,10 @@ impl ThinDev {
     /// Set the table for the thin device's target
     pub fn set_table(&mut self, dm: &DM, table: TargetLine<ThinTargetParams>) -> DmResult<()> {
         let table = ThinDevTargetTable::new(table.start, table.length, table.params);
+        validate_table(&table)?;
         self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
         self.table_load(dm, &table, DmOptions::default())?;
-        self.resume(dm)?;
+        resume_or_clear(dm, self)?;
 
         self.table = table;
         Ok(())
@@ -424,7 +487,7 @@ mod tests {
     use crate::{
         consts::IEC,
         core::errors::Error,
-        shared::DmDevice,
+        shared::{device_exists, DmDevice},
         testing::{
             blkdev_size, test_name, test_string, test_uuid, test_with_spec, udev_settle,
             xfs_create_fs, xfs_set_uuid,
@@ -453,7 +516,7 @@ mod tests {
                 &tp,
                 ThinDevId::new_u64(0).expect("is below limit")
             ),
-            Err(_)
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
         );
 
         udev_settle().unwrap();
@@ -0,0 +1,365 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Read-only access to the block mappings of thin devices, read from a
+// snapshot of a thin pool's metadata, in the on-disk format written by the
+// kernel's persistent-data library.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    ops::Deref,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::Path,
+};
+
+use nix::libc::O_DIRECT;
+
+use crate::{
+    core::errors,
+    result::{DmError, DmResult, ErrorEnum},
+    thindevid::ThinDevId,
+    units::{DataBlocks, MetaBlocks},
+};
+
+/// The size of a block of thin pool metadata, in bytes.
+const META_BLOCK_BYTES: usize = 4096;
+
+const THIN_SUPERBLOCK_MAGIC: u64 = 27022010;
+const SUPERBLOCK_MAGIC_OFFSET: usize = 32;
+const DATA_MAPPING_ROOT_OFFSET: usize = 320;
+
+const NODE_HEADER_SIZE: usize = 32;
+const INTERNAL_NODE: u32 = 1;
+const LEAF_NODE: u32 = 2;
+
+/// The btrees are shallow, so a deeper one must be corrupt, or contain a
+/// cycle.
+const MAX_BTREE_DEPTH: usize = 16;
+
+/// The low 24 bits of a mapping's value are the time at which the block
+/// was mapped, the rest is the data block.
+const MAPPING_TIME_BITS: u32 = 24;
+
+/// A run of a thin device's blocks that are mapped to consecutive blocks of
+/// its pool's data device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ThinMappedRange {
+    /// The first block of the thin device
+    pub thin_start: DataBlocks,
+    /// The data block to which thin_start is mapped
+    pub data_start: DataBlocks,
+    /// The number of blocks in the run
+    pub length: DataBlocks,
+}
+
+/// The block mappings of a thin device.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ThinMappings {
+    ranges: Vec<ThinMappedRange>,
+}
+
+impl ThinMappings {
+    /// The runs of mapped blocks, in order of the thin device's blocks.
+    pub fn mapped(&self) -> &[ThinMappedRange] {
+        &self.ranges
+    }
+
+    /// The number of mapped blocks.
+    pub fn mapped_blocks(&self) -> DataBlocks {
+        self.ranges.iter().map(|range| range.length).sum()
+    }
+
+    /// The runs of unmapped blocks, as start and length, of a thin device
+    /// of size blocks, in order.
+    pub fn unmapped(&self, size: DataBlocks) -> Vec<(DataBlocks, DataBlocks)> {
+        let mut unmapped = Vec::new();
+        let mut next = DataBlocks(0);
+        for range in &self.ranges {
+            if range.thin_start >= size {
+                break;
+            }
+            if range.thin_start > next {
+                unmapped.push((next, range.thin_start - next));
+            }
+            next = range.thin_start + range.length;
+        }
+        if next < size {
+            unmapped.push((next, size - next));
+        }
+        unmapped
+    }
+
+    fn push(&mut self, thin_block: u64, data_block: u64) {
+        if let Some(last) = self.ranges.last_mut() {
+            if *last.thin_start + *last.length == thin_block
+                && *last.data_start + *last.length == data_block
+            {
+                last.length += DataBlocks(1);
+                return;
+            }
+        }
+        self.ranges.push(ThinMappedRange {
+            thin_start: DataBlocks(thin_block),
+            data_start: DataBlocks(data_block),
+            length: DataBlocks(1),
+        });
+    }
+}
+
+/// A block of metadata, aligned so that it may be read with O_DIRECT.
+#[repr(C, align(4096))]
+struct MetaBlock([u8; META_BLOCK_BYTES]);
+
+impl Deref for MetaBlock {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+struct MetadataReader<'a> {
+    devnode: &'a Path,
+    file: File,
+}
+
+impl<'a> MetadataReader<'a> {
+    /// Open the metadata device for direct I/O, as thin_dump(8) does. The
+    /// kernel writes the metadata through dm-bufio, not the page cache, so
+    /// the page cache may hold stale copies of its blocks. A file on a
+    /// filesystem that does not support direct I/O, e.g. a copy of the
+    /// metadata, is read through the page cache.
+    fn open(devnode: &'a Path) -> DmResult<MetadataReader<'a>> {
+        let io_err = |err: io::Error| {
+            DmError::Core(errors::Error::MetadataIo(devnode.into(), err.to_string()))
+        };
+        let file = match OpenOptions::new()
+            .read(true)
+            .custom_flags(O_DIRECT)
+            .open(devnode)
+        {
+            Err(err) if err.raw_os_error() == Some(nix::libc::EINVAL) => {
+                File::open(devnode).map_err(io_err)?
+            }
+            result => result.map_err(io_err)?,
+        };
+        Ok(MetadataReader { devnode, file })
+    }
+
+    fn corrupt(&self, block: u64, what: &str) -> DmError {
+        let err_msg = format!(
+            "thin pool metadata on {} is invalid at block {block}: {what}",
+            self.devnode.display()
+        );
+        DmError::Dm(ErrorEnum::Invalid, err_msg)
+    }
+
+    fn read_block(&self, block: u64) -> DmResult<Box<MetaBlock>> {
+        let offset = block
+            .checked_mul(META_BLOCK_BYTES as u64)
+            .ok_or_else(|| self.corrupt(block, "block is beyond the device's end"))?;
+        let mut buf = Box::new(MetaBlock([0u8; META_BLOCK_BYTES]));
+        self.file.read_exact_at(&mut buf.0, offset).map_err(|err| {
+            DmError::Core(errors::Error::MetadataIo(
+                self.devnode.into(),
+                err.to_string(),
+            ))
+        })?;
+        Ok(buf)
+    }
+
+    /// Visit the entries of the btree rooted at block, in order of their
+    /// keys, with the keys and the values, which must be 8 bytes long.
+    fn walk<F>(&self, block: u64, depth: usize, visit: &mut F) -> DmResult<()>
+    where
+        F: FnMut(u64, u64) -> DmResult<()>,
+    {
+        if depth > MAX_BTREE_DEPTH {
+            return Err(self.corrupt(block, "btree is too deep"));
+        }
+
+        let node = self.read_block(block)?;
+        let flags = le_u32(&node, 4);
+        let nr_entries = le_u32(&node, 16) as usize;
+        let max_entries = le_u32(&node, 20) as usize;
+        let value_size = le_u32(&node, 24) as usize;
+        if le_u64(&node, 8) != block {
+            return Err(self.corrupt(block, "btree node is not at its own location"));
+        }
+        if value_size != 8
+            || nr_entries > max_entries
+            || NODE_HEADER_SIZE + max_entries * 16 > META_BLOCK_BYTES
+        {
+            return Err(self.corrupt(block, "btree node header is inconsistent"));
+        }
+
+        let values_offset = NODE_HEADER_SIZE + max_entries * 8;
+        for i in 0..nr_entries {
+            let key = le_u64(&node, NODE_HEADER_SIZE + i * 8);
+            let value = le_u64(&node, values_offset + i * 8);
+            match flags {
+                INTERNAL_NODE => self.walk(value, depth + 1, visit)?,
+                LEAF_NODE => visit(key, value)?,
+                _ => return Err(self.corrupt(block, "btree node is neither internal nor leaf")),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Read the block mappings of the thin device thin_id from the snapshot of
+/// a thin pool's metadata held at held_root on the pool's metadata device,
+/// meta_devnode. The held root is reported in the pool's status, see
+/// [`crate::ThinPoolWorkingStatus::held_metadata_root`], once a snapshot has
+/// been reserved, see [`crate::ThinPoolDev::reserve_metadata_snap`].
+///
+/// The live metadata changes as the pool is written, and so may not be read
+/// consistently, but the snapshot does not change until it is released.
+pub fn read_thin_mappings(
+    meta_devnode: &Path,
+    held_root: MetaBlocks,
+    thin_id: ThinDevId,
+) -> DmResult<ThinMappings> {
+    let reader = MetadataReader::open(meta_devnode)?;
+
+    let superblock = reader.read_block(*held_root)?;
+    if le_u64(&superblock, SUPERBLOCK_MAGIC_OFFSET) != THIN_SUPERBLOCK_MAGIC {
+        return Err(reader.corrupt(*held_root, "block is not a thin pool superblock"));
+    }
+    let mapping_root = le_u64(&superblock, DATA_MAPPING_ROOT_OFFSET);
+
+    // The top level btree maps each thin device to the root of the btree of
+    // its own mappings.
+    let thin_id = u64::from(u32::from(thin_id));
+    let mut thin_root = None;
+    reader.walk(mapping_root, 0, &mut |key, value| {
+        if key == thin_id {
+            thin_root = Some(value);
+        }
+        Ok(())
+    })?;
+    let thin_root = thin_root.ok_or_else(|| {
+        let err_msg = format!("thin device {thin_id} is not in the metadata snapshot");
+        DmError::Dm(ErrorEnum::NotFound, err_msg)
+    })?;
+
+    let mut mappings = ThinMappings::default();
+    reader.walk(thin_root, 0, &mut |key, value| {
+        mappings.push(key, value >> MAPPING_TIME_BITS);
+        Ok(())
+    })?;
+    Ok(mappings)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Make a btree node at block with the given flags, keys and values.
+    fn node(block: u64, flags: u32, entries: &[(u64, u64)]) -> Vec<u8> {
+        let max_entries = 126;
+        let mut node = vec![0u8; META_BLOCK_BYTES];
+        node[4..8].copy_from_slice(&flags.to_le_bytes());
+        node[8..16].copy_from_slice(&block.to_le_bytes());
+        node[16..20].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+        node[20..24].copy_from_slice(&(max_entries as u32).to_le_bytes());
+        node[24..28].copy_from_slice(&8u32.to_le_bytes());
+        for (i, &(key, value)) in entries.iter().enumerate() {
+            let key_offset = NODE_HEADER_SIZE + i * 8;
+            let value_offset = NODE_HEADER_SIZE + max_entries * 8 + i * 8;
+            node[key_offset..key_offset + 8].copy_from_slice(&key.to_le_bytes());
+            node[value_offset..value_offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        node
+    }
+
+    #[test]
+    /// Verify that mappings are read through the two levels of btrees and
+    /// coalesced into runs.
+    fn test_read_thin_mappings() {
+        let mut superblock = vec![0u8; META_BLOCK_BYTES];
+        superblock[SUPERBLOCK_MAGIC_OFFSET..SUPERBLOCK_MAGIC_OFFSET + 8]
+            .copy_from_slice(&THIN_SUPERBLOCK_MAGIC.to_le_bytes());
+        superblock[DATA_MAPPING_ROOT_OFFSET..DATA_MAPPING_ROOT_OFFSET + 8]
+            .copy_from_slice(&1u64.to_le_bytes());
+
+        let mapping = |data_block: u64| data_block << MAPPING_TIME_BITS | 3;
+        let blocks = [
+            superblock,
+            node(1, LEAF_NODE, &[(0, 2), (7, 0)]),
+            node(2, INTERNAL_NODE, &[(0, 3), (5, 4)]),
+            node(3, LEAF_NODE, &[(0, mapping(10)), (1, mapping(11))]),
+            node(4, LEAF_NODE, &[(5, mapping(12)), (6, mapping(13))]),
+        ];
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for block in &blocks {
+            file.write_all(block).unwrap();
+        }
+
+        let mappings =
+            read_thin_mappings(file.path(), MetaBlocks(0), ThinDevId::new_u64(0).unwrap()).unwrap();
+        assert_eq!(
+            mappings.mapped(),
+            &[
+                ThinMappedRange {
+                    thin_start: DataBlocks(0),
+                    data_start: DataBlocks(10),
+                    length: DataBlocks(2),
+                },
+                ThinMappedRange {
+                    thin_start: DataBlocks(5),
+                    data_start: DataBlocks(12),
+                    length: DataBlocks(2),
+                },
+            ]
+        );
+        assert_eq!(mappings.mapped_blocks(), DataBlocks(4));
+        assert_eq!(
+            mappings.unmapped(DataBlocks(10)),
+            vec![
+                (DataBlocks(2), DataBlocks(3)),
+                (DataBlocks(7), DataBlocks(3))
+            ]
+        );
+
+        assert_matches!(
+            read_thin_mappings(file.path(), MetaBlocks(0), ThinDevId::new_u64(1).unwrap()),
+            Err(DmError::Dm(ErrorEnum::NotFound, _))
+        );
+        // The root of the thin device with id 7 is not a btree node
+        assert_matches!(
+            read_thin_mappings(file.path(), MetaBlocks(0), ThinDevId::new_u64(7).unwrap()),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        assert_matches!(
+            read_thin_mappings(file.path(), MetaBlocks(1), ThinDevId::new_u64(0).unwrap()),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        // The offset of the block overflows
+        assert_matches!(
+            read_thin_mappings(
+                file.path(),
+                MetaBlocks(u64::MAX),
+                ThinDevId::new_u64(0).unwrap()
+            ),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+    }
+}
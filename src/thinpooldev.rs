@@ -5,15 +5,19 @@
 use std::{collections::hash_set::HashSet, fmt, path::PathBuf, str::FromStr};
 
 use crate::{
+    consts::IEC,
     core::{DevId, Device, DeviceInfo, DmFlags, DmName, DmOptions, DmUuid, DM},
     lineardev::{LinearDev, LinearDevTargetParams},
     result::{DmError, DmResult, ErrorEnum},
     shared::{
-        device_create, device_exists, device_match, get_status, get_status_line_fields,
-        make_unexpected_value_error, parse_device, parse_value, DmDevice, TargetLine, TargetParams,
-        TargetTable, TargetTypeBuf,
+        check_superblock_magic, device_create, device_info_if_exists, device_match, escape_param,
+        get_status, get_status_line_fields, make_unexpected_value_error, message, parse_device,
+        parse_value, resume_or_clear, split_params, suspend_or_clear, validate_block_size,
+        DmDevice, TargetLine, TargetParams, TargetTable, TargetType, TargetTypeBuf,
     },
-    units::{DataBlocks, MetaBlocks, Sectors},
+    thindevid::ThinDevId,
+    thinmeta::{read_thin_mappings, ThinMappings},
+    units::{DataBlocks, MetaBlocks, Sectors, MAX_META_DEV_SIZE},
 };
 
 #[cfg(test)]
@@ -22,7 +26,38 @@ use std::path::Path;
 #[cfg(test)]
 use crate::core::devnode_to_devno;
 
-const THINPOOL_TARGET_NAME: &str = "thin-pool";
+// Specified in kernel docs
+/// The minimum size of a thin pool's data block.
+pub const MIN_DATA_BLOCK_SIZE: Sectors = Sectors(128); // 64 KiB
+/// The maximum size of a thin pool's data block.
+pub const MAX_DATA_BLOCK_SIZE: Sectors = Sectors(2 * IEC::Mi); // 1 GiB
+
+/// Check that data_block_size is one the kernel accepts for a thin pool: a
+/// multiple of MIN_DATA_BLOCK_SIZE, no greater than MAX_DATA_BLOCK_SIZE.
+pub fn validate_data_block_size(data_block_size: Sectors) -> DmResult<()> {
+    validate_block_size(
+        data_block_size,
+        MIN_DATA_BLOCK_SIZE,
+        MAX_DATA_BLOCK_SIZE,
+        "thin pool data block size",
+    )
+}
+
+pub(crate) const THINPOOL_TARGET_NAME: &str = "thin-pool";
+
+/// The magic number of a thin pool's metadata superblock.
+const THINPOOL_SUPERBLOCK_MAGIC: u64 = 27022010;
+
+/// The minimum size recommended in the kernel docs for a metadata device.
+const MIN_RECOMMENDED_METADATA_SIZE: Sectors = Sectors(4 * IEC::Ki); // 2 MiB
+
+/// The number of entries in a full metadata btree leaf node: a 4 KiB block
+/// less its 32 byte header, at 16 bytes per key/value pair.
+const BTREE_ENTRIES_PER_NODE: u64 = 254;
+
+/// The number of reference counts held by one space map bitmap block: a
+/// 4 KiB block less its 16 byte header, at 2 bits per reference count.
+const SPACE_MAP_ENTRIES_PER_BLOCK: u64 = 4080 * 4;
 
 /// Struct representing params for a thin pool target
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -68,7 +103,8 @@ impl FromStr for ThinPoolTargetParams {
     type Err = DmError;
 
     fn from_str(s: &str) -> DmResult<ThinPoolTargetParams> {
-        let vals = s.split(' ').collect::<Vec<_>>();
+        let vals = split_params(s);
+        let vals = vals.iter().map(String::as_str).collect::<Vec<_>>();
 
         if vals.len() < 5 {
             let err_msg = format!(
@@ -117,15 +153,14 @@ impl TargetParams for ThinPoolTargetParams {
         let feature_args = if self.feature_args.is_empty() {
             "0".to_owned()
         } else {
-            format!(
-                "{} {}",
-                self.feature_args.len(),
-                self.feature_args
-                    .iter()
-                    .cloned()
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            )
+            // Sorted, so that equal params always have equal param strings
+            let mut args = self
+                .feature_args
+                .iter()
+                .map(|x| escape_param(x))
+                .collect::<Vec<_>>();
+            args.sort();
+            format!("{} {}", args.len(), args.join(" "))
         };
 
         format!(
@@ -139,7 +174,7 @@ impl TargetParams for ThinPoolTargetParams {
     }
 
     fn target_type(&self) -> TargetTypeBuf {
-        TargetTypeBuf::new(THINPOOL_TARGET_NAME.into()).expect("THINPOOL_TARGET_NAME is valid")
+        TargetType::THIN_POOL.to_owned()
     }
 }
 
@@ -171,6 +206,8 @@ impl fmt::Display for ThinPoolDevTargetTable {
 }
 
 impl TargetTable for ThinPoolDevTargetTable {
+    type Params = ThinPoolTargetParams;
+
     fn from_raw_table(table: &[(u64, u64, String, String)]) -> DmResult<ThinPoolDevTargetTable> {
         if table.len() != 1 {
             let err_msg = format!(
@@ -187,8 +224,8 @@ impl TargetTable for ThinPoolDevTargetTable {
         ))
     }
 
-    fn to_raw_table(&self) -> Vec<(u64, u64, String, String)> {
-        to_raw_table_unique!(self)
+    fn lines(&self) -> &[TargetLine<Self::Params>] {
+        std::slice::from_ref(&self.table)
     }
 }
 
@@ -232,6 +269,10 @@ impl DmDevice<ThinPoolDevTargetTable> for ThinPoolDev {
         name!(self)
     }
 
+    fn record_table(&mut self, table: ThinPoolDevTargetTable) {
+        self.table = table;
+    }
+
     fn size(&self) -> Sectors {
         self.data_dev.size()
     }
@@ -266,6 +307,8 @@ pub struct ThinPoolUsage {
     pub total_data: DataBlocks,
 }
 
+serialize_fields!(ThinPoolUsage, used_meta, total_meta, used_data, total_data);
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 /// Indicates if a working thinpool is working optimally, or is
 /// experiencing a non-fatal error condition.
@@ -278,6 +321,13 @@ pub enum ThinPoolStatusSummary {
     OutOfSpace,
 }
 
+serialize_names!(
+    ThinPoolStatusSummary,
+    Good => "good",
+    ReadOnly => "read_only",
+    OutOfSpace => "out_of_space"
+);
+
 /// Policy if no space on device
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ThinPoolNoSpacePolicy {
@@ -287,6 +337,8 @@ pub enum ThinPoolNoSpacePolicy {
     Queue,
 }
 
+serialize_names!(ThinPoolNoSpacePolicy, Error => "error", Queue => "queue");
+
 /// Status of a working thin pool, i.e, one that does not have status Fail
 #[derive(Debug, Clone)]
 pub struct ThinPoolWorkingStatus {
@@ -296,7 +348,9 @@ pub struct ThinPoolWorkingStatus {
     pub usage: ThinPoolUsage,
     /// A single block value indicating the held metadata root
     pub held_metadata_root: Option<MetaBlocks>,
-    /// discard_passdown/no_discard_passdown
+    /// discard_passdown/no_discard_passdown. The kernel disables passdown,
+    /// whatever the table requests, if the data device does not support
+    /// discards.
     pub discard_passdown: bool,
     /// no space policy
     pub no_space_policy: ThinPoolNoSpacePolicy,
@@ -307,8 +361,24 @@ pub struct ThinPoolWorkingStatus {
     /// The lowater value for the metadata device in metablocks. This value
     /// is set by the kernel. Available in kernel version 4.19 and later.
     pub meta_low_water: Option<u64>,
+    /// Any fields following those above, which newer kernels may report,
+    /// and, if the status was parsed leniently, any unrecognized flags.
+    pub extra: Vec<String>,
 }
 
+serialize_fields!(
+    ThinPoolWorkingStatus,
+    transaction_id,
+    usage,
+    held_metadata_root,
+    discard_passdown,
+    no_space_policy,
+    summary,
+    needs_check,
+    meta_low_water,
+    extra
+);
+
 impl ThinPoolWorkingStatus {
     /// Make a new ThinPoolWorkingStatus struct
     #[allow(clippy::too_many_arguments)]
@@ -331,6 +401,7 @@ impl ThinPoolWorkingStatus {
             summary,
             needs_check,
             meta_low_water,
+            extra: Vec::new(),
         }
     }
 }
@@ -346,10 +417,25 @@ pub enum ThinPoolStatus {
     Fail,
 }
 
+serialize_status!(ThinPoolStatus);
+
 impl FromStr for ThinPoolStatus {
     type Err = DmError;
 
     fn from_str(status_line: &str) -> DmResult<ThinPoolStatus> {
+        ThinPoolStatus::parse(status_line, false)
+    }
+}
+
+impl ThinPoolStatus {
+    /// Parse a status line, like from_str(), but return any word that is
+    /// not recognized in place of the discard passdown or needs check flags
+    /// in the extra field, taking the flag to be unset, rather than failing.
+    pub fn from_str_lenient(status_line: &str) -> DmResult<ThinPoolStatus> {
+        ThinPoolStatus::parse(status_line, true)
+    }
+
+    fn parse(status_line: &str, lenient: bool) -> DmResult<ThinPoolStatus> {
         if status_line.starts_with("Error") {
             return Ok(ThinPoolStatus::Error);
         }
@@ -387,9 +473,15 @@ impl FromStr for ThinPoolStatus {
             }
         };
 
+        let mut extra = Vec::new();
+
         let discard_passdown = match status_vals[5] {
             "discard_passdown" => true,
             "no_discard_passdown" => false,
+            val if lenient => {
+                extra.push(val.to_string());
+                false
+            }
             val => {
                 return Err(make_unexpected_value_error(6, val, "discard passdown"));
             }
@@ -406,6 +498,10 @@ impl FromStr for ThinPoolStatus {
         let needs_check = match status_vals[7] {
             "-" => false,
             "needs_check" => true,
+            val if lenient => {
+                extra.push(val.to_string());
+                false
+            }
             val => {
                 return Err(make_unexpected_value_error(8, val, "needs check"));
             }
@@ -415,18 +511,20 @@ impl FromStr for ThinPoolStatus {
             .get(8)
             .and_then(|v| parse_value(v, "meta low water").ok());
 
-        Ok(ThinPoolStatus::Working(Box::new(
-            ThinPoolWorkingStatus::new(
-                transaction_id,
-                usage,
-                held_metadata_root,
-                discard_passdown,
-                no_space_policy,
-                summary,
-                needs_check,
-                meta_low_water,
-            ),
-        )))
+        extra.extend(status_vals.iter().skip(9).map(|v| v.to_string()));
+
+        let mut status = ThinPoolWorkingStatus::new(
+            transaction_id,
+            usage,
+            held_metadata_root,
+            discard_passdown,
+            no_space_policy,
+            summary,
+            needs_check,
+            meta_low_water,
+        );
+        status.extra = extra;
+        Ok(ThinPoolStatus::Working(Box::new(status)))
     }
 }
 
@@ -450,7 +548,8 @@ impl ThinPoolDev {
         low_water_mark: DataBlocks,
         feature_args: Vec<String>,
     ) -> DmResult<ThinPoolDev> {
-        if device_exists(dm, name)? {
+        validate_data_block_size(data_block_size)?;
+        if device_info_if_exists(dm, name)?.is_some() {
             let err_msg = format!("thinpooldev {name} already exists");
             return Err(DmError::Dm(ErrorEnum::Invalid, err_msg));
         }
@@ -488,6 +587,9 @@ impl ThinPoolDev {
     /// on the metadata device. If the metadata is corrupted, subsequent
     /// errors will result, so it is expected that the metadata is
     /// well-formed and consistent with the data on the data device.
+    ///
+    /// Like [`LinearDev::setup`], this takes two ioctls if the device
+    /// already exists, and four if it is created.
     #[allow(clippy::too_many_arguments)]
     pub fn setup(
         dm: &DM,
@@ -499,10 +601,10 @@ impl ThinPoolDev {
         low_water_mark: DataBlocks,
         feature_args: Vec<String>,
     ) -> DmResult<ThinPoolDev> {
+        validate_data_block_size(data_block_size)?;
         let table =
             ThinPoolDev::gen_table(&meta, &data, data_block_size, low_water_mark, feature_args);
-        let dev = if device_exists(dm, name)? {
-            let dev_info = dm.device_info(&DevId::Name(name))?;
+        let dev = if let Some(dev_info) = device_info_if_exists(dm, name)? {
             let dev = ThinPoolDev {
                 dev_info: Box::new(dev_info),
                 meta_dev: meta,
@@ -523,6 +625,35 @@ impl ThinPoolDev {
         Ok(dev)
     }
 
+    /// Set up a thin pool from metadata and data devices that already hold
+    /// a pool, as [`ThinPoolDev::setup`] does, but first check that the
+    /// metadata device begins with a thin pool superblock. Returns an error
+    /// if it does not, so that a device that was never formatted, or holds
+    /// something else, is not mistaken for a pool's metadata.
+    #[allow(clippy::too_many_arguments)]
+    pub fn adopt(
+        dm: &DM,
+        name: &DmName,
+        uuid: Option<&DmUuid>,
+        meta: LinearDev,
+        data: LinearDev,
+        data_block_size: Sectors,
+        low_water_mark: DataBlocks,
+        feature_args: Vec<String>,
+    ) -> DmResult<ThinPoolDev> {
+        check_superblock_magic(&meta.devnode(), THINPOOL_SUPERBLOCK_MAGIC, "thin pool")?;
+        ThinPoolDev::setup(
+            dm,
+            name,
+            uuid,
+            meta,
+            data,
+            data_block_size,
+            low_water_mark,
+            feature_args,
+        )
+    }
+
     /// Generate a table to be passed to DM. The format of the table
     /// entries is:
     /// <start sec (0)> <length> "thin-pool" <thin-pool-specific string>
@@ -564,10 +695,56 @@ impl ThinPoolDev {
 
     /// Get the current status of the thinpool.
     /// Returns an error if there was an error getting the status value.
+    ///
+    /// Unless DM_NOFLUSH is set in options, see [`DmOptions::set_no_flush`],
+    /// the pool commits its metadata before reporting its status.
     pub fn status(&self, dm: &DM, options: DmOptions) -> DmResult<ThinPoolStatus> {
         status!(self, dm, options)
     }
 
+    /// Take a snapshot of the pool's metadata, which can then be read
+    /// consistently while the pool is in use. Only one snapshot may be held
+    /// at a time. Its location is reported in the pool's status as the held
+    /// metadata root.
+    pub fn reserve_metadata_snap(&self, dm: &DM) -> DmResult<()> {
+        message(dm, self, "reserve_metadata_snap")
+    }
+
+    /// Release the snapshot of the pool's metadata.
+    pub fn release_metadata_snap(&self, dm: &DM) -> DmResult<()> {
+        message(dm, self, "release_metadata_snap")
+    }
+
+    /// Read the block mappings of the thin device thin_id from a snapshot
+    /// of the pool's metadata, e.g., to copy only the blocks of the thin
+    /// device that are mapped. A snapshot is reserved for the read and
+    /// released afterwards, so no other snapshot may be held.
+    pub fn thin_mappings(&self, dm: &DM, thin_id: ThinDevId) -> DmResult<ThinMappings> {
+        self.reserve_metadata_snap(dm)?;
+        let result = match self.status(dm, DmOptions::default().set_no_flush(true)) {
+            Ok(ThinPoolStatus::Working(status)) => match status.held_metadata_root {
+                Some(held_root) => read_thin_mappings(&self.meta_dev.devnode(), held_root, thin_id),
+                None => Err(DmError::Dm(
+                    ErrorEnum::Error,
+                    format!("thin pool {} reports no held metadata root", self.name()),
+                )),
+            },
+            Ok(_) => Err(DmError::Dm(
+                ErrorEnum::Error,
+                format!("thin pool {} has failed", self.name()),
+            )),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = self.release_metadata_snap(dm) {
+            warn!(
+                "Failed to release the metadata snapshot of {}: {}",
+                self.name(),
+                err
+            );
+        }
+        result
+    }
+
     /// Set the table for the existing metadata device.
     /// This action puts the device in a state where it is ready to be resumed.
     /// Warning: It is the client's responsibility to make sure the designated
@@ -626,9 +803,9 @@ impl ThinPoolDev {
 
             self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
             self.table_load(dm, &table, DmOptions::default())?;
-            self.table = table;
+            resume_or_clear(dm, self)?;
 
-            self.resume(dm)?;
+            self.table = table;
         }
 
         Ok(())
@@ -641,9 +818,9 @@ impl ThinPoolDev {
 
             self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
             self.table_load(dm, &table, DmOptions::default())?;
-            self.table = table;
+            resume_or_clear(dm, self)?;
 
-            self.resume(dm)?;
+            self.table = table;
         }
 
         Ok(())
@@ -710,6 +887,167 @@ impl ThinPoolDev {
     pub fn discard_passdown(&mut self, dm: &DM) -> DmResult<()> {
         self.unset_feature_arg("no_discard_passdown", dm)
     }
+
+    /// Whether the table requests that discards be passed down.
+    pub fn discard_passdown_requested(&self) -> bool {
+        !self
+            .table
+            .table
+            .params
+            .feature_args
+            .contains("no_discard_passdown")
+    }
+
+    /// Whether discards are actually passed down, according to the pool's
+    /// status. This may differ from what the table requests. Return None
+    /// if the pool has failed.
+    pub fn discard_passdown_active(&self, dm: &DM) -> DmResult<Option<bool>> {
+        match self.status(dm, DmOptions::default().set_no_flush(true))? {
+            ThinPoolStatus::Working(status) => Ok(Some(status.discard_passdown)),
+            ThinPoolStatus::Error | ThinPoolStatus::Fail => Ok(None),
+        }
+    }
+
+    /// Request that discards be passed down, or not, and reload the table
+    /// even if it already requests it, so that the kernel reconsiders
+    /// whether passdown can be enabled, e.g. after the data device has
+    /// been changed. Return whether discards are then actually passed down,
+    /// as for [`ThinPoolDev::discard_passdown_active`].
+    pub fn reload_discard_passdown(&mut self, dm: &DM, enable: bool) -> DmResult<Option<bool>> {
+        let mut table = self.table().clone();
+        if enable {
+            table
+                .table
+                .params
+                .feature_args
+                .remove("no_discard_passdown");
+        } else {
+            table
+                .table
+                .params
+                .feature_args
+                .insert("no_discard_passdown".to_string());
+        }
+
+        self.suspend(dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))?;
+        self.table_load(dm, &table, DmOptions::default())?;
+        resume_or_clear(dm, self)?;
+        self.table = table;
+
+        let active = self.discard_passdown_active(dm)?;
+        if active == Some(!enable) {
+            warn!(
+                "Discard passdown {} requested for thin pool {}, but is {}",
+                if enable { "was" } else { "was not" },
+                self.name(),
+                if enable { "disabled" } else { "enabled" },
+            );
+        }
+        Ok(active)
+    }
+
+    /// Try to return a pool which has fallen back to read-only mode to
+    /// read-write mode, by reloading its table unchanged, then suspending and
+    /// resuming it. On resume, the kernel sets the pool's mode anew from its
+    /// table, which asks for read-write mode. Return whether the pool is
+    /// then in read-write mode.
+    ///
+    /// If the pool is already in read-write mode, nothing is done. Nor is
+    /// anything done, and false returned, if the pool is out of data space,
+    /// since only extending the data device can help; if the needs_check
+    /// flag is set, since the kernel will not write to the metadata until it
+    /// has been checked, e.g. with thin_check(8), and the flag cleared; or if
+    /// the pool has failed, since the kernel never lets a pool leave failed
+    /// mode.
+    pub fn recover(&mut self, dm: &DM) -> DmResult<bool> {
+        match self.status(dm, DmOptions::default().set_no_flush(true))? {
+            ThinPoolStatus::Working(status) => match status.summary {
+                ThinPoolStatusSummary::Good => return Ok(true),
+                ThinPoolStatusSummary::OutOfSpace => return Ok(false),
+                ThinPoolStatusSummary::ReadOnly if status.needs_check => {
+                    warn!(
+                        "Thin pool {} is read-only and its metadata needs checking",
+                        self.name()
+                    );
+                    return Ok(false);
+                }
+                ThinPoolStatusSummary::ReadOnly => (),
+            },
+            ThinPoolStatus::Fail => {
+                warn!(
+                    "Thin pool {} has failed and can not be recovered",
+                    self.name()
+                );
+                return Ok(false);
+            }
+            ThinPoolStatus::Error => (),
+        }
+
+        // The table is loaded before the pool is suspended, so that if it
+        // can not be loaded, the pool is not left suspended.
+        let table = self.table().clone();
+        self.table_load(dm, &table, DmOptions::default())?;
+        suspend_or_clear(
+            dm,
+            self,
+            DmOptions::default().set_flags(DmFlags::DM_NOFLUSH),
+        )?;
+        resume_or_clear(dm, self)?;
+
+        match self.status(dm, DmOptions::default().set_no_flush(true))? {
+            ThinPoolStatus::Working(status) if status.summary == ThinPoolStatusSummary::Good => {
+                info!("Thin pool {} returned to read-write mode", self.name());
+                Ok(true)
+            }
+            status => {
+                warn!(
+                    "Thin pool {} is not in read-write mode after reloading its table: {:?}",
+                    self.name(),
+                    status
+                );
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Estimate the size of the metadata device required by a thin pool, in the
+/// manner of thin_metadata_size(8).
+///
+/// data_size is the size of the pool's data device, data_block_size the
+/// pool's allocation unit, and max_thins the greatest number of thin devices
+/// and snapshots that are expected to exist in the pool at once. The estimate
+/// assumes that metadata btree nodes are, on average, two thirds full. It is
+/// never less than the minimum size recommended in the kernel docs and never
+/// more than the largest metadata device the kernel can make use of.
+pub fn thin_metadata_size(
+    data_size: Sectors,
+    data_block_size: Sectors,
+    max_thins: u64,
+) -> DmResult<MetaBlocks> {
+    if data_block_size == Sectors(0) {
+        let err_msg = "thin pool data block size must be greater than 0";
+        return Err(DmError::Dm(ErrorEnum::Invalid, err_msg.into()));
+    }
+
+    let nr_blocks = *data_size / *data_block_size + u64::from(*data_size % *data_block_size != 0);
+
+    let mapping_entries = nr_blocks.saturating_mul(3) / 2;
+    let mapping_blocks = mapping_entries / BTREE_ENTRIES_PER_NODE
+        + u64::from(mapping_entries % BTREE_ENTRIES_PER_NODE != 0);
+    let space_map_blocks = nr_blocks / SPACE_MAP_ENTRIES_PER_BLOCK
+        + u64::from(nr_blocks % SPACE_MAP_ENTRIES_PER_BLOCK != 0);
+
+    // One superblock, and one btree root for each thin device
+    let estimate = MetaBlocks(
+        1u64.saturating_add(mapping_blocks)
+            .saturating_add(space_map_blocks)
+            .saturating_add(max_thins),
+    );
+
+    Ok(estimate
+        .max(MIN_RECOMMENDED_METADATA_SIZE.metablocks())
+        .min(MAX_META_DEV_SIZE))
 }
 
 #[cfg(test)]
@@ -717,19 +1055,10 @@ use std::fs::OpenOptions;
 
 #[cfg(test)]
 use crate::{
-    consts::IEC,
     lineardev::LinearTargetParams,
     testing::{blkdev_size, test_name},
 };
 
-/// Values are explicitly stated in the device-mapper kernel documentation.
-#[cfg(test)]
-const MIN_DATA_BLOCK_SIZE: Sectors = Sectors(128); // 64 KiB
-#[cfg(test)]
-#[allow(dead_code)]
-const MAX_DATA_BLOCK_SIZE: Sectors = Sectors(2 * IEC::Mi); // 1 GiB
-#[cfg(test)]
-const MIN_RECOMMENDED_METADATA_SIZE: Sectors = Sectors(4 * IEC::Ki); // 2 MiB
 #[cfg(test)]
 #[allow(dead_code)]
 // Note that this value is stated in the kernel docs to be 16 GiB, but the
@@ -738,9 +1067,9 @@ const MIN_RECOMMENDED_METADATA_SIZE: Sectors = Sectors(4 * IEC::Ki); // 2 MiB
 const MAX_METADATA_SIZE: MetaBlocks = MetaBlocks(255 * ((1 << 14) - 64));
 
 #[cfg(test)]
-/// Generate a minimal thinpool dev. Use all the space available not consumed
-/// by the metadata device for the data device.
-pub fn minimal_thinpool(dm: &DM, path: &Path) -> ThinPoolDev {
+// Make the metadata and data devices of a minimal thinpool, both on the
+// device at path.
+fn minimal_thinpool_devs(dm: &DM, path: &Path) -> (LinearDev, LinearDev) {
     let dev_size = blkdev_size(&OpenOptions::new().read(true).open(path).unwrap()).sectors();
     let dev = Device::from(devnode_to_devno(path).unwrap().unwrap());
     let meta_params = LinearTargetParams::new(dev, Sectors(0));
@@ -771,6 +1100,14 @@ pub fn minimal_thinpool(dm: &DM, path: &Path) -> ThinPoolDev {
     )
     .unwrap();
 
+    (meta, data)
+}
+
+#[cfg(test)]
+/// Generate a minimal thinpool dev. Use all the space available not consumed
+/// by the metadata device for the data device.
+pub fn minimal_thinpool(dm: &DM, path: &Path) -> ThinPoolDev {
+    let (meta, data) = minimal_thinpool_devs(dm, path);
     ThinPoolDev::new(
         dm,
         &test_name("pool").expect("valid format"),
@@ -789,11 +1126,17 @@ pub fn minimal_thinpool(dm: &DM, path: &Path) -> ThinPoolDev {
 
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::{
+        fs::OpenOptions,
+        io::{Seek, SeekFrom, Write},
+        path::Path,
+    };
 
     use crate::{
-        core::{errors::Error, DmFlags},
+        core::DmFlags,
+        shared::{typed_status, RawTargetStatus, TargetStatus, TargetType},
         testing::{test_name, test_with_spec},
+        thindev::ThinDev,
     };
 
     use super::*;
@@ -880,7 +1223,7 @@ mod tests {
                     "skip_block_zeroing".to_owned()
                 ],
             ),
-            Err(DmError::Core(Error::Ioctl(_, _, _, _)))
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
         );
         dm.device_remove(&DevId::Name(&meta_name), DmOptions::default())
             .unwrap();
@@ -888,6 +1231,111 @@ mod tests {
             .unwrap();
     }
 
+    /// Verify that reloading discard passdown updates the table, and that
+    /// the effective setting follows a request to disable it.
+    fn test_reload_discard_passdown(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+        assert!(!tp.discard_passdown_requested());
+        assert_eq!(tp.discard_passdown_active(&dm).unwrap(), Some(false));
+
+        let active = tp.reload_discard_passdown(&dm, true).unwrap();
+        assert!(tp.discard_passdown_requested());
+        assert_eq!(active, tp.discard_passdown_active(&dm).unwrap());
+        assert_eq!(tp.verify(&dm).unwrap(), None);
+
+        assert_eq!(tp.reload_discard_passdown(&dm, false).unwrap(), Some(false));
+        assert!(!tp.discard_passdown_requested());
+
+        tp.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_reload_discard_passdown() {
+        test_with_spec(1, test_reload_discard_passdown);
+    }
+
+    /// Verify that typed_status parses a thin pool's status, and passes on
+    /// the status of a linear target as it is.
+    fn test_typed_status(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+
+        let status = typed_status(&dm, &DevId::Name(tp.name()), DmOptions::default()).unwrap();
+        assert_matches!(
+            status.as_slice(),
+            [(Sectors(0), length, TargetStatus::ThinPool(ThinPoolStatus::Working(_)))]
+                if *length == tp.data_dev().size()
+        );
+
+        let status = typed_status(
+            &dm,
+            &DevId::Name(tp.meta_dev().name()),
+            DmOptions::default(),
+        )
+        .unwrap();
+        assert_matches!(
+            status.as_slice(),
+            [(_, _, TargetStatus::Raw(RawTargetStatus { target_type, params }))]
+                if &**target_type == TargetType::LINEAR && params.is_empty()
+        );
+
+        tp.teardown(&dm).unwrap();
+    }
+
+    /// Verify that a pool can not be adopted from devices that do not hold
+    /// one, but can be once it has been created on them.
+    fn test_adopt(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let adopt = |(meta, data)| {
+            ThinPoolDev::adopt(
+                &dm,
+                &test_name("pool").expect("valid format"),
+                None,
+                meta,
+                data,
+                MIN_DATA_BLOCK_SIZE,
+                DataBlocks(1),
+                vec![
+                    "no_discard_passdown".to_owned(),
+                    "skip_block_zeroing".to_owned(),
+                ],
+            )
+        };
+
+        // The metadata device has never been formatted. The linear devices
+        // are left in place, and are set up again by minimal_thinpool,
+        // which formats the metadata device.
+        assert_matches!(
+            adopt(minimal_thinpool_devs(&dm, paths[0])),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+        minimal_thinpool(&dm, paths[0]).teardown(&dm).unwrap();
+
+        let mut tp = adopt(minimal_thinpool_devs(&dm, paths[0])).unwrap();
+        assert_matches!(
+            tp.status(&dm, DmOptions::default()).unwrap(),
+            ThinPoolStatus::Working(_)
+        );
+        tp.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_adopt() {
+        test_with_spec(1, test_adopt);
+    }
+
+    #[test]
+    fn loop_test_typed_status() {
+        test_with_spec(1, test_typed_status);
+    }
+
     #[test]
     fn loop_test_low_data_block_size() {
         test_with_spec(1, test_low_data_block_size);
@@ -917,10 +1365,7 @@ mod tests {
         match tp.status(&dm, DmOptions::default()).unwrap() {
             ThinPoolStatus::Working(ref status) => {
                 let usage = &status.usage;
-                assert_eq!(
-                    *usage.total_data * tp.table().table.params.data_block_size,
-                    2u8 * data_size
-                );
+                assert_eq!(usage.total_data * tp.data_block_size(), 2u8 * data_size);
             }
             ThinPoolStatus::Error => panic!("devicemapper could not obtain thin pool status"),
             ThinPoolStatus::Fail => panic!("thin pool should not have failed"),
@@ -998,7 +1443,7 @@ mod tests {
         let dm = DM::new().unwrap();
         let tp = minimal_thinpool(&dm, paths[0]);
 
-        tp.status(&dm, DmOptions::default().set_flags(DmFlags::DM_NOFLUSH))
+        tp.status(&dm, DmOptions::default().set_no_flush(true))
             .unwrap();
     }
 
@@ -1007,6 +1452,137 @@ mod tests {
         test_with_spec(1, test_status_noflush);
     }
 
+    /// Verify that the blocks written to a thin device are found mapped in
+    /// a snapshot of the pool's metadata, and that the snapshot is released.
+    fn test_thin_mappings(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+        let thin_id = ThinDevId::new_u64(0).expect("is below limit");
+        let mut td = ThinDev::new(
+            &dm,
+            &test_name("thin").expect("valid format"),
+            None,
+            Sectors(16 * IEC::Ki),
+            &tp,
+            thin_id,
+        )
+        .unwrap();
+
+        assert_eq!(
+            tp.thin_mappings(&dm, thin_id).unwrap(),
+            ThinMappings::default()
+        );
+
+        let mut file = OpenOptions::new().write(true).open(td.devnode()).unwrap();
+        file.seek(SeekFrom::Start(3 * MIN_DATA_BLOCK_SIZE.bytes().0 as u64))
+            .unwrap();
+        file.write_all(&[1u8; 4096]).unwrap();
+        file.sync_all().unwrap();
+        drop(file);
+
+        let mappings = tp.thin_mappings(&dm, thin_id).unwrap();
+        assert_eq!(mappings.mapped_blocks(), DataBlocks(1));
+        assert_eq!(mappings.mapped()[0].thin_start, DataBlocks(3));
+        match tp.status(&dm, DmOptions::default()).unwrap() {
+            ThinPoolStatus::Working(status) => assert_eq!(status.held_metadata_root, None),
+            status => panic!("unexpected thin pool status {status:?}"),
+        }
+
+        td.destroy(&dm, &tp).unwrap();
+        tp.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_thin_mappings() {
+        test_with_spec(1, test_thin_mappings);
+    }
+
+    /// Verify that recovering a pool which is in read-write mode leaves it
+    /// so, without changing its table.
+    fn test_recover(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+
+        let dm = DM::new().unwrap();
+        let mut tp = minimal_thinpool(&dm, paths[0]);
+        let table = tp.table().clone();
+
+        assert!(tp.recover(&dm).unwrap());
+        assert_eq!(tp.table(), &table);
+        assert!(!dm
+            .device_info(&DevId::Name(tp.name()))
+            .unwrap()
+            .flags()
+            .is_suspended());
+
+        tp.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    fn loop_test_recover() {
+        test_with_spec(1, test_recover);
+    }
+
+    #[test]
+    fn test_thin_metadata_size() {
+        assert_matches!(
+            thin_metadata_size(Sectors(IEC::Gi), Sectors(0), 1),
+            Err(DmError::Dm(ErrorEnum::Invalid, _))
+        );
+
+        // A small pool gets the recommended minimum
+        assert_eq!(
+            thin_metadata_size(Sectors(2 * IEC::Mi), MIN_DATA_BLOCK_SIZE, 1).unwrap(),
+            MIN_RECOMMENDED_METADATA_SIZE.metablocks()
+        );
+
+        // A huge pool gets no more than the kernel can use
+        assert_eq!(
+            thin_metadata_size(Sectors(u64::MAX), MIN_DATA_BLOCK_SIZE, 1).unwrap(),
+            MAX_META_DEV_SIZE
+        );
+
+        // 1 TiB of 64 KiB blocks
+        let size = thin_metadata_size(Sectors(2 * IEC::Gi), MIN_DATA_BLOCK_SIZE, 1000).unwrap();
+        assert_eq!(size, MetaBlocks(1 + 99_079 + 1_029 + 1000));
+
+        // Larger blocks need less metadata, more thins need more
+        assert!(
+            thin_metadata_size(Sectors(2 * IEC::Gi), 4u64 * MIN_DATA_BLOCK_SIZE, 1000).unwrap()
+                < size
+        );
+        assert!(
+            thin_metadata_size(Sectors(2 * IEC::Gi), MIN_DATA_BLOCK_SIZE, 2000).unwrap() > size
+        );
+    }
+
+    #[test]
+    fn test_validate_data_block_size() {
+        assert!(validate_data_block_size(MIN_DATA_BLOCK_SIZE).is_ok());
+        assert!(validate_data_block_size(MAX_DATA_BLOCK_SIZE).is_ok());
+        assert!(validate_data_block_size(3u64 * MIN_DATA_BLOCK_SIZE).is_ok());
+        for (size, msg) in [
+            (
+                MIN_DATA_BLOCK_SIZE / 2u64,
+                "thin pool data block size 64 sectors is less than the minimum of 128 sectors",
+            ),
+            (
+                MAX_DATA_BLOCK_SIZE + MIN_DATA_BLOCK_SIZE,
+                "thin pool data block size 2097280 sectors is greater than the maximum of 2097152 sectors",
+            ),
+            (
+                MIN_DATA_BLOCK_SIZE + Sectors(64),
+                "thin pool data block size 192 sectors is not a multiple of 128 sectors (65536 bytes)",
+            ),
+        ] {
+            assert_matches!(
+                validate_data_block_size(size),
+                Err(DmError::Dm(ErrorEnum::Invalid, ref err_msg)) if err_msg == msg
+            );
+        }
+    }
+
     #[test]
     fn test_thinpool_target_params_zero() {
         let result = "thin-pool 42:42 42:43 16 2 0"
@@ -1022,4 +1598,44 @@ mod tests {
             .unwrap();
         assert_eq!(result.feature_args, HashSet::new());
     }
+
+    #[test]
+    fn test_thinpool_target_params_escaped() {
+        let params = ThinPoolTargetParams::new(
+            Device {
+                major: 42,
+                minor: 42,
+            },
+            Device {
+                major: 42,
+                minor: 43,
+            },
+            Sectors(16),
+            DataBlocks(2),
+            vec!["odd arg\\".to_owned()],
+        );
+        let param_str = params.param_str();
+        assert_eq!(param_str, "42:42 42:43 16 2 1 odd\\ arg\\\\");
+        assert_eq!(
+            format!("thin-pool {param_str}")
+                .parse::<ThinPoolTargetParams>()
+                .unwrap(),
+            params
+        );
+    }
+
+    #[test]
+    fn test_thinpool_status_lenient() {
+        let status_line = "0 10/512 16/1024 - rw new_passdown queue_if_no_space - 64 new1 new2";
+        assert_matches!(status_line.parse::<ThinPoolStatus>(), Err(_));
+
+        match ThinPoolStatus::from_str_lenient(status_line).unwrap() {
+            ThinPoolStatus::Working(status) => {
+                assert!(!status.discard_passdown);
+                assert_eq!(status.meta_low_water, Some(64));
+                assert_eq!(status.extra, vec!["new_passdown", "new1", "new2"]);
+            }
+            _ => panic!("status should be working"),
+        }
+    }
 }
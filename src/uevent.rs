@@ -0,0 +1,299 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// A listener for the uevents generated by the kernel for DM devices, which
+// does not depend on udev.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::IoSliceMut,
+    os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    path::Path,
+};
+
+use nix::{
+    errno::Errno,
+    sys::socket::{
+        bind, recvmsg, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol,
+        SockType,
+    },
+};
+
+use crate::{
+    core::{errors, Device, DmNameBuf, DmUuidBuf},
+    result::{DmError, DmResult},
+};
+
+/// The netlink multicast group on which the kernel broadcasts uevents.
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+/// Large enough for any uevent; the kernel limits a uevent's environment to
+/// 2 KiB.
+const UEVENT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// The action reported by a uevent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UeventAction {
+    /// The device was created.
+    Add,
+    /// The device changed, e.g. it was resumed or renamed.
+    Change,
+    /// The device was removed.
+    Remove,
+    /// Any other action.
+    Other(String),
+}
+
+impl From<&str> for UeventAction {
+    fn from(action: &str) -> UeventAction {
+        match action {
+            "add" => UeventAction::Add,
+            "change" => UeventAction::Change,
+            "remove" => UeventAction::Remove,
+            other => UeventAction::Other(other.to_owned()),
+        }
+    }
+}
+
+/// A uevent for a DM device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DmUevent {
+    /// The action
+    pub action: UeventAction,
+    /// The DM device
+    pub device: Device,
+    /// The kernel's name for the device, e.g. dm-3
+    pub devname: String,
+    /// The DM name of the device, if known
+    pub name: Option<DmNameBuf>,
+    /// The DM uuid of the device, if it has one and it is known
+    pub uuid: Option<DmUuidBuf>,
+    /// The udev cookie passed with the ioctl that caused the event, if any
+    pub cookie: Option<u32>,
+    /// All the key/value pairs of the uevent
+    pub env: HashMap<String, String>,
+}
+
+/// Listens on the kernel's uevent netlink socket for events on DM devices.
+///
+/// The kernel does not include a DM device's name or uuid in most of its
+/// uevents, so they are read from sysfs when the event is received.
+/// The listener remembers them, so that they can also be reported for the
+/// device's remove event, once sysfs no longer has them.
+///
+/// The listener's file descriptor may be polled, like a DM context's, to
+/// wait for events; see [`UeventListener::try_next_event`].
+#[derive(Debug)]
+pub struct UeventListener {
+    socket: OwnedFd,
+    buffer: Vec<u8>,
+    ids: HashMap<Device, (Option<DmNameBuf>, Option<DmUuidBuf>)>,
+}
+
+impl UeventListener {
+    /// Create a listener, subscribed to kernel uevents.
+    pub fn new() -> DmResult<UeventListener> {
+        let socket = socket(
+            AddressFamily::Netlink,
+            SockType::Datagram,
+            SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkKObjectUEvent,
+        )
+        .map_err(|err| io_error("create uevent socket", err))?;
+        bind(
+            socket.as_raw_fd(),
+            &NetlinkAddr::new(0, KERNEL_UEVENT_GROUP),
+        )
+        .map_err(|err| io_error("bind uevent socket", err))?;
+
+        Ok(UeventListener {
+            socket,
+            buffer: vec![0; UEVENT_BUFFER_SIZE],
+            ids: HashMap::new(),
+        })
+    }
+
+    /// Wait for the next uevent for a DM device.
+    pub fn next_event(&mut self) -> DmResult<DmUevent> {
+        loop {
+            if let Some(event) = self
+                .recv_event(MsgFlags::empty())
+                .map_err(|err| io_error("receive uevent", err))?
+            {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Return the next uevent for a DM device, or None if none is pending.
+    /// Uevents for other devices that are pending are discarded.
+    pub fn try_next_event(&mut self) -> DmResult<Option<DmUevent>> {
+        loop {
+            match self.recv_event(MsgFlags::MSG_DONTWAIT) {
+                Ok(Some(event)) => return Ok(Some(event)),
+                Ok(None) => (),
+                Err(Errno::EAGAIN) => return Ok(None),
+                Err(err) => return Err(io_error("receive uevent", err)),
+            }
+        }
+    }
+
+    /// Receive one uevent. Return None if it is not for a DM device, or if
+    /// it was not sent by the kernel.
+    fn recv_event(&mut self, flags: MsgFlags) -> Result<Option<DmUevent>, Errno> {
+        let (len, sender) = {
+            let mut iov = [IoSliceMut::new(&mut self.buffer)];
+            let msg = recvmsg::<NetlinkAddr>(self.socket.as_raw_fd(), &mut iov, None, flags)?;
+            (msg.bytes, msg.address)
+        };
+        if !from_kernel(sender.as_ref()) {
+            return Ok(None);
+        }
+
+        let (action, env) = match parse_uevent(&self.buffer[..len]) {
+            Some(uevent) => uevent,
+            None => return Ok(None),
+        };
+
+        let device = match dm_device(&env) {
+            Some(device) => device,
+            None => return Ok(None),
+        };
+
+        let action = UeventAction::from(action.as_str());
+        let (name, uuid) = if action == UeventAction::Remove {
+            self.ids.remove(&device).unwrap_or((None, None))
+        } else {
+            let ids = read_ids(device);
+            self.ids.insert(device, ids.clone());
+            ids
+        };
+
+        Ok(Some(DmUevent {
+            action,
+            device,
+            devname: env.get("DEVNAME").cloned().unwrap_or_default(),
+            name,
+            uuid,
+            cookie: env.get("DM_COOKIE").and_then(|c| c.parse().ok()),
+            env,
+        }))
+    }
+}
+
+impl AsFd for UeventListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+impl AsRawFd for UeventListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+fn io_error(action: &str, err: Errno) -> DmError {
+    DmError::Core(errors::Error::GeneralIo(format!(
+        "failed to {action}: {err}"
+    )))
+}
+
+/// Whether a message was sent by the kernel, whose port id is 0. Any local
+/// process may unicast a message to the listener's socket, so a message
+/// from any other sender, like one whose sender is unknown, may be forged.
+fn from_kernel(sender: Option<&NetlinkAddr>) -> bool {
+    sender.map(|addr| addr.pid()) == Some(0)
+}
+
+/// Split a kernel uevent, "<action>@<devpath>" followed by "KEY=value"
+/// pairs, all null-terminated, into its action and its key/value pairs.
+/// Return None if the message is not a kernel uevent.
+fn parse_uevent(msg: &[u8]) -> Option<(String, HashMap<String, String>)> {
+    let mut fields = msg
+        .split(|b| *b == 0)
+        .filter(|field| !field.is_empty())
+        .map(String::from_utf8_lossy);
+
+    let header = fields.next()?;
+    let (action, _) = header.split_once('@')?;
+    let env = fields
+        .filter_map(|field| {
+            field
+                .split_once('=')
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        })
+        .collect::<HashMap<_, _>>();
+    Some((action.to_owned(), env))
+}
+
+/// Return the device a uevent is for, if it is a DM device.
+fn dm_device(env: &HashMap<String, String>) -> Option<Device> {
+    if env.get("SUBSYSTEM").map(String::as_str) != Some("block")
+        || !env.get("DEVNAME")?.starts_with("dm-")
+    {
+        return None;
+    }
+    Some(Device {
+        major: env.get("MAJOR")?.parse().ok()?,
+        minor: env.get("MINOR")?.parse().ok()?,
+    })
+}
+
+/// Read a DM device's name and uuid from sysfs.
+fn read_ids(device: Device) -> (Option<DmNameBuf>, Option<DmUuidBuf>) {
+    let dm_dir = Path::new("/sys/dev/block")
+        .join(device.to_string())
+        .join("dm");
+    let read = |file: &str| {
+        fs::read_to_string(dm_dir.join(file))
+            .ok()
+            .map(|val| val.trim_end().to_owned())
+            .filter(|val| !val.is_empty())
+    };
+    (
+        read("name").and_then(|name| DmNameBuf::new(name).ok()),
+        read("uuid").and_then(|uuid| DmUuidBuf::new(uuid).ok()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uevent() {
+        let msg = b"change@/devices/virtual/block/dm-3\0ACTION=change\0\
+            DEVPATH=/devices/virtual/block/dm-3\0SUBSYSTEM=block\0\
+            DM_COOKIE=4194304\0MAJOR=253\0MINOR=3\0DEVNAME=dm-3\0\
+            DEVTYPE=disk\0SEQNUM=4242\0";
+        let (action, env) = parse_uevent(msg).unwrap();
+        assert_eq!(UeventAction::from(action.as_str()), UeventAction::Change);
+        assert_eq!(env["DM_COOKIE"], "4194304");
+        assert_eq!(
+            dm_device(&env),
+            Some(Device {
+                major: 253,
+                minor: 3
+            })
+        );
+
+        let msg = b"add@/devices/virtual/block/loop0\0ACTION=add\0\
+            SUBSYSTEM=block\0MAJOR=7\0MINOR=0\0DEVNAME=loop0\0";
+        let (_, env) = parse_uevent(msg).unwrap();
+        assert_eq!(dm_device(&env), None);
+
+        assert_eq!(parse_uevent(b"libudev\0\xfe\xed\xca\xfe"), None);
+        assert_eq!(parse_uevent(b""), None);
+    }
+
+    #[test]
+    /// Verify that only messages sent by the kernel are accepted.
+    fn test_from_kernel() {
+        assert!(from_kernel(Some(&NetlinkAddr::new(0, KERNEL_UEVENT_GROUP))));
+        assert!(!from_kernel(Some(&NetlinkAddr::new(4242, 0))));
+        assert!(!from_kernel(None));
+    }
+}
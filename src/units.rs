@@ -16,8 +16,7 @@ const META_BLOCK_SIZE: Sectors = Sectors(8);
 /// DM_SM_METADATA_MAX_BLOCKS.
 /// As far as I can tell, this is not a limit on the size of a designated
 /// metadata device, but instead on the possible usage of that device.
-#[allow(dead_code)]
-const MAX_META_DEV_SIZE: MetaBlocks = MetaBlocks(255 * ((1 << 14) - 64));
+pub(crate) const MAX_META_DEV_SIZE: MetaBlocks = MetaBlocks(255 * ((1 << 14) - 64));
 
 range_u64!(
     /// A type for data blocks
@@ -25,6 +24,28 @@ range_u64!(
     "data blocks"
 );
 
+impl DataBlocks {
+    /// Return the number of Sectors in the DataBlocks, given the size of a
+    /// block, or None if the result would overflow.
+    pub fn checked_sectors(self, block_size: Sectors) -> Option<Sectors> {
+        self.0.checked_mul(*block_size).map(Sectors)
+    }
+}
+
+impl std::ops::Mul<Sectors> for DataBlocks {
+    type Output = Sectors;
+    fn mul(self, rhs: Sectors) -> Sectors {
+        Sectors(self.0 * *rhs)
+    }
+}
+
+impl std::ops::Mul<DataBlocks> for Sectors {
+    type Output = Sectors;
+    fn mul(self, rhs: DataBlocks) -> Sectors {
+        rhs * self
+    }
+}
+
 range_u64!(
     /// A type for meta blocks
     MetaBlocks,
@@ -68,6 +89,36 @@ impl Sectors {
     pub fn metablocks(self) -> MetaBlocks {
         MetaBlocks(self / META_BLOCK_SIZE)
     }
+
+    /// Round these sectors up to the next multiple of alignment.
+    /// Panics if alignment is 0.
+    pub fn round_up_to(self, alignment: Sectors) -> Sectors {
+        match self % alignment {
+            Sectors(0) => self,
+            rem => self + (alignment - rem),
+        }
+    }
+
+    /// Round these sectors down to the previous multiple of alignment.
+    /// Panics if alignment is 0.
+    pub fn round_down_to(self, alignment: Sectors) -> Sectors {
+        self - self % alignment
+    }
+
+    /// Whether these sectors are a multiple of alignment. No number of
+    /// sectors is aligned to an alignment of 0.
+    pub fn is_aligned_to(self, alignment: Sectors) -> bool {
+        alignment != Sectors(0) && self % alignment == Sectors(0)
+    }
+
+    /// The number of blocks of block_size in these sectors. Return None if
+    /// block_size is 0 or the sectors are not a whole number of blocks.
+    pub fn checked_to_blocks(self, block_size: Sectors) -> Option<DataBlocks> {
+        if !self.is_aligned_to(block_size) {
+            return None;
+        }
+        Some(DataBlocks(self / block_size))
+    }
 }
 
 #[cfg(test)]
@@ -87,4 +138,35 @@ mod test {
         let sectors = max_bytes.sectors();
         assert_eq!(sectors, Sectors(0));
     }
+
+    #[test]
+    fn test_blocks_and_sectors() {
+        assert_eq!(DataBlocks(3) * Sectors(128), Sectors(384));
+        assert_eq!(Sectors(128) * DataBlocks(3), Sectors(384));
+        assert_eq!(
+            DataBlocks(3).checked_sectors(Sectors(128)),
+            Some(Sectors(384))
+        );
+        assert_eq!(DataBlocks(u64::MAX).checked_sectors(Sectors(2)), None);
+
+        assert_eq!(
+            Sectors(384).checked_to_blocks(Sectors(128)),
+            Some(DataBlocks(3))
+        );
+        assert_eq!(Sectors(385).checked_to_blocks(Sectors(128)), None);
+        assert_eq!(Sectors(384).checked_to_blocks(Sectors(0)), None);
+    }
+
+    #[test]
+    fn test_alignment() {
+        let alignment = Sectors(8);
+        assert_eq!(Sectors(0).round_up_to(alignment), Sectors(0));
+        assert_eq!(Sectors(1).round_up_to(alignment), Sectors(8));
+        assert_eq!(Sectors(8).round_up_to(alignment), Sectors(8));
+        assert_eq!(Sectors(15).round_down_to(alignment), Sectors(8));
+        assert_eq!(Sectors(16).round_down_to(alignment), Sectors(16));
+        assert!(Sectors(16).is_aligned_to(alignment));
+        assert!(!Sectors(17).is_aligned_to(alignment));
+        assert!(!Sectors(16).is_aligned_to(Sectors(0)));
+    }
 }
@@ -0,0 +1,266 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Inspection and reset of the zones of zoned block devices, such as a
+// dm-zoned device or a linear device mapped onto a zoned disk, through the
+// BLKREPORTZONE and BLKRESETZONE ioctls.
+
+use std::{
+    fs::{File, OpenOptions},
+    mem::size_of,
+    os::unix::io::AsRawFd,
+    path::Path,
+    ptr,
+};
+
+use nix::{errno::Errno, libc::ioctl as nix_ioctl, request_code_readwrite, request_code_write};
+
+use crate::{
+    core::errors,
+    result::{DmError, DmResult},
+    units::Sectors,
+};
+
+/// The ioctl type of the block layer's ioctls.
+const BLK_IOCTL: u8 = 0x12;
+const BLKREPORTZONE: u8 = 130;
+const BLKRESETZONE: u8 = 131;
+
+/// Set in the flags of a zone report if the zones' capacities are valid.
+const BLK_ZONE_REP_CAPACITY: u32 = 1;
+
+/// The number of zones requested from the kernel at a time.
+const ZONES_PER_REPORT: usize = 256;
+
+// From include/uapi/linux/blkzoned.h
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BlkZone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    zone_type: u8,
+    cond: u8,
+    non_seq: u8,
+    reset: u8,
+    resv: [u8; 4],
+    capacity: u64,
+    reserved: [u8; 24],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BlkZoneReport {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct BlkZoneRange {
+    sector: u64,
+    nr_sectors: u64,
+}
+
+const _: () = assert!(size_of::<BlkZone>() == 64);
+const _: () = assert!(size_of::<BlkZoneReport>() == 16);
+
+/// The type of a zone.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ZoneType {
+    /// A zone that may be written randomly
+    Conventional,
+    /// A zone that must be written sequentially
+    SequentialWriteRequired,
+    /// A zone that should be written sequentially
+    SequentialWritePreferred,
+    /// A type unknown to this crate
+    Other(u8),
+}
+
+impl From<u8> for ZoneType {
+    fn from(val: u8) -> ZoneType {
+        match val {
+            1 => ZoneType::Conventional,
+            2 => ZoneType::SequentialWriteRequired,
+            3 => ZoneType::SequentialWritePreferred,
+            val => ZoneType::Other(val),
+        }
+    }
+}
+
+/// The condition of a zone.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ZoneCondition {
+    /// The zone has no write pointer, e.g. it is conventional
+    NotWritePointer,
+    /// The zone is empty
+    Empty,
+    /// The zone was opened by a write
+    ImplicitOpen,
+    /// The zone was opened explicitly
+    ExplicitOpen,
+    /// The zone is partly written and closed
+    Closed,
+    /// The zone is read-only
+    ReadOnly,
+    /// The zone is full
+    Full,
+    /// The zone is offline
+    Offline,
+    /// A condition unknown to this crate
+    Other(u8),
+}
+
+impl From<u8> for ZoneCondition {
+    fn from(val: u8) -> ZoneCondition {
+        match val {
+            0x0 => ZoneCondition::NotWritePointer,
+            0x1 => ZoneCondition::Empty,
+            0x2 => ZoneCondition::ImplicitOpen,
+            0x3 => ZoneCondition::ExplicitOpen,
+            0x4 => ZoneCondition::Closed,
+            0xd => ZoneCondition::ReadOnly,
+            0xe => ZoneCondition::Full,
+            0xf => ZoneCondition::Offline,
+            val => ZoneCondition::Other(val),
+        }
+    }
+}
+
+/// A zone of a zoned block device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Zone {
+    /// The first sector of the zone
+    pub start: Sectors,
+    /// The length of the zone
+    pub length: Sectors,
+    /// The position of the zone's write pointer
+    pub write_pointer: Sectors,
+    /// The sectors of the zone that may be written, if the kernel reports
+    /// it; this may be less than its length.
+    pub capacity: Option<Sectors>,
+    /// The type of the zone
+    pub zone_type: ZoneType,
+    /// The condition of the zone
+    pub condition: ZoneCondition,
+}
+
+fn open_device(devnode: &Path, write: bool) -> DmResult<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open(devnode)
+        .map_err(|err| DmError::Core(errors::Error::MetadataIo(devnode.into(), err.to_string())))
+}
+
+fn zone_error(action: &str, devnode: &Path, err: Errno) -> DmError {
+    DmError::Core(errors::Error::GeneralIo(format!(
+        "failed to {action} of {}: {err}",
+        devnode.display()
+    )))
+}
+
+/// Report the zones of the zoned block device at devnode, beginning with
+/// the zone containing sector start. Return an empty list if the device is
+/// not zoned.
+pub fn report_zones(devnode: &Path, start: Sectors) -> DmResult<Vec<Zone>> {
+    let file = open_device(devnode, false)?;
+    let op = request_code_readwrite!(BLK_IOCTL, BLKREPORTZONE, size_of::<BlkZoneReport>());
+    #[cfg(any(target_os = "android", target_env = "musl"))]
+    let op = op as i32;
+
+    // The report is followed directly by room for the zones. The buffer is
+    // of u64s, so that the report and the zones are aligned.
+    let len = size_of::<BlkZoneReport>() + ZONES_PER_REPORT * size_of::<BlkZone>();
+    let mut buffer = vec![0u64; len / size_of::<u64>()];
+    let mut zones = Vec::new();
+    let mut sector = *start;
+    loop {
+        let report = BlkZoneReport {
+            sector,
+            nr_zones: ZONES_PER_REPORT as u32,
+            flags: 0,
+        };
+        unsafe { ptr::write(buffer.as_mut_ptr() as *mut BlkZoneReport, report) };
+        match Errno::result(unsafe { nix_ioctl(file.as_raw_fd(), op, buffer.as_mut_ptr()) }) {
+            Ok(_) => (),
+            // A device that is not zoned has no zones to report.
+            Err(Errno::ENOTTY) | Err(Errno::EOPNOTSUPP) => return Ok(zones),
+            Err(err) => return Err(zone_error("report zones", devnode, err)),
+        }
+
+        let report = unsafe { ptr::read(buffer.as_ptr() as *const BlkZoneReport) };
+        let nr_zones = (report.nr_zones as usize).min(ZONES_PER_REPORT);
+        if nr_zones == 0 {
+            return Ok(zones);
+        }
+        let capacity_valid = report.flags & BLK_ZONE_REP_CAPACITY != 0;
+        for i in 0..nr_zones {
+            let zone = unsafe {
+                ptr::read(
+                    (buffer.as_ptr() as *const u8)
+                        .add(size_of::<BlkZoneReport>() + i * size_of::<BlkZone>())
+                        as *const BlkZone,
+                )
+            };
+            zones.push(Zone {
+                start: Sectors(zone.start),
+                length: Sectors(zone.len),
+                write_pointer: Sectors(zone.wp),
+                capacity: capacity_valid.then_some(Sectors(zone.capacity)),
+                zone_type: ZoneType::from(zone.zone_type),
+                condition: ZoneCondition::from(zone.cond),
+            });
+        }
+        let last = zones.last().expect("at least one zone was reported");
+        sector = *last.start + *last.length;
+    }
+}
+
+/// Reset the write pointers of the zones of the zoned block device at
+/// devnode from sector start for length sectors, which must be whole
+/// zones. All data in the zones is lost.
+pub fn reset_zones(devnode: &Path, start: Sectors, length: Sectors) -> DmResult<()> {
+    let file = open_device(devnode, true)?;
+    let op = request_code_write!(BLK_IOCTL, BLKRESETZONE, size_of::<BlkZoneRange>());
+    #[cfg(any(target_os = "android", target_env = "musl"))]
+    let op = op as i32;
+
+    let range = BlkZoneRange {
+        sector: *start,
+        nr_sectors: *length,
+    };
+    Errno::result(unsafe { nix_ioctl(file.as_raw_fd(), op, &range) })
+        .map_err(|err| zone_error("reset zones", devnode, err))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::testing::test_with_spec;
+
+    use super::*;
+
+    #[test]
+    fn test_zone_values() {
+        assert_eq!(ZoneType::from(2), ZoneType::SequentialWriteRequired);
+        assert_eq!(ZoneType::from(9), ZoneType::Other(9));
+        assert_eq!(ZoneCondition::from(0xe), ZoneCondition::Full);
+        assert_eq!(ZoneCondition::from(0x5), ZoneCondition::Other(0x5));
+    }
+
+    /// Verify that a device that is not zoned reports no zones.
+    fn test_report_not_zoned(paths: &[&Path]) {
+        assert!(!paths.is_empty());
+        assert!(report_zones(paths[0], Sectors(0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn loop_test_report_not_zoned() {
+        test_with_spec(1, test_report_not_zoned);
+    }
+}